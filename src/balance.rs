@@ -0,0 +1,131 @@
+//! Spreading the payloads to a peer over its active links, the one we requested and the
+//! one the peer requested, for their aggregate bandwidth: in turn, in proportion to
+//! configured weights, or by flow so that the packets of a connection stay in order
+
+use crate::LinkBalance;
+
+/// Picks the link each payload to a peer is sent on
+#[derive(Debug, Default)]
+pub struct Balancer {
+  /// Credit of each link in smooth weighted round robin: each pick adds the weights,
+  /// and the link with the most credit is picked and pays the total back
+  credit: Vec<i64>,
+  /// Number of payloads sent in turn
+  turn: usize
+}
+
+impl Balancer {
+  /// Index of the link to send the next payload on, of links with the given weights;
+  /// `flow` is the hash of the payload's flow, if it is a single IP packet
+  pub fn pick(&mut self, policy: LinkBalance, weights: &[u32], flow: Option<u64>)
+    -> usize
+  {
+    if weights.len() < 2 {
+      return 0
+    }
+    match (policy, flow) {
+      (LinkBalance::Primary, _) => 0,
+      (LinkBalance::RoundRobin, _) => {
+        let link = self.turn % weights.len();
+        self.turn = self.turn.wrapping_add(1);
+        link
+      }
+      (LinkBalance::Flow, Some(flow)) => by_flow(weights, flow),
+      (LinkBalance::Weighted | LinkBalance::Flow, _) => self.weighted(weights)
+    }
+  }
+
+  fn weighted(&mut self, weights: &[u32]) -> usize {
+    if self.credit.len() != weights.len() {
+      self.credit = vec![0; weights.len()];
+    }
+    let total = weights.iter().map(|weight| *weight as i64).sum::<i64>();
+    if total == 0 {
+      return 0
+    }
+    for (credit, weight) in self.credit.iter_mut().zip(weights) {
+      *credit += *weight as i64;
+    }
+    let mut best = 0;
+    for (i, credit) in self.credit.iter().enumerate() {
+      if *credit > self.credit[best] {
+        best = i;
+      }
+    }
+    self.credit[best] -= total;
+    best
+  }
+}
+
+/// Link a flow is pinned to, spreading flows in proportion to the weights
+fn by_flow(weights: &[u32], flow: u64) -> usize {
+  let total = weights.iter().map(|weight| *weight as u64).sum::<u64>();
+  if total == 0 {
+    return 0
+  }
+  let mut point = flow % total;
+  for (i, weight) in weights.iter().enumerate() {
+    if point < *weight as u64 {
+      return i
+    }
+    point -= *weight as u64;
+  }
+  0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn counts(policy: LinkBalance, weights: &[u32], picks: usize) -> Vec<usize> {
+    let mut balancer = Balancer::default();
+    let mut counts = vec![0; weights.len()];
+    for _ in 0..picks {
+      counts[balancer.pick(policy, weights, None)] += 1;
+    }
+    counts
+  }
+
+  #[test]
+  fn weighted_follows_the_weights() {
+    assert_eq!(counts(LinkBalance::Weighted, &[3, 1], 400), [300, 100]);
+    assert_eq!(counts(LinkBalance::Weighted, &[2, 5], 700), [200, 500]);
+    assert_eq!(counts(LinkBalance::Weighted, &[1, 0], 10), [10, 0]);
+  }
+
+  #[test]
+  fn weighted_interleaves_links() {
+    let mut balancer = Balancer::default();
+    let picks = (0..4).map(|_| balancer.pick(LinkBalance::Weighted, &[3, 1], None))
+      .collect::<Vec<_>>();
+    // the lighter link isn't left for the end of each round
+    assert_eq!(picks, [0, 0, 1, 0]);
+  }
+
+  #[test]
+  fn round_robin_ignores_the_weights() {
+    assert_eq!(counts(LinkBalance::RoundRobin, &[3, 1], 100), [50, 50]);
+  }
+
+  #[test]
+  fn primary_and_single_link_use_the_first() {
+    assert_eq!(counts(LinkBalance::Primary, &[1, 1], 10), [10, 0]);
+    assert_eq!(counts(LinkBalance::Weighted, &[1], 10), [10]);
+  }
+
+  #[test]
+  fn flows_stay_on_one_link() {
+    let mut balancer = Balancer::default();
+    for flow in [0, 7, 12345, u64::MAX] {
+      let link = balancer.pick(LinkBalance::Flow, &[3, 1], Some(flow));
+      for _ in 0..10 {
+        assert_eq!(balancer.pick(LinkBalance::Flow, &[3, 1], Some(flow)), link);
+      }
+    }
+    let mut counts = [0; 2];
+    for flow in 0..400 {
+      counts[balancer.pick(LinkBalance::Flow, &[3, 1], Some(flow))] += 1;
+    }
+    assert_eq!(counts, [300, 100]);
+  }
+}
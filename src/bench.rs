@@ -0,0 +1,101 @@
+//! Echo requests sent over a peer's link: goodput, loss and round trip times as
+//! measured by `rns-vpn bench`, and single round trips for `rns-vpn ping`
+
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use reticulum::destination::link::LinkId;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// Wait this long for outstanding replies after the last request was sent
+pub const REPLY_TIMEOUT_SECS: u64 = 5;
+pub const MAX_COUNT: u32 = 100_000;
+
+/// Echo requests awaiting replies: each benchmark or ping takes a range of sequence
+/// numbers of its own, so that several can run at once
+#[derive(Default)]
+pub struct EchoWaiters {
+  next_seq: u64,
+  waiters: Vec<(Range<u64>, UnboundedSender<(u64, Instant)>)>
+}
+
+impl EchoWaiters {
+  /// Take `count` sequence numbers, returning them with the receiver of their replies
+  /// and arrival times
+  pub fn register(&mut self, count: u64)
+    -> (Range<u64>, UnboundedReceiver<(u64, Instant)>)
+  {
+    let seqs = self.next_seq..self.next_seq + count;
+    self.next_seq = seqs.end;
+    let (reply_tx, reply_rx) = tokio::sync::mpsc::unbounded_channel();
+    self.waiters.push((seqs.clone(), reply_tx));
+    (seqs, reply_rx)
+  }
+
+  pub fn unregister(&mut self, seqs: &Range<u64>) {
+    self.waiters.retain(|(waiter_seqs, _)| waiter_seqs != seqs);
+  }
+
+  /// Hand a reply to whoever sent the request
+  pub fn reply(&self, seq: u64, received: Instant) {
+    if let Some((_, reply_tx)) = self.waiters.iter().find(|(seqs, _)| seqs.contains(&seq)) {
+      let _ = reply_tx.send((seq, received));
+    }
+  }
+}
+
+/// Outcome of a ping
+pub enum Ping {
+  /// The peer has no link
+  NoLink,
+  /// The peer's link was requested but hasn't been activated
+  LinkInactive(LinkId),
+  /// No reply within `REPLY_TIMEOUT_SECS`
+  Unresponsive(LinkId),
+  /// Round trip time of the reply
+  Reply(LinkId, Duration)
+}
+
+pub struct Report {
+  pub sent: u64,
+  pub received: u64,
+  /// Bytes of each echo request
+  pub size: usize,
+  /// From sending the first request to receiving the last reply
+  pub duration: Duration,
+  pub rtt_min: Option<Duration>,
+  pub rtt_avg: Option<Duration>,
+  pub rtt_max: Option<Duration>
+}
+
+impl Report {
+  pub fn new(sent: u64, size: usize, rtts: &[Duration], duration: Duration) -> Self {
+    let received = rtts.len() as u64;
+    Report {
+      sent,
+      received,
+      size,
+      duration,
+      rtt_min: rtts.iter().min().copied(),
+      rtt_avg: (received > 0).then(|| rtts.iter().sum::<Duration>() / received as u32),
+      rtt_max: rtts.iter().max().copied()
+    }
+  }
+
+  /// Fraction of requests left unanswered
+  pub fn loss(&self) -> f64 {
+    if self.sent == 0 {
+      return 0.0
+    }
+    1.0 - self.received as f64 / self.sent as f64
+  }
+
+  /// Echoed bytes per second, in kilobits
+  pub fn goodput_kbps(&self) -> f64 {
+    let secs = self.duration.as_secs_f64();
+    if secs == 0.0 {
+      return 0.0
+    }
+    (self.received * self.size as u64) as f64 * 8.0 / 1000.0 / secs
+  }
+}
@@ -0,0 +1,160 @@
+//! Builder for embedding the client in other applications: the config, identity,
+//! transport and tun settings are given up front, and the returned handle runs the client
+//! until it is shut down
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use rand_core::OsRng;
+use reticulum::hash::AddressHash;
+use reticulum::identity::PrivateIdentity;
+use reticulum::transport::{Transport, TransportConfig};
+use tokio_util::sync::CancellationToken;
+
+use crate::{Client, Config, CreateClientError, DeviceMode, PeerCallback, PeerEvent,
+  RunError};
+
+/// Name the transport created by the builder goes by
+const TRANSPORT_NAME: &str = "server";
+
+pub struct ClientBuilder {
+  config: Config,
+  identity: Option<PrivateIdentity>,
+  transport: Option<Transport>,
+  shutdown: CancellationToken,
+  config_path: Option<PathBuf>,
+  peer_callbacks: Vec<PeerCallback>
+}
+
+impl ClientBuilder {
+  pub fn new(config: Config) -> Self {
+    ClientBuilder {
+      config,
+      identity: None,
+      transport: None,
+      shutdown: CancellationToken::new(),
+      config_path: None,
+      peer_callbacks: Vec::new()
+    }
+  }
+
+  /// Identity the client announces its destination with (default: a new random identity,
+  /// so the destination hash changes on every run)
+  pub fn identity(mut self, identity: PrivateIdentity) -> Self {
+    self.identity = Some(identity);
+    self
+  }
+
+  /// Use a transport set up by the application, created with the same identity, instead
+  /// of one with the config's interfaces
+  pub fn transport(mut self, transport: Transport) -> Self {
+    self.transport = Some(transport);
+    self
+  }
+
+  /// Stop the client when `shutdown` is cancelled, e.g. along with the rest of the
+  /// application; the client doesn't handle signals itself
+  pub fn shutdown_token(mut self, shutdown: CancellationToken) -> Self {
+    self.shutdown = shutdown;
+    self
+  }
+
+  /// Config file to write peers added or removed with `persist` to
+  pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+    self.config_path = Some(path.into());
+    self
+  }
+
+  /// Same as setting `tun_name` in the config
+  pub fn tun_name(mut self, name: impl Into<String>) -> Self {
+    self.config.tun_name = Some(name.into());
+    self
+  }
+
+  /// Same as setting `mtu` in the config
+  pub fn mtu(mut self, mtu: u16) -> Self {
+    self.config.mtu = mtu;
+    self
+  }
+
+  /// Same as setting `mode` in the config
+  pub fn mode(mut self, mode: DeviceMode) -> Self {
+    self.config.mode = mode;
+    self
+  }
+
+  /// Call `callback` whenever the link to a peer comes up or goes down
+  pub fn on_peer_event(mut self,
+    callback: impl Fn(PeerEvent, IpAddr, AddressHash) + Send + Sync + 'static
+  ) -> Self {
+    self.peer_callbacks.push(Box::new(callback));
+    self
+  }
+
+  /// Set up the tun device and the transport
+  pub async fn build(self) -> Result<ClientHandle, CreateClientError> {
+    if self.transport.is_some() && self.identity.is_none() {
+      return Err(CreateClientError::ConfigError("a transport requires the identity it \
+        was created with".to_owned()))
+    }
+    let mut config = self.config;
+    if let Some(path) = config.reticulum_config.clone() {
+      crate::rns_config::add_interfaces(&mut config.interfaces, &path)
+        .map_err(CreateClientError::ConfigError)?;
+    }
+    let interfaces = config.interfaces.clone();
+    let mut client = Client::new(config).await?;
+    client.peer_callbacks = self.peer_callbacks;
+    client.config_path = self.config_path;
+    let identity = self.identity.unwrap_or_else(|| PrivateIdentity::new_from_rand(OsRng));
+    let transport = match self.transport {
+      Some(transport) => transport,
+      None => {
+        let transport = Transport::new(TransportConfig::new(TRANSPORT_NAME, &identity,
+          true));
+        let mut spawned = Vec::new();
+        for (name, interface) in interfaces.iter() {
+          if let Some(iface) = interface.spawn(&transport, name).await {
+            spawned.push((name.clone(), iface));
+          }
+        }
+        client.interfaces = spawned;
+        transport
+      }
+    };
+    Ok(ClientHandle {
+      client,
+      shutdown: self.shutdown,
+      run: std::sync::Mutex::new(Some((transport, identity)))
+    })
+  }
+}
+
+/// Client built by `ClientBuilder`
+pub struct ClientHandle {
+  client: Client,
+  shutdown: CancellationToken,
+  /// Taken by `start`
+  run: std::sync::Mutex<Option<(Transport, PrivateIdentity)>>
+}
+
+impl ClientHandle {
+  /// Run the client until `shutdown` is called, the shutdown token is cancelled or a
+  /// loop fails for good; a client only runs once
+  pub async fn start(&self) -> Result<(), RunError> {
+    let Some((transport, identity)) = self.run.lock().unwrap().take() else {
+      return Err(RunError::AlreadyStarted)
+    };
+    self.client.run(transport, identity, self.shutdown.clone()).await
+  }
+
+  /// Make `start` return, also if called before it
+  pub fn shutdown(&self) {
+    self.client.shutdown();
+  }
+
+  /// The client, to manage its peers while it runs
+  pub fn client(&self) -> &Client {
+    &self.client
+  }
+}
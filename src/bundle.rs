@@ -0,0 +1,132 @@
+//! Peer bundles: a node's public keys, tunnel address and allowed IPs signed with its
+//! signing key, as an uppercase hex string (usable as a QR code in alphanumeric mode)
+//! that other nodes import as a peer
+//!
+//! Layout: version, X25519 public key, ed25519 verifying key, tunnel address, number of
+//! allowed networks, the networks, ed25519 signature over all of the preceding bytes.
+//! Addresses are a family byte (4 or 6) followed by the address bytes, networks an
+//! address followed by the prefix length.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+use reticulum::hash::AddressHash;
+use reticulum::identity::{Identity, PrivateIdentity};
+
+use crate::PeerIdentity;
+
+const PREFIX: &str = "RNSVPN:";
+const VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// An imported bundle
+pub struct Bundle {
+  /// Destination hash derived from the keys in the bundle
+  pub dest: AddressHash,
+  /// Public keys in the bundle, for pinning the peer's identity
+  pub identity: PeerIdentity,
+  pub ip: IpAddr,
+  pub allowed_ips: Vec<IpNet>
+}
+
+/// Bundle of the given identity with its tunnel address and allowed IPs
+pub fn export(id: &PrivateIdentity, ip: IpAddr, allowed_ips: &[IpNet])
+  -> Result<String, String>
+{
+  if allowed_ips.len() > u8::MAX as usize {
+    return Err(format!("a peer bundle holds at most {} allowed IPs", u8::MAX))
+  }
+  let mut bytes = vec![VERSION];
+  bytes.extend_from_slice(id.as_identity().public_key_bytes());
+  bytes.extend_from_slice(id.as_identity().verifying_key_bytes());
+  push_addr(&mut bytes, ip);
+  bytes.push(allowed_ips.len() as u8);
+  for net in allowed_ips.iter() {
+    push_addr(&mut bytes, net.addr());
+    bytes.push(net.prefix_len());
+  }
+  let signature = id.sign(&bytes).to_bytes();
+  bytes.extend_from_slice(&signature);
+  let hex = bytes.iter().map(|byte| format!("{byte:02X}")).collect::<String>();
+  Ok(format!("{PREFIX}{hex}"))
+}
+
+/// Decode a bundle and check its signature
+pub fn import(s: &str) -> Result<Bundle, String> {
+  let hex = s.trim().strip_prefix(PREFIX)
+    .ok_or_else(|| format!("not a peer bundle: missing {PREFIX} prefix"))?;
+  if hex.len() % 2 != 0 {
+    return Err("invalid peer bundle: odd number of hex digits".to_owned())
+  }
+  let bytes = (0..hex.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|_| "invalid peer bundle: not hex".to_owned())?;
+  let Some(signed_len) = bytes.len().checked_sub(SIGNATURE_LEN) else {
+    return Err("invalid peer bundle: too short".to_owned())
+  };
+  let (signed, signature) = bytes.split_at(signed_len);
+  let mut reader = Reader(signed);
+  let version = reader.take(1)?[0];
+  if version != VERSION {
+    return Err(format!("unsupported peer bundle version {version}"))
+  }
+  let public_key = reader.take(KEY_LEN)?;
+  let verifying_key = reader.take(KEY_LEN)?;
+  let ip = reader.addr()?;
+  let count = reader.take(1)?[0];
+  let allowed_ips = (0..count)
+    .map(|_| {
+      let addr = reader.addr()?;
+      let prefix_len = reader.take(1)?[0];
+      IpNet::new(addr, prefix_len).map_err(|_| "invalid peer bundle: bad prefix".to_owned())
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+  if !reader.0.is_empty() {
+    return Err("invalid peer bundle: trailing bytes".to_owned())
+  }
+  let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(
+    verifying_key.try_into().unwrap())
+    .map_err(|_| "invalid peer bundle: bad verifying key".to_owned())?;
+  let signature = ed25519_dalek::Signature::from_bytes(signature.try_into().unwrap());
+  verifying_key.verify_strict(signed, &signature)
+    .map_err(|_| "peer bundle signature does not match".to_owned())?;
+  let identity = PeerIdentity::new(
+    &Identity::new_from_slices(public_key, verifying_key.as_bytes()));
+  Ok(Bundle { dest: identity.destination_hash(), identity, ip, allowed_ips })
+}
+
+fn push_addr(bytes: &mut Vec<u8>, addr: IpAddr) {
+  match addr {
+    IpAddr::V4(addr) => {
+      bytes.push(4);
+      bytes.extend_from_slice(&addr.octets());
+    }
+    IpAddr::V6(addr) => {
+      bytes.push(6);
+      bytes.extend_from_slice(&addr.octets());
+    }
+  }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    if self.0.len() < len {
+      return Err("invalid peer bundle: too short".to_owned())
+    }
+    let (taken, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(taken)
+  }
+
+  fn addr(&mut self) -> Result<IpAddr, String> {
+    match self.take(1)?[0] {
+      4 => Ok(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(self.take(4)?).unwrap()))),
+      6 => Ok(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(self.take(16)?).unwrap()))),
+      family => Err(format!("invalid peer bundle: unknown address family {family}"))
+    }
+  }
+}
@@ -0,0 +1,304 @@
+//! JSON-RPC 2.0 management API served on a Unix control socket
+//!
+//! Each request and response is one line of JSON. Methods:
+//!
+//! * `peers` -- list peers with their link state and counters
+//! * `add_peer` -- `{"ip": <ip>, "peer": <peer settings>, "persist": <bool>}`: add a
+//!   peer, also to the config file with `persist`
+//! * `remove_peer` -- `{"ip": <ip>, "persist": <bool>}`: remove a peer, closing its
+//!   link, also from the config file with `persist`
+//! * `stats` -- client-wide counters
+//! * `bench` -- `{"ip": <ip>, "count": <n>, "size": <bytes>}`: send echo requests to a
+//!   peer over its link and report goodput, loss and round trip times
+//! * `ping` -- `{"ip": <ip>}`: send one echo request to a peer over its link and report
+//!   the link state and round trip time
+//! * `shutdown` -- shut the client down
+//! * `subscribe` -- send events on this connection from now on, each as an `event`
+//!   notification: `peer_linked`, `peer_activated` and `peer_closed` with the peer's
+//!   `ip`, `dest` and `link_id`, `packet_dropped` with the `reason` and the peer's `dest`
+//!   if known, and `tun_error` with the `error`
+
+use std::net::IpAddr;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use reticulum::transport::Transport;
+
+use crate::{bench, Client, Event, PeerConfig, PeerLink};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Request was valid but the client couldn't carry it out
+const REQUEST_FAILED: i64 = -32000;
+
+const fn default_bench_count() -> u32 { 100 }
+const fn default_bench_size() -> usize { 1000 }
+
+#[derive(Deserialize)]
+struct Request {
+  jsonrpc: String,
+  #[serde(default)]
+  id: Value,
+  method: String,
+  #[serde(default)]
+  params: Value
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddPeerParams {
+  ip: IpAddr,
+  peer: PeerConfig,
+  #[serde(default)]
+  persist: bool
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RemovePeerParams {
+  ip: IpAddr,
+  #[serde(default)]
+  persist: bool
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PeerParams {
+  ip: IpAddr
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BenchParams {
+  ip: IpAddr,
+  #[serde(default = "default_bench_count")]
+  count: u32,
+  #[serde(default = "default_bench_size")]
+  size: usize
+}
+
+/// Error returned to the caller
+struct Error {
+  code: i64,
+  message: String
+}
+
+impl Error {
+  fn new(code: i64, message: impl Into<String>) -> Self {
+    Error { code, message: message.into() }
+  }
+}
+
+/// Serve requests on a control connection until it is closed, and events once the
+/// connection subscribed to them
+pub async fn serve(client: &Client, transport: &Transport, stream: tokio::net::UnixStream) {
+  let (reader, mut writer) = stream.into_split();
+  let mut lines = BufReader::new(reader).lines();
+  let mut events = None;
+  loop {
+    let (response, subscribed) = tokio::select! {
+      line = lines.next_line() => {
+        let line = match line {
+          Ok(Some(line)) => line,
+          Ok(None) => break,
+          Err(err) => {
+            tracing::debug!("control connection read error: {err:?}");
+            break
+          }
+        };
+        if line.trim().is_empty() {
+          continue
+        }
+        let response = handle(client, transport, &line).await;
+        let subscribed = serde_json::from_str::<Request>(&line)
+          .is_ok_and(|request| request.method == "subscribe")
+          && response.get("result").is_some();
+        (response, subscribed)
+      }
+      Some(event) = recv_event(&mut events), if events.is_some() =>
+        (json!({"jsonrpc": "2.0", "method": "event", "params": event}), false)
+    };
+    if subscribed && events.is_none() {
+      events = Some(client.events());
+    }
+    let mut response = response.to_string();
+    response.push('\n');
+    if let Err(err) = writer.write_all(response.as_bytes()).await {
+      tracing::debug!("control connection write error: {err:?}");
+      break
+    }
+  }
+}
+
+/// Next event as JSON; `None` after missing events because the connection fell behind
+async fn recv_event(events: &mut Option<tokio::sync::broadcast::Receiver<Event>>)
+  -> Option<Value>
+{
+  use tokio::sync::broadcast::error::RecvError;
+  match events.as_mut()?.recv().await {
+    Ok(event) => Some(event_json(&event)),
+    Err(RecvError::Lagged(missed)) => {
+      tracing::debug!(missed, "control connection missed events");
+      None
+    }
+    // the client is gone
+    Err(RecvError::Closed) => std::future::pending().await
+  }
+}
+
+/// Handle one JSON-RPC request line, returning the response
+pub async fn handle(client: &Client, transport: &Transport, line: &str) -> Value {
+  let request = match serde_json::from_str::<Request>(line) {
+    Ok(request) => request,
+    Err(err) => {
+      let code = if serde_json::from_str::<Value>(line).is_ok() {
+        INVALID_REQUEST
+      } else {
+        PARSE_ERROR
+      };
+      return error_response(Value::Null, Error::new(code, err.to_string()))
+    }
+  };
+  if request.jsonrpc != "2.0" {
+    return error_response(request.id, Error::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""))
+  }
+  tracing::debug!("control request: {}", request.method);
+  match dispatch(client, transport, &request.method, request.params).await {
+    Ok(result) => json!({"jsonrpc": "2.0", "id": request.id, "result": result}),
+    Err(err) => error_response(request.id, err)
+  }
+}
+
+async fn dispatch(client: &Client, transport: &Transport, method: &str, params: Value)
+  -> Result<Value, Error>
+{
+  match method {
+    "peers" => Ok(Value::Array(client.peer_links().await.iter().map(peer_json).collect())),
+    "add_peer" => {
+      let params = parse_params::<AddPeerParams>(params)?;
+      client.add_peer(params.ip, params.peer, params.persist).await
+        .map_err(|err| Error::new(REQUEST_FAILED, err.to_string()))?;
+      Ok(Value::Null)
+    }
+    "remove_peer" => {
+      let params = parse_params::<RemovePeerParams>(params)?;
+      let removed = client.remove_peer(params.ip, params.persist).await
+        .map_err(|err| Error::new(REQUEST_FAILED, err.to_string()))?;
+      if !removed {
+        return Err(Error::new(REQUEST_FAILED, format!("no peer {}", params.ip)))
+      }
+      Ok(Value::Null)
+    }
+    "stats" => {
+      let peers = client.peer_links().await;
+      let mut stats = serde_json::Map::new();
+      for (name, _, value) in client.metrics.counters() {
+        stats.insert(name.to_owned(), value.into());
+      }
+      stats.insert("peers".to_owned(), peers.len().into());
+      stats.insert("links_active".to_owned(),
+        peers.iter().filter(|peer| peer.link_active).count().into());
+      Ok(Value::Object(stats))
+    }
+    "bench" => {
+      let params = parse_params::<BenchParams>(params)?;
+      let report = client.bench(transport, params.ip, params.count, params.size).await
+        .map_err(|err| Error::new(REQUEST_FAILED, err))?;
+      Ok(json!({
+        "sent": report.sent,
+        "received": report.received,
+        "size": report.size,
+        "duration_secs": report.duration.as_secs_f64(),
+        "loss": report.loss(),
+        "goodput_kbps": report.goodput_kbps(),
+        "rtt_min_ms": report.rtt_min.map(|rtt| rtt.as_secs_f64() * 1000.0),
+        "rtt_avg_ms": report.rtt_avg.map(|rtt| rtt.as_secs_f64() * 1000.0),
+        "rtt_max_ms": report.rtt_max.map(|rtt| rtt.as_secs_f64() * 1000.0)
+      }))
+    }
+    "ping" => {
+      let params = parse_params::<PeerParams>(params)?;
+      let ping = client.ping(transport, params.ip).await
+        .map_err(|err| Error::new(REQUEST_FAILED, err))?;
+      let link_id = |link_id| format!("{link_id}").trim_matches('/').to_owned();
+      Ok(match ping {
+        bench::Ping::NoLink => json!({"state": "no_link"}),
+        bench::Ping::LinkInactive(id) =>
+          json!({"state": "link_inactive", "link_id": link_id(id)}),
+        bench::Ping::Unresponsive(id) =>
+          json!({"state": "unresponsive", "link_id": link_id(id)}),
+        bench::Ping::Reply(id, rtt) => json!({"state": "reply", "link_id": link_id(id),
+          "rtt_ms": rtt.as_secs_f64() * 1000.0})
+      })
+    }
+    "shutdown" => {
+      client.shutdown();
+      Ok(Value::Null)
+    }
+    // events are sent by `serve` once it sees the subscription succeeded
+    "subscribe" => Ok(Value::Null),
+    _ => Err(Error::new(METHOD_NOT_FOUND, format!("unknown method {method}")))
+  }
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, Error> {
+  serde_json::from_value(params).map_err(|err| Error::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn error_response(id: Value, err: Error) -> Value {
+  json!({
+    "jsonrpc": "2.0",
+    "id": id,
+    "error": {"code": err.code, "message": err.message}
+  })
+}
+
+fn peer_json(peer: &PeerLink) -> Value {
+  json!({
+    "ip": peer.ip.addr(),
+    "name": peer.name,
+    "dest": format!("{}", peer.dest).trim_matches('/'),
+    "link_active": peer.link_active,
+    "link_id": peer.link_id.map(|link_id| format!("{link_id}").trim_matches('/').to_owned()),
+    "mtu": peer.mtu,
+    "tx_packets": peer.tx_packets,
+    "tx_bytes": peer.tx_bytes,
+    "rx_packets": peer.rx_packets,
+    "rx_bytes": peer.rx_bytes,
+    "since_last_packet_secs": peer.since_last_packet.map(|since| since.as_secs_f64()),
+    "since_last_seen_secs": peer.since_last_seen.map(|since| since.as_secs_f64()),
+    "links_established": peer.links_established,
+    "quality": peer.quality.to_string(),
+    "rtt_ms": peer.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+    "loss": peer.loss,
+    "tx_rate_kbps": peer.tx_rate_kbps,
+    "rx_rate_kbps": peer.rx_rate_kbps,
+    "drops": {
+      "too_large": peer.drops.too_large,
+      "rate_limited": peer.drops.rate_limited,
+      "no_link": peer.drops.no_link,
+      "spoofed": peer.drops.spoofed
+    }
+  })
+}
+
+fn event_json(event: &Event) -> Value {
+  let hash = |value: &dyn std::fmt::Display| {
+    format!("{value}").trim_matches('/').to_owned()
+  };
+  match event {
+    Event::PeerLinked { ip, dest, link_id } => json!({"event": "peer_linked", "ip": ip,
+      "dest": hash(dest), "link_id": hash(link_id)}),
+    Event::PeerActivated { ip, dest, link_id } => json!({"event": "peer_activated",
+      "ip": ip, "dest": hash(dest), "link_id": hash(link_id)}),
+    Event::PeerClosed { ip, dest, link_id } => json!({"event": "peer_closed", "ip": ip,
+      "dest": hash(dest), "link_id": hash(link_id)}),
+    Event::PacketDropped { dest, reason } => json!({"event": "packet_dropped",
+      "dest": dest.as_ref().map(|dest| hash(dest)), "reason": reason.as_str()}),
+    Event::TunError { error } => json!({"event": "tun_error", "error": error})
+  }
+}
@@ -0,0 +1,18 @@
+//! Peer discovery: tunnel addresses carried as comma-separated text in announce app
+//! data
+
+use std::net::IpAddr;
+
+/// Encode tunnel addresses as announce app data
+pub fn encode(addresses: &[IpAddr]) -> Vec<u8> {
+  addresses.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(",").into_bytes()
+}
+
+/// Decode tunnel addresses from announce app data
+pub fn decode(app_data: &[u8]) -> Option<Vec<IpAddr>> {
+  let s = std::str::from_utf8(app_data).ok()?;
+  let addresses = s.split(',')
+    .map(|ip| ip.trim().parse().ok())
+    .collect::<Option<Vec<IpAddr>>>()?;
+  (!addresses.is_empty()).then_some(addresses)
+}
@@ -0,0 +1,94 @@
+//! Tunnel DNS servers and search domains (Linux)
+//!
+//! Applied to the tun device with `resolvectl` when systemd-resolved is running,
+//! otherwise with `resolvconf`, and removed again on shutdown.
+
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+
+use crate::CreateClientError;
+
+/// Present while systemd-resolved is running
+const RESOLVED_DIR: &str = "/run/systemd/resolve";
+
+/// DNS settings applied for the tun device, removed by `cleanup`
+pub struct Dns {
+  tun_name: String,
+  resolved: bool
+}
+
+impl Dns {
+  /// Use the servers and search domains for names resolved through the tun device
+  pub fn apply(tun_name: &str, servers: &[IpAddr], domains: &[String])
+    -> Result<Self, CreateClientError>
+  {
+    let resolved = std::path::Path::new(RESOLVED_DIR).is_dir();
+    let dns = Dns { tun_name: tun_name.to_owned(), resolved };
+    tracing::info!(?servers, ?domains, "configuring tunnel dns with {}",
+      if resolved { "systemd-resolved" } else { "resolvconf" });
+    let result = if resolved {
+      let mut dns_args = vec!["dns".to_owned(), tun_name.to_owned()];
+      dns_args.extend(servers.iter().map(IpAddr::to_string));
+      let mut domain_args = vec!["domain".to_owned(), tun_name.to_owned()];
+      domain_args.extend(domains.iter().cloned());
+      run("resolvectl", &dns_args).and_then(|()| {
+        if domains.is_empty() { Ok(()) } else { run("resolvectl", &domain_args) }
+      })
+    } else {
+      let mut conf = String::new();
+      for server in servers.iter() {
+        conf.push_str(&format!("nameserver {server}\n"));
+      }
+      if !domains.is_empty() {
+        conf.push_str(&format!("search {}\n", domains.join(" ")));
+      }
+      resolvconf(&["-a", tun_name], Some(&conf))
+    };
+    if let Err(err) = result {
+      dns.cleanup();
+      return Err(CreateClientError::DnsError(err))
+    }
+    Ok(dns)
+  }
+
+  /// Remove the DNS settings of the tun device
+  pub fn cleanup(&self) {
+    tracing::debug!("removing tunnel dns");
+    let result = if self.resolved {
+      run("resolvectl", &["revert".to_owned(), self.tun_name.clone()])
+    } else {
+      resolvconf(&["-d", &self.tun_name], None)
+    };
+    if let Err(err) = result {
+      tracing::warn!(error = ?err, "failed to remove tunnel dns");
+    }
+  }
+}
+
+fn run(program: &str, args: &[String]) -> Result<(), std::io::Error> {
+  tracing::debug!("{} {}", program, args.join(" "));
+  let output = Command::new(program).args(args).output()?;
+  check_output(program, output)
+}
+
+/// Add (`-a`) an interface's resolv.conf read from stdin, or delete (`-d`) it
+fn resolvconf(args: &[&str], conf: Option<&str>) -> Result<(), std::io::Error> {
+  tracing::debug!("resolvconf {}", args.join(" "));
+  let stdin = if conf.is_some() { Stdio::piped() } else { Stdio::null() };
+  let mut child = Command::new("resolvconf").args(args).stdin(stdin)
+    .stdout(Stdio::null()).stderr(Stdio::piped()).spawn()?;
+  if let Some(conf) = conf {
+    child.stdin.take().unwrap().write_all(conf.as_bytes())?;
+  }
+  check_output("resolvconf", child.wait_with_output()?)
+}
+
+fn check_output(program: &str, output: std::process::Output) -> Result<(), std::io::Error> {
+  if output.status.success() {
+    Ok(())
+  } else {
+    Err(std::io::Error::other(format!("{program} failed: {}",
+      String::from_utf8_lossy(&output.stderr).trim())))
+  }
+}
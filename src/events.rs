@@ -0,0 +1,68 @@
+//! Lifecycle events of peers, their links and the tun, broadcast to whoever subscribed
+//! with `Client::events`; a subscriber falling behind misses the oldest events
+
+use std::net::IpAddr;
+
+use reticulum::destination::link::LinkId;
+use reticulum::hash::AddressHash;
+
+/// Events kept for subscribers that haven't received them yet
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum Event {
+  /// A link to the peer was requested
+  PeerLinked { ip: IpAddr, dest: AddressHash, link_id: LinkId },
+  /// The link to the peer came up
+  PeerActivated { ip: IpAddr, dest: AddressHash, link_id: LinkId },
+  /// The link to the peer closed, or was closed for being idle or the peer not responding
+  PeerClosed { ip: IpAddr, dest: AddressHash, link_id: LinkId },
+  /// A packet was dropped, to or from the peer with this destination if known
+  PacketDropped { dest: Option<AddressHash>, reason: DropReason },
+  /// Reading from or writing to the tun failed
+  TunError { error: String }
+}
+
+/// Why a packet was dropped
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum DropReason {
+  /// Larger than the peer's MTU
+  TooLarge,
+  /// Over the peer's or the global rate limit
+  RateLimited,
+  /// No active link to the peer it is routed to
+  NoLink,
+  /// Not routed to any peer
+  NoRoute,
+  /// Dropped from a full tun or peer queue
+  QueueFull,
+  /// Denied by the filter rules
+  Filtered,
+  /// Received from an unauthorized or unauthenticated link
+  Unauthorized,
+  /// Received with a source address belonging to another peer
+  Spoofed,
+  /// Received again, or without a sequence number on a sequenced link
+  Replayed,
+  /// Received from a peer but not an IP packet
+  Malformed
+}
+
+impl DropReason {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DropReason::TooLarge => "too_large",
+      DropReason::RateLimited => "rate_limited",
+      DropReason::NoLink => "no_link",
+      DropReason::NoRoute => "no_route",
+      DropReason::QueueFull => "queue_full",
+      DropReason::Filtered => "filtered",
+      DropReason::Unauthorized => "unauthorized",
+      DropReason::Spoofed => "spoofed",
+      DropReason::Replayed => "replayed",
+      DropReason::Malformed => "malformed"
+    }
+  }
+}
@@ -0,0 +1,218 @@
+//! Forward error correction with XOR parity: after each group of payloads sent to a
+//! peer, a parity frame carrying the XOR of their lengths and of their bytes (zero
+//! padded to the longest) lets the receiver rebuild any single payload of the group
+//! that was lost
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+use crate::frame;
+use crate::pool::{BufferPool, PooledBuf};
+
+/// Groups kept for recovery on each link; the oldest is dropped when exceeded
+const MAX_GROUPS: usize = 4;
+
+/// Parity of the payloads sent to a peer
+pub struct Encoder {
+  group_size: u8,
+  group: u16,
+  count: u8,
+  len_parity: u16,
+  parity: Vec<u8>
+}
+
+impl Encoder {
+  pub fn new(group_size: u8) -> Self {
+    Encoder { group_size, group: 0, count: 0, len_parity: 0, parity: Vec::new() }
+  }
+
+  /// Frame a payload as FEC data, returning the parity frame of its group too if it
+  /// completes the group; the frames are built in buffers from the pool
+  pub fn encode(&mut self, payload: &[u8], buffers: &Arc<BufferPool>)
+    -> (PooledBuf, Option<PooledBuf>)
+  {
+    let mut data = buffers.take();
+    frame::fec_data(self.group, self.count, payload, &mut data);
+    xor_into(&mut self.parity, payload);
+    self.len_parity ^= payload.len() as u16;
+    self.count += 1;
+    if self.count < self.group_size {
+      return (data, None)
+    }
+    let mut parity = buffers.take();
+    frame::fec_parity(self.group, self.count, self.len_parity, &self.parity, &mut parity);
+    self.group = self.group.wrapping_add(1);
+    self.reset();
+    (data, Some(parity))
+  }
+
+  /// Start a new group, e.g. on a new link, without sending parity for the current one
+  pub fn reset(&mut self) {
+    self.count = 0;
+    self.len_parity = 0;
+    self.parity.clear();
+  }
+}
+
+/// Payloads received so far of one group
+#[derive(Default)]
+struct Group {
+  payloads: BTreeMap<u8, Vec<u8>>
+}
+
+/// Recent groups received on one link
+#[derive(Default)]
+pub struct Decoder {
+  groups: BTreeMap<u16, Group>,
+  /// Group numbers in the order they were first seen
+  order: VecDeque<u16>
+}
+
+impl Decoder {
+  /// Record a received payload; returns false if it was received or recovered before
+  pub fn data(&mut self, group: u16, index: u8, payload: &[u8]) -> bool {
+    let payloads = &mut self.group(group).payloads;
+    if payloads.contains_key(&index) {
+      return false
+    }
+    payloads.insert(index, payload.to_vec());
+    true
+  }
+
+  /// Rebuild the payload of a group that was lost from its parity, if exactly one was
+  pub fn parity(&mut self, group: u16, count: u8, len_parity: u16, parity: &[u8])
+    -> Option<Vec<u8>>
+  {
+    let payloads = &mut self.group(group).payloads;
+    if payloads.len() + 1 != count as usize {
+      return None
+    }
+    let missing = (0..count).find(|index| !payloads.contains_key(index))?;
+    let mut payload = parity.to_vec();
+    let mut len = len_parity;
+    for received in payloads.values() {
+      xor_into(&mut payload, received);
+      len ^= received.len() as u16;
+    }
+    if len as usize > payload.len() {
+      return None
+    }
+    payload.truncate(len as usize);
+    payloads.insert(missing, payload.clone());
+    Some(payload)
+  }
+
+  fn group(&mut self, group: u16) -> &mut Group {
+    if !self.groups.contains_key(&group) {
+      if self.order.len() >= MAX_GROUPS {
+        if let Some(oldest) = self.order.pop_front() {
+          self.groups.remove(&oldest);
+        }
+      }
+      self.order.push_back(group);
+    }
+    self.groups.entry(group).or_default()
+  }
+}
+
+/// XOR `bytes` into `parity`, extending it with zeros as needed
+fn xor_into(parity: &mut Vec<u8>, bytes: &[u8]) {
+  if parity.len() < bytes.len() {
+    parity.resize(bytes.len(), 0);
+  }
+  for (parity, byte) in parity.iter_mut().zip(bytes) {
+    *parity ^= byte;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::frame::Frame;
+
+  const PAYLOADS: [&[u8]; 3] = [b"first payload", b"second", b"the third payload"];
+
+  /// Frames of one group of `PAYLOADS`, with the parity frame last
+  fn encode() -> Vec<PooledBuf> {
+    let buffers = BufferPool::new(64);
+    let mut encoder = Encoder::new(PAYLOADS.len() as u8);
+    let mut frames = Vec::new();
+    for payload in PAYLOADS {
+      let (data, parity) = encoder.encode(payload, &buffers);
+      frames.push(data);
+      frames.extend(parity);
+    }
+    frames
+  }
+
+  /// Feed frames to a decoder, returning the payloads it recovered
+  fn decode(decoder: &mut Decoder, frames: &[PooledBuf]) -> Vec<Vec<u8>> {
+    let mut recovered = Vec::new();
+    for frame in frames {
+      match Frame::parse(frame) {
+        Some(Frame::FecData(body)) => {
+          let (group, index, payload) = frame::parse_fec_data(body).unwrap();
+          decoder.data(group, index, payload);
+        }
+        Some(Frame::FecParity(body)) => {
+          let (group, count, len_parity, parity) = frame::parse_fec_parity(body).unwrap();
+          recovered.extend(decoder.parity(group, count, len_parity, parity));
+        }
+        _ => panic!("not an FEC frame")
+      }
+    }
+    recovered
+  }
+
+  #[test]
+  fn parity_completes_group() {
+    let frames = encode();
+    assert_eq!(frames.len(), PAYLOADS.len() + 1);
+    assert!(matches!(Frame::parse(frames.last().unwrap()), Some(Frame::FecParity(_))));
+  }
+
+  #[test]
+  fn recovers_each_lost_payload() {
+    for lost in 0..PAYLOADS.len() {
+      let mut frames = encode();
+      frames.remove(lost);
+      let recovered = decode(&mut Decoder::default(), &frames);
+      assert_eq!(recovered, [PAYLOADS[lost].to_vec()]);
+    }
+  }
+
+  #[test]
+  fn nothing_to_recover() {
+    assert!(decode(&mut Decoder::default(), &encode()).is_empty());
+  }
+
+  #[test]
+  fn two_lost_payloads_are_not_recovered() {
+    let mut frames = encode();
+    frames.drain(..2);
+    assert!(decode(&mut Decoder::default(), &frames).is_empty());
+  }
+
+  #[test]
+  fn recovered_payload_is_a_duplicate() {
+    let mut frames = encode();
+    let lost = frames.remove(1);
+    let mut decoder = Decoder::default();
+    decode(&mut decoder, &frames);
+    let Some(Frame::FecData(body)) = Frame::parse(&lost) else { panic!() };
+    let (group, index, payload) = frame::parse_fec_data(body).unwrap();
+    assert!(!decoder.data(group, index, payload));
+  }
+
+  #[test]
+  fn groups_are_numbered() {
+    let buffers = BufferPool::new(64);
+    let mut encoder = Encoder::new(1);
+    let groups: Vec<u16> = (0..3).map(|_| {
+      let (data, _) = encoder.encode(b"payload", &buffers);
+      let Some(Frame::FecData(body)) = Frame::parse(&data) else { panic!() };
+      frame::parse_fec_data(body).unwrap().0
+    }).collect();
+    assert_eq!(groups, [0, 1, 2]);
+  }
+}
@@ -0,0 +1,68 @@
+//! Allow/deny rules from the `[filter]` config section, evaluated on packets read from
+//! the tun and on packets received from peers
+
+use crate::{FilterAction, FilterConfig, FilterDirection, FilterProtocol, FilterRule, PacketInfo};
+
+impl FilterConfig {
+  /// Whether there are rules that could drop a packet
+  pub fn is_active(&self) -> bool {
+    !self.rules.is_empty() || self.default == FilterAction::Deny
+  }
+
+  /// Whether the first rule matching the packet, or the default action if no rule
+  /// matches, allows it; packets that can't be parsed only match rules without
+  /// conditions
+  pub fn allows(&self, direction: FilterDirection, packet: Option<&PacketInfo>) -> bool {
+    let action = self.rules.iter()
+      .find(|rule| rule.matches(direction, packet))
+      .map_or(self.default, |rule| rule.action);
+    action == FilterAction::Allow
+  }
+
+  /// Problems with the rules
+  pub fn problems(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (i, rule) in self.rules.iter().enumerate() {
+      if rule.port.is_some()
+        && !matches!(rule.proto, Some(FilterProtocol::Tcp | FilterProtocol::Udp))
+      {
+        problems.push(format!("filter rule {}: port requires proto \"tcp\" or \"udp\"",
+          i + 1));
+      }
+      if let (Some(src), Some(dst)) = (rule.src, rule.dst) {
+        if src.addr().is_ipv4() != dst.addr().is_ipv4() {
+          problems.push(format!("filter rule {}: src and dst are of different address \
+            families", i + 1));
+        }
+      }
+    }
+    problems
+  }
+}
+
+impl FilterRule {
+  fn matches(&self, direction: FilterDirection, packet: Option<&PacketInfo>) -> bool {
+    if self.direction.is_some_and(|rule_direction| rule_direction != direction) {
+      return false
+    }
+    let Some(packet) = packet else {
+      return self.src.is_none() && self.dst.is_none() && self.proto.is_none()
+        && self.port.is_none()
+    };
+    if self.src.is_some_and(|src| !src.contains(&packet.source))
+      || self.dst.is_some_and(|dst| !dst.contains(&packet.destination))
+    {
+      return false
+    }
+    let proto_matches = match self.proto {
+      None => true,
+      Some(FilterProtocol::Tcp) => packet.protocol == etherparse::IpNumber::TCP,
+      Some(FilterProtocol::Udp) => packet.protocol == etherparse::IpNumber::UDP,
+      Some(FilterProtocol::Icmp) => packet.protocol == etherparse::IpNumber::ICMP
+        || packet.protocol == etherparse::IpNumber::IPV6_ICMP
+    };
+    proto_matches && self.port.is_none_or(|port| {
+      packet.ports.is_some_and(|(_, destination_port)| destination_port == port)
+    })
+  }
+}
@@ -0,0 +1,169 @@
+//! Forwarding and masquerading for peers using this client as their exit node or
+//! gateway (Linux)
+//!
+//! Rules are installed with nftables in a table of their own, falling back to iptables
+//! when `nft` is not available, and removed again on shutdown.
+
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+
+use crate::{CreateClientError, FirewallBackend};
+
+const IPV4_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+const IPV6_FORWARD: &str = "/proc/sys/net/ipv6/conf/all/forwarding";
+const NFT_TABLE: &str = "rns_vpn";
+
+/// Firewall state installed by the client, undone by `cleanup`
+pub struct Firewall {
+  tun_name: String,
+  nftables: bool,
+  /// Forwarding sysctls with the value they had before being enabled
+  forwarding: std::sync::Mutex<Vec<(&'static str, String)>>,
+  /// Whether the nftables table has been created
+  nft_table: std::sync::Mutex<bool>,
+  /// iptables rules as (program, table, rule args)
+  iptables_rules: std::sync::Mutex<Vec<(&'static str, &'static str, Vec<String>)>>
+}
+
+impl Firewall {
+  pub fn new(tun_name: &str, backend: FirewallBackend) -> Result<Self, CreateClientError> {
+    let nftables = match backend {
+      FirewallBackend::Nftables => true,
+      FirewallBackend::Iptables => false,
+      FirewallBackend::Auto => {
+        let available = Command::new("nft").arg("--version").output()
+          .is_ok_and(|output| output.status.success());
+        if !available {
+          tracing::info!("nft not available: using iptables");
+        }
+        available
+      }
+    };
+    Ok(Firewall {
+      tun_name: tun_name.to_owned(),
+      nftables,
+      forwarding: std::sync::Mutex::new(Vec::new()),
+      nft_table: std::sync::Mutex::new(false),
+      iptables_rules: std::sync::Mutex::new(Vec::new())
+    })
+  }
+
+  /// Enable IP forwarding for the address family, remembering the previous setting
+  pub fn enable_forwarding(&self, ipv6: bool) -> Result<(), CreateClientError> {
+    let path = if ipv6 { IPV6_FORWARD } else { IPV4_FORWARD };
+    let previous = std::fs::read_to_string(path).map_err(CreateClientError::SysctlError)?;
+    if previous.trim() == "1" {
+      return Ok(())
+    }
+    tracing::info!("enabling ip forwarding: {}", path);
+    std::fs::write(path, "1").map_err(CreateClientError::SysctlError)?;
+    self.forwarding.lock().unwrap().push((path, previous.trim().to_owned()));
+    Ok(())
+  }
+
+  /// Forward traffic from a peer address to other networks and back, masquerading it
+  /// as it leaves through devices other than the tun
+  pub fn allow_exit(&self, source: IpAddr) -> Result<(), CreateClientError> {
+    tracing::info!("forwarding and masquerading traffic from {}", source);
+    if self.nftables {
+      self.nft_allow_exit(source)
+    } else {
+      self.iptables_allow_exit(source)
+    }
+  }
+
+  fn nft_allow_exit(&self, source: IpAddr) -> Result<(), CreateClientError> {
+    let mut nft_table = self.nft_table.lock().unwrap();
+    let mut script = String::new();
+    if !*nft_table {
+      // declaring the table first makes deleting it succeed when it doesn't exist, so
+      // a table left behind by an unclean shutdown is replaced
+      script.push_str(&format!("table inet {NFT_TABLE}\n\
+        delete table inet {NFT_TABLE}\n\
+        table inet {NFT_TABLE} {{\n\
+          chain forward {{ type filter hook forward priority filter; }}\n\
+          chain postrouting {{ type nat hook postrouting priority srcnat; }}\n\
+        }}\n"));
+    }
+    let family = if source.is_ipv6() { "ip6" } else { "ip" };
+    let tun = &self.tun_name;
+    script.push_str(&format!(
+      "add rule inet {NFT_TABLE} forward iifname \"{tun}\" {family} saddr {source} accept\n\
+      add rule inet {NFT_TABLE} forward oifname \"{tun}\" {family} daddr {source} \
+        ct state established,related accept\n\
+      add rule inet {NFT_TABLE} postrouting {family} saddr {source} oifname != \"{tun}\" \
+        masquerade\n"));
+    nft(&script).map_err(CreateClientError::NftablesError)?;
+    *nft_table = true;
+    Ok(())
+  }
+
+  fn iptables_allow_exit(&self, source: IpAddr) -> Result<(), CreateClientError> {
+    let program = if source.is_ipv6() { "ip6tables" } else { "iptables" };
+    let source = source.to_string();
+    let tun = self.tun_name.as_str();
+    let rules: [(&str, Vec<&str>); 3] = [
+      ("filter", vec!["FORWARD", "-i", tun, "-s", &source, "-j", "ACCEPT"]),
+      ("filter", vec!["FORWARD", "-o", tun, "-d", &source, "-m", "conntrack", "--ctstate",
+        "RELATED,ESTABLISHED", "-j", "ACCEPT"]),
+      ("nat", vec!["POSTROUTING", "-s", &source, "!", "-o", tun, "-j", "MASQUERADE"])
+    ];
+    for (table, rule) in rules {
+      let rule = rule.into_iter().map(str::to_owned).collect::<Vec<_>>();
+      // insert forwarding rules ahead of a restrictive FORWARD chain
+      let action = if table == "filter" { "-I" } else { "-A" };
+      iptables(program, table, action, &rule).map_err(CreateClientError::IptablesError)?;
+      self.iptables_rules.lock().unwrap().push((program, table, rule));
+    }
+    Ok(())
+  }
+
+  /// Remove the installed rules and restore the forwarding settings
+  pub fn cleanup(&self) {
+    if std::mem::take(&mut *self.nft_table.lock().unwrap()) {
+      tracing::debug!("removing nftables table {}", NFT_TABLE);
+      if let Err(err) = nft(&format!("delete table inet {NFT_TABLE}\n")) {
+        tracing::warn!("failed to remove nftables table {}: {:?}", NFT_TABLE, err);
+      }
+    }
+    for (program, table, rule) in std::mem::take(&mut *self.iptables_rules.lock().unwrap()) {
+      if let Err(err) = iptables(program, table, "-D", &rule) {
+        tracing::warn!("failed to remove {} rule {:?}: {:?}", program, rule, err);
+      }
+    }
+    for (path, previous) in std::mem::take(&mut *self.forwarding.lock().unwrap()) {
+      tracing::debug!("restoring {} to {}", path, previous);
+      if let Err(err) = std::fs::write(path, previous) {
+        tracing::warn!("failed to restore {}: {:?}", path, err);
+      }
+    }
+  }
+}
+
+/// Apply an nftables script atomically
+fn nft(script: &str) -> Result<(), std::io::Error> {
+  tracing::debug!("nft -f -\n{}", script);
+  let mut child = Command::new("nft").args(["-f", "-"]).stdin(Stdio::piped())
+    .stdout(Stdio::null()).stderr(Stdio::piped()).spawn()?;
+  child.stdin.take().unwrap().write_all(script.as_bytes())?;
+  check_output("nft", child.wait_with_output()?)
+}
+
+/// Add (`-A`), insert (`-I`) or delete (`-D`) a rule
+fn iptables(program: &str, table: &str, action: &str, rule: &[String])
+  -> Result<(), std::io::Error>
+{
+  tracing::debug!("{} -t {} {} {}", program, table, action, rule.join(" "));
+  let output = Command::new(program).args(["-t", table, action]).args(rule).output()?;
+  check_output(program, output)
+}
+
+fn check_output(program: &str, output: std::process::Output) -> Result<(), std::io::Error> {
+  if output.status.success() {
+    Ok(())
+  } else {
+    Err(std::io::Error::other(format!("{program} failed: {}",
+      String::from_utf8_lossy(&output.stderr).trim())))
+  }
+}
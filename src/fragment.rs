@@ -0,0 +1,204 @@
+//! Reassembly of packets fragmented to fit in link packets
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+use reticulum::destination::link::LinkId;
+
+/// Packets being reassembled at once; the oldest is dropped when exceeded
+const MAX_PENDING: usize = 256;
+/// Packets being reassembled at once from one link; the link's oldest is dropped when
+/// exceeded, so that one link can't push out the packets of others
+const MAX_PENDING_PER_LINK: usize = 32;
+
+static NEXT_FRAGMENT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// ID for the fragments of the next fragmented packet
+pub fn next_id() -> u16 {
+  NEXT_FRAGMENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Fragments received so far of one packet
+struct Partial {
+  fragments: Vec<Option<Vec<u8>>>,
+  received: usize,
+  started: Instant
+}
+
+/// Packets being reassembled, keyed by the link they arrive on and their fragment ID
+#[derive(Default)]
+pub struct Reassembler {
+  pending: BTreeMap<(LinkId, u16), Partial>
+}
+
+impl Reassembler {
+  /// Add a fragment, returning the packet once all of its fragments have arrived
+  pub fn push(&mut self, link_id: LinkId, id: u16, index: u8, count: u8, data: &[u8])
+    -> Option<Vec<u8>>
+  {
+    if !self.pending.contains_key(&(link_id, id)) {
+      let link_pending = self.pending.range((link_id, 0)..=(link_id, u16::MAX));
+      if link_pending.count() >= MAX_PENDING_PER_LINK {
+        self.drop_oldest(Some(link_id));
+      } else if self.pending.len() >= MAX_PENDING {
+        self.drop_oldest(None);
+      }
+    }
+    let partial = self.pending.entry((link_id, id)).or_insert_with(|| Partial {
+      fragments: vec![None; count as usize],
+      received: 0,
+      started: Instant::now()
+    });
+    if partial.fragments.len() != count as usize {
+      tracing::warn!("link {} fragment {} count changed: dropping packet", link_id, id);
+      self.pending.remove(&(link_id, id));
+      return None
+    }
+    let fragment = &mut partial.fragments[index as usize];
+    if fragment.is_none() {
+      *fragment = Some(data.to_vec());
+      partial.received += 1;
+    }
+    if partial.received < partial.fragments.len() {
+      return None
+    }
+    let partial = self.pending.remove(&(link_id, id))?;
+    Some(partial.fragments.into_iter().flatten().flatten().collect())
+  }
+
+  /// Drop the oldest packet being reassembled from the link, or from any link
+  fn drop_oldest(&mut self, link_id: Option<LinkId>) {
+    let oldest = self.pending.iter()
+      .filter(|((from, _), _)| link_id.is_none_or(|link_id| *from == link_id))
+      .min_by_key(|(_, partial)| partial.started)
+      .map(|(key, _)| *key);
+    if let Some(oldest) = oldest {
+      tracing::debug!("dropping incomplete packet {} from link {}: too many pending",
+        oldest.1, oldest.0);
+      self.pending.remove(&oldest);
+    }
+  }
+
+  /// Drop packets still missing fragments after the timeout
+  pub fn expire(&mut self, timeout: Duration) {
+    self.pending.retain(|(link_id, id), partial| {
+      let keep = partial.started.elapsed() < timeout;
+      if !keep {
+        tracing::debug!("dropping incomplete packet {} from link {}: timed out", id, link_id);
+      }
+      keep
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::frame::{self, Frame};
+
+  fn link(n: u8) -> LinkId {
+    LinkId::new_from_slice(&[n; 16])
+  }
+
+  /// ID, index, count and data of each fragment of `payload`
+  fn fragments(payload: &[u8], max_len: usize) -> Vec<(u16, u8, u8, Vec<u8>)> {
+    frame::fragments(payload, 7, max_len).unwrap().iter().map(|fragment| {
+      let Some(Frame::Fragment(body)) = Frame::parse(fragment) else { panic!() };
+      let (id, index, count, data) = frame::parse_fragment(body).unwrap();
+      (id, index, count, data.to_vec())
+    }).collect()
+  }
+
+  fn payload() -> Vec<u8> {
+    (0..=255).cycle().take(1000).collect()
+  }
+
+  #[test]
+  fn fragments_fit() {
+    let frames = frame::fragments(&payload(), 7, 300).unwrap();
+    assert_eq!(frames.len(), 4);
+    assert!(frames.iter().all(|frame| frame.len() <= 300));
+  }
+
+  #[test]
+  fn too_many_fragments() {
+    assert!(frame::fragments(&payload(), 7, frame::FRAGMENT_HEADER_LEN + 3).is_none());
+  }
+
+  #[test]
+  fn reassembles_in_any_order() {
+    let mut fragments = fragments(&payload(), 300);
+    fragments.reverse();
+    let mut reassembler = Reassembler::default();
+    let (last, rest) = fragments.split_last().unwrap();
+    for (id, index, count, data) in rest {
+      assert!(reassembler.push(link(1), *id, *index, *count, data).is_none());
+    }
+    let (id, index, count, data) = last;
+    assert_eq!(reassembler.push(link(1), *id, *index, *count, data), Some(payload()));
+    assert!(reassembler.pending.is_empty());
+  }
+
+  #[test]
+  fn duplicate_fragment_does_not_complete() {
+    let fragments = fragments(&payload(), 600);
+    let (id, index, count, data) = &fragments[0];
+    let mut reassembler = Reassembler::default();
+    assert!(reassembler.push(link(1), *id, *index, *count, data).is_none());
+    assert!(reassembler.push(link(1), *id, *index, *count, data).is_none());
+  }
+
+  #[test]
+  fn links_are_kept_apart() {
+    let fragments = fragments(&payload(), 600);
+    let mut reassembler = Reassembler::default();
+    let (id, index, count, data) = &fragments[0];
+    assert!(reassembler.push(link(1), *id, *index, *count, data).is_none());
+    let (id, index, count, data) = &fragments[1];
+    assert!(reassembler.push(link(2), *id, *index, *count, data).is_none());
+    assert_eq!(reassembler.pending.len(), 2);
+  }
+
+  #[test]
+  fn count_change_drops_packet() {
+    let mut reassembler = Reassembler::default();
+    assert!(reassembler.push(link(1), 7, 0, 2, b"a").is_none());
+    assert!(reassembler.push(link(1), 7, 1, 3, b"b").is_none());
+    assert!(reassembler.pending.is_empty());
+  }
+
+  #[test]
+  fn expires_incomplete_packets() {
+    let mut reassembler = Reassembler::default();
+    assert!(reassembler.push(link(1), 7, 0, 2, b"a").is_none());
+    reassembler.expire(Duration::from_secs(60));
+    assert_eq!(reassembler.pending.len(), 1);
+    reassembler.expire(Duration::ZERO);
+    assert!(reassembler.pending.is_empty());
+  }
+
+  #[test]
+  fn drops_oldest_when_full() {
+    let mut reassembler = Reassembler::default();
+    for n in 0..=(MAX_PENDING / MAX_PENDING_PER_LINK) as u8 {
+      for id in 0..MAX_PENDING_PER_LINK as u16 {
+        assert!(reassembler.push(link(n), id, 0, 2, b"a").is_none());
+      }
+    }
+    assert_eq!(reassembler.pending.len(), MAX_PENDING);
+    assert!(!reassembler.pending.contains_key(&(link(0), 0)));
+  }
+
+  #[test]
+  fn drops_oldest_of_link_over_its_limit() {
+    let mut reassembler = Reassembler::default();
+    assert!(reassembler.push(link(1), 0, 0, 2, b"a").is_none());
+    for id in 0..=MAX_PENDING_PER_LINK as u16 {
+      assert!(reassembler.push(link(2), id, 0, 2, b"a").is_none());
+    }
+    assert_eq!(reassembler.pending.len(), MAX_PENDING_PER_LINK + 1);
+    assert!(reassembler.pending.contains_key(&(link(1), 0)));
+    assert!(!reassembler.pending.contains_key(&(link(2), 0)));
+  }
+}
@@ -0,0 +1,589 @@
+//! Framing of link payloads
+//!
+//! Tunneled IP packets are sent as-is: the high nibble of their first byte is the IP
+//! version (4 or 6). Control frames start with a type byte whose high nibble is
+//! neither, so they are never mistaken for IP packets and are never written to the tun.
+
+use std::net::IpAddr;
+
+use bytes::BytesMut;
+
+use crate::ADDRESS_HASH_LEN;
+
+/// Gratuitous hello sent on a freshly activated link; discarded on receipt
+pub const HELLO: u8 = 0x01;
+/// Batch of coalesced IP packets, each prefixed with its length as a big-endian u16
+pub const BATCH: u8 = 0x02;
+
+/// Keepalive sent on an otherwise idle link to keep underlay NAT mappings fresh;
+/// discarded on receipt
+pub const KEEPALIVE: u8 = 0x03;
+
+/// Request for a tunnel address lease from a hub, followed by the destination hash
+/// of the requester
+pub const LEASE_REQUEST: u8 = 0x04;
+/// Leased tunnel address sent by a hub: prefix length followed by the 4 or 16
+/// address bytes
+pub const LEASE_OFFER: u8 = 0x05;
+
+/// Tunnel MTU of the sender as a big-endian u16 followed by its destination hash, sent
+/// on a freshly activated link so both peers use the smaller MTU of the two
+pub const MTU: u8 = 0x06;
+
+/// Fragment of a packet too large for a single link packet: fragment ID as a
+/// big-endian u16, fragment index and fragment count, followed by the fragment data
+pub const FRAGMENT: u8 = 0x07;
+
+/// Compression algorithm supported by the sender followed by its destination hash,
+/// sent on a freshly activated link; the peer compresses payloads to the sender only
+/// if it has compression enabled too
+pub const COMPRESSION: u8 = 0x08;
+/// Compressed IP packet or batch: algorithm, uncompressed length as a big-endian u16,
+/// then the compressed data
+pub const COMPRESSED: u8 = 0x09;
+
+/// Ethernet frame tunneled in tap mode
+pub const ETHERNET: u8 = 0x0A;
+
+/// Request to sequence payloads followed by the destination hash of the sender, sent
+/// on a freshly activated link by a peer with replay protection enabled
+pub const SEQUENCING: u8 = 0x0B;
+/// Sequenced payload: sequence number as a big-endian u64, starting at 1 on each link,
+/// then the IP packet, batch, compressed or Ethernet frame
+pub const SEQUENCED: u8 = 0x0C;
+
+/// Echo request sent by a benchmark: sequence number as a big-endian u64 followed by
+/// padding; answered with an echo reply carrying the same body
+pub const ECHO_REQUEST: u8 = 0x0D;
+pub const ECHO_REPLY: u8 = 0x0E;
+
+/// Peers of a hub with active links, sent by a hub in mesh mode: for each, its
+/// destination hash, the length of its tunnel address (4 or 16) and the address
+pub const MESH_PEERS: u8 = 0x0F;
+
+/// Subnets the sender can reach and asks to have routed to it, replacing any it sent
+/// before on the link: for each, its prefix length, the length of its address (4 or
+/// 16) and the address
+pub const ROUTES: u8 = 0x10;
+
+/// Peers known to the sender, sent on a freshly activated link by a peer with peer
+/// exchange enabled: same entries as a mesh peers frame
+pub const PEX: u8 = 0x11;
+
+/// Random challenge sent on an inbound link identified as a peer's that has a psk;
+/// no packets are accepted on the link until it is answered
+pub const PSK_CHALLENGE: u8 = 0x12;
+/// HMAC of a psk challenge keyed by the psk, sent on the link the challenge came from
+pub const PSK_RESPONSE: u8 = 0x13;
+
+/// Payload protected by forward error correction: group number as a big-endian u16
+/// and index in the group, then the payload
+pub const FEC_DATA: u8 = 0x14;
+/// Parity of a group of FEC payloads: group number as a big-endian u16, number of
+/// payloads in the group, XOR of their lengths as a big-endian u16, then the XOR of
+/// the payloads
+pub const FEC_PARITY: u8 = 0x15;
+
+/// Random challenge sent on an inbound link once its mtu frame claims a destination;
+/// the link is not taken as the destination's until it is answered
+pub const IDENTIFY_CHALLENGE: u8 = 0x16;
+/// Answer to an identify challenge: X25519 public key and ed25519 verifying key of the
+/// sender's identity, then its ed25519 signature of the challenge and the link ID
+pub const IDENTIFY_RESPONSE: u8 = 0x17;
+
+/// LZ4 block compression
+pub const LZ4: u8 = 0x01;
+
+/// Bytes added to a batch for each packet
+pub const BATCH_PACKET_OVERHEAD: usize = 2;
+/// Bytes added to each fragment
+pub const FRAGMENT_HEADER_LEN: usize = 5;
+/// Bytes added to a sequenced payload
+pub const SEQUENCE_HEADER_LEN: usize = 9;
+/// Smallest echo frame
+pub const ECHO_HEADER_LEN: usize = 9;
+/// Bytes added to an FEC payload
+pub const FEC_DATA_HEADER_LEN: usize = 4;
+/// Bytes of the body of an identify response
+pub const IDENTIFY_RESPONSE_LEN: usize = 128;
+/// Bytes of the largest mesh peers or peer exchange entry
+pub const PEER_MAX_LEN: usize = ADDRESS_HASH_LEN + 17;
+/// Bytes of the largest routes entry
+pub const ROUTE_MAX_LEN: usize = 18;
+
+/// A parsed link payload
+pub enum Frame<'a> {
+  /// IP packet to be written to the tun
+  Ip(&'a [u8]),
+  Hello,
+  /// Body of a batch frame; split with `split_batch`
+  Batch(&'a [u8]),
+  Keepalive,
+  /// Body of a lease request
+  LeaseRequest(&'a [u8]),
+  /// Body of a lease offer; parse with `parse_lease_offer`
+  LeaseOffer(&'a [u8]),
+  /// Body of an MTU frame; parse with `parse_mtu`
+  Mtu(&'a [u8]),
+  /// Body of a fragment; parse with `parse_fragment`
+  Fragment(&'a [u8]),
+  /// Body of a compression capability frame; parse with `parse_compression`
+  Compression(&'a [u8]),
+  /// Body of a compressed frame; unpack with `decompress`
+  Compressed(&'a [u8]),
+  /// Ethernet frame to be written to the tap
+  Ethernet(&'a [u8]),
+  /// Body of a sequencing request: the destination hash of the sender
+  Sequencing(&'a [u8]),
+  /// Body of a sequenced payload; parse with `parse_sequenced`
+  Sequenced(&'a [u8]),
+  /// Body of an echo request, returned in the reply
+  EchoRequest(&'a [u8]),
+  /// Body of an echo reply; parse with `parse_echo`
+  EchoReply(&'a [u8]),
+  /// Body of a mesh peers frame; parse with `parse_peers`
+  MeshPeers(&'a [u8]),
+  /// Body of a routes frame; parse with `parse_routes`
+  Routes(&'a [u8]),
+  /// Body of a peer exchange frame; parse with `parse_peers`
+  Pex(&'a [u8]),
+  /// Body of a psk challenge: the challenge
+  PskChallenge(&'a [u8]),
+  /// Body of a psk response: the HMAC
+  PskResponse(&'a [u8]),
+  /// Body of an FEC payload; parse with `parse_fec_data`
+  FecData(&'a [u8]),
+  /// Body of an FEC parity frame; parse with `parse_fec_parity`
+  FecParity(&'a [u8]),
+  /// Body of an identify challenge: the challenge
+  IdentifyChallenge(&'a [u8]),
+  /// Body of an identify response; parse with `parse_identify_response`
+  IdentifyResponse(&'a [u8]),
+  /// Control frame with an unrecognized type byte
+  Unknown(u8)
+}
+
+impl<'a> Frame<'a> {
+  pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+    let first = *bytes.first()?;
+    let frame = match first >> 4 {
+      4 | 6 => Frame::Ip(bytes),
+      _ => match first {
+        HELLO => Frame::Hello,
+        BATCH => Frame::Batch(&bytes[1..]),
+        KEEPALIVE => Frame::Keepalive,
+        LEASE_REQUEST => Frame::LeaseRequest(&bytes[1..]),
+        LEASE_OFFER => Frame::LeaseOffer(&bytes[1..]),
+        MTU => Frame::Mtu(&bytes[1..]),
+        FRAGMENT => Frame::Fragment(&bytes[1..]),
+        COMPRESSION => Frame::Compression(&bytes[1..]),
+        COMPRESSED => Frame::Compressed(&bytes[1..]),
+        ETHERNET => Frame::Ethernet(&bytes[1..]),
+        SEQUENCING => Frame::Sequencing(&bytes[1..]),
+        SEQUENCED => Frame::Sequenced(&bytes[1..]),
+        ECHO_REQUEST => Frame::EchoRequest(&bytes[1..]),
+        ECHO_REPLY => Frame::EchoReply(&bytes[1..]),
+        MESH_PEERS => Frame::MeshPeers(&bytes[1..]),
+        ROUTES => Frame::Routes(&bytes[1..]),
+        PEX => Frame::Pex(&bytes[1..]),
+        PSK_CHALLENGE => Frame::PskChallenge(&bytes[1..]),
+        PSK_RESPONSE => Frame::PskResponse(&bytes[1..]),
+        FEC_DATA => Frame::FecData(&bytes[1..]),
+        FEC_PARITY => Frame::FecParity(&bytes[1..]),
+        IDENTIFY_CHALLENGE => Frame::IdentifyChallenge(&bytes[1..]),
+        IDENTIFY_RESPONSE => Frame::IdentifyResponse(&bytes[1..]),
+        _ => Frame::Unknown(first)
+      }
+    };
+    Some(frame)
+  }
+
+  /// Whether the frame carries packets for the tun; all others are link control
+  pub fn carries_packets(&self) -> bool {
+    matches!(self, Frame::Ip(_) | Frame::Batch(_) | Frame::Compressed(_)
+      | Frame::Ethernet(_) | Frame::Sequenced(_) | Frame::FecData(_)
+      | Frame::FecParity(_))
+  }
+}
+
+pub fn hello() -> [u8; 1] {
+  [HELLO]
+}
+
+pub fn keepalive() -> [u8; 1] {
+  [KEEPALIVE]
+}
+
+/// Write an Ethernet frame into an empty buffer
+pub fn ethernet(frame: &[u8], bytes: &mut BytesMut) {
+  bytes.extend_from_slice(&[ETHERNET]);
+  bytes.extend_from_slice(frame);
+}
+
+/// Append a packet to a batch frame, starting the frame if the batch is empty
+pub fn push_batch(batch: &mut BytesMut, packet: &[u8]) {
+  if batch.is_empty() {
+    batch.extend_from_slice(&[BATCH]);
+  }
+  batch.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+  batch.extend_from_slice(packet);
+}
+
+/// Split the body of a batch frame into its packets; a truncated trailing packet is
+/// dropped
+pub fn split_batch(mut body: &[u8]) -> Vec<&[u8]> {
+  let mut packets = Vec::new();
+  while body.len() >= BATCH_PACKET_OVERHEAD {
+    let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    body = &body[BATCH_PACKET_OVERHEAD..];
+    if body.len() < len {
+      tracing::warn!("dropping truncated packet in batch ({} of {len} bytes)", body.len());
+      break
+    }
+    packets.push(&body[..len]);
+    body = &body[len..];
+  }
+  packets
+}
+
+pub fn lease_request(dest: &[u8]) -> Vec<u8> {
+  let mut frame = vec![LEASE_REQUEST];
+  frame.extend_from_slice(dest);
+  frame
+}
+
+pub fn lease_offer(ip: ipnet::IpNet) -> Vec<u8> {
+  let mut frame = vec![LEASE_OFFER, ip.prefix_len()];
+  match ip.addr() {
+    std::net::IpAddr::V4(addr) => frame.extend_from_slice(&addr.octets()),
+    std::net::IpAddr::V6(addr) => frame.extend_from_slice(&addr.octets())
+  }
+  frame
+}
+
+pub fn parse_lease_offer(body: &[u8]) -> Option<ipnet::IpNet> {
+  let (prefix_len, addr) = body.split_first()?;
+  let addr = match addr.len() {
+    4 => std::net::IpAddr::from(<[u8; 4]>::try_from(addr).ok()?),
+    16 => std::net::IpAddr::from(<[u8; 16]>::try_from(addr).ok()?),
+    _ => return None
+  };
+  ipnet::IpNet::new(addr, *prefix_len).ok()
+}
+
+pub fn mtu(mtu: u16, dest: &[u8]) -> Vec<u8> {
+  let mut frame = vec![MTU];
+  frame.extend_from_slice(&mtu.to_be_bytes());
+  frame.extend_from_slice(dest);
+  frame
+}
+
+/// MTU and destination hash of the sender
+pub fn parse_mtu(body: &[u8]) -> Option<(u16, &[u8])> {
+  if body.len() < 2 {
+    return None
+  }
+  Some((u16::from_be_bytes([body[0], body[1]]), &body[2..]))
+}
+
+/// Split a payload into fragment frames of at most `max_len` bytes each; `None` if it
+/// would take more than 255 fragments
+pub fn fragments(payload: &[u8], id: u16, max_len: usize) -> Option<Vec<Vec<u8>>> {
+  let chunks = payload.chunks(max_len - FRAGMENT_HEADER_LEN);
+  let count = u8::try_from(chunks.len()).ok()?;
+  Some(chunks.enumerate().map(|(index, chunk)| {
+    let mut frame = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+    frame.push(FRAGMENT);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.push(index as u8);
+    frame.push(count);
+    frame.extend_from_slice(chunk);
+    frame
+  }).collect())
+}
+
+/// Fragment ID, index, count and data
+pub fn parse_fragment(body: &[u8]) -> Option<(u16, u8, u8, &[u8])> {
+  if body.len() < FRAGMENT_HEADER_LEN - 1 {
+    return None
+  }
+  let (index, count) = (body[2], body[3]);
+  if count == 0 || index >= count {
+    return None
+  }
+  Some((u16::from_be_bytes([body[0], body[1]]), index, count, &body[4..]))
+}
+
+pub fn compression(algorithm: u8, dest: &[u8]) -> Vec<u8> {
+  let mut frame = vec![COMPRESSION, algorithm];
+  frame.extend_from_slice(dest);
+  frame
+}
+
+/// Algorithm and destination hash of the sender
+pub fn parse_compression(body: &[u8]) -> Option<(u8, &[u8])> {
+  body.split_first().map(|(algorithm, dest)| (*algorithm, dest))
+}
+
+/// LZ4-compress a payload into a compressed frame in an empty buffer; false if that
+/// doesn't make it smaller
+pub fn compress(payload: &[u8], frame: &mut BytesMut) -> bool {
+  let Ok(len) = u16::try_from(payload.len()) else { return false };
+  frame.extend_from_slice(&[COMPRESSED, LZ4]);
+  frame.extend_from_slice(&len.to_be_bytes());
+  let header_len = frame.len();
+  frame.resize(header_len + lz4_flex::block::get_maximum_output_size(payload.len()), 0);
+  let compressed = lz4_flex::block::compress_into(payload, &mut frame[header_len..]);
+  let Ok(compressed_len) = compressed else { return false };
+  frame.truncate(header_len + compressed_len);
+  frame.len() < payload.len()
+}
+
+/// Unpack the body of a compressed frame
+pub fn decompress(body: &[u8]) -> Option<Vec<u8>> {
+  if body.len() < 3 || body[0] != LZ4 {
+    return None
+  }
+  let len = u16::from_be_bytes([body[1], body[2]]) as usize;
+  lz4_flex::block::decompress(&body[3..], len).ok()
+}
+
+pub fn sequencing(dest: &[u8]) -> Vec<u8> {
+  let mut frame = vec![SEQUENCING];
+  frame.extend_from_slice(dest);
+  frame
+}
+
+/// Write a sequenced frame into an empty buffer
+pub fn sequenced(seq: u64, payload: &[u8], frame: &mut BytesMut) {
+  frame.reserve(SEQUENCE_HEADER_LEN + payload.len());
+  frame.extend_from_slice(&[SEQUENCED]);
+  frame.extend_from_slice(&seq.to_be_bytes());
+  frame.extend_from_slice(payload);
+}
+
+/// Sequence number and payload
+pub fn parse_sequenced(body: &[u8]) -> Option<(u64, &[u8])> {
+  let (seq, payload) = body.split_first_chunk::<8>()?;
+  Some((u64::from_be_bytes(*seq), payload))
+}
+
+/// Write an FEC data frame into an empty buffer
+pub fn fec_data(group: u16, index: u8, payload: &[u8], frame: &mut BytesMut) {
+  frame.reserve(FEC_DATA_HEADER_LEN + payload.len());
+  frame.extend_from_slice(&[FEC_DATA]);
+  frame.extend_from_slice(&group.to_be_bytes());
+  frame.extend_from_slice(&[index]);
+  frame.extend_from_slice(payload);
+}
+
+/// Group number, index and payload
+pub fn parse_fec_data(body: &[u8]) -> Option<(u16, u8, &[u8])> {
+  let (group, rest) = body.split_first_chunk::<2>()?;
+  let (index, payload) = rest.split_first()?;
+  Some((u16::from_be_bytes(*group), *index, payload))
+}
+
+/// Write an FEC parity frame into an empty buffer
+pub fn fec_parity(group: u16, count: u8, len_parity: u16, parity: &[u8],
+  frame: &mut BytesMut)
+{
+  frame.reserve(6 + parity.len());
+  frame.extend_from_slice(&[FEC_PARITY]);
+  frame.extend_from_slice(&group.to_be_bytes());
+  frame.extend_from_slice(&[count]);
+  frame.extend_from_slice(&len_parity.to_be_bytes());
+  frame.extend_from_slice(parity);
+}
+
+/// Group number, payload count, length parity and parity
+pub fn parse_fec_parity(body: &[u8]) -> Option<(u16, u8, u16, &[u8])> {
+  let (group, rest) = body.split_first_chunk::<2>()?;
+  let (count, rest) = rest.split_first()?;
+  let (len_parity, parity) = rest.split_first_chunk::<2>()?;
+  Some((u16::from_be_bytes(*group), *count, u16::from_be_bytes(*len_parity), parity))
+}
+
+/// Echo request of `len` bytes in total, at least `ECHO_HEADER_LEN`
+pub fn echo_request(seq: u64, len: usize) -> Vec<u8> {
+  let mut frame = vec![0x0; len.max(ECHO_HEADER_LEN)];
+  frame[0] = ECHO_REQUEST;
+  frame[1..ECHO_HEADER_LEN].copy_from_slice(&seq.to_be_bytes());
+  frame
+}
+
+pub fn psk_challenge(challenge: &[u8]) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(1 + challenge.len());
+  frame.push(PSK_CHALLENGE);
+  frame.extend_from_slice(challenge);
+  frame
+}
+
+pub fn psk_response(response: &[u8]) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(1 + response.len());
+  frame.push(PSK_RESPONSE);
+  frame.extend_from_slice(response);
+  frame
+}
+
+pub fn identify_challenge(challenge: &[u8]) -> Vec<u8> {
+  let mut frame = vec![IDENTIFY_CHALLENGE];
+  frame.extend_from_slice(challenge);
+  frame
+}
+
+pub fn identify_response(public_key: &[u8], verifying_key: &[u8], signature: &[u8])
+  -> Vec<u8>
+{
+  let mut frame = Vec::with_capacity(1 + IDENTIFY_RESPONSE_LEN);
+  frame.push(IDENTIFY_RESPONSE);
+  frame.extend_from_slice(public_key);
+  frame.extend_from_slice(verifying_key);
+  frame.extend_from_slice(signature);
+  frame
+}
+
+/// Public key, verifying key and signature
+pub fn parse_identify_response(body: &[u8]) -> Option<(&[u8; 32], &[u8; 32], &[u8; 64])> {
+  if body.len() != IDENTIFY_RESPONSE_LEN {
+    return None
+  }
+  let (public_key, rest) = body.split_first_chunk::<32>()?;
+  let (verifying_key, signature) = rest.split_first_chunk::<32>()?;
+  Some((public_key, verifying_key, signature.try_into().ok()?))
+}
+
+pub fn echo_reply(body: &[u8]) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(1 + body.len());
+  frame.push(ECHO_REPLY);
+  frame.extend_from_slice(body);
+  frame
+}
+
+/// Sequence number of an echo request or reply
+pub fn parse_echo(body: &[u8]) -> Option<u64> {
+  body.first_chunk::<8>().map(|seq| u64::from_be_bytes(*seq))
+}
+
+pub fn mesh_peers(peers: &[(&[u8], IpAddr)]) -> Vec<u8> {
+  peers_frame(MESH_PEERS, peers)
+}
+
+pub fn pex(peers: &[(&[u8], IpAddr)]) -> Vec<u8> {
+  peers_frame(PEX, peers)
+}
+
+fn peers_frame(frame_type: u8, peers: &[(&[u8], IpAddr)]) -> Vec<u8> {
+  let mut frame = vec![frame_type];
+  for (dest, ip) in peers.iter() {
+    frame.extend_from_slice(dest);
+    match ip {
+      IpAddr::V4(ip) => {
+        frame.push(4);
+        frame.extend_from_slice(&ip.octets());
+      }
+      IpAddr::V6(ip) => {
+        frame.push(16);
+        frame.extend_from_slice(&ip.octets());
+      }
+    }
+  }
+  frame
+}
+
+/// Destination hash and tunnel address of each peer in a mesh peers or peer exchange
+/// frame
+pub fn parse_peers(mut body: &[u8]) -> Option<Vec<(&[u8], IpAddr)>> {
+  let mut peers = Vec::new();
+  while !body.is_empty() {
+    let (dest, rest) = body.split_at_checked(ADDRESS_HASH_LEN)?;
+    let (len, rest) = rest.split_first()?;
+    let (addr, rest) = rest.split_at_checked(*len as usize)?;
+    let ip = match addr.len() {
+      4 => IpAddr::from(<[u8; 4]>::try_from(addr).ok()?),
+      16 => IpAddr::from(<[u8; 16]>::try_from(addr).ok()?),
+      _ => return None
+    };
+    peers.push((dest, ip));
+    body = rest;
+  }
+  Some(peers)
+}
+
+pub fn routes(nets: &[ipnet::IpNet]) -> Vec<u8> {
+  let mut frame = vec![ROUTES];
+  for net in nets.iter() {
+    frame.push(net.prefix_len());
+    match net.addr() {
+      IpAddr::V4(addr) => {
+        frame.push(4);
+        frame.extend_from_slice(&addr.octets());
+      }
+      IpAddr::V6(addr) => {
+        frame.push(16);
+        frame.extend_from_slice(&addr.octets());
+      }
+    }
+  }
+  frame
+}
+
+pub fn parse_routes(mut body: &[u8]) -> Option<Vec<ipnet::IpNet>> {
+  let mut nets = Vec::new();
+  while !body.is_empty() {
+    let (prefix_len, rest) = body.split_first()?;
+    let (len, rest) = rest.split_first()?;
+    let (addr, rest) = rest.split_at_checked(*len as usize)?;
+    let addr = match addr.len() {
+      4 => IpAddr::from(<[u8; 4]>::try_from(addr).ok()?),
+      16 => IpAddr::from(<[u8; 16]>::try_from(addr).ok()?),
+      _ => return None
+    };
+    nets.push(ipnet::IpNet::new(addr, *prefix_len).ok()?);
+    body = rest;
+  }
+  Some(nets)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn batch_round_trip() {
+    let packets: [&[u8]; 3] = [b"first", b"", b"third packet"];
+    let mut batch = BytesMut::new();
+    for packet in packets {
+      push_batch(&mut batch, packet);
+    }
+    let Some(Frame::Batch(body)) = Frame::parse(&batch) else {
+      panic!("not a batch frame")
+    };
+    assert_eq!(split_batch(body), packets);
+  }
+
+  #[test]
+  fn empty_batch() {
+    assert!(split_batch(&[]).is_empty());
+  }
+
+  #[test]
+  fn truncated_batch() {
+    let mut batch = BytesMut::new();
+    push_batch(&mut batch, b"first");
+    push_batch(&mut batch, b"second");
+    // the second packet is cut short, then its length prefix too
+    assert_eq!(split_batch(&batch[1..batch.len() - 1]), [b"first"]);
+    assert_eq!(split_batch(&batch[1..1 + BATCH_PACKET_OVERHEAD + 5 + 1]), [b"first"]);
+  }
+
+  #[test]
+  fn sequenced_frame_round_trip() {
+    let mut frame = BytesMut::new();
+    sequenced(7, b"payload", &mut frame);
+    let Some(Frame::Sequenced(body)) = Frame::parse(&frame) else {
+      panic!("not a sequenced frame")
+    };
+    assert_eq!(parse_sequenced(body), Some((7, &b"payload"[..])));
+  }
+}
@@ -0,0 +1,38 @@
+//! Health probes over HTTP for orchestrators and load balancers: `/healthz` answers
+//! while the client runs with its tun open, `/readyz` once the Reticulum network is
+//! reachable and enough peers are linked
+
+use crate::metrics;
+
+/// State the probes are answered from
+pub struct Health {
+  /// Something was received over a Reticulum interface: an announce or a link
+  pub network_reachable: bool,
+  pub links_active: usize,
+  /// Links needed to be ready
+  pub ready_links: usize
+}
+
+impl Health {
+  fn ready(&self) -> bool {
+    self.network_reachable && self.links_active >= self.ready_links
+  }
+}
+
+/// Answer one HTTP request on the connection: 200 for `GET /healthz`, 200 or 503 for
+/// `GET /readyz`, 404 otherwise
+pub async fn respond(stream: &mut tokio::net::TcpStream, health: &Health) {
+  metrics::serve(stream, "health", |request| {
+    if request.starts_with(b"GET /healthz ") {
+      metrics::response("200 OK", "text/plain", "ok\n")
+    } else if request.starts_with(b"GET /readyz ") {
+      let body = format!("{} of {} links active{}\n", health.links_active,
+        health.ready_links,
+        if health.network_reachable { "" } else { ", no interface up" });
+      let status = if health.ready() { "200 OK" } else { "503 Service Unavailable" };
+      metrics::response(status, "text/plain", &body)
+    } else {
+      metrics::response("404 Not Found", "text/plain", "")
+    }
+  }).await
+}
@@ -0,0 +1,67 @@
+//! User scripts and callbacks run when the link to a peer comes up or goes down
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use reticulum::destination::link::LinkId;
+use reticulum::hash::AddressHash;
+
+/// Kill a hook script still running after this long
+const HOOK_TIMEOUT_SECS: u64 = 30;
+
+/// Change of the link to a peer
+#[derive(Clone, Copy, Debug)]
+pub enum PeerEvent {
+  Up,
+  Down
+}
+
+/// Called with the event, the peer's tunnel address and its destination hash when the
+/// link to a peer comes up or goes down; runs on the client's task, so it must not block
+pub type PeerCallback = Box<dyn Fn(PeerEvent, IpAddr, AddressHash) + Send + Sync>;
+
+impl PeerEvent {
+  fn as_str(&self) -> &'static str {
+    match self {
+      PeerEvent::Up => "up",
+      PeerEvent::Down => "down"
+    }
+  }
+}
+
+/// Run a hook script with the event and peer in its environment, waiting for it to
+/// exit; failures are logged
+pub async fn run(script: PathBuf, event: PeerEvent, ip: IpAddr, name: Option<String>,
+  dest: AddressHash, link_id: LinkId
+) {
+  tracing::debug!(script = %script.display(), event = event.as_str(), %ip,
+    "running peer hook");
+  let mut command = tokio::process::Command::new(&script);
+  command.env("RNS_VPN_EVENT", event.as_str())
+    .env("RNS_VPN_PEER_IP", ip.to_string())
+    .env("RNS_VPN_PEER_DEST", format!("{dest}").trim_matches('/'))
+    .env("RNS_VPN_LINK_ID", format!("{link_id}").trim_matches('/'))
+    .stdin(std::process::Stdio::null())
+    .kill_on_drop(true);
+  if let Some(name) = name {
+    command.env("RNS_VPN_PEER_NAME", name);
+  }
+  let child = command.spawn();
+  let mut child = match child {
+    Ok(child) => child,
+    Err(err) => {
+      tracing::error!(script = %script.display(), error = ?err, "failed to run peer hook");
+      return
+    }
+  };
+  match tokio::time::timeout(Duration::from_secs(HOOK_TIMEOUT_SECS), child.wait()).await {
+    Ok(Ok(status)) if status.success() => {}
+    Ok(Ok(status)) => tracing::warn!(script = %script.display(), %status,
+      "peer hook failed"),
+    Ok(Err(err)) => tracing::error!(script = %script.display(), error = ?err,
+      "failed to wait for peer hook"),
+    Err(_) => tracing::warn!(script = %script.display(),
+      timeout_secs = HOOK_TIMEOUT_SECS, "peer hook timed out: killing")
+  }
+}
@@ -0,0 +1,80 @@
+//! ICMP errors for packets read from the tun that can't be sent to any peer, so that
+//! applications fail fast instead of waiting for timeouts
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use etherparse::{icmpv4, icmpv6, Icmpv4Type, Icmpv6Type, IpNumber, PacketBuilder};
+
+/// Bytes of the packet quoted, keeping errors within the minimum datagram size every
+/// host must accept (576 and 1280 bytes, less the IP and ICMP headers)
+const MAX_QUOTED_IPV4: usize = 576 - 20 - 8;
+const MAX_QUOTED_IPV6: usize = 1280 - 40 - 8;
+const TTL: u8 = 64;
+/// ICMP types that are errors themselves: destination unreachable, source quench,
+/// redirect, time exceeded and parameter problem
+const ICMPV4_ERROR_TYPES: [u8; 5] = [3, 4, 5, 11, 12];
+/// ICMPv6 types below this are errors
+const ICMPV6_INFORMATIONAL_TYPES: u8 = 128;
+
+/// ICMP host unreachable or ICMPv6 no route to destination for a packet, sent from the
+/// local address of the same family and quoting as much of the packet as fits; `None`
+/// for packets that must not get an error: ICMP errors, fragments and packets from or
+/// to a multicast, broadcast or unspecified address
+pub fn unreachable(packet: &[u8], local_ips: &[IpAddr]) -> Option<Vec<u8>> {
+  let (headers, payload) = etherparse::IpHeaders::from_slice(packet).ok()?;
+  if payload.fragmented {
+    return None
+  }
+  let icmp_type = payload.payload.first();
+  let is_error = match payload.ip_number {
+    IpNumber::ICMP => icmp_type.is_some_and(|ty| ICMPV4_ERROR_TYPES.contains(ty)),
+    IpNumber::IPV6_ICMP => icmp_type.is_some_and(|ty| *ty < ICMPV6_INFORMATIONAL_TYPES),
+    _ => false
+  };
+  if is_error {
+    return None
+  }
+  let mut error = Vec::new();
+  if let Some((ipv4_header, _)) = headers.ipv4() {
+    let source = ipv4_header.source;
+    let destination = Ipv4Addr::from(ipv4_header.destination);
+    if !is_unicast(&IpAddr::from(source)) || destination.is_multicast()
+      || destination.is_broadcast()
+    {
+      return None
+    }
+    let local_ip = local_ips.iter().find_map(|ip| match ip {
+      IpAddr::V4(ip) => Some(ip.octets()),
+      IpAddr::V6(_) => None
+    })?;
+    let quoted = &packet[..packet.len().min(MAX_QUOTED_IPV4)];
+    PacketBuilder::ipv4(local_ip, source, TTL)
+      .icmpv4(Icmpv4Type::DestinationUnreachable(icmpv4::DestUnreachableHeader::Host))
+      .write(&mut error, quoted).ok()?;
+  } else if let Some((ipv6_header, _)) = headers.ipv6() {
+    let source = ipv6_header.source;
+    if !is_unicast(&IpAddr::from(source))
+      || Ipv6Addr::from(ipv6_header.destination).is_multicast()
+    {
+      return None
+    }
+    let local_ip = local_ips.iter().find_map(|ip| match ip {
+      IpAddr::V4(_) => None,
+      IpAddr::V6(ip) => Some(ip.octets())
+    })?;
+    let quoted = &packet[..packet.len().min(MAX_QUOTED_IPV6)];
+    PacketBuilder::ipv6(local_ip, source, TTL)
+      .icmpv6(Icmpv6Type::DestinationUnreachable(icmpv6::DestUnreachableCode::NoRoute))
+      .write(&mut error, quoted).ok()?;
+  } else {
+    return None
+  }
+  Some(error)
+}
+
+fn is_unicast(ip: &IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => !ip.is_unspecified() && !ip.is_multicast() && !ip.is_broadcast(),
+    IpAddr::V6(ip) => !ip.is_unspecified() && !ip.is_multicast()
+  }
+}
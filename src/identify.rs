@@ -0,0 +1,49 @@
+//! Identification of inbound links: a link claiming a destination with its mtu frame
+//! gets a random challenge, and is only taken as the destination's once the peer
+//! answers with the identity the destination hash derives from and that identity's
+//! signature of the challenge and the link ID
+
+use rand_core::{OsRng, RngCore};
+use reticulum::destination::link::LinkId;
+use reticulum::identity::{Identity, PrivateIdentity};
+
+use crate::frame;
+
+pub const CHALLENGE_LEN: usize = 16;
+/// Signed along with the challenge so that responses are never valid signatures for
+/// anything else signed by the identity
+const CONTEXT: &[u8] = b"rns-vpn identify";
+
+/// Destination and MTU an inbound link claimed, waiting for the answer to its challenge
+pub struct PendingLink {
+  pub dest: reticulum::hash::AddressHash,
+  pub mtu: u16,
+  pub challenge: [u8; CHALLENGE_LEN]
+}
+
+pub fn challenge() -> [u8; CHALLENGE_LEN] {
+  let mut challenge = [0u8; CHALLENGE_LEN];
+  OsRng.fill_bytes(&mut challenge);
+  challenge
+}
+
+/// Identify response frame answering a challenge on a link
+pub fn response(id: &PrivateIdentity, challenge: &[u8], link_id: &LinkId) -> Vec<u8> {
+  let identity = id.as_identity();
+  let signature = id.sign(&signed(challenge, link_id)).to_bytes();
+  frame::identify_response(identity.public_key_bytes(), identity.verifying_key_bytes(),
+    &signature)
+}
+
+/// Identity answering a challenge on a link, if its signature checks out
+pub fn verify(challenge: &[u8], link_id: &LinkId, body: &[u8]) -> Option<Identity> {
+  let (public_key, verifying_key, signature) = frame::parse_identify_response(body)?;
+  let key = ed25519_dalek::VerifyingKey::from_bytes(verifying_key).ok()?;
+  let signature = ed25519_dalek::Signature::from_bytes(signature);
+  key.verify_strict(&signed(challenge, link_id), &signature).ok()?;
+  Some(Identity::new_from_slices(public_key, verifying_key))
+}
+
+fn signed(challenge: &[u8], link_id: &LinkId) -> Vec<u8> {
+  [CONTEXT, challenge, link_id.as_slice()].concat()
+}
@@ -1,257 +1,4720 @@
-use std::collections::BTreeMap;
-use std::net::IpAddr;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use etherparse;
 use ipnet::IpNet;
-use log;
-use riptun::TokioTun;
+use tracing;
+use tracing::Instrument;
 use serde::{Deserialize, Serialize};
 use tokio;
+use tokio_util::sync::CancellationToken;
 
-use reticulum::destination::DestinationName;
-use reticulum::destination::link::{LinkEvent, LinkId};
+use reticulum::destination::{
+  DestinationDesc, DestinationName, SingleInputDestination, SingleOutputDestination
+};
+use reticulum::destination::link::{Link, LinkEvent, LinkId};
 use reticulum::hash::AddressHash;
-use reticulum::identity::PrivateIdentity;
+use reticulum::identity::{Identity, PrivateIdentity};
+use reticulum::iface::tcp_client::TcpClient;
+use reticulum::iface::tcp_server::TcpServer;
+use reticulum::iface::udp::UdpInterface;
 use reticulum::transport::Transport;
 
-// TODO: config?
-const TUN_NQUEUES : usize = 1;
-const MTU: usize = 1500;
+mod balance;
+mod bench;
+mod builder;
+pub mod bundle;
+#[cfg(unix)]
+mod control;
+mod discovery;
+mod events;
+mod fec;
+#[cfg(target_os = "linux")]
+mod dns;
+mod filter;
+#[cfg(target_os = "linux")]
+mod firewall;
+mod fragment;
+mod frame;
+mod health;
+mod hooks;
+mod icmp;
+mod identify;
+pub mod logfile;
+mod mac_table;
+mod metrics;
+mod mss;
+mod multipath;
+mod overrides;
+mod peer_map;
+mod persist;
+#[cfg(target_os = "linux")]
+mod policy;
+mod pool;
+mod psk;
+mod quality;
+mod queue;
+mod ratelimit;
+mod replay;
+pub mod rns_config;
+mod routing;
+pub mod selftest;
+pub mod shared_instance;
+mod supervisor;
+#[cfg(unix)]
+pub mod syslog;
+#[cfg(target_os = "linux")]
+mod systemd;
+mod tun;
 
-const fn default_announce_freq_secs() -> u32 { 1 }
+use frame::Frame;
+use metrics::Metrics;
+use peer_map::PeerMap;
+use pool::{BufferPool, PooledBuf};
+use queue::PacketQueue;
+use supervisor::Failure;
+use tun::Tun;
+
+pub use builder::{ClientBuilder, ClientHandle};
+pub use events::{DropReason, Event};
+pub use hooks::{PeerCallback, PeerEvent};
+pub use quality::LinkQuality;
+pub use tun::is_privileged;
+
+#[cfg(target_os = "linux")]
+const TUN_NAME: &str = "rip%d";
+#[cfg(target_os = "linux")]
+const TAP_NAME: &str = "riptap%d";
+const DESTINATION_APP: &str = "rns_vpn";
+const DESTINATION_ASPECT: &str = "client";
+/// Smallest MTU allowed: the minimum IPv4 datagram size every host must accept
+const MIN_MTU: u16 = 576;
+/// Smallest MTU allowed with an IPv6 tunnel address
+const MIN_MTU_IPV6: u16 = 1280;
+const SWEEP_INTERVAL_SECS: u64 = 1;
+/// First interval between announces, doubled after each announce
+const ANNOUNCE_INITIAL_SECS: u64 = 1;
+/// Announce intervals are varied randomly by up to this fraction so that nodes started
+/// together don't announce in lockstep
+const ANNOUNCE_JITTER: f64 = 0.1;
+const LEASE_RETRY_SECS: u64 = 2;
+/// First delay before re-linking a peer whose link closed, doubled on each attempt
+const RELINK_BACKOFF_MIN_SECS: u64 = 1;
+const RELINK_BACKOFF_MAX_SECS: u64 = 60;
+/// Largest payload of a single link packet; larger payloads are fragmented
+const LINK_MDU: usize = 431;
+/// Drop a partially reassembled packet after this long
+const FRAGMENT_TIMEOUT_SECS: u64 = 5;
+const ADDRESS_HASH_LEN: usize = 16;
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Ethernet header with an 802.1Q VLAN tag
+const ETHERNET_MAX_HEADER_LEN: usize = 18;
+/// Forget a MAC address learned in tap mode after not seeing it for this long
+const MAC_AGE_SECS: u64 = 300;
+/// Rate limit of ICMP errors written to the tun, so that a flood of unroutable packets
+/// isn't answered in kind
+const ICMP_RATE_LIMIT_KBPS: u32 = 64;
+
+/// Current config format version
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Destination hash of a client with the given identity, as configured by its peers
+pub fn destination_hash(id: PrivateIdentity) -> AddressHash {
+  SingleInputDestination::new(id, DestinationName::new(DESTINATION_APP, DESTINATION_ASPECT))
+    .desc.address_hash
+}
+
+/// Destination hash of a peer with the given identity
+fn peer_destination_hash(identity: Identity) -> AddressHash {
+  SingleOutputDestination::new(identity,
+    DestinationName::new(DESTINATION_APP, DESTINATION_ASPECT)).desc.address_hash
+}
+
+const fn default_config_version() -> u32 { CONFIG_VERSION }
+const fn default_announce_freq_secs() -> u32 { 120 }
+const fn default_coalesce_max_bytes() -> usize { 256 }
+const fn default_tun_queues() -> usize { 1 }
+const fn default_tun_queue_depth() -> usize { 256 }
+const fn default_peer_queue_depth() -> usize { 256 }
+const fn default_offline_queue_ttl_ms() -> u32 { 5000 }
+const fn default_mtu() -> u16 { 1500 }
+const fn default_link_keepalive_secs() -> u32 { 25 }
+const fn default_link_weights() -> [u32; 2] { [1, 1] }
+const fn default_link_probe_secs() -> u32 { 10 }
+const fn default_dead_peer_timeout_secs() -> u32 { 90 }
+const fn default_shutdown_timeout_secs() -> u32 { 10 }
+const fn default_manage_addresses() -> bool { true }
+const fn default_health_ready_peers() -> usize { 1 }
+fn default_instance_name() -> String { shared_instance::DEFAULT_INSTANCE_NAME.to_owned() }
 
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-  pub vpn_ip: IpNet,
-  /// Map of (IP, destination hash)
-  pub peers: BTreeMap<IpAddr, String>,
+  /// Config format version
+  #[serde(default = "default_config_version")]
+  pub version: u32,
+  /// IP assigned to this client, or `None` to lease one from the `lease_from` hub
+  /// (`"auto"` in the config file)
+  #[serde(deserialize_with = "deserialize_vpn_ip", serialize_with = "serialize_vpn_ip")]
+  pub vpn_ip: Option<IpNet>,
+  /// Optional IPv6 address assigned to this client alongside `vpn_ip`
+  #[serde(default)]
+  pub vpn_ip6: Option<IpNet>,
+  /// Map of (IP, peer): each peer is either a destination hash or a table of
+  /// peer settings
+  #[serde(deserialize_with = "deserialize_peers")]
+  pub peers: BTreeMap<IpAddr, PeerConfig>,
+  /// Steady-state seconds between announces; announces start at one per second and
+  /// back off to this interval
   #[serde(default = "default_announce_freq_secs")]
-  pub announce_freq_secs: u32
+  pub announce_freq_secs: u32,
+  /// Optional management address assigned to the tun; traffic to it is always
+  /// terminated locally, by whatever listens on it (e.g. `health_listen` or
+  /// `metrics_listen` bound to it), and never forwarded to a peer link
+  #[serde(default)]
+  pub management_ip: Option<IpNet>,
+  /// Source addresses allowed to reach the management address (empty allows all
+  /// peers): a peer's tunnel IP allows the peer, as packets are only accepted from the
+  /// peer their source is routed to
+  #[serde(default)]
+  pub management_allowed: Vec<IpAddr>,
+  /// Serve Prometheus metrics over HTTP at `/metrics` on this address
+  #[serde(default)]
+  pub metrics_listen: Option<SocketAddr>,
+  /// Serve health probes over HTTP at `/healthz` and `/readyz` on this address
+  #[serde(default)]
+  pub health_listen: Option<SocketAddr>,
+  /// Peers with an active link needed for `/readyz` to report ready
+  #[serde(default = "default_health_ready_peers")]
+  pub health_ready_peers: usize,
+  /// Serve the JSON-RPC management API on a Unix socket at this path
+  #[serde(default)]
+  pub control_socket: Option<std::path::PathBuf>,
+  /// Script run when the link to a peer is activated
+  #[serde(default)]
+  pub on_peer_up: Option<std::path::PathBuf>,
+  /// Script run when the link to a peer closes, the peer is marked down or the link is
+  /// closed for being idle
+  #[serde(default)]
+  pub on_peer_down: Option<std::path::PathBuf>,
+  /// Switch to this user once the tun device is set up (unix only)
+  #[serde(default)]
+  pub user: Option<String>,
+  /// Switch to this group once the tun device is set up, by default the primary group
+  /// of `user` (unix only)
+  #[serde(default)]
+  pub group: Option<String>,
+  /// Send broadcast and multicast packets to every peer with an active link
+  #[serde(default)]
+  pub forward_broadcast: bool,
+  /// Send a hello frame over each link when it is activated to prime the path
+  #[serde(default)]
+  pub send_hello: bool,
+  /// Compress tunneled packets to peers that have compression enabled too
+  #[serde(default)]
+  pub compression: Option<Compression>,
+  /// Ask peers to sequence their packets and drop duplicated or replayed ones
+  #[serde(default)]
+  pub replay_protection: bool,
+  /// Remove a leftover tun device holding our address (e.g. after an unclean
+  /// shutdown) instead of failing
+  #[serde(default)]
+  pub force: bool,
+  /// Log addresses, protocol and ports of each forwarded packet at debug level
+  #[serde(default)]
+  pub trace_packets: bool,
+  /// Where the log goes once the config is loaded
+  #[serde(default)]
+  pub log_backend: LogBackend,
+  /// Coalesce small outbound packets per peer for up to this many microseconds
+  /// before sending them in one batch (default: disabled)
+  #[serde(default)]
+  pub coalesce_us: Option<u32>,
+  /// Send a coalesced batch once it reaches this many bytes
+  #[serde(default = "default_coalesce_max_bytes")]
+  pub coalesce_max_bytes: usize,
+  /// Limit the rate of packets sent to all peers together to this many kilobits per
+  /// second, dropping packets over the limit (default: unlimited)
+  #[serde(default)]
+  pub rate_limit_kbps: Option<u32>,
+  /// Send a keepalive on each active link that nothing has been sent on for this many
+  /// seconds (0 disables)
+  #[serde(default = "default_link_keepalive_secs")]
+  pub link_keepalive_secs: u32,
+  /// Send an echo probe on each active link every this many seconds to estimate its
+  /// round trip time and loss (0 disables)
+  #[serde(default = "default_link_probe_secs")]
+  pub link_probe_secs: u32,
+  /// Mark a peer down, closing its link, when nothing has been received from it for
+  /// this many seconds (0 disables)
+  #[serde(default = "default_dead_peer_timeout_secs")]
+  pub dead_peer_timeout_secs: u32,
+  /// Replace an active link with one over a path requested through the best other
+  /// interface, once nothing has been received on it for this many keepalive intervals
+  /// (0 disables)
+  #[serde(default)]
+  pub failover_missed_keepalives: u32,
+  /// Spread the payloads to a peer over both the link we requested and the one the
+  /// peer requested, and take payloads on our link from peers doing the same
+  #[serde(default)]
+  pub link_balance: LinkBalance,
+  /// Weights of the link we requested and the one the peer requested for `weighted`
+  /// and `flow` balancing
+  #[serde(default = "default_link_weights")]
+  pub link_weights: [u32; 2],
+  /// Give up on closing links and removing addresses and routes on shutdown after this
+  /// many seconds, so that a stalled link or device can't keep the client from exiting
+  /// (0 waits indefinitely)
+  #[serde(default = "default_shutdown_timeout_secs")]
+  pub shutdown_timeout_secs: u32,
+  /// Tunnel IP packets over a tun device, or Ethernet frames over a tap device (Linux
+  /// only)
+  #[serde(default)]
+  pub mode: DeviceMode,
+  /// Existing bridge to add the tap device to (tap mode only)
+  #[serde(default)]
+  pub bridge: Option<String>,
+  /// Name of the tun or tap device: a fixed name, or a pattern whose `%d` is replaced
+  /// by the first free number (Linux and Windows only; default: `rip%d`, or `riptap%d`
+  /// in tap mode)
+  #[serde(default)]
+  pub tun_name: Option<String>,
+  /// Open the existing persistent device named `tun_name` instead of creating one,
+  /// leaving its addresses, routes, MTU and link state to whoever created it, so that
+  /// the client can run unprivileged (Linux only)
+  #[serde(default)]
+  pub attach_tun: bool,
+  /// Assign addresses, routes, MTU and link state to the tun; when false these are
+  /// left to other network management, e.g. systemd-networkd (Linux only)
+  #[serde(default = "default_manage_addresses")]
+  pub manage_addresses: bool,
+  /// Have systemd-networkd configure the tun's addresses, routes and MTU from a
+  /// `.network` file written for it, instead of configuring them over netlink (Linux
+  /// only)
+  #[serde(default)]
+  pub networkd: bool,
+  /// Number of tun queues, each read by its own packet worker (Linux only)
+  #[serde(default = "default_tun_queues")]
+  pub tun_queues: usize,
+  /// Packets read from each tun queue waiting to be routed to peers
+  #[serde(default = "default_tun_queue_depth")]
+  pub tun_queue_depth: usize,
+  /// Packets routed to each peer waiting for its sender
+  #[serde(default = "default_peer_queue_depth")]
+  pub peer_queue_depth: usize,
+  /// What to do with a packet when the tun or peer queue it goes to is full
+  #[serde(default)]
+  pub queue_policy: QueuePolicy,
+  /// Hold up to this many packets for each peer while its link is down and send them
+  /// once it is activated; the oldest are dropped when it is full (0 disables)
+  #[serde(default)]
+  pub offline_queue_packets: usize,
+  /// Drop held packets after this many milliseconds
+  #[serde(default = "default_offline_queue_ttl_ms")]
+  pub offline_queue_ttl_ms: u32,
+  /// MTU of the tun device; the smaller of the two MTUs is used on each link
+  #[serde(default = "default_mtu")]
+  pub mtu: u16,
+  /// Lower the MSS of TCP SYNs to and from each peer to fit its link, so that TCP
+  /// doesn't depend on path MTU discovery across narrow links
+  #[serde(default)]
+  pub mss_clamp: bool,
+  /// Answer packets from the tun that have no peer, or whose peer has no link, with an
+  /// ICMP destination unreachable instead of dropping them silently
+  #[serde(default)]
+  pub icmp_unreachable: bool,
+  /// Named Reticulum interfaces to attach to the transport
+  #[serde(default)]
+  pub interfaces: BTreeMap<String, InterfaceConfig>,
+  /// Also attach the interfaces of this Python-RNS config dir or file (`"auto"`: where
+  /// rnsd looks for it); those named like one in `interfaces` are left out
+  #[serde(default)]
+  pub reticulum_config: Option<std::path::PathBuf>,
+  /// Announce our tunnel addresses and learn peers from the announces of trusted
+  /// destinations
+  #[serde(default)]
+  pub discovery: bool,
+  /// Destination hashes whose announced addresses are trusted for discovery
+  #[serde(default)]
+  pub discovery_trusted: Vec<DestinationHash>,
+  /// Tunnel address of the peer to send all traffic through: default routes are
+  /// installed through the tun, except for the Reticulum interface endpoints
+  #[serde(default)]
+  pub exit_node: Option<IpAddr>,
+  /// With `exit_node`, keep the Reticulum transport out of the tunnel by marking its
+  /// packets with this fwmark and routing all unmarked traffic through the routing
+  /// table of the same number, instead of adding host routes for the interface
+  /// endpoints (Linux only)
+  #[serde(default)]
+  pub fwmark: Option<u32>,
+  /// DNS servers for names resolved through the tunnel (Linux only)
+  #[serde(default)]
+  pub dns: Vec<IpAddr>,
+  /// Search domains for names resolved through the tunnel; with systemd-resolved,
+  /// `~` routing-only domains are supported too (Linux only)
+  #[serde(default)]
+  pub dns_search: Vec<String>,
+  /// Firewall used to forward and masquerade traffic of peers allowed exit traffic
+  #[serde(default)]
+  pub firewall_backend: FirewallBackend,
+  /// Allow/deny rules for packets sent to and received from peers
+  #[serde(default)]
+  pub filter: FilterConfig,
+  /// Destination hashes allowed to deliver packets over their links in addition to
+  /// those of the peers
+  #[serde(default)]
+  pub allowed_identities: Vec<DestinationHash>,
+  /// Destination hash of the hub peer to lease the tunnel address from when
+  /// `vpn_ip` is `"auto"`
+  #[serde(default)]
+  pub lease_from: Option<DestinationHash>,
+  /// Address pool to lease tunnel addresses from to clients (hub mode)
+  #[serde(default)]
+  pub lease_pool: Option<IpNet>,
+  /// Send packets from one peer to another peer straight out of the other peer's link
+  /// instead of through the tun (hub mode)
+  #[serde(default)]
+  pub relay: bool,
+  /// With `relay`, share the peers with active links with each other; otherwise link
+  /// directly to the peers shared by a hub
+  #[serde(default)]
+  pub mesh: bool,
+  /// Subnets reachable through this node, advertised to peers on each link
+  #[serde(default)]
+  pub advertise_routes: Vec<IpNet>,
+  /// Networks within which subnets advertised by peers are routed to them
+  #[serde(default)]
+  pub accept_routes: Vec<IpNet>,
+  /// Send each peer the destination hashes and tunnel addresses of the other known
+  /// peers when its link is activated, and add the peers shared by trusted introducers
+  #[serde(default)]
+  pub pex: bool,
+  /// Destination hashes whose shared peers are added with `pex`
+  #[serde(default)]
+  pub pex_trusted: Vec<DestinationHash>
+}
+
+fn deserialize_vpn_ip<'de, D>(deserializer: D) -> Result<Option<IpNet>, D::Error>
+where
+  D: serde::Deserializer<'de>
+{
+  let s = String::deserialize(deserializer)?;
+  if s == "auto" {
+    Ok(None)
+  } else {
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+  }
+}
+
+fn serialize_vpn_ip<S>(vpn_ip: &Option<IpNet>, serializer: S) -> Result<S::Ok, S::Error>
+where
+  S: serde::Serializer
+{
+  match vpn_ip {
+    Some(vpn_ip) => vpn_ip.serialize(serializer),
+    None => serializer.serialize_str("auto")
+  }
+}
+
+/// Reticulum interface definition
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum InterfaceConfig {
+  /// UDP interface listening on `listen` and sending to `forward`
+  Udp {
+    listen: SocketAddr,
+    forward: Option<SocketAddr>
+  },
+  /// TCP interface connecting to `connect` (`<host>:<port>`)
+  TcpClient {
+    connect: String
+  },
+  /// TCP interface accepting connections on `listen`
+  TcpServer {
+    listen: SocketAddr
+  },
+  /// Instance shared by a local rnsd, over TCP on `port` if given, otherwise on the Unix
+  /// socket of `instance_name` (Linux) or the default port
+  SharedInstance {
+    #[serde(default = "default_instance_name")]
+    instance_name: String,
+    #[serde(default)]
+    port: Option<u16>
+  }
+}
+
+impl InterfaceConfig {
+  /// Add the interface to the transport, returning its address unless it failed
+  pub async fn spawn(&self, transport: &Transport, name: &str) -> Option<AddressHash> {
+    let iface = match self {
+      InterfaceConfig::Udp { listen, forward } => {
+        tracing::info!("interface {name}: udp listen {listen} forward {forward:?}");
+        transport.iface_manager().lock().await.spawn(
+          UdpInterface::new(listen.to_string(),
+            forward.map(|forward| forward.to_string())),
+          UdpInterface::spawn)
+      }
+      InterfaceConfig::TcpClient { connect } => {
+        tracing::info!("interface {name}: tcp connect {connect}");
+        transport.iface_manager().lock().await.spawn(
+          TcpClient::new(connect.clone()), TcpClient::spawn)
+      }
+      InterfaceConfig::TcpServer { listen } => {
+        tracing::info!("interface {name}: tcp listen {listen}");
+        // the server spawns an interface for each inbound connection
+        transport.iface_manager().lock().await.spawn(
+          TcpServer::new(listen.to_string(), transport.iface_manager()),
+          TcpServer::spawn)
+      }
+      InterfaceConfig::SharedInstance { instance_name, port } => {
+        let addr = match shared_instance::address(instance_name, *port).await {
+          Ok(addr) => addr,
+          Err(err) => {
+            tracing::error!("interface {name}: failed to relay to shared instance \
+              {instance_name}: {err:?}");
+            return None
+          }
+        };
+        tracing::info!("interface {name}: shared instance {instance_name} at {addr}");
+        transport.iface_manager().lock().await.spawn(
+          TcpClient::new(addr.to_string()), TcpClient::spawn)
+      }
+    };
+    Some(iface)
+  }
+}
+
+/// Payload compression algorithm
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+  Lz4
+}
+
+/// Kind of device created for the tunnel
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceMode {
+  /// Layer 3: IP packets, sent to peers by destination address
+  #[default]
+  Tun,
+  /// Layer 2: Ethernet frames, sent to peers by learned destination MAC address
+  Tap
+}
+
+/// Overflow policy of a full packet queue
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+  /// Drop the oldest queued packet, favoring fresh packets for latency-sensitive traffic
+  #[default]
+  DropOldest,
+  /// Drop the new packet, keeping the queued ones
+  DropNewest,
+  /// Wait for room, slowing down reading from the tun or routing rather than dropping
+  /// packets
+  Block
+}
+
+/// How payloads to a peer are spread over its links
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkBalance {
+  /// Only the link we requested
+  #[default]
+  Primary,
+  /// Each link in turn
+  RoundRobin,
+  /// Links in proportion to their weights
+  Weighted,
+  /// Links in proportion to their weights by flow, keeping the packets of a connection
+  /// on one link so that they aren't reordered
+  Flow
+}
+
+/// Log destination
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogBackend {
+  /// Standard output
+  #[default]
+  Console,
+  /// The systemd journal, with event and span fields as journal fields; Linux only
+  Journald,
+  /// The local syslog daemon; unix only
+  Syslog
+}
+
+/// Firewall used for exit traffic
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FirewallBackend {
+  /// nftables if `nft` is available, otherwise iptables
+  #[default]
+  Auto,
+  Nftables,
+  Iptables
+}
+
+/// Packet filter: the first rule matching a packet decides whether it passes
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+  /// Action for packets matching no rule
+  #[serde(default)]
+  pub default: FilterAction,
+  #[serde(default)]
+  pub rules: Vec<FilterRule>
+}
+
+/// Packet filter rule; unset fields match any packet
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterRule {
+  pub action: FilterAction,
+  /// Only match packets received from peers (`in`) or sent to them (`out`)
+  #[serde(default)]
+  pub direction: Option<FilterDirection>,
+  #[serde(default)]
+  pub src: Option<IpNet>,
+  #[serde(default)]
+  pub dst: Option<IpNet>,
+  #[serde(default)]
+  pub proto: Option<FilterProtocol>,
+  /// Destination port (`tcp` and `udp` only)
+  #[serde(default)]
+  pub port: Option<u16>
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+  #[default]
+  Allow,
+  Deny
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterDirection {
+  /// Received from a peer, written to the tun
+  In,
+  /// Read from the tun, sent to a peer
+  Out
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterProtocol {
+  Tcp,
+  Udp,
+  /// ICMP or ICMPv6
+  Icmp
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+  /// Destination hash
+  pub dest: DestinationHash,
+  /// Name used for the peer in logs, status output and hooks; taken from the key of
+  /// a peer table named instead of keyed by the peer's address
+  #[serde(default)]
+  pub name: Option<String>,
+  /// Tunnel address of a named peer
+  #[serde(default, skip_serializing)]
+  pub ip: Option<IpNet>,
+  /// Additional tunnel addresses of the peer, e.g. the IPv6 address of a dual-stack
+  /// peer
+  #[serde(default)]
+  pub addresses: Vec<IpAddr>,
+  /// Networks behind the peer: packets to them are sent to the peer, preferring the
+  /// peer with the longest matching prefix, and packets from them are accepted
+  #[serde(default)]
+  pub allowed_ips: Vec<IpNet>,
+  /// Forward and masquerade traffic from the peer to other networks, for peers using
+  /// this client as their exit node (Linux only)
+  #[serde(default)]
+  pub allow_exit_traffic: bool,
+  /// Limit the rate of packets sent to the peer to this many kilobits per second,
+  /// dropping packets over the limit (default: unlimited)
+  #[serde(default)]
+  pub rate_limit_kbps: Option<u32>,
+  /// Tear down the link after this many seconds without traffic in either
+  /// direction (default: never)
+  #[serde(default)]
+  pub idle_timeout_secs: Option<u32>,
+  /// Send a keepalive on the link whenever nothing has been sent to the peer for
+  /// this many seconds, keeping underlay NAT mappings alive (default: never)
+  #[serde(default)]
+  pub persistent_keepalive_secs: Option<u32>,
+  /// Pre-shared key the peer must prove it knows on each link before its packets are
+  /// accepted; the peer needs the same psk for this client
+  #[serde(default)]
+  pub psk: Option<String>,
+  /// Public keys of the peer: announces for its destination hash from any other
+  /// identity are ignored
+  #[serde(default)]
+  pub identity: Option<PeerIdentity>,
+  /// Send a parity frame after every this many payloads to the peer, so that one lost
+  /// payload per group can be rebuilt (default: disabled)
+  #[serde(default)]
+  pub fec_group_size: Option<u8>,
+  /// MTU of packets to and from the peer when below the tun MTU, e.g. for a peer behind
+  /// a narrow interface; announced to the peer on its links
+  #[serde(default)]
+  pub mtu: Option<u16>
+}
+
+/// Destination hash in the config, parsed from its hex string when the config is
+/// loaded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DestinationHash(pub AddressHash);
+
+impl Default for DestinationHash {
+  fn default() -> Self {
+    DestinationHash(AddressHash::new_from_slice(&[0x0; ADDRESS_HASH_LEN]))
+  }
+}
+
+impl std::str::FromStr for DestinationHash {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    AddressHash::new_from_hex_string(s).map(DestinationHash)
+      .map_err(|err| format!("invalid destination hash {s}: {err:?}"))
+  }
+}
+
+impl std::fmt::Display for DestinationHash {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str(format!("{}", self.0).trim_matches('/'))
+  }
+}
+
+impl<'de> Deserialize<'de> for DestinationHash {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>
+  {
+    String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+impl Serialize for DestinationHash {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer
+  {
+    serializer.collect_str(self)
+  }
+}
+
+/// Public keys of a peer in the config: its X25519 public key followed by its ed25519
+/// verifying key, as 128 hex digits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerIdentity {
+  public_key: [u8; 32],
+  verifying_key: [u8; 32]
+}
+
+impl PeerIdentity {
+  pub fn new(identity: &Identity) -> Self {
+    PeerIdentity {
+      public_key: identity.public_key_bytes().try_into().unwrap(),
+      verifying_key: identity.verifying_key_bytes().try_into().unwrap()
+    }
+  }
+
+  pub fn destination_hash(&self) -> AddressHash {
+    let identity = Identity::new_from_slices(&self.public_key, &self.verifying_key);
+    peer_destination_hash(identity)
+  }
+
+  fn matches(&self, identity: &Identity) -> bool {
+    *self == PeerIdentity::new(identity)
+  }
+}
+
+impl std::str::FromStr for PeerIdentity {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    let invalid = || format!("invalid peer identity {s}: expected 128 hex digits");
+    if s.len() != 128 || !s.is_ascii() {
+      return Err(invalid())
+    }
+    let bytes = (0..s.len()).step_by(2)
+      .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|_| invalid())?;
+    Ok(PeerIdentity {
+      public_key: bytes[..32].try_into().unwrap(),
+      verifying_key: bytes[32..].try_into().unwrap()
+    })
+  }
+}
+
+impl std::fmt::Display for PeerIdentity {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    for byte in self.public_key.iter().chain(self.verifying_key.iter()) {
+      write!(f, "{byte:02x}")?;
+    }
+    Ok(())
+  }
+}
+
+impl<'de> Deserialize<'de> for PeerIdentity {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>
+  {
+    String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+impl Serialize for PeerIdentity {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer
+  {
+    serializer.collect_str(self)
+  }
+}
+
+/// Peer given either as a destination hash string or a table of settings
+struct PeerEntry(PeerConfig);
+
+impl<'de> Deserialize<'de> for PeerEntry {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>
+  {
+    struct PeerVisitor;
+    impl<'de> serde::de::Visitor<'de> for PeerVisitor {
+      type Value = PeerConfig;
+      fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a destination hash or a table of peer settings")
+      }
+      fn visit_str<E: serde::de::Error>(self, dest: &str) -> Result<PeerConfig, E> {
+        let dest = dest.parse().map_err(E::custom)?;
+        Ok(PeerConfig { dest, ..Default::default() })
+      }
+      fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A)
+        -> Result<PeerConfig, A::Error>
+      {
+        PeerConfig::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+      }
+    }
+    deserializer.deserialize_any(PeerVisitor).map(PeerEntry)
+  }
+}
+
+/// Peers keyed by their address, or by a name with the address given as `ip`
+fn deserialize_peers<'de, D>(deserializer: D)
+  -> Result<BTreeMap<IpAddr, PeerConfig>, D::Error>
+where
+  D: serde::Deserializer<'de>
+{
+  use serde::de::Error;
+  let entries = BTreeMap::<String, PeerEntry>::deserialize(deserializer)?;
+  let mut peers = BTreeMap::new();
+  for (key, PeerEntry(mut peer)) in entries {
+    let ip = match (key.parse::<IpAddr>(), peer.ip) {
+      (Ok(ip), None) => ip,
+      (Ok(_), Some(_)) => return Err(D::Error::custom(format!(
+        "peer {key} is keyed by its address: ip is only for named peers"))),
+      (Err(_), Some(ip)) => {
+        peer.name = Some(key);
+        ip.addr()
+      }
+      (Err(_), None) => return Err(D::Error::custom(format!(
+        "named peer {key} has no ip")))
+    };
+    if peers.insert(ip, peer).is_some() {
+      return Err(D::Error::custom(format!("peer address {ip} is given more than once")))
+    }
+  }
+  Ok(peers)
+}
+
+/// Prefix of environment variables overriding config values; the single underscore
+/// `RNS_VPN_` variables are not config values
+pub const CONFIG_ENV_PREFIX: &str = "RNS_VPN__";
+
+/// Config file format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+  Toml,
+  Json,
+  Yaml
+}
+
+impl ConfigFormat {
+  /// Format given by the file extension: `.json`, `.yaml` or `.yml`, otherwise TOML
+  pub fn from_path(path: &std::path::Path) -> Self {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("json") => ConfigFormat::Json,
+      Some("yaml" | "yml") => ConfigFormat::Yaml,
+      _ => ConfigFormat::Toml
+    }
+  }
+}
+
+impl std::str::FromStr for ConfigFormat {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "toml" => Ok(ConfigFormat::Toml),
+      "json" => Ok(ConfigFormat::Json),
+      "yaml" => Ok(ConfigFormat::Yaml),
+      _ => Err(format!("unknown config format {s}: expected toml, json or yaml"))
+    }
+  }
+}
+
+impl Config {
+  /// Whether the client configures the tun's addresses and routes itself
+  fn manages_network(&self) -> bool {
+    self.manage_addresses && !self.attach_tun
+  }
+
+  /// Parse a TOML config, rejecting unknown keys and unsupported versions
+  pub fn from_toml(s: &str) -> Result<Self, CreateClientError> {
+    Config::parse(s, ConfigFormat::Toml)
+  }
+
+  /// Parse a config in the given format, rejecting unknown keys and unsupported
+  /// versions
+  pub fn parse(s: &str, format: ConfigFormat) -> Result<Self, CreateClientError> {
+    parse_document::<Config>(s, format)?.check_version()
+  }
+
+  /// Parse a config, if there is a config file, and apply the overrides among the
+  /// given environment variables (see `CONFIG_ENV_PREFIX`)
+  pub fn parse_with_overrides(s: Option<&str>, format: ConfigFormat,
+    vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, CreateClientError>
+  {
+    let overrides = vars.into_iter()
+      .filter_map(|(name, value)| {
+        Some((name.strip_prefix(CONFIG_ENV_PREFIX)?.to_owned(), value))
+      })
+      .collect::<Vec<_>>();
+    if overrides.is_empty() {
+      // parse directly so that errors point at the line in the file
+      return Config::parse(s.unwrap_or_default(), format)
+    }
+    let mut document = match s {
+      Some(s) => parse_document::<serde_json::Value>(s, format)?,
+      None => serde_json::Value::Object(Default::default())
+    };
+    for (name, value) in overrides.iter() {
+      overrides::apply(&mut document, name, value)
+        .map_err(CreateClientError::ConfigError)?;
+    }
+    serde_json::from_value::<Config>(document)
+      .map_err(|err| CreateClientError::ConfigError(format!("invalid config: {err}")))?
+      .check_version()
+  }
+
+  fn check_version(self) -> Result<Self, CreateClientError> {
+    if self.version > CONFIG_VERSION {
+      return Err(CreateClientError::ConfigError(format!(
+        "config version {} is newer than supported version {}", self.version,
+        CONFIG_VERSION)))
+    }
+    Ok(self)
+  }
+}
+
+fn parse_document<T: serde::de::DeserializeOwned>(s: &str, format: ConfigFormat)
+  -> Result<T, CreateClientError>
+{
+  match format {
+    ConfigFormat::Toml => toml::from_str(s).map_err(|err| err.to_string()),
+    ConfigFormat::Json => serde_json::from_str(s).map_err(|err| err.to_string()),
+    ConfigFormat::Yaml => serde_yaml::from_str(s).map_err(|err| err.to_string())
+  }.map_err(|err| CreateClientError::ConfigError(format!("invalid config: {err}")))
+}
+
+/// Problems found in a config by `Config::check`
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+  /// Problems that keep the client from starting
+  pub errors: Vec<String>,
+  /// Settings that are accepted but likely mistakes
+  pub warnings: Vec<String>
+}
+
+impl Config {
+  /// Check the whole config without creating any devices, collecting every problem
+  /// instead of stopping at the first
+  pub fn check(&self) -> ConfigReport {
+    let mut report = ConfigReport::default();
+    let errors = &mut report.errors;
+    if self.vpn_ip6.is_some_and(|vpn_ip6| !matches!(vpn_ip6, IpNet::V6(_))) {
+      errors.push("configured VPN IPv6 address is not an IPv6 address".to_owned());
+    }
+    if self.announce_freq_secs == 0 {
+      errors.push("announce_freq_secs must be at least 1".to_owned());
+    }
+    if self.tun_queues == 0 {
+      errors.push("tun_queues must be at least 1".to_owned());
+    }
+    if self.tun_queue_depth == 0 {
+      errors.push("tun_queue_depth must be at least 1".to_owned());
+    }
+    if self.peer_queue_depth == 0 {
+      errors.push("peer_queue_depth must be at least 1".to_owned());
+    }
+    if self.offline_queue_packets > 0 && self.offline_queue_ttl_ms == 0 {
+      errors.push("offline_queue_ttl_ms must be at least 1".to_owned());
+    }
+    let min_mtu = if self.vpn_ip6.is_some() { MIN_MTU_IPV6 } else { MIN_MTU };
+    if self.mtu < min_mtu {
+      errors.push(format!("mtu must be at least {min_mtu}"));
+    }
+    if self.failover_missed_keepalives > 0 && self.link_keepalive_secs == 0 {
+      errors.push("failover_missed_keepalives requires link_keepalive_secs".to_owned());
+    }
+    if self.failover_missed_keepalives > 0 && self.dead_peer_timeout_secs > 0
+      && self.failover_missed_keepalives.saturating_mul(self.link_keepalive_secs)
+        >= self.dead_peer_timeout_secs
+    {
+      report.warnings.push("failover_missed_keepalives * link_keepalive_secs is not below \
+        dead_peer_timeout_secs: peers are marked down before failing over".to_owned());
+    }
+    if self.link_balance != LinkBalance::Primary {
+      if self.replay_protection {
+        errors.push("link_balance requires replay_protection to be disabled".to_owned());
+      }
+      if self.link_weights == [0, 0] {
+        errors.push("link_weights must not both be 0".to_owned());
+      }
+    }
+    if self.link_balance == LinkBalance::Flow && self.coalesce_us.is_some() {
+      errors.push("flow link_balance requires coalesce_us to be unset".to_owned());
+    }
+    if self.rate_limit_kbps == Some(0) {
+      errors.push("rate_limit_kbps must be at least 1".to_owned());
+    }
+    errors.extend(exit_node_problem(self, &self.peers));
+    if let Some(tun_name) = &self.tun_name {
+      errors.extend(tun_name_problem(tun_name));
+    }
+    #[cfg(not(target_os = "linux"))]
+    if self.attach_tun {
+      errors.push("attach_tun is only supported on Linux".to_owned());
+    }
+    let fixed_tun_name = self.tun_name.as_ref().is_some_and(|name| !name.contains("%d"));
+    if self.attach_tun && !fixed_tun_name {
+      errors.push("attach_tun requires the tun_name of the existing device".to_owned());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if !self.manage_addresses {
+      errors.push("manage_addresses = false is only supported on Linux".to_owned());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if self.networkd {
+      errors.push("networkd is only supported on Linux".to_owned());
+    }
+    if self.networkd && !self.manages_network() {
+      errors.push("networkd can't be combined with attach_tun or manage_addresses = \
+        false".to_owned());
+    }
+    if (self.attach_tun || !self.manage_addresses) && self.fwmark.is_some() {
+      report.warnings.push("fwmark has no effect with attach_tun or manage_addresses = \
+        false: routing is left to whoever manages the device".to_owned());
+    }
+    if !self.manage_addresses && !fixed_tun_name {
+      report.warnings.push("manage_addresses = false without a fixed tun_name: the \
+        device name may differ between runs".to_owned());
+    }
+    if self.bridge.is_some() && self.mode != DeviceMode::Tap {
+      errors.push("bridge requires mode = \"tap\"".to_owned());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if self.mode == DeviceMode::Tap {
+      errors.push("mode = \"tap\" is only supported on Linux".to_owned());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if self.peers.values().any(|peer| peer.allow_exit_traffic) {
+      errors.push("allow_exit_traffic is only supported on Linux".to_owned());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if self.fwmark.is_some() {
+      errors.push("fwmark is only supported on Linux".to_owned());
+    }
+    if self.fwmark.is_some_and(|fwmark| fwmark == 0 || (253..=255).contains(&fwmark)) {
+      errors.push("fwmark must be at least 1 and not a reserved routing table \
+        (253-255)".to_owned());
+    }
+    if self.fwmark.is_some() && self.exit_node.is_none() {
+      report.warnings.push("fwmark has no effect without exit_node".to_owned());
+    }
+    if self.health_listen.is_some() && self.health_ready_peers > self.peers.len() {
+      report.warnings.push("health_ready_peers is more than the configured peers: \
+        /readyz never reports ready".to_owned());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if !self.dns.is_empty() || !self.dns_search.is_empty() {
+      errors.push("dns and dns_search are only supported on Linux".to_owned());
+    }
+    if self.dns.is_empty() && !self.dns_search.is_empty() {
+      errors.push("dns_search requires dns servers".to_owned());
+    }
+    // switching user keeps no more than CAP_NET_ADMIN, and only on Linux
+    if self.user.is_some() {
+      if !self.dns.is_empty() {
+        errors.push("dns can't be combined with user: removing it on shutdown needs root"
+          .to_owned());
+      }
+      if self.networkd {
+        errors.push("networkd can't be combined with user: the .network file is \
+          rewritten and removed as root".to_owned());
+      }
+      #[cfg(not(target_os = "linux"))]
+      if self.vpn_ip.is_none() {
+        errors.push("vpn_ip = \"auto\" can't be combined with user: leased addresses \
+          are added as root".to_owned());
+      }
+      #[cfg(not(target_os = "linux"))]
+      if self.manages_network() {
+        report.warnings.push("user: routes can't be updated while running, and \
+          addresses and routes aren't removed on shutdown".to_owned());
+      }
+      if self.control_socket.is_some() {
+        report.warnings.push("control_socket is created after switching to user: its \
+          directory must be writable by the user".to_owned());
+      }
+    }
+    errors.extend(self.filter.problems());
+    if self.filter.is_active() && self.mode == DeviceMode::Tap {
+      report.warnings.push("filter rules don't apply to Ethernet frames in tap mode"
+        .to_owned());
+    }
+    if self.relay && self.mode == DeviceMode::Tap {
+      report.warnings.push("relay only applies in tun mode".to_owned());
+    }
+    if self.advertise_routes.len() > (LINK_MDU - 1) / frame::ROUTE_MAX_LEN {
+      errors.push(format!("at most {} advertise_routes are supported",
+        (LINK_MDU - 1) / frame::ROUTE_MAX_LEN));
+    }
+    match self.lease_from {
+      None if self.vpn_ip.is_none() =>
+        errors.push("vpn_ip = \"auto\" requires lease_from".to_owned()),
+      Some(lease_from) if !self.peers.values().any(|peer| peer.dest == lease_from) =>
+        errors.push("lease_from destination is not a configured peer".to_owned()),
+      _ => {}
+    }
+    errors.extend(peer_problems(self, &self.peers));
+    // overlapping networks are routed to the peer with the longest prefix
+    let nets = self.peers.iter()
+      .flat_map(|(ip, peer)| peer.allowed_ips.iter().map(move |net| (ip, net.trunc())))
+      .collect::<Vec<_>>();
+    for (i, (ip, net)) in nets.iter().enumerate() {
+      for (other_ip, other) in nets[i + 1..].iter() {
+        if ip != other_ip && net != other && (net.contains(other) || other.contains(net)) {
+          report.warnings.push(format!("allowed IPs {net} of peer {ip} overlap {other} of \
+            peer {other_ip}: the longest prefix is used"));
+        }
+      }
+    }
+    report
+  }
 }
 
 pub struct Client {
   config: Config,
-  tun: Tun
+  tun: Tun,
+  peer_map: tokio::sync::Mutex<PeerMap>,
+  peer_reload_tx: tokio::sync::mpsc::UnboundedSender<PeerUpdate>,
+  peer_reload_rx: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<PeerUpdate>>,
+  discovery_trusted: Vec<AddressHash>,
+  allowed_identities: Vec<AddressHash>,
+  /// Destination each inbound link identified itself as, verified by its answer to an
+  /// identify challenge
+  in_links: tokio::sync::Mutex<BTreeMap<LinkId, AddressHash>>,
+  /// Inbound links that claimed a destination with their mtu frame and were challenged
+  /// to prove it
+  pending_links: std::sync::Mutex<BTreeMap<LinkId, identify::PendingLink>>,
+  /// Sequence numbers received on each inbound link that sequences its payloads
+  replay_windows: std::sync::Mutex<BTreeMap<LinkId, replay::ReplayWindow>>,
+  /// Psk authentication of each inbound link of a peer with a psk
+  link_auth: std::sync::Mutex<BTreeMap<LinkId, psk::LinkAuth>>,
+  /// Recent FEC groups received on each inbound link
+  fec_decoders: std::sync::Mutex<BTreeMap<LinkId, fec::Decoder>>,
+  /// Hub to lease the tunnel address from
+  lease_from: Option<AddressHash>,
+  /// Tunnel address leased from the hub
+  leased_ip: std::sync::Mutex<Option<IpNet>>,
+  /// Addresses leased to clients (hub mode)
+  leases: tokio::sync::Mutex<BTreeMap<AddressHash, IpNet>>,
+  reassembler: tokio::sync::Mutex<fragment::Reassembler>,
+  /// Peer each MAC address was learned behind (tap mode)
+  mac_table: std::sync::Mutex<mac_table::MacTable>,
+  metrics: Metrics,
+  /// Buffers packets are read, batched and framed into on their way from the tun to
+  /// the links
+  buffers: Arc<BufferPool>,
+  /// Queue of each peer's sender, started when the first packet is routed to the peer
+  peer_queues: std::sync::Mutex<BTreeMap<IpAddr, PeerQueue>>,
+  /// Queues of new senders for the sender loop to run
+  new_senders_tx: tokio::sync::mpsc::UnboundedSender<(IpAddr, PeerQueue)>,
+  new_senders_rx:
+    tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<(IpAddr, PeerQueue)>>,
+  /// Rate limit of packets sent to all peers
+  rate_limit: Option<std::sync::Mutex<ratelimit::TokenBucket>>,
+  /// Rate limit of ICMP errors written to the tun
+  icmp_rate_limit: std::sync::Mutex<ratelimit::TokenBucket>,
+  /// Announce without waiting for the current interval, e.g. after a peer link dropped
+  announce_now: tokio::sync::Notify,
+  /// Benchmarks and pings waiting for echo replies
+  echo_waiters: std::sync::Mutex<bench::EchoWaiters>,
+  /// Policy routing, firewall and DNS changes made to the host
+  network: NetworkSetup,
+  /// Callbacks given to `ClientBuilder::on_peer_event`
+  peer_callbacks: Vec<PeerCallback>,
+  /// Sender of the events returned by `events`
+  events: tokio::sync::broadcast::Sender<Event>,
+  /// Config file peers added or removed with `persist` are written to
+  config_path: Option<std::path::PathBuf>,
+  /// Names and addresses of the transport's interfaces, which links are failed over
+  /// between
+  interfaces: Vec<(String, AddressHash)>,
+  shutdown: CancellationToken
+}
+
+/// Packets routed to a peer waiting for its sender
+type PeerQueue = Arc<PacketQueue<PooledBuf>>;
+
+/// Change to the peers applied by the reload loop
+enum PeerUpdate {
+  /// Replace all peers
+  Replace(BTreeMap<IpAddr, Peer>),
+  Add(IpAddr, Peer),
+  Remove(IpAddr)
+}
+
+/// Link state of a configured peer
+#[derive(Clone, Debug)]
+pub struct PeerLink {
+  pub ip: IpNet,
+  pub name: Option<String>,
+  pub dest: AddressHash,
+  pub link_active: bool,
+  pub link_id: Option<LinkId>,
+  /// MTU agreed with the peer over the current link
+  pub mtu: Option<u16>,
+  pub tx_packets: u64,
+  pub tx_bytes: u64,
+  pub rx_packets: u64,
+  pub rx_bytes: u64,
+  /// Time since a packet was last sent to or received from the peer
+  pub since_last_packet: Option<Duration>,
+  /// Time since anything, including keepalives, was last received from the peer
+  pub since_last_seen: Option<Duration>,
+  /// Links to the peer activated since it was added
+  pub links_established: u64,
+  pub drops: PeerDrops,
+  /// Quality of the current link as estimated from probes
+  pub quality: LinkQuality,
+  /// Smoothed round trip time of the probes on the current link
+  pub rtt: Option<Duration>,
+  /// Fraction of the recent probes on the current link that were lost
+  pub loss: Option<f64>,
+  /// Smoothed rates of the traffic to and from the peer, in kilobits per second
+  pub tx_rate_kbps: f64,
+  pub rx_rate_kbps: f64
+}
+
+impl PeerLink {
+  fn new(ip: IpAddr, peer: &Peer) -> Self {
+    PeerLink {
+      ip: IpNet::from(ip),
+      name: peer.name.clone(),
+      dest: peer.dest,
+      link_active: peer.link_active,
+      link_id: peer.link_id,
+      mtu: peer.packet_mtu(),
+      tx_packets: peer.tx_packets,
+      tx_bytes: peer.tx_bytes,
+      rx_packets: peer.rx_packets,
+      rx_bytes: peer.rx_bytes,
+      since_last_packet: peer.last_packet.map(|last_packet| last_packet.elapsed()),
+      since_last_seen: peer.last_seen.map(|last_seen| last_seen.elapsed()),
+      links_established: peer.links_established,
+      drops: peer.drops,
+      quality: peer.quality.quality(),
+      rtt: peer.quality.rtt(),
+      loss: peer.quality.loss(),
+      tx_rate_kbps: peer.quality.tx_rate_kbps(),
+      rx_rate_kbps: peer.quality.rx_rate_kbps()
+    }
+  }
 }
 
-#[derive(Debug)]
+/// Packets to or from a peer that were dropped, by reason
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerDrops {
+  /// Larger than the path MTU
+  pub too_large: u64,
+  /// Over the peer's or the global rate limit
+  pub rate_limited: u64,
+  /// No active link to send them on
+  pub no_link: u64,
+  /// Received with a source address belonging to another peer
+  pub spoofed: u64
+}
+
+/// Failure setting up the client or applying changes to it
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum CreateClientError {
+  /// Invalid config or a setting that can't be applied here
+  #[error("{0}")]
   ConfigError(String),
-  RiptunError(riptun::Error),
-  IpAddBroadcastError(std::io::Error),
-  IpLinkUpError(std::io::Error),
-  IpRouteAddError(std::io::Error),
-  IptablesError(std::io::Error)
+  #[cfg(target_os = "linux")]
+  #[error("failed to create tun device: {0}")]
+  RiptunError(#[source] riptun::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to connect to netlink: {0}")]
+  NetlinkError(#[source] std::io::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to get link: {0}")]
+  IpLinkGetError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("no link {0}")]
+  IpLinkNotFoundError(String),
+  #[cfg(target_os = "linux")]
+  #[error("failed to set link up: {0}")]
+  IpLinkUpError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to delete link: {0}")]
+  IpLinkDeleteError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to set link MTU: {0}")]
+  IpLinkSetMtuError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to add link to bridge: {0}")]
+  IpLinkSetControllerError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to get addresses: {0}")]
+  IpAddrGetError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to add address: {0}")]
+  IpAddrAddError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to delete address: {0}")]
+  IpAddrDelError(#[source] rtnetlink::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to create tap device: {0}")]
+  TapDeviceError(#[source] std::io::Error),
+  #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+  #[error("failed to create tun device: {0}")]
+  TunDeviceError(#[source] std::io::Error),
+  #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+  #[error("ifconfig failed: {0}")]
+  IfconfigError(#[source] std::io::Error),
+  #[cfg(windows)]
+  #[error("wintun failed: {0}")]
+  WintunError(#[source] wintun::Error),
+  #[cfg(windows)]
+  #[error("IP helper failed: {0}")]
+  IpHelperError(#[source] std::io::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to set sysctl: {0}")]
+  SysctlError(#[source] std::io::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to set up nftables: {0}")]
+  NftablesError(#[source] std::io::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to set up policy routing: {0}")]
+  PolicyRoutingError(#[source] std::io::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to set up DNS: {0}")]
+  DnsError(#[source] std::io::Error),
+  #[cfg(target_os = "linux")]
+  #[error("failed to hand the tun over to systemd-networkd: {0}")]
+  NetworkdError(#[source] std::io::Error),
+  #[error("failed to write config: {0}")]
+  ConfigWriteError(#[source] std::io::Error),
+  #[error("failed to get routes: {0}")]
+  IpRouteGetError(#[source] std::io::Error),
+  #[error("failed to add route: {0}")]
+  IpRouteAddError(#[source] std::io::Error),
+  #[error("failed to delete route: {0}")]
+  IpRouteDelError(#[source] std::io::Error),
+  #[error("failed to set up iptables: {0}")]
+  IptablesError(#[source] std::io::Error),
+  /// The address is assigned to another device
+  #[error("{0}")]
+  IpAddrInUseError(String)
+}
+
+/// Why `Client::run` stopped other than by being cancelled or shut down
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum RunError {
+  /// A loop failed for good: the tun device or the transport is gone, or it kept
+  /// failing after restarts
+  #[error("{name} loop failed: {reason}")]
+  LoopFailed { name: &'static str, reason: String },
+  /// A loop running for the life of the client returned
+  #[error("{0} loop exited")]
+  LoopExited(&'static str),
+  /// `ClientHandle::start` was called again
+  #[error("client already started")]
+  AlreadyStarted
 }
 
 struct Peer {
   dest: AddressHash,
+  name: Option<String>,
+  /// Additional tunnel addresses
+  addresses: Vec<IpAddr>,
+  /// Networks routed to the peer
+  allowed_ips: Vec<IpNet>,
   link_id: Option<LinkId>,
-  link_active: bool
+  link_active: bool,
+  idle_timeout: Option<Duration>,
+  last_activity: Instant,
+  persistent_keepalive: Option<Duration>,
+  rate_limit: Option<ratelimit::TokenBucket>,
+  last_sent: Instant,
+  /// When anything was last received from the peer, or its link was requested
+  last_received: Instant,
+  /// Pending batch frame of coalesced packets
+  batch: Option<PooledBuf>,
+  batch_started: Instant,
+  /// Smaller of our MTU and the one announced by the peer on the current link
+  path_mtu: Option<u16>,
+  /// Configured MTU of the peer
+  mtu: Option<u16>,
+  /// Peer announced it supports our compression on the current link
+  compression: bool,
+  /// Peer asked for sequenced payloads on the current link
+  sequencing: bool,
+  /// Sequence number of the last payload sent on the current link
+  tx_sequence: u64,
+  /// When the current link was requested
+  link_requested: Instant,
+  /// Destination from the peer's last announce, used to re-link without waiting for
+  /// the next one
+  desc: Option<DestinationDesc>,
+  /// When to try re-linking after the link closed
+  relink_at: Option<Instant>,
+  relink_backoff: Duration,
+  tx_packets: u64,
+  tx_bytes: u64,
+  rx_packets: u64,
+  rx_bytes: u64,
+  last_packet: Option<Instant>,
+  /// When anything was last received from the peer
+  last_seen: Option<Instant>,
+  links_established: u64,
+  drops: PeerDrops,
+  /// Tunnel address of the hub that shared the peer in mesh mode, or of the introducer
+  /// whose routes covered it, which traffic goes through while there is no direct link
+  mesh_via: Option<IpAddr>,
+  /// Subnets advertised by the peer and accepted
+  advertised_routes: Vec<IpNet>,
+  /// Pre-shared key authenticating the peer's inbound links
+  psk: Option<String>,
+  /// Identity the peer's announces must come from
+  identity: Option<PeerIdentity>,
+  /// Packets held while the link is down, with when they were held
+  offline_queue: VecDeque<(Instant, PooledBuf)>,
+  /// Parity of the payloads sent to the peer, if FEC is enabled for it
+  fec: Option<fec::Encoder>,
+  /// Probes, round trip time, loss and throughput of the current link
+  quality: quality::QualityTracker,
+  /// Quality of the paths to the peer through each interface
+  paths: multipath::Paths,
+  /// Span of events about the peer, so that they can be filtered by destination
+  span: tracing::Span
 }
 
-struct Tun {
-  tun: TokioTun,
-  read_buf: tokio::sync::Mutex<[u8; MTU]>
+/// Changes made to the host network for the tunnel besides creating the tun, kept so
+/// that they are undone both on shutdown and when creating the client fails part way
+#[derive(Default)]
+struct NetworkSetup {
+  /// Routing table and rules keeping the Reticulum transport out of the tunnel
+  #[cfg(target_os = "linux")]
+  policy_routing: Option<policy::PolicyRouting>,
+  /// Forwarding and masquerading for peers allowed exit traffic
+  #[cfg(target_os = "linux")]
+  firewall: Option<firewall::Firewall>,
+  /// Tunnel DNS settings
+  #[cfg(target_os = "linux")]
+  dns: Option<dns::Dns>
+}
+
+impl NetworkSetup {
+  /// Route the peers' networks into the tun and apply the policy routing, firewall and
+  /// DNS settings of the config; on failure, what was applied so far is kept for `undo`
+  async fn apply(&mut self, tun: &Tun, config: &Config, routes: &[IpNet])
+    -> Result<(), CreateClientError>
+  {
+    // keep the underlay out of the tunnel before routing everything into it
+    #[cfg(target_os = "linux")]
+    if let (Some(_), Some(fwmark)) = (config.exit_node, config.fwmark) {
+      if config.manages_network() {
+        self.policy_routing = Some(policy::PolicyRouting::new(fwmark, tun.name(),
+          config.vpn_ip6.is_some(), &config.interfaces).await?);
+      }
+    }
+    if config.exit_node.is_some() && config.fwmark.is_none() {
+      for ip in underlay_ips(&config.interfaces).await? {
+        tun.add_bypass_route(ip).await?;
+      }
+    }
+    for net in routes {
+      for net in kernel_routes(*net, config) {
+        tun.add_route(net).await?;
+      }
+    }
+    #[cfg(target_os = "linux")]
+    {
+      let exit_ips = config.peers.iter().filter(|(_, peer)| peer.allow_exit_traffic)
+        .flat_map(|(ip, peer)| std::iter::once(ip).chain(peer.addresses.iter()))
+        .copied()
+        .collect::<Vec<_>>();
+      if !exit_ips.is_empty() {
+        let firewall = self.firewall
+          .insert(firewall::Firewall::new(tun.name(), config.firewall_backend)?);
+        for ip in exit_ips {
+          firewall.enable_forwarding(ip.is_ipv6())?;
+          firewall.allow_exit(ip)?;
+        }
+      }
+      if !config.dns.is_empty() {
+        self.dns = Some(dns::Dns::apply(tun.name(), &config.dns, &config.dns_search)?);
+      }
+    }
+    Ok(())
+  }
+
+  /// Undo the changes in reverse order, along with the tun's addresses and routes
+  async fn undo(&self, tun: &Tun) {
+    #[cfg(target_os = "linux")]
+    if let Some(dns) = &self.dns {
+      dns.cleanup();
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(firewall) = &self.firewall {
+      firewall.cleanup();
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(policy_routing) = &self.policy_routing {
+      policy_routing.cleanup();
+    }
+    tun.remove_addresses().await;
+  }
 }
 
 impl Client {
-  pub fn new(config: Config) -> Result<Self, CreateClientError> {
-    if config.peers.contains_key(&config.vpn_ip.addr()) {
-      log::error!("configured VPN IP ({}) conflicts with peer IPs: {:?}",
-        config.vpn_ip, config.peers);
+  pub async fn new(config: Config) -> Result<Self, CreateClientError> {
+    let report = config.check();
+    for warning in report.warnings.iter() {
+      tracing::warn!("config: {warning}");
+    }
+    if !report.errors.is_empty() {
+      return Err(CreateClientError::ConfigError(report.errors.join("; ")))
+    }
+    let mut peer_map = tokio::sync::Mutex::new(
+      PeerMap::new(build_peer_map(&config, &config.peers)));
+    let addresses = [config.vpn_ip, config.vpn_ip6].into_iter().flatten()
+      .collect::<Vec<_>>();
+    let lease_from = config.lease_from.map(|dest| dest.0);
+    let discovery_trusted = config.discovery_trusted.iter().map(|dest| dest.0).collect();
+    let allowed_identities = config.allowed_identities.iter().map(|dest| dest.0).collect();
+    let tun = Tun::new(&addresses, &config).await?;
+    let routes = peer_map.get_mut().iter().flat_map(|(ip, peer)| peer.routes(*ip, &addresses))
+      .collect::<Vec<_>>();
+    let mut network = NetworkSetup::default();
+    if let Err(err) = network.apply(&tun, &config, &routes).await {
+      tracing::error!("network setup failed: undoing changes");
+      network.undo(&tun).await;
+      return Err(err)
+    }
+    let (peer_reload_tx, peer_reload_rx) = tokio::sync::mpsc::unbounded_channel();
+    let peer_reload_rx = tokio::sync::Mutex::new(peer_reload_rx);
+    let buffers = BufferPool::new(config.mtu as usize + ETHERNET_MAX_HEADER_LEN);
+    let (new_senders_tx, new_senders_rx) = tokio::sync::mpsc::unbounded_channel();
+    let new_senders_rx = tokio::sync::Mutex::new(new_senders_rx);
+    Ok(Client {
+      config, tun, peer_map, peer_reload_tx, peer_reload_rx, discovery_trusted,
+      allowed_identities, in_links: tokio::sync::Mutex::new(BTreeMap::new()), lease_from,
+      leased_ip: std::sync::Mutex::new(None),
+      leases: tokio::sync::Mutex::new(BTreeMap::new()),
+      reassembler: tokio::sync::Mutex::new(fragment::Reassembler::default()),
+      mac_table: std::sync::Mutex::new(mac_table::MacTable::default()),
+      pending_links: std::sync::Mutex::new(BTreeMap::new()),
+      replay_windows: std::sync::Mutex::new(BTreeMap::new()),
+      link_auth: std::sync::Mutex::new(BTreeMap::new()),
+      fec_decoders: std::sync::Mutex::new(BTreeMap::new()),
+      metrics: Metrics::default(),
+      buffers,
+      peer_queues: std::sync::Mutex::new(BTreeMap::new()),
+      new_senders_tx,
+      new_senders_rx,
+      rate_limit: config.rate_limit_kbps
+        .map(|kbps| std::sync::Mutex::new(ratelimit::TokenBucket::new(kbps))),
+      icmp_rate_limit: std::sync::Mutex::new(
+        ratelimit::TokenBucket::new(ICMP_RATE_LIMIT_KBPS)),
+      announce_now: tokio::sync::Notify::new(),
+      echo_waiters: std::sync::Mutex::new(bench::EchoWaiters::default()),
+      network,
+      peer_callbacks: Vec::new(),
+      events: tokio::sync::broadcast::channel(events::CHANNEL_CAPACITY).0,
+      config_path: None,
+      interfaces: Vec::new(),
+      shutdown: CancellationToken::new()
+    })
+  }
+
+  /// Replace the configured peers while running: links of removed peers are closed
+  /// and new peers are linked on their next announce
+  pub fn reload_peers(&self, peers: BTreeMap<IpAddr, PeerConfig>)
+    -> Result<(), CreateClientError>
+  {
+    check_peers(&self.config, &peers)?;
+    if let Some(problem) = exit_node_problem(&self.config, &peers) {
+      return Err(CreateClientError::ConfigError(problem))
+    }
+    let peer_map = build_peer_map(&self.config, &peers);
+    // the receiver is owned by the client so sending can't fail
+    let _ = self.peer_reload_tx.send(PeerUpdate::Replace(peer_map));
+    Ok(())
+  }
+
+  /// Write peers added or removed with `persist` to this config file
+  pub fn set_config_path(&mut self, path: std::path::PathBuf) {
+    self.config_path = Some(path);
+  }
+
+  /// Set the names and addresses of the interfaces spawned on the transport, so that
+  /// links failing over can move to another one
+  pub fn set_interfaces(&mut self, interfaces: Vec<(String, AddressHash)>) {
+    self.interfaces = interfaces;
+  }
+
+  /// Add a peer while running, and to the config file if `persist` is set; it is
+  /// linked on its next announce
+  pub async fn add_peer(&self, ip: IpAddr, peer: PeerConfig, persist: bool)
+    -> Result<(), CreateClientError>
+  {
+    let peers = BTreeMap::from([(ip, peer)]);
+    check_peers(&self.config, &peers)?;
+    let (ip, peer) = build_peer_map(&self.config, &peers).pop_first().unwrap();
+    {
+      let mut peer_map = self.peer_map.lock().await;
+      for addr in std::iter::once(&ip).chain(peer.addresses.iter()) {
+        if peer_map.find(addr).is_some() {
+          return Err(CreateClientError::ConfigError(
+            format!("peer address {addr} is already in use")))
+        }
+      }
+    }
+    if persist {
+      persist::add_peer(self.persist_path()?, ip, &peers[&ip])
+        .map_err(CreateClientError::ConfigWriteError)?;
+    }
+    let _ = self.peer_reload_tx.send(PeerUpdate::Add(ip, peer));
+    Ok(())
+  }
+
+  /// Remove a peer while running, closing its link, and from the config file if
+  /// `persist` is set; returns false if there is no such peer
+  pub async fn remove_peer(&self, ip: IpAddr, persist: bool)
+    -> Result<bool, CreateClientError>
+  {
+    if !self.peer_map.lock().await.contains_key(&ip) {
+      return Ok(false)
+    }
+    if self.config.exit_node == Some(ip) {
       return Err(CreateClientError::ConfigError(
-        "configured VPN IP exists in peer IPs".to_owned()))
+        format!("peer {ip} is the exit_node and can't be removed")))
+    }
+    if persist && !persist::remove_peer(self.persist_path()?, ip)
+      .map_err(CreateClientError::ConfigWriteError)?
+    {
+      tracing::warn!(%ip, "peer to remove is not in the config file");
     }
-    let tun = Tun::new(config.vpn_ip)?;
-    Ok(Client { config, tun })
+    let _ = self.peer_reload_tx.send(PeerUpdate::Remove(ip));
+    Ok(true)
   }
 
-  pub async fn run(&self, mut transport: Transport, id: PrivateIdentity) {
-    // set up peer map
-    let peer_map = {
-      let mut peer_map = BTreeMap::<IpAddr, Peer>::new();
-      for (ip, dest) in self.config.peers.iter().map(|(k,v)| (k.clone(), v.clone())) {
-        let dest = match AddressHash::new_from_hex_string(dest.as_str()) {
-          Ok(dest) => dest,
-          Err(err) => {
-            log::error!("error parsing peer destination hash: {err:?}");
-            return
-          }
-        };
-        let peer = Peer { dest, link_id: None, link_active: false };
-        assert!(peer_map.insert(ip, peer).is_none());
+  fn persist_path(&self) -> Result<&std::path::Path, CreateClientError> {
+    self.config_path.as_deref().ok_or_else(|| {
+      CreateClientError::ConfigError("no config file to write peers to".to_owned())
+    })
+  }
+
+  /// Send echo requests of `size` bytes to a peer as fast as its link takes them and
+  /// collect the replies
+  async fn bench(&self, transport: &Transport, ip: IpAddr, count: u32, size: usize)
+    -> Result<bench::Report, String>
+  {
+    if count == 0 || count > bench::MAX_COUNT {
+      return Err(format!("count must be between 1 and {}", bench::MAX_COUNT))
+    }
+    let max_size = self.config.mtu as usize;
+    if !(frame::ECHO_HEADER_LEN..=max_size).contains(&size) {
+      return Err(format!("size must be between {} and {max_size}", frame::ECHO_HEADER_LEN))
+    }
+    let peer = self.peer_map.lock().await.get_mut(&ip)
+      .map(|peer| (peer.dest, peer.link_active, peer.span.clone()));
+    let Some((dest, link_active, span)) = peer else {
+      return Err(format!("no peer {ip}"))
+    };
+    if !link_active {
+      return Err(format!("no active link to peer {ip}"))
+    }
+    let (seqs, mut echo_rx) = self.echo_waiters.lock().unwrap().register(count as u64);
+    tracing::info!(parent: &span, count, size, "starting benchmark");
+    let start = Instant::now();
+    let mut sent_at = Vec::with_capacity(count as usize);
+    for seq in seqs.clone() {
+      sent_at.push(Instant::now());
+      let request = frame::echo_request(seq, size);
+      if send_link_data(transport, &dest, &request).await.is_err() {
+        tracing::warn!(parent: &span, "benchmark link went away");
+        break
       }
-      tokio::sync::Mutex::new(peer_map)
+    }
+    let deadline = tokio::time::Instant::now()
+      + Duration::from_secs(bench::REPLY_TIMEOUT_SECS);
+    let mut replied = vec![false; sent_at.len()];
+    let mut rtts = Vec::with_capacity(sent_at.len());
+    let mut last_reply = start;
+    while rtts.len() < sent_at.len() {
+      let Ok(Some((seq, received))) = tokio::time::timeout_at(deadline, echo_rx.recv()).await
+        else { break };
+      let index = (seq - seqs.start) as usize;
+      let Some(replied) = replied.get_mut(index).filter(|replied| !**replied) else {
+        continue
+      };
+      *replied = true;
+      rtts.push(received - sent_at[index]);
+      last_reply = received;
+    }
+    self.echo_waiters.lock().unwrap().unregister(&seqs);
+    let report = bench::Report::new(sent_at.len() as u64, size, &rtts, last_reply - start);
+    tracing::info!(parent: &span, sent = report.sent, received = report.received,
+      "benchmark done");
+    Ok(report)
+  }
+
+  /// Send one echo request to a peer over its link and wait for the reply
+  async fn ping(&self, transport: &Transport, ip: IpAddr) -> Result<bench::Ping, String> {
+    let peer = self.peer_map.lock().await.get_mut(&ip)
+      .map(|peer| (peer.dest, peer.link_id, peer.link_active));
+    let Some((dest, link_id, link_active)) = peer else {
+      return Err(format!("no peer {ip}"))
     };
-    // create in destination
-    let in_destination = transport
-      .add_destination(id, DestinationName::new("rns_vpn", "client")).await;
-    let in_destination_hash = in_destination.lock().await.desc.address_hash;
-    log::info!("created destination: {}",
-      format!("{}", in_destination_hash).trim_matches('/'));
-    // send announces
-    let announce_loop = async || loop {
-      transport.send_announce(&in_destination, None).await;
-      tokio::time::sleep(
-        std::time::Duration::from_secs(self.config.announce_freq_secs as u64)
-      ).await;
+    let Some(link_id) = link_id else { return Ok(bench::Ping::NoLink) };
+    if !link_active {
+      return Ok(bench::Ping::LinkInactive(link_id))
+    }
+    let (seqs, mut echo_rx) = self.echo_waiters.lock().unwrap().register(1);
+    let sent_at = Instant::now();
+    let sent = send_link_data(transport, &dest, &frame::echo_request(seqs.start, 0)).await
+      .is_ok();
+    let reply = if sent {
+      tokio::time::timeout(Duration::from_secs(bench::REPLY_TIMEOUT_SECS), echo_rx.recv())
+        .await.ok().flatten()
+    } else {
+      None
     };
-    // set up links
-    let link_loop = async || {
-      let mut announce_recv = transport.recv_announces().await;
-      while let Ok(announce) = announce_recv.recv().await {
+    self.echo_waiters.lock().unwrap().unregister(&seqs);
+    Ok(match reply {
+      Some((_, received)) => bench::Ping::Reply(link_id, received - sent_at),
+      None => bench::Ping::Unresponsive(link_id)
+    })
+  }
+
+  /// Subscribe to peer, link and tun events
+  pub fn events(&self) -> tokio::sync::broadcast::Receiver<Event> {
+    self.events.subscribe()
+  }
+
+  /// Make `run` return as if cancelled, also if called before it
+  pub fn shutdown(&self) {
+    self.shutdown.cancel();
+  }
+
+  /// Current link state of each configured peer
+  pub async fn peer_links(&self) -> Vec<PeerLink> {
+    self.peer_map.lock().await.iter().map(|(ip, peer)| PeerLink::new(*ip, peer)).collect()
+  }
+
+  /// Run the client until `shutdown` is cancelled or `Client::shutdown` called, or a loop
+  /// fails for good
+  pub async fn run(&self, mut transport: Transport, id: PrivateIdentity,
+    shutdown: CancellationToken) -> Result<(), RunError>
+  {
+    let peer_map = &self.peer_map;
+    // create in destination
+    let in_destination = transport
+      .add_destination(id.clone(), DestinationName::new(DESTINATION_APP, DESTINATION_ASPECT))
+      .await;
+    let in_destination_hash = in_destination.lock().await.desc.address_hash;
+    tracing::info!("created destination: {}",
+      format!("{}", in_destination_hash).trim_matches('/'));
+    // send announces: rapidly at startup, backing off to the configured interval, and
+    // again right away when a peer link drops
+    let announce_loop = async || {
+      let max_interval = Duration::from_secs(self.config.announce_freq_secs as u64);
+      let initial_interval = Duration::from_secs(ANNOUNCE_INITIAL_SECS).min(max_interval);
+      let mut interval = initial_interval;
+      loop {
+        let app_data = self.config.discovery.then(|| {
+          let addresses = self.tunnel_ips().iter().map(|ip| ip.addr())
+            .collect::<Vec<_>>();
+          discovery::encode(&addresses)
+        });
+        transport.send_announce(&in_destination, app_data.as_deref()).await;
+        Metrics::inc(&self.metrics.announces_sent);
+        tokio::select! {
+          _ = tokio::time::sleep(jitter(interval)) => {
+            interval = (interval * 2).min(max_interval);
+          }
+          _ = self.announce_now.notified() => {
+            tracing::debug!("peer link dropped: announcing");
+            interval = initial_interval;
+          }
+        }
+      }
+    };
+    // set up links
+    let link_loop = async || {
+      let mut announce_recv = transport.recv_announces().await;
+      loop {
+        let announce = match announce_recv.recv().await {
+          Ok(announce) => announce,
+          Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) =>
+            return Failure::Recoverable(format!("missed {missed} announces")),
+          Err(tokio::sync::broadcast::error::RecvError::Closed) =>
+            return Failure::Fatal("transport closed".to_owned())
+        };
+        Metrics::inc(&self.metrics.announces_received);
         let destination = announce.destination.lock().await;
-        // loop up destination in peers
-        for peer in peer_map.lock().await.values_mut() {
-          if destination.desc.address_hash == peer.dest {
+        let desc = destination.desc;
+        // the transport checks the announce is signed by the identity it carries; the
+        // identity must also be the one the destination hash was derived from
+        if peer_destination_hash(desc.identity) != desc.address_hash {
+          if self.is_peer_or_trusted(&desc.address_hash).await {
+            tracing::warn!(dest = %desc.address_hash,
+              "ignoring announce from an identity not matching the destination hash");
+            Metrics::inc(&self.metrics.rejected_announces);
+          }
+          continue
+        }
+        if self.config.discovery {
+          self.discover_peer(desc.address_hash, announce.app_data.as_slice()).await;
+        }
+        // loop up destination in peers, linking them once the peer map is unlocked
+        let mut unlinked = Vec::new();
+        for (ip, peer) in peer_map.lock().await.iter_mut() {
+          if desc.address_hash == peer.dest {
+            if peer.identity.is_some_and(|identity| !identity.matches(&desc.identity)) {
+              tracing::warn!(parent: &peer.span,
+                "ignoring announce from an identity other than the pinned one");
+              Metrics::inc(&self.metrics.rejected_announces);
+              continue
+            }
+            peer.desc = Some(desc);
             if peer.link_id.is_none() {
-              let link = transport.link(destination.desc).await;
-              peer.link_id = Some(link.lock().await.id().clone());
-              log::debug!("created link {} for peer {}",
-                peer.link_id.as_ref().unwrap(), peer.dest);
-              peer.link_active = false;   // wait for link activated event
+              unlinked.push(*ip);
             }
           }
         }
+        for ip in unlinked {
+          self.link_peer(&transport, ip, desc).await;
+        }
       }
     };
-    // tun loop: read data from tun and send on links
-    let tun_loop = async || {
-      while let Ok(bytes) = self.tun.read().await {
-        log::trace!("got tun bytes ({})", bytes.len());
-        if let Ok((ip_header, _)) = etherparse::IpHeaders::from_slice(bytes.as_slice())
-          .map_err(|e| log::error!("couldn't parse packet from tun: {e:?}"))
-        {
-          let mut destination_ip = None;
-          if let Some((ipv4_header, _)) = ip_header.ipv4() {
-            destination_ip = Some(IpAddr::from(ipv4_header.destination));
-          } else if let Some((ipv6_header, _)) = ip_header.ipv6() {
-            destination_ip = Some(IpAddr::from(ipv6_header.destination));
-          } else {
-            log::error!("failed to get ipv4 or ipv6 headers from ip header: {:?}", ip_header);
-          }
-          if let Some(destination_ip) = destination_ip {
-            if let Some(peer) = peer_map.lock().await.get(&destination_ip) {
-              if let Some(link_id) = peer.link_id.as_ref() {
-                if let Some(link) = transport.find_out_link(&peer.dest).await {
-                  log::trace!("sending to {} on link {}", peer.dest, link_id);
-                  let link = link.lock().await;
-                  let packet = link.data_packet(&bytes).unwrap();
-                  drop(link);
-                  transport.send_packet(packet).await;
-                } else {
-                  log::warn!("could not get link {} for peer {}", link_id, peer.dest);
-                }
-              }
+    // tun loop: read data from tun and queue it for routing, never waiting on the
+    // transport
+    let tun_loop = async |queue, packets: &PacketQueue<PooledBuf>| {
+      let header_len = match self.config.mode {
+        DeviceMode::Tun => 0,
+        DeviceMode::Tap => ETHERNET_MAX_HEADER_LEN
+      };
+      let max_len = self.config.mtu as usize + header_len;
+      loop {
+        // read into a buffer from the pool, given back once the packet is sent
+        let mut buf = self.buffers.take();
+        buf.resize(max_len, 0);
+        let nbytes = match self.tun.read(queue, &mut buf).await {
+          Ok(nbytes) => nbytes,
+          Err(err) => {
+            self.tun_failed(&self.metrics.tun_read_errors, &err);
+            let reason = format!("tun read failed: {err:?}");
+            return if supervisor::is_device_gone(&err) {
+              Failure::Fatal(reason)
+            } else {
+              Failure::Recoverable(reason)
             }
           }
+        };
+        tracing::trace!(bytes = nbytes, "got tun bytes");
+        buf.truncate(nbytes);
+        self.count_queued(packets.push(buf).await, None);
+      }
+    };
+    // forward loop: route queued packets, queueing each for the sender of its peer; the
+    // peer map is locked only to look the peer up, never while waiting on the transport
+    let forward_loop = async |packets: &PacketQueue<PooledBuf>| {
+      while let Some(packet) = packets.pop().await {
+        if self.config.mode == DeviceMode::Tap {
+          self.route_ethernet(packet).await;
+          continue
+        }
+        if !self.filter_allows(FilterDirection::Out, &packet) {
+          continue
+        }
+        self.trace_packet("tun -> link", &packet);
+        let Some((_, destination_ip)) = packet_addrs(&packet) else { continue };
+        match outbound(&self.config, &destination_ip, |ip| self.is_broadcast(ip)) {
+          Outbound::Local => {
+            tracing::trace!(ip = %destination_ip, "not forwarding packet for management \
+              address");
+            continue
+          }
+          Outbound::Broadcast => {
+            tracing::trace!(ip = %destination_ip, bytes = packet.len(),
+              "sending broadcast packet");
+            self.queue_for_all(&packet).await;
+            continue
+          }
+          Outbound::Routed => {}
+        }
+        let queue = match peer_map.lock().await.route(&destination_ip) {
+          Some((ip, peer))
+            if peer.link_id.is_some() || self.config.offline_queue_packets > 0 =>
+            Some((peer.dest, self.peer_queue(ip))),
+          Some((_, peer)) => {
+            peer.drops.no_link += 1;
+            self.dropped(Some(peer.dest), DropReason::NoLink);
+            None
+          }
+          None => {
+            self.dropped(None, DropReason::NoRoute);
+            None
+          }
+        };
+        match queue {
+          Some((dest, queue)) => self.count_queued(queue.push(packet).await, Some(dest)),
+          None if self.config.icmp_unreachable => self.send_unreachable(&packet).await,
+          None => {}
         }
       }
     };
+    // one reader and router per tun queue, sharing the peer map
+    let packet_queues = (0..self.tun.queues())
+      .map(|_| PacketQueue::new(self.config.tun_queue_depth, self.config.queue_policy))
+      .collect::<Vec<_>>();
+    // a queue failing for good stops the client, as the device is gone
+    let tun_workers = async || {
+      let (tun_loop, forward_loop) = (&tun_loop, &forward_loop);
+      let workers = packet_queues.iter().enumerate().map(|(queue, packets)| {
+        let span = tracing::info_span!("tun", queue);
+        Box::pin(async move {
+          tokio::select! {
+            reason = supervisor::supervise("tun", &self.metrics,
+              async || tun_loop(queue, packets).await) =>
+              RunError::LoopFailed { name: "tun", reason },
+            _ = forward_loop(packets) => RunError::LoopExited("forward")
+          }
+        }.instrument(span))
+      });
+      futures::future::select_all(workers).await.0
+    };
     // upstream link data: put link data into tun
     let upstream_loop = async || {
       let mut in_link_events = transport.in_link_events();
-      while let Ok(link_event) = in_link_events.recv().await {
+      loop {
+        let link_event = match in_link_events.recv().await {
+          Ok(link_event) => link_event,
+          Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) =>
+            return Failure::Recoverable(format!("missed {missed} link events")),
+          Err(tokio::sync::broadcast::error::RecvError::Closed) =>
+            return Failure::Fatal("transport closed".to_owned())
+        };
         match link_event.event {
           LinkEvent::Data(payload) => if link_event.address_hash == in_destination_hash {
-            log::trace!("link {} payload ({})", link_event.id, payload.len());
-            match self.tun.send(payload.as_slice()).await {
-              Ok(n) => log::trace!("tun sent {n} bytes"),
-              Err(err) => {
-                log::error!("tun error sending bytes: {err:?}");
-                break
+            let span = tracing::debug_span!("link", link_id = %link_event.id);
+            let result = self.link_data(&transport, link_event.id, payload.as_slice())
+              .instrument(span).await;
+            if let Err(err) = result {
+              let reason = format!("tun write failed: {err:?}");
+              return if supervisor::is_device_gone(&err) {
+                Failure::Fatal(reason)
+              } else {
+                Failure::Recoverable(reason)
               }
             }
           }
           LinkEvent::Activated => if link_event.address_hash == in_destination_hash {
-            log::debug!("link activated {}", link_event.id);
-            // look up destination in peers
-            for peer in peer_map.lock().await.values_mut() {
+            tracing::debug!(link_id = %link_event.id, "link activated");
+            // look up destination in peers, greeting them once the peer map is unlocked
+            let mut activated = Vec::new();
+            for (ip, peer) in peer_map.lock().await.iter_mut() {
               if peer.link_id == Some(link_event.id) {
-                peer.link_active = true;
+                peer.activate();
+                self.spawn_hook(PeerEvent::Up, *ip, peer, link_event.id);
+                self.metrics.observe_link_activation(peer.link_requested.elapsed());
+                activated.push((peer.dest, peer.span.clone(), peer.mtu));
+              }
+            }
+            for (dest, span, mtu) in activated.iter() {
+              if self.config.send_hello {
+                tracing::debug!(parent: span, link_id = %link_event.id, "sending hello");
+              }
+              for frame in greeting(&self.config, *mtu, in_destination_hash.as_slice()) {
+                if send_link_data(&transport, dest, &frame).await.is_err() {
+                  tracing::warn!(parent: span, link_id = %link_event.id,
+                    "could not get link");
+                  break
+                }
               }
             }
+            if !activated.is_empty() && self.config.relay && self.config.mesh {
+              self.share_mesh_peers(&transport).await;
+            }
           }
           LinkEvent::Closed => if link_event.address_hash == in_destination_hash {
-            log::debug!("link closed {}", link_event.id);
+            tracing::debug!(link_id = %link_event.id, "link closed");
+            self.in_links.lock().await.remove(&link_event.id);
+            self.pending_links.lock().unwrap().remove(&link_event.id);
+            self.replay_windows.lock().unwrap().remove(&link_event.id);
+            self.link_auth.lock().unwrap().remove(&link_event.id);
+            self.fec_decoders.lock().unwrap().remove(&link_event.id);
             // remove closed link
-            for peer in peer_map.lock().await.values_mut() {
+            for (ip, peer) in peer_map.lock().await.iter_mut() {
               if peer.link_id == Some(link_event.id) {
-                peer.link_active = false;
-                let _ = peer.link_id.take();
+                if peer.link_active {
+                  self.spawn_hook(PeerEvent::Down, *ip, peer, link_event.id);
+                }
+                peer.schedule_relink();
+                self.announce_now.notify_one();
               }
             }
           }
         }
       }
     };
-    tokio::select!{
-      _ = announce_loop() => log::info!("announce loop exited: shutting down"),
-      _ = link_loop() => log::info!("link loop exited: shutting down"),
-      _ = tun_loop() => log::info!("tun loop exited: shutting down"),
-      _ = upstream_loop() => log::info!("upstream loop exited: shutting down"),
-      _ = tokio::signal::ctrl_c() => log::info!("got ctrl-c: shutting down")
-    }
-  }
-}
-
-impl Tun {
-  pub fn new(ip: IpNet) -> Result<Self, CreateClientError> {
-    log::debug!("creating tun device");
-    let ip: IpNet = ip.into();
-    let tun = TokioTun::new("rip%d", TUN_NQUEUES)
-      .map_err(CreateClientError::RiptunError)?;
-    log::debug!("created tun device: {}", tun.name());
-    log::debug!("adding broadcast ip addr: {}", ip);
-    let output = std::process::Command::new("ip")
-      .arg("addr")
-      .arg("add")
-      .arg(ip.to_string())
-      .arg("brd")
-      .arg(ip.addr().to_string())
-      .arg("dev")
-      .arg(tun.name())
-      .output()
-      .map_err(CreateClientError::IpAddBroadcastError)?;
-    if !output.status.success() {
-      return Err(CreateClientError::IpAddBroadcastError(
-        std::io::Error::other(format!("ip addr add command failed ({:?})",
-          output.status.code())).into()));
-    }
-    log::debug!("{} setting link up", tun.name());
-    let output = std::process::Command::new("ip")
-      .arg("link")
-      .arg("set")
-      .arg("dev")
-      .arg(tun.name())
-      .arg("up")
-      .output()
-      .map_err(CreateClientError::IpLinkUpError)?;
-    if !output.status.success() {
-      return Err(CreateClientError::IpLinkUpError(
-        std::io::Error::other(format!("ip link set command failed ({:?})",
-          output.status.code()))))
-    }
-    let adapter = Tun {
-      tun, read_buf: tokio::sync::Mutex::new([0x0; MTU])
-    };
-    Ok(adapter)
-  }
-
-  #[allow(dead_code)]
-  pub fn tun(&self) -> &TokioTun {
-    &self.tun
-  }
-
-  // TODO: can we return a lock of &[u8] to avoid creating vec?
-  pub async fn read(&self) -> Result<Vec<u8>, std::io::Error> {
-    let mut buf = self.read_buf.lock().await;
-    let nbytes = self.tun.recv(&mut buf[..]).await?;
-    Ok(buf[..nbytes].to_vec())
-  }
-
-  pub async fn send(&self, datagram: &[u8]) -> Result<usize, std::io::Error> {
-    self.tun.send(datagram).await
+    // health sweep: tear down links that have been idle for too long or whose peer
+    // stopped responding, and drop packets held for too long
+    let dead_peer_timeout = Duration::from_secs(self.config.dead_peer_timeout_secs as u64);
+    let offline_queue_ttl = Duration::from_millis(self.config.offline_queue_ttl_ms as u64);
+    let failover_timeout = Duration::from_secs(self.config.link_keepalive_secs as u64)
+      * self.config.failover_missed_keepalives;
+    let sweep_loop = async || loop {
+      tokio::time::sleep(Duration::from_secs(SWEEP_INTERVAL_SECS)).await;
+      self.reassembler.lock().await.expire(Duration::from_secs(FRAGMENT_TIMEOUT_SECS));
+      self.mac_table.lock().unwrap().expire(Duration::from_secs(MAC_AGE_SECS));
+      // links closed and peers re-linked once the peer map is unlocked
+      let mut closed = Vec::new();
+      let mut failed_over = Vec::new();
+      for (ip, peer) in peer_map.lock().await.iter_mut() {
+        peer.expire_held(offline_queue_ttl);
+        let Some(link_id) = peer.link_id else { continue };
+        if !dead_peer_timeout.is_zero() && peer.last_received.elapsed() >= dead_peer_timeout {
+          tracing::warn!(parent: &peer.span, link_id = %link_id,
+            timeout = ?dead_peer_timeout, "peer down: nothing received");
+          Metrics::inc(&self.metrics.peers_down);
+          if peer.link_active {
+            self.spawn_hook(PeerEvent::Down, *ip, peer, link_id);
+          }
+          closed.push(peer.dest);
+          peer.schedule_relink();
+          self.announce_now.notify_one();
+          continue
+        }
+        // the new link gets until the dead peer timeout to come up
+        if !failover_timeout.is_zero() && peer.link_active
+          && peer.last_received.elapsed() >= failover_timeout
+        {
+          let iface = peer.paths.fail_over(self.interfaces.len(), Instant::now());
+          let iface_name = iface.map(|iface| self.interfaces[iface].0.as_str());
+          tracing::warn!(parent: &peer.span, link_id = %link_id,
+            timeout = ?failover_timeout, interface = iface_name,
+            "link stopped passing keepalives: failing over");
+          Metrics::inc(&self.metrics.link_failovers);
+          self.spawn_hook(PeerEvent::Down, *ip, peer, link_id);
+          closed.push(peer.dest);
+          peer.reset_link();
+          if peer.desc.is_none() {
+            peer.schedule_relink();
+          }
+          failed_over.push((*ip, peer.dest, peer.desc, iface));
+          continue
+        }
+        if !is_idle(peer.idle_timeout, peer.last_activity, Instant::now()) {
+          continue
+        }
+        tracing::debug!(parent: &peer.span, link_id = %link_id,
+          timeout = ?peer.idle_timeout, "link idle: closing");
+        if peer.link_active {
+          self.spawn_hook(PeerEvent::Down, *ip, peer, link_id);
+        }
+        closed.push(peer.dest);
+        // link is re-established on the next announce
+        peer.reset_link();
+      }
+      for dest in closed.iter() {
+        close_link(&transport, dest).await;
+      }
+      for (ip, dest, desc, iface) in failed_over {
+        self.request_path(&transport, &dest, iface).await;
+        if let Some(desc) = desc {
+          self.link_peer(&transport, ip, desc).await;
+        }
+      }
+    };
+    // relink loop: re-link peers whose link closed with backoff, asking for a fresh
+    // path in case the old one went away
+    let relink_loop = async || loop {
+      tokio::time::sleep(Duration::from_secs(SWEEP_INTERVAL_SECS)).await;
+      // peers re-linked once the peer map is unlocked
+      let mut due = Vec::new();
+      for (ip, peer) in peer_map.lock().await.iter_mut() {
+        let Some(relink_at) = peer.relink_at else { continue };
+        if peer.link_id.is_some() {
+          // linked again on an announce
+          peer.relink_at = None;
+          continue
+        }
+        if Instant::now() < relink_at {
+          continue
+        }
+        if peer.desc.is_some() {
+          tracing::info!(parent: &peer.span, "re-linking");
+        } else {
+          // linked once its announce arrives
+          peer.relink_at = None;
+        }
+        due.push((*ip, peer.dest, peer.desc, peer.paths.current()));
+      }
+      for (ip, dest, desc, iface) in due {
+        self.request_path(&transport, &dest, iface).await;
+        if let Some(desc) = desc {
+          self.link_peer(&transport, ip, desc).await;
+        }
+      }
+    };
+    // keepalive loop: keep idle peer paths alive and show peers we are still up, and
+    // probe link quality; neither counts as activity for the idle timeout
+    let link_keepalive = (self.config.link_keepalive_secs > 0)
+      .then(|| Duration::from_secs(self.config.link_keepalive_secs as u64));
+    let link_probe = (self.config.link_probe_secs > 0)
+      .then(|| Duration::from_secs(self.config.link_probe_secs as u64));
+    let keepalive_loop = async || loop {
+      tokio::time::sleep(Duration::from_secs(SWEEP_INTERVAL_SECS)).await;
+      // probes (with their sequence number) and keepalives sent once the peer map is
+      // unlocked
+      let mut due = Vec::new();
+      for (ip, peer) in peer_map.lock().await.iter_mut() {
+        peer.quality.sample(peer.tx_bytes, peer.rx_bytes);
+        if !peer.link_active {
+          continue
+        }
+        peer.quality.expire();
+        log_quality(peer);
+        // a probe keeps the path alive too
+        let probe_due = link_probe.is_some_and(|link_probe| {
+          peer.quality.last_probe.is_none_or(|last| last.elapsed() >= link_probe)
+        });
+        if probe_due {
+          let seq = quality::next_probe_seq();
+          tracing::trace!(parent: &peer.span, seq, "sending probe");
+          due.push((*ip, peer.dest, peer.span.clone(), Some(seq)));
+          continue
+        }
+        let is_due = keepalive_due(peer.persistent_keepalive, link_keepalive,
+          peer.last_sent, Instant::now());
+        if !is_due {
+          continue
+        }
+        tracing::trace!(parent: &peer.span, "sending keepalive");
+        due.push((*ip, peer.dest, peer.span.clone(), None));
+      }
+      for (ip, dest, span, probe) in due {
+        let frame = match probe {
+          Some(seq) => frame::echo_request(seq, 0),
+          None => frame::keepalive()
+        };
+        if send_link_data(&transport, &dest, &frame).await.is_err() {
+          tracing::warn!(parent: &span, "could not get link");
+          continue
+        }
+        let mut peer_map = peer_map.lock().await;
+        let Some(peer) = peer_map.get_mut(&ip) else { continue };
+        if let Some(seq) = probe {
+          peer.quality.probe_sent(seq, Instant::now());
+        }
+        peer.last_sent = Instant::now();
+      }
+    };
+    // lease loop: request a tunnel address from the hub until one is leased
+    let lease_loop = async || {
+      if let Some(lease_from) = self.lease_from {
+        while self.leased_ip().is_none() {
+          let link_active = peer_map.lock().await.values()
+            .any(|peer| peer.dest == lease_from && peer.link_active);
+          if link_active {
+            tracing::debug!(dest = %lease_from, "requesting tunnel address lease");
+            let request = frame::lease_request(in_destination_hash.as_slice());
+            let _ = send_link_data(&transport, &lease_from, &request).await;
+          }
+          tokio::time::sleep(Duration::from_secs(LEASE_RETRY_SECS)).await;
+        }
+      }
+      std::future::pending::<()>().await
+    };
+    // out link data: lease offers from the hub, identify and psk challenges from peers
+    // and payloads peers balance onto our links to them
+    let balancing = self.config.link_balance != LinkBalance::Primary;
+    let out_link_loop = async || {
+      let mut out_link_events = transport.out_link_events();
+      while let Ok(link_event) = out_link_events.recv().await {
+        let LinkEvent::Data(payload) = link_event.event else { continue };
+        match Frame::parse(payload.as_slice()) {
+          Some(Frame::LeaseOffer(body))
+            if Some(link_event.address_hash) == self.lease_from =>
+          {
+            match frame::parse_lease_offer(body) {
+              Some(ip) => self.apply_lease(ip).await,
+              None => tracing::warn!(link_id = %link_event.id, "got invalid lease offer")
+            }
+          }
+          Some(Frame::IdentifyChallenge(body)) => {
+            self.answer_identify_challenge(&transport, &id, in_destination_hash,
+              link_event.id, link_event.address_hash, body).await;
+          }
+          Some(Frame::PskChallenge(body)) => {
+            self.answer_psk_challenge(&transport, link_event.address_hash, body).await;
+          }
+          Some(Frame::Fragment(body)) if balancing => {
+            if let Some(payload) = self.reassemble(link_event.id, body).await {
+              self.write_balanced(&transport, link_event.id, &payload).await;
+            }
+          }
+          Some(frame) if balancing && frame.carries_packets() => {
+            self.write_balanced(&transport, link_event.id, payload.as_slice()).await;
+          }
+          _ => {}
+        }
+      }
+    };
+    // reload loop: apply peers replaced with `reload_peers`
+    let reload_loop = async || {
+      let mut peer_reload_rx = self.peer_reload_rx.lock().await;
+      while let Some(update) = peer_reload_rx.recv().await {
+        let mut peer_map = peer_map.lock().await;
+        let (removed, new_peers) = match update {
+          PeerUpdate::Replace(new_peers) => {
+            // drop peers that were removed or whose destination changed
+            let removed = peer_map.iter()
+              .filter(|(ip, peer)| {
+                new_peers.get(ip).is_none_or(|new| new.dest != peer.dest)
+              })
+              .map(|(ip, _)| *ip)
+              .collect::<Vec<_>>();
+            (removed, new_peers)
+          }
+          PeerUpdate::Add(ip, peer) => (Vec::new(), BTreeMap::from([(ip, peer)])),
+          PeerUpdate::Remove(ip) => (vec![ip], BTreeMap::new())
+        };
+        for ip in removed {
+          let Some(peer) = peer_map.remove(&ip) else { continue };
+          tracing::info!(%ip, dest = %peer.dest, "removing peer");
+          self.stop_sender(&ip);
+          if peer.link_id.is_some() {
+            close_link(&transport, &peer.dest).await;
+          }
+          self.update_routes(&self.peer_routes(ip, &peer), &[]).await;
+        }
+        for (ip, new_peer) in new_peers {
+          match peer_map.get_mut(&ip) {
+            Some(peer) => {
+              let routes = self.peer_routes(ip, peer);
+              // routes advertised on the link are kept
+              let mut new_peer = new_peer;
+              new_peer.advertised_routes = peer.advertised_routes.clone();
+              self.update_routes(&routes, &self.peer_routes(ip, &new_peer)).await;
+              peer_map.update_settings(&ip, new_peer);
+            }
+            None => {
+              tracing::info!(%ip, dest = %new_peer.dest, "adding peer");
+              self.update_routes(&[], &self.peer_routes(ip, &new_peer)).await;
+              peer_map.insert(ip, new_peer);
+            }
+          }
+        }
+      }
+    };
+    // metrics loop: serve metrics to one scraper at a time
+    let metrics_loop = async || {
+      let Some(addr) = self.config.metrics_listen else {
+        return std::future::pending::<()>().await
+      };
+      let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+          tracing::error!("failed to listen for metrics on {addr}: {err:?}");
+          return std::future::pending::<()>().await
+        }
+      };
+      tracing::info!("serving metrics on http://{addr}/metrics");
+      loop {
+        let mut stream = match listener.accept().await {
+          Ok((stream, _)) => stream,
+          Err(err) => {
+            tracing::warn!("failed to accept metrics connection: {err:?}");
+            continue
+          }
+        };
+        let metrics = self.metrics.render(&self.peer_links().await);
+        metrics::respond(&mut stream, &metrics).await;
+      }
+    };
+    // health loop: answer probes one at a time
+    let health_loop = async || {
+      let Some(addr) = self.config.health_listen else {
+        return std::future::pending::<()>().await
+      };
+      let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+          tracing::error!("failed to listen for health probes on {addr}: {err:?}");
+          return std::future::pending::<()>().await
+        }
+      };
+      tracing::info!("serving health probes on http://{addr}/healthz");
+      loop {
+        let mut stream = match listener.accept().await {
+          Ok((stream, _)) => stream,
+          Err(err) => {
+            tracing::warn!("failed to accept health connection: {err:?}");
+            continue
+          }
+        };
+        let links_active = self.peer_links().await.iter()
+          .filter(|peer_link| peer_link.link_active).count();
+        let health = health::Health {
+          network_reachable: links_active > 0
+            || self.metrics.announces_received
+              .load(std::sync::atomic::Ordering::Relaxed) > 0,
+          links_active,
+          ready_links: self.config.health_ready_peers
+        };
+        health::respond(&mut stream, &health).await;
+      }
+    };
+    // sender loop: run the sender of each peer packets were routed to, so that waiting
+    // on one peer's link doesn't hold up the others
+    let sender_loop = async || {
+      use futures::StreamExt;
+      let mut new_senders_rx = self.new_senders_rx.lock().await;
+      let mut senders = futures::stream::FuturesUnordered::new();
+      loop {
+        tokio::select! {
+          Some((ip, queue)) = new_senders_rx.recv() =>
+            senders.push(self.peer_sender(&transport, ip, queue)),
+          Some(()) = senders.next(), if !senders.is_empty() => {}
+        }
+      }
+    };
+    // control loop: serve the management API, one task per connection
+    #[cfg(not(unix))]
+    let control_loop = async || std::future::pending::<()>().await;
+    #[cfg(unix)]
+    let control_loop = async || {
+      use futures::StreamExt;
+      let Some(path) = &self.config.control_socket else {
+        return std::future::pending::<()>().await
+      };
+      // remove a socket left behind by a previous run
+      let _ = std::fs::remove_file(path);
+      let listener = match tokio::net::UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+          tracing::error!("failed to listen on control socket {}: {err:?}", path.display());
+          return std::future::pending::<()>().await
+        }
+      };
+      {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(0o600);
+        if let Err(err) = std::fs::set_permissions(path, permissions) {
+          tracing::warn!("failed to restrict control socket permissions: {err:?}");
+        }
+      }
+      tracing::info!("control socket listening on {}", path.display());
+      let mut connections = futures::stream::FuturesUnordered::new();
+      loop {
+        tokio::select! {
+          accepted = listener.accept() => match accepted {
+            Ok((stream, _)) => connections.push(control::serve(self, &transport, stream)),
+            Err(err) => tracing::warn!("failed to accept control connection: {err:?}")
+          },
+          Some(()) = connections.next(), if !connections.is_empty() => {}
+        }
+      }
+    };
+    // systemd loop: report readiness, then feed the watchdog and report peer counts
+    #[cfg(not(target_os = "linux"))]
+    let systemd_loop = async || std::future::pending::<()>().await;
+    #[cfg(target_os = "linux")]
+    let systemd_loop = async || {
+      systemd::notify_ready();
+      let interval = systemd::alive_interval();
+      loop {
+        let peers = self.peer_links().await;
+        let links_active = peers.iter().filter(|peer| peer.link_active).count();
+        systemd::notify_alive(&format!("{} peers, {} links active", peers.len(),
+          links_active));
+        tokio::time::sleep(interval).await;
+      }
+    };
+    let failed = |name: &'static str, reason: String| -> Result<(), RunError> {
+      tracing::error!("{name} loop failed: {reason}: shutting down");
+      Err(RunError::LoopFailed { name, reason })
+    };
+    let exited = |name: &'static str| -> Result<(), RunError> {
+      tracing::error!("{name} loop exited: shutting down");
+      Err(RunError::LoopExited(name))
+    };
+    let result = tokio::select!{
+      _ = announce_loop().instrument(tracing::info_span!("announce")) =>
+        exited("announce"),
+      reason = supervisor::supervise("link", &self.metrics, link_loop)
+        .instrument(tracing::info_span!("link")) => failed("link", reason),
+      err = tun_workers() => {
+        tracing::error!("{err}: shutting down");
+        Err(err)
+      }
+      reason = supervisor::supervise("upstream", &self.metrics, upstream_loop)
+        .instrument(tracing::info_span!("upstream")) => failed("upstream", reason),
+      _ = sweep_loop().instrument(tracing::info_span!("sweep")) => exited("sweep"),
+      _ = sender_loop().instrument(tracing::info_span!("sender")) => exited("sender"),
+      _ = keepalive_loop().instrument(tracing::info_span!("keepalive")) =>
+        exited("keepalive"),
+      _ = relink_loop().instrument(tracing::info_span!("relink")) => exited("relink"),
+      _ = reload_loop().instrument(tracing::info_span!("reload")) => exited("reload"),
+      _ = lease_loop().instrument(tracing::info_span!("lease")) => exited("lease"),
+      _ = out_link_loop().instrument(tracing::info_span!("out_link")) =>
+        exited("out link"),
+      _ = metrics_loop().instrument(tracing::info_span!("metrics")) =>
+        exited("metrics"),
+      _ = health_loop().instrument(tracing::info_span!("health")) => exited("health"),
+      _ = control_loop().instrument(tracing::info_span!("control")) =>
+        exited("control"),
+      _ = systemd_loop().instrument(tracing::info_span!("systemd")) =>
+        exited("systemd"),
+      _ = shutdown.cancelled() => {
+        tracing::info!("cancelled: shutting down");
+        Ok(())
+      }
+      _ = self.shutdown.cancelled() => {
+        tracing::info!("shutdown requested: shutting down");
+        Ok(())
+      }
+    };
+    #[cfg(target_os = "linux")]
+    systemd::notify_stopping();
+    let shutdown_timeout = (self.config.shutdown_timeout_secs > 0)
+      .then(|| Duration::from_secs(self.config.shutdown_timeout_secs as u64));
+    match bounded(shutdown_timeout, self.teardown(&transport)).await {
+      true => tracing::info!("shutdown complete"),
+      false => tracing::warn!(timeout = ?shutdown_timeout,
+        "shutdown timed out: exiting without finishing teardown")
+    }
+    result
+  }
+
+  /// Close the links to peers so they don't wait for them to time out and remove the
+  /// tunnel addresses and routes
+  async fn teardown(&self, transport: &Transport) {
+    for (ip, peer) in self.peer_map.lock().await.iter_mut() {
+      tracing::info!(parent: &peer.span, tx_packets = peer.tx_packets,
+        tx_bytes = peer.tx_bytes, rx_packets = peer.rx_packets, rx_bytes = peer.rx_bytes,
+        links_established = peer.links_established, drops = ?peer.drops, "peer statistics");
+      if let Some(link_id) = peer.link_id.take() {
+        // wait for the down hook: nothing would be left to run it after shutdown
+        if let Some(script) = self.config.on_peer_down.clone().filter(|_| peer.link_active) {
+          hooks::run(script, PeerEvent::Down, *ip, peer.name.clone(), peer.dest, link_id)
+            .instrument(peer.span.clone()).await;
+        }
+        tracing::debug!(parent: &peer.span, "closing link");
+        close_link(transport, &peer.dest).await;
+        peer.link_active = false;
+      }
+    }
+    self.network.undo(&self.tun).await;
+  }
+
+  /// Handle a payload received on an inbound link; fails only if writing to the tun
+  /// fails
+  async fn link_data(&self, transport: &Transport, link_id: LinkId, payload: &[u8])
+    -> Result<(), std::io::Error>
+  {
+    tracing::trace!(bytes = payload.len(), "link payload");
+    self.touch_peer(link_id).await;
+    let frame = Frame::parse(payload);
+    if frame.as_ref().is_some_and(Frame::carries_packets) {
+      return self.write_payload(transport, link_id, payload).await
+    }
+    match frame {
+      // control frames are never written to the tun
+      Some(Frame::Hello) => tracing::debug!("got hello"),
+      Some(Frame::Keepalive) => tracing::trace!("got keepalive"),
+      Some(Frame::LeaseRequest(body)) => self.lease(transport, link_id, body).await,
+      Some(Frame::Mtu(body)) => self.send_identify_challenge(transport, link_id, body).await,
+      Some(Frame::IdentifyResponse(body)) => {
+        if self.check_identify_response(link_id, body).await {
+          self.send_psk_challenge(transport, link_id).await;
+        }
+      }
+      Some(Frame::Fragment(body)) => {
+        if let Some(payload) = self.reassemble(link_id, body).await {
+          return self.write_payload(transport, link_id, &payload).await
+        }
+      }
+      Some(Frame::Compression(body)) => self.set_compression(link_id, body).await,
+      Some(Frame::Sequencing(body)) => self.set_sequencing(link_id, body).await,
+      Some(Frame::EchoRequest(body)) => self.echo(transport, link_id, body).await,
+      Some(Frame::EchoReply(body)) => {
+        let Some(seq) = frame::parse_echo(body) else {
+          tracing::warn!("got invalid echo reply");
+          return Ok(())
+        };
+        if seq >= quality::PROBE_SEQ_BASE {
+          self.probe_reply(seq, Instant::now()).await;
+        } else {
+          self.echo_waiters.lock().unwrap().reply(seq, Instant::now());
+        }
+      }
+      Some(Frame::MeshPeers(body)) => self.add_mesh_peers(transport, link_id, body).await,
+      Some(Frame::Routes(body)) => self.set_advertised_routes(link_id, body).await,
+      Some(Frame::Pex(body)) => self.add_pex_peers(transport, link_id, body).await,
+      Some(Frame::PskResponse(body)) => self.check_psk_response(link_id, body).await,
+      Some(Frame::PskChallenge(_)) => tracing::warn!("dropping unexpected psk challenge"),
+      Some(Frame::IdentifyChallenge(_)) => {
+        tracing::warn!("dropping unexpected identify challenge");
+      }
+      Some(Frame::LeaseOffer(_)) => tracing::warn!("dropping unexpected lease offer"),
+      Some(Frame::Unknown(frame_type)) => {
+        tracing::warn!(frame_type, "dropping unknown frame type");
+      }
+      // written to the tun above
+      Some(Frame::Ip(_) | Frame::Batch(_) | Frame::Compressed(_) | Frame::Ethernet(_)
+        | Frame::Sequenced(_) | Frame::FecData(_) | Frame::FecParity(_)) | None => {}
+    }
+    Ok(())
+  }
+
+  /// Request a path to a peer through one of the interfaces, or through any if none is
+  /// given
+  async fn request_path(&self, transport: &Transport, dest: &AddressHash,
+    iface: Option<usize>)
+  {
+    let iface = iface.map(|iface| self.interfaces[iface].1);
+    transport.request_path(dest, iface).await;
+  }
+
+  /// Request a link to a peer with the peer map unlocked while it is sent; the link is
+  /// closed again if the peer was linked or removed meanwhile
+  async fn link_peer(&self, transport: &Transport, ip: IpAddr, desc: DestinationDesc) {
+    let link = transport.link(desc).await;
+    let link_id = *link.lock().await.id();
+    {
+      let mut peer_map = self.peer_map.lock().await;
+      let peer = peer_map.get_mut(&ip)
+        .filter(|peer| peer.dest == desc.address_hash && peer.link_id.is_none());
+      if let Some(peer) = peer {
+        peer.linked(link_id);
+        self.emit(Event::PeerLinked { ip, dest: peer.dest, link_id });
+        return
+      }
+    }
+    tracing::debug!(%ip, link_id = %link_id, "peer linked meanwhile: closing new link");
+    link.lock().await.close();
+  }
+
+  /// Queue of a peer's sender, starting the sender for the first packet routed to the
+  /// peer; called with the peer map locked, so that the peer isn't removed meanwhile
+  fn peer_queue(&self, ip: IpAddr) -> PeerQueue {
+    let (capacity, policy) = (self.config.peer_queue_depth, self.config.queue_policy);
+    self.peer_queues.lock().unwrap().entry(ip)
+      .or_insert_with(|| {
+        let queue = Arc::new(PacketQueue::new(capacity, policy));
+        let _ = self.new_senders_tx.send((ip, queue.clone()));
+        queue
+      })
+      .clone()
+  }
+
+  /// Stop the sender of a removed peer, dropping the packets queued for it
+  fn stop_sender(&self, ip: &IpAddr) {
+    if let Some(queue) = self.peer_queues.lock().unwrap().remove(ip) {
+      queue.close();
+    }
+  }
+
+  /// Count a packet that waited for room in a full queue or was dropped from it
+  fn count_queued(&self, pushed: queue::Pushed, dest: Option<AddressHash>) {
+    let counter = match pushed {
+      queue::Pushed::Queued | queue::Pushed::Closed => return,
+      queue::Pushed::Blocked => {
+        Metrics::inc(&self.metrics.queue_blocked_packets);
+        return
+      }
+      queue::Pushed::DroppedOldest => &self.metrics.queue_dropped_oldest_packets,
+      queue::Pushed::DroppedNewest => &self.metrics.queue_dropped_newest_packets
+    };
+    tracing::trace!(policy = ?self.config.queue_policy,
+      "packet queue full: dropped packet");
+    Metrics::inc(&self.metrics.queue_dropped_packets);
+    Metrics::inc(counter);
+    self.dropped(dest, DropReason::QueueFull);
+  }
+
+  /// Queue a copy of a broadcast packet or flooded Ethernet frame for each peer with an
+  /// active link
+  async fn queue_for_all(&self, packet: &[u8]) {
+    let queues = self.peer_map.lock().await.iter()
+      .filter(|(_, peer)| peer.link_active)
+      .map(|(ip, peer)| (peer.dest, self.peer_queue(*ip)))
+      .collect::<Vec<_>>();
+    for (dest, queue) in queues {
+      let mut copy = self.buffers.take();
+      copy.extend_from_slice(packet);
+      self.count_queued(queue.push(copy).await, Some(dest));
+    }
+  }
+
+  /// Send the packets queued for a peer until it is removed, and its pending batch once
+  /// that has waited long enough; the frames are built with the peer map locked, and
+  /// sent after unlocking it
+  async fn peer_sender(&self, transport: &Transport, ip: IpAddr, queue: PeerQueue) {
+    let coalesce = self.config.coalesce_us.map(|us| Duration::from_micros(us as u64));
+    // frames built for each packet, reused so as not to allocate for each
+    let mut frames = Vec::new();
+    // when to send the pending batch if no packet fills it first
+    let mut flush_at: Option<tokio::time::Instant> = None;
+    let mut balancer = balance::Balancer::default();
+    loop {
+      let popped = match flush_at {
+        Some(flush_at) => tokio::time::timeout_at(flush_at, queue.pop()).await,
+        None => Ok(queue.pop().await)
+      };
+      let packet = match popped {
+        Ok(Some(packet)) => Some(packet),
+        // the peer was removed
+        Ok(None) => return,
+        Err(_) => None
+      };
+      // payloads of a flow are kept on one link
+      let flow = packet.as_ref().filter(|_| self.config.link_balance == LinkBalance::Flow)
+        .and_then(|packet| PacketInfo::parse(packet)).map(|info| info.flow());
+      let (dest, span, packet_len, balanced) = {
+        let mut peer_map = self.peer_map.lock().await;
+        let Some(peer) = peer_map.get_mut(&ip) else {
+          flush_at = None;
+          continue
+        };
+        let span = peer.span.clone();
+        let packet_len = span.in_scope(|| match packet {
+          Some(packet) if self.config.mode == DeviceMode::Tap =>
+            self.frame_ethernet(peer, packet, &mut frames),
+          Some(packet) => self.frame_packet(peer, packet, &mut frames),
+          None => {
+            frame_batch(&self.buffers, peer, &mut frames);
+            None
+          }
+        });
+        flush_at = coalesce.filter(|_| peer.batch.is_some())
+          .map(|coalesce| (peer.batch_started + coalesce).into());
+        // if balancing, whether the peer's link must be authenticated with its psk; FEC
+        // parity is recovered from the payloads of one link
+        let balanced = self.config.link_balance != LinkBalance::Primary
+          && peer.link_active && peer.fec.is_none();
+        (peer.dest, span, packet_len, balanced.then_some(peer.psk.is_some()))
+      };
+      let peer_link = match balanced {
+        Some(has_psk) if !frames.is_empty() => self.peer_link(&dest, has_psk).await,
+        _ => None
+      };
+      let mut sent = Ok(());
+      for frame in frames.drain(..) {
+        if sent.is_err() {
+          continue
+        }
+        let weights = &self.config.link_weights;
+        let link = match peer_link {
+          Some(link_id)
+            if balancer.pick(self.config.link_balance, weights, flow) == 1 =>
+          {
+            transport.find_in_link(&link_id).await
+          }
+          _ => None
+        };
+        sent = match link {
+          Some(link) => send_on_link(transport, &link, &dest, &frame).await,
+          None => send_link_data(transport, &dest, &frame).await
+        };
+      }
+      // a packet sent on its own is counted once its link was found
+      let Some(len) = packet_len else { continue };
+      let mut peer_map = self.peer_map.lock().await;
+      let Some(peer) = peer_map.get_mut(&ip) else { continue };
+      match sent {
+        Ok(()) => peer.record_sent(len),
+        Err(reason) => {
+          if reason == DropReason::TooLarge {
+            peer.drops.too_large += 1;
+          } else {
+            tracing::warn!(parent: &span, "could not get link");
+            peer.drops.no_link += 1;
+          }
+          self.dropped(Some(dest), reason);
+        }
+      }
+    }
+  }
+
+  /// Inbound link of a peer that its payloads may be balanced onto: identified as the
+  /// peer, and authenticated if it has a psk
+  async fn peer_link(&self, dest: &AddressHash, has_psk: bool) -> Option<LinkId> {
+    let link_id = self.in_links.lock().await.iter()
+      .find_map(|(link_id, link_dest)| (link_dest == dest).then_some(*link_id))?;
+    let authenticated = matches!(self.link_auth.lock().unwrap().get(&link_id),
+      Some(psk::LinkAuth::Authenticated));
+    (!has_psk || authenticated).then_some(link_id)
+  }
+
+  /// Check an IP packet for a peer against its MTU, link and rate limit, then add it to
+  /// the peer's batch if coalescing or else frame it for sending; while the link is
+  /// down the packet is held instead if enabled. Returns the length of a packet framed
+  /// on its own, to be counted once sent
+  fn frame_packet(&self, peer: &mut Peer, packet: PooledBuf, frames: &mut Vec<PooledBuf>)
+    -> Option<usize>
+  {
+    if peer.packet_mtu().is_some_and(|mtu| packet.len() > mtu as usize) {
+      tracing::debug!(bytes = packet.len(), mtu = peer.packet_mtu(),
+        "dropping packet larger than path mtu");
+      peer.drops.too_large += 1;
+      self.dropped(Some(peer.dest), DropReason::TooLarge);
+      return None
+    }
+    let packet = match self.clamp_mss(peer, &packet) {
+      Some(clamped) => {
+        let mut packet = self.buffers.take();
+        packet.extend_from_slice(&clamped);
+        packet
+      }
+      None => packet
+    };
+    if !peer.link_active && self.config.offline_queue_packets > 0 {
+      tracing::trace!(bytes = packet.len(), "holding packet until the link is up");
+      peer.hold(packet, self.config.offline_queue_packets);
+      return None
+    }
+    let Some(link_id) = peer.link_id else {
+      peer.drops.no_link += 1;
+      self.dropped(Some(peer.dest), DropReason::NoLink);
+      return None
+    };
+    if !self.rate_allows(peer, packet.len()) {
+      tracing::trace!(bytes = packet.len(), "dropping packet over rate limit");
+      Metrics::inc(&self.metrics.rate_limited_packets);
+      peer.drops.rate_limited += 1;
+      self.dropped(Some(peer.dest), DropReason::RateLimited);
+      return None
+    }
+    let max_bytes = self.config.coalesce_max_bytes;
+    match self.config.coalesce_us {
+      Some(_) if packet.len() + frame::BATCH_PACKET_OVERHEAD < max_bytes => {
+        let batch_len = peer.batch.as_ref().map_or(0, |batch| batch.len());
+        if batch_len + frame::BATCH_PACKET_OVERHEAD + packet.len() > max_bytes {
+          frame_batch(&self.buffers, peer, frames);
+        }
+        tracing::trace!(link_id = %link_id, bytes = packet.len(), "coalescing packet");
+        let batch = peer.batch.get_or_insert_with(|| {
+          peer.batch_started = Instant::now();
+          self.buffers.take()
+        });
+        frame::push_batch(batch, &packet);
+        peer.record_sent(packet.len());
+        None
+      }
+      _ => {
+        // keep packet order: send anything coalesced first
+        frame_batch(&self.buffers, peer, frames);
+        tracing::trace!(link_id = %link_id, bytes = packet.len(), "sending packet");
+        let len = packet.len();
+        frame_peer_data(&self.buffers, peer, packet, frames);
+        Some(len)
+      }
+    }
+  }
+
+  /// Check an Ethernet frame for a peer against its MTU and rate limit, then frame it
+  /// for sending; returns its length, to be counted once sent
+  fn frame_ethernet(&self, peer: &mut Peer, ethernet: PooledBuf,
+    frames: &mut Vec<PooledBuf>) -> Option<usize>
+  {
+    let max_len = peer.packet_mtu().map(|mtu| mtu as usize + ETHERNET_MAX_HEADER_LEN);
+    if max_len.is_some_and(|max_len| ethernet.len() > max_len) {
+      tracing::debug!(bytes = ethernet.len(), mtu = peer.packet_mtu(),
+        "dropping frame larger than path mtu");
+      peer.drops.too_large += 1;
+      self.dropped(Some(peer.dest), DropReason::TooLarge);
+      return None
+    }
+    if !self.rate_allows(peer, ethernet.len()) {
+      Metrics::inc(&self.metrics.rate_limited_packets);
+      peer.drops.rate_limited += 1;
+      self.dropped(Some(peer.dest), DropReason::RateLimited);
+      return None
+    }
+    tracing::trace!(bytes = ethernet.len(), "sending frame");
+    frame_batch(&self.buffers, peer, frames);
+    let mut payload = self.buffers.take();
+    frame::ethernet(&ethernet, &mut payload);
+    frame_peer_data(&self.buffers, peer, payload, frames);
+    Some(ethernet.len())
+  }
+
+  /// Queue the packets held while a peer's link was down for its sender, dropping
+  /// those held too long
+  async fn send_held(&self, ip: IpAddr) {
+    let ttl = Duration::from_millis(self.config.offline_queue_ttl_ms as u64);
+    let (dest, held, queue) = {
+      let mut peer_map = self.peer_map.lock().await;
+      let Some(peer) = peer_map.get_mut(&ip) else { return };
+      peer.expire_held(ttl);
+      if peer.offline_queue.is_empty() {
+        return
+      }
+      tracing::debug!(parent: &peer.span, packets = peer.offline_queue.len(),
+        "sending packets held while the link was down");
+      (peer.dest, std::mem::take(&mut peer.offline_queue), self.peer_queue(ip))
+    };
+    for (_, packet) in held {
+      self.count_queued(queue.push(packet).await, Some(dest));
+    }
+  }
+
+  /// Run the hook script for a peer's link coming up or going down in the background,
+  /// if one is configured, and tell callbacks and event subscribers
+  fn spawn_hook(&self, event: PeerEvent, ip: IpAddr, peer: &Peer, link_id: LinkId) {
+    let script = match event {
+      PeerEvent::Up => &self.config.on_peer_up,
+      PeerEvent::Down => &self.config.on_peer_down
+    };
+    if let Some(script) = script.clone() {
+      tokio::spawn(hooks::run(script, event, ip, peer.name.clone(), peer.dest, link_id)
+        .instrument(peer.span.clone()));
+    }
+    self.emit(match event {
+      PeerEvent::Up => Event::PeerActivated { ip, dest: peer.dest, link_id },
+      PeerEvent::Down => Event::PeerClosed { ip, dest: peer.dest, link_id }
+    });
+    for callback in self.peer_callbacks.iter() {
+      callback(event, ip, peer.dest);
+    }
+  }
+
+  fn emit(&self, event: Event) {
+    // fails only when nobody subscribed
+    let _ = self.events.send(event);
+  }
+
+  fn dropped(&self, dest: Option<AddressHash>, reason: DropReason) {
+    self.emit(Event::PacketDropped { dest, reason });
+  }
+
+  /// Count a failed tun read or write
+  fn tun_failed(&self, counter: &std::sync::atomic::AtomicU64, err: &std::io::Error) {
+    Metrics::inc(counter);
+    self.emit(Event::TunError { error: err.to_string() });
+  }
+
+  /// Networks routed to the tun for a peer
+  fn peer_routes(&self, ip: IpAddr, peer: &Peer) -> Vec<IpNet> {
+    let tunnel_nets = self.tunnel_ips().iter().map(IpNet::trunc).collect::<Vec<_>>();
+    peer.routes(ip, &tunnel_nets)
+  }
+
+  /// Replace the routes of a peer
+  async fn update_routes(&self, old: &[IpNet], new: &[IpNet]) {
+    for net in old.iter().filter(|net| !new.contains(net)) {
+      for net in kernel_routes(*net, &self.config) {
+        self.tun.remove_route(net).await;
+      }
+    }
+    for net in new.iter().filter(|net| !old.contains(net)) {
+      for net in kernel_routes(*net, &self.config) {
+        if let Err(err) = self.tun.add_route(net).await {
+          tracing::error!(%net, error = ?err, "failed to add route");
+        }
+      }
+    }
+  }
+
+  /// Record that something was received from the peer on an inbound link
+  async fn touch_peer(&self, link_id: LinkId) {
+    let Some(dest) = self.in_links.lock().await.get(&link_id).copied() else { return };
+    if let Some(peer) = self.peer_map.lock().await.values_mut().find(|peer| peer.dest == dest)
+    {
+      peer.last_received = Instant::now();
+      peer.last_seen = Some(peer.last_received);
+    }
+  }
+
+  /// Add a fragment received on a link, returning the packet once complete; fragments
+  /// are only kept for links that identified and authenticated as a peer
+  async fn reassemble(&self, link_id: LinkId, body: &[u8]) -> Option<Vec<u8>> {
+    let Some((id, index, count, data)) = frame::parse_fragment(body) else {
+      tracing::warn!("got invalid fragment");
+      return None
+    };
+    self.authorized_dest(link_id).await?;
+    tracing::trace!(id, index, count, "got fragment");
+    self.reassembler.lock().await.push(link_id, id, index, count, data)
+  }
+
+  /// Enable compression to a peer that announced it supports our algorithm
+  async fn set_compression(&self, link_id: LinkId, body: &[u8]) {
+    let Some((algorithm, dest)) = frame::parse_compression(body) else {
+      tracing::warn!("got invalid compression frame");
+      return
+    };
+    let Some(dest) = self.identified_as(link_id, dest).await else {
+      tracing::warn!("ignoring compression frame: link not identified as its destination");
+      return
+    };
+    if self.config.compression.is_none() || algorithm != frame::LZ4 {
+      tracing::debug!(%dest, algorithm, "not compressing: algorithm not enabled");
+      return
+    }
+    let mut peer_map = self.peer_map.lock().await;
+    if let Some(peer) = peer_map.values_mut().find(|peer| peer.dest == dest) {
+      tracing::info!(parent: &peer.span, "peer supports compression: enabling");
+      peer.compression = true;
+    }
+  }
+
+  /// Record the reply to a link quality probe
+  async fn probe_reply(&self, seq: u64, received: Instant) {
+    let mut peer_map = self.peer_map.lock().await;
+    let peer = peer_map.values_mut().find(|peer| peer.quality.reply(seq, received));
+    if let Some(peer) = peer {
+      tracing::trace!(parent: &peer.span, seq, rtt = ?peer.quality.rtt(), "probe reply");
+      if let Some(rtt) = peer.quality.rtt() {
+        peer.paths.rtt_sample(rtt);
+      }
+      log_quality(peer);
+    }
+  }
+
+  /// Answer an echo request from a peer on our link to it
+  async fn echo(&self, transport: &Transport, link_id: LinkId, body: &[u8]) {
+    let Some(dest) = self.authorized_dest(link_id).await else { return };
+    if send_link_data(transport, &dest, &frame::echo_reply(body)).await.is_err() {
+      tracing::debug!(%dest, "could not get link to answer echo request");
+    }
+  }
+
+  /// Sequence payloads to a peer that asked for it
+  async fn set_sequencing(&self, link_id: LinkId, body: &[u8]) {
+    let Some(dest) = self.identified_as(link_id, body).await else {
+      tracing::warn!("ignoring sequencing frame: link not identified as its destination");
+      return
+    };
+    let mut peer_map = self.peer_map.lock().await;
+    if let Some(peer) = peer_map.values_mut().find(|peer| peer.dest == dest) {
+      tracing::info!(parent: &peer.span, "peer asked for sequenced payloads: enabling");
+      peer.sequencing = true;
+    }
+  }
+
+  /// Record the sequence number of a payload received on a link, returning false if
+  /// it is a duplicate or replay; links start sequencing with their first sequenced
+  /// payload, and only if replay protection is enabled
+  fn accept_sequence(&self, link_id: LinkId, seq: u64) -> bool {
+    if !self.config.replay_protection {
+      return true
+    }
+    self.replay_windows.lock().unwrap().entry(link_id).or_default().accept(seq)
+  }
+
+  /// Destination a frame on an inbound link names, if the link was identified as it
+  async fn identified_as(&self, link_id: LinkId, dest: &[u8]) -> Option<AddressHash> {
+    if dest.len() != ADDRESS_HASH_LEN {
+      return None
+    }
+    let dest = AddressHash::new_from_slice(dest);
+    (self.in_links.lock().await.get(&link_id) == Some(&dest)).then_some(dest)
+  }
+
+  /// Challenge an inbound link to prove the destination its mtu frame claims, unless it
+  /// was challenged or identified before
+  async fn send_identify_challenge(&self, transport: &Transport, link_id: LinkId,
+    body: &[u8])
+  {
+    let Some((mtu, dest)) = frame::parse_mtu(body) else {
+      tracing::warn!("got invalid mtu frame");
+      return
+    };
+    if dest.len() != ADDRESS_HASH_LEN {
+      tracing::warn!("got mtu frame with invalid destination hash");
+      return
+    }
+    let dest = AddressHash::new_from_slice(dest);
+    if self.in_links.lock().await.contains_key(&link_id) {
+      tracing::debug!(%dest, "ignoring mtu frame: link already identified");
+      return
+    }
+    let challenge = {
+      let mut pending_links = self.pending_links.lock().unwrap();
+      if pending_links.contains_key(&link_id) {
+        return
+      }
+      let challenge = identify::challenge();
+      pending_links.insert(link_id, identify::PendingLink { dest, mtu, challenge });
+      challenge
+    };
+    tracing::debug!(%dest, "sending identify challenge");
+    if let Some(link) = transport.find_in_link(&link_id).await {
+      let packet = link.lock().await.data_packet(&frame::identify_challenge(&challenge))
+        .unwrap();
+      transport.send_packet(packet).await;
+    } else {
+      tracing::warn!(%dest, "could not get link to send identify challenge");
+    }
+  }
+
+  /// Identify an inbound link as the destination it claimed once it answers its
+  /// challenge with a signature by the identity the destination hash derives from;
+  /// returns whether it did
+  async fn check_identify_response(&self, link_id: LinkId, body: &[u8]) -> bool {
+    let Some(pending) = self.pending_links.lock().unwrap().remove(&link_id) else {
+      tracing::warn!("ignoring unexpected identify response");
+      return false
+    };
+    let dest = pending.dest;
+    let identity = identify::verify(&pending.challenge, &link_id, body)
+      .filter(|identity| peer_destination_hash(*identity) == dest);
+    let Some(identity) = identity else {
+      tracing::warn!(%dest, %link_id,
+        "identify response does not prove the destination: dropping packets on the link");
+      Metrics::inc(&self.metrics.unauthorized_packets);
+      return false
+    };
+    let pinned = self.peer_map.lock().await.values().find(|peer| peer.dest == dest)
+      .and_then(|peer| peer.identity);
+    if pinned.is_some_and(|pinned| !pinned.matches(&identity)) {
+      tracing::warn!(%dest, %link_id, "identify response from an identity other than the \
+        pinned one: dropping packets on the link");
+      Metrics::inc(&self.metrics.unauthorized_packets);
+      return false
+    }
+    // whether the destination may deliver packets is checked for each packet, as
+    // leased clients only become peers after their link is up
+    self.in_links.lock().await.insert(link_id, dest);
+    let mut peer_map = self.peer_map.lock().await;
+    let Some(peer) = peer_map.values_mut().find(|peer| peer.dest == dest) else {
+      tracing::debug!(%dest, %link_id, "link identified as unknown destination");
+      return true
+    };
+    tracing::debug!(parent: &peer.span, %link_id, "link identified");
+    // identifying the link comes before touch_peer can find the peer
+    peer.last_received = Instant::now();
+    peer.last_seen = Some(peer.last_received);
+    // the smaller of the two MTUs is used for packets to the peer
+    let path_mtu = pending.mtu.min(self.config.mtu);
+    if peer.path_mtu != Some(path_mtu) {
+      tracing::info!(parent: &peer.span, mtu = pending.mtu, path_mtu, "agreed path mtu");
+    }
+    peer.path_mtu = Some(path_mtu);
+    true
+  }
+
+  /// Answer the identify challenge of a peer on our link to it, then send what it only
+  /// takes on an identified link: our compression and sequencing settings, and unless
+  /// it authenticates the link with a psk first, our routes, known peers and held
+  /// packets
+  async fn answer_identify_challenge(&self, transport: &Transport, id: &PrivateIdentity,
+    in_destination_hash: AddressHash, link_id: LinkId, dest: AddressHash,
+    challenge: &[u8])
+  {
+    let peer = self.peer_map.lock().await.iter()
+      .find(|(_, peer)| peer.dest == dest && peer.link_id == Some(link_id))
+      .map(|(ip, peer)| (*ip, peer.psk.is_some(), peer.span.clone()));
+    let Some((ip, has_psk, span)) = peer else { return };
+    if challenge.len() != identify::CHALLENGE_LEN {
+      tracing::warn!(parent: &span, "got invalid identify challenge");
+      return
+    }
+    tracing::debug!(parent: &span, "answering identify challenge");
+    let response = identify::response(id, challenge, &link_id);
+    if send_link_data(transport, &dest, &response).await.is_err() {
+      tracing::warn!(parent: &span, "could not get link to answer identify challenge");
+      return
+    }
+    if self.config.compression.is_some() {
+      let compression = frame::compression(frame::LZ4, in_destination_hash.as_slice());
+      let _ = send_link_data(transport, &dest, &compression).await;
+    }
+    if self.config.replay_protection {
+      let sequencing = frame::sequencing(in_destination_hash.as_slice());
+      let _ = send_link_data(transport, &dest, &sequencing).await;
+    }
+    // with a psk, the peer only takes these once it authenticated the link, so they
+    // follow the answer to its psk challenge instead
+    if has_psk {
+      return
+    }
+    self.advertise_routes(transport, &dest).await;
+    if self.config.pex {
+      self.share_pex(transport, ip, &dest).await;
+    }
+    self.send_held(ip).await;
+  }
+
+  /// Tunnel addresses of this client
+  fn tunnel_ips(&self) -> Vec<IpNet> {
+    [self.config.vpn_ip.or(self.leased_ip()), self.config.vpn_ip6].into_iter().flatten()
+      .collect()
+  }
+
+  fn leased_ip(&self) -> Option<IpNet> {
+    *self.leased_ip.lock().unwrap()
+  }
+
+  /// Assign the tunnel address leased from the hub to the tun
+  async fn apply_lease(&self, ip: IpNet) {
+    if self.leased_ip().is_some() {
+      return
+    }
+    tracing::info!(%ip, dest = %self.lease_from.unwrap(), "leased tunnel address");
+    match self.tun.add_address(ip, self.config.force).await {
+      Ok(()) => *self.leased_ip.lock().unwrap() = Some(ip),
+      Err(err) => tracing::error!(%ip, error = ?err, "failed to assign leased address")
+    }
+  }
+
+  /// Lease an address from the pool to the requesting destination (hub mode) and
+  /// reply on the link the request arrived on
+  async fn lease(&self, transport: &Transport, link_id: LinkId, body: &[u8]) {
+    let Some(pool) = self.config.lease_pool else {
+      tracing::warn!("dropping lease request: no lease_pool configured");
+      return
+    };
+    let Some(dest) = self.identified_as(link_id, body).await else {
+      tracing::warn!("dropping lease request: link not identified as its destination");
+      return
+    };
+    let ip = {
+      let mut leases = self.leases.lock().await;
+      let mut peer_map = self.peer_map.lock().await;
+      let ip = match leases.get(&dest) {
+        Some(ip) => *ip,
+        None => {
+          let local_ips = local_ips(&self.config);
+          let leased = leases.values().map(|ip| ip.addr()).collect::<Vec<_>>();
+          let Some(addr) = pool.hosts().find(|ip| !local_ips.contains(ip)
+            && !leased.contains(ip) && peer_map.find(ip).is_none())
+          else {
+            tracing::error!(%pool, %dest, "lease pool exhausted");
+            return
+          };
+          let ip = IpNet::new(addr, pool.prefix_len()).unwrap();
+          leases.insert(dest, ip);
+          ip
+        }
+      };
+      // route the leased address to the client
+      if peer_map.find(&ip.addr()).is_none() {
+        peer_map.insert(ip.addr(), Peer::from_dest(dest, vec![]));
+      }
+      ip
+    };
+    tracing::info!(%ip, %dest, "leasing address");
+    if let Some(link) = transport.find_in_link(&link_id).await {
+      let packet = link.lock().await.data_packet(&frame::lease_offer(ip)).unwrap();
+      transport.send_packet(packet).await;
+    } else {
+      tracing::warn!("could not get link to reply to lease request");
+    }
+  }
+
+  /// Whether a destination hash is a peer's or trusted for discovery
+  async fn is_peer_or_trusted(&self, dest: &AddressHash) -> bool {
+    self.discovery_trusted.contains(dest)
+      || self.peer_map.lock().await.values().any(|peer| peer.dest == *dest)
+  }
+
+  /// Add a peer from the announce of a trusted destination carrying its tunnel
+  /// addresses
+  async fn discover_peer(&self, dest: AddressHash, app_data: &[u8]) {
+    if !self.discovery_trusted.contains(&dest) {
+      return
+    }
+    let mut peer_map = self.peer_map.lock().await;
+    if peer_map.values().any(|peer| peer.dest == dest) {
+      return
+    }
+    let Some(addresses) = discovery::decode(app_data) else {
+      tracing::warn!(%dest, "trusted destination announced without tunnel addresses");
+      return
+    };
+    let local_ips = local_ips(&self.config);
+    for ip in addresses.iter() {
+      if local_ips.contains(ip) || peer_map.find(ip).is_some() {
+        tracing::warn!(%dest, %ip, "discovered peer address conflicts with configured \
+          addresses");
+        return
+      }
+    }
+    tracing::info!(%dest, ?addresses, "discovered peer");
+    let peer = Peer::from_dest(dest, addresses[1..].to_vec());
+    self.update_routes(&[], &self.peer_routes(addresses[0], &peer)).await;
+    peer_map.insert(addresses[0], peer);
+  }
+
+  /// Send each peer with an active link the destination hashes and tunnel addresses of
+  /// the other peers with active links (hub in mesh mode)
+  async fn share_mesh_peers(&self, transport: &Transport) {
+    let active = self.peer_map.lock().await.iter()
+      .filter(|(_, peer)| peer.link_active)
+      .map(|(ip, peer)| (*ip, peer.dest))
+      .collect::<Vec<_>>();
+    for (ip, dest) in active.iter() {
+      let others = active.iter().filter(|(other, _)| other != ip)
+        .map(|(ip, dest)| (dest.as_slice(), *ip))
+        .collect::<Vec<_>>();
+      // control frames aren't fragmented
+      for chunk in others.chunks((LINK_MDU - 1) / frame::PEER_MAX_LEN) {
+        let _ = send_link_data(transport, dest, &frame::mesh_peers(chunk)).await;
+      }
+    }
+  }
+
+  /// Add the peers shared by a hub in mesh mode, so that traffic to them skips the hub
+  /// once there is a direct link; a hub may only share addresses routed to it
+  async fn add_mesh_peers(&self, transport: &Transport, link_id: LinkId, body: &[u8]) {
+    if !self.config.mesh || self.config.relay {
+      tracing::debug!("ignoring mesh peers: not a mesh client");
+      return
+    }
+    let Some(hub_dest) = self.authorized_dest(link_id).await else { return };
+    let Some(shared) = frame::parse_peers(body) else {
+      tracing::warn!("got invalid mesh peers");
+      return
+    };
+    let local_ips = local_ips(&self.config);
+    let mut peer_map = self.peer_map.lock().await;
+    let Some(hub_ip) = peer_map.iter()
+      .find(|(_, peer)| peer.dest == hub_dest && peer.mesh_via.is_none())
+      .map(|(ip, _)| *ip)
+    else {
+      return
+    };
+    for (dest, ip) in shared {
+      let dest = AddressHash::new_from_slice(dest);
+      if local_ips.contains(&ip) || peer_map.values().any(|peer| peer.dest == dest) {
+        continue
+      }
+      if peer_map.find(&ip).is_none_or(|peer| peer.dest != hub_dest) {
+        tracing::warn!(%hub_dest, %ip, "ignoring mesh peer not routed to the hub");
+        continue
+      }
+      tracing::info!(%dest, %ip, via = %hub_ip, "adding mesh peer");
+      let mut peer = Peer::from_dest(dest, Vec::new());
+      peer.mesh_via = Some(hub_ip);
+      self.update_routes(&[], &self.peer_routes(ip, &peer)).await;
+      peer_map.insert(ip, peer);
+      transport.request_path(&dest, None).await;
+    }
+  }
+
+  /// Send a peer the subnets to route to this client, if any
+  async fn advertise_routes(&self, transport: &Transport, dest: &AddressHash) {
+    if !self.config.advertise_routes.is_empty() {
+      let routes = frame::routes(&self.config.advertise_routes);
+      let _ = send_link_data(transport, dest, &routes).await;
+    }
+  }
+
+  /// Challenge an inbound link identified as a peer's that has a psk, unless it was
+  /// challenged before
+  async fn send_psk_challenge(&self, transport: &Transport, link_id: LinkId) {
+    let Some(dest) = self.in_links.lock().await.get(&link_id).copied() else { return };
+    if !self.peer_map.lock().await.values()
+      .any(|peer| peer.dest == dest && peer.psk.is_some())
+    {
+      return
+    }
+    let challenge = {
+      let mut link_auth = self.link_auth.lock().unwrap();
+      if link_auth.contains_key(&link_id) {
+        return
+      }
+      let challenge = psk::challenge();
+      link_auth.insert(link_id, psk::LinkAuth::Challenged(challenge));
+      challenge
+    };
+    tracing::debug!(%dest, "sending psk challenge");
+    if let Some(link) = transport.find_in_link(&link_id).await {
+      let packet = link.lock().await.data_packet(&frame::psk_challenge(&challenge))
+        .unwrap();
+      transport.send_packet(packet).await;
+    } else {
+      tracing::warn!(%dest, "could not get link to send psk challenge");
+    }
+  }
+
+  /// Accept packets on an inbound link once the peer answered its psk challenge
+  async fn check_psk_response(&self, link_id: LinkId, body: &[u8]) {
+    let Some(dest) = self.in_links.lock().await.get(&link_id).copied() else {
+      tracing::warn!("got psk response on a link that has not identified itself");
+      return
+    };
+    let peer_map = self.peer_map.lock().await;
+    let Some(peer) = peer_map.values().find(|peer| peer.dest == dest) else { return };
+    let Some(psk) = &peer.psk else {
+      tracing::debug!(parent: &peer.span, "ignoring psk response: peer has no psk");
+      return
+    };
+    let mut link_auth = self.link_auth.lock().unwrap();
+    let Some(psk::LinkAuth::Challenged(challenge)) = link_auth.get(&link_id) else {
+      tracing::debug!(parent: &peer.span, "ignoring unexpected psk response");
+      return
+    };
+    if psk::verify(psk.as_bytes(), challenge, body) {
+      tracing::info!(parent: &peer.span, %link_id, "link authenticated with psk");
+      link_auth.insert(link_id, psk::LinkAuth::Authenticated);
+    } else {
+      tracing::warn!(parent: &peer.span, %link_id,
+        "psk response does not match: dropping packets on the link");
+      Metrics::inc(&self.metrics.unauthorized_packets);
+    }
+  }
+
+  /// Answer the psk challenge of a peer on our link to it, then send what it only
+  /// takes on an authenticated link
+  async fn answer_psk_challenge(&self, transport: &Transport, dest: AddressHash,
+    challenge: &[u8])
+  {
+    let peer = self.peer_map.lock().await.iter()
+      .find(|(_, peer)| peer.dest == dest)
+      .map(|(ip, peer)| (*ip, peer.psk.clone(), peer.span.clone()));
+    let Some((ip, psk, span)) = peer else { return };
+    let Some(psk) = psk else {
+      tracing::warn!(parent: &span,
+        "got psk challenge but no psk is configured for the peer");
+      return
+    };
+    if challenge.len() != psk::CHALLENGE_LEN {
+      tracing::warn!(parent: &span, "got invalid psk challenge");
+      return
+    }
+    tracing::debug!(parent: &span, "answering psk challenge");
+    let response = psk::response(psk.as_bytes(), challenge);
+    let response = frame::psk_response(&response);
+    if send_link_data(transport, &dest, &response).await.is_err() {
+      tracing::warn!(parent: &span, "could not get link to answer psk challenge");
+      return
+    }
+    self.advertise_routes(transport, &dest).await;
+    if self.config.pex {
+      self.share_pex(transport, ip, &dest).await;
+    }
+    self.send_held(ip).await;
+  }
+
+  /// Send a peer the destination hashes and tunnel addresses of all other known peers
+  async fn share_pex(&self, transport: &Transport, ip: IpAddr, dest: &AddressHash) {
+    let known = self.peer_map.lock().await.iter()
+      .filter(|(other, _)| **other != ip)
+      .map(|(ip, peer)| (*ip, peer.dest))
+      .collect::<Vec<_>>();
+    let known = known.iter().map(|(ip, dest)| (dest.as_slice(), *ip)).collect::<Vec<_>>();
+    // control frames aren't fragmented
+    for chunk in known.chunks((LINK_MDU - 1) / frame::PEER_MAX_LEN) {
+      let _ = send_link_data(transport, dest, &frame::pex(chunk)).await;
+    }
+  }
+
+  /// Add the peers shared by a trusted introducer whose addresses aren't in use or
+  /// are routed to the introducer, in which case traffic to them keeps going through
+  /// the introducer while there is no direct link
+  async fn add_pex_peers(&self, transport: &Transport, link_id: LinkId, body: &[u8]) {
+    if !self.config.pex {
+      tracing::debug!("ignoring shared peers: peer exchange disabled");
+      return
+    }
+    let Some(introducer) = self.authorized_dest(link_id).await else { return };
+    if !self.config.pex_trusted.iter().any(|trusted| trusted.0 == introducer) {
+      tracing::debug!(%introducer, "ignoring peers shared by an untrusted destination");
+      return
+    }
+    let Some(shared) = frame::parse_peers(body) else {
+      tracing::warn!(%introducer, "got invalid shared peers");
+      return
+    };
+    let local_ips = local_ips(&self.config);
+    let mut peer_map = self.peer_map.lock().await;
+    let Some(introducer_ip) = peer_map.iter()
+      .find(|(_, peer)| peer.dest == introducer)
+      .map(|(ip, _)| *ip)
+    else {
+      return
+    };
+    for (dest, ip) in shared {
+      let dest = AddressHash::new_from_slice(dest);
+      if local_ips.contains(&ip) || peer_map.values().any(|peer| peer.dest == dest) {
+        continue
+      }
+      let via = match peer_map.find(&ip) {
+        None => None,
+        Some(peer) if peer.dest == introducer && !peer_map.contains_key(&ip) =>
+          Some(introducer_ip),
+        Some(_) => {
+          tracing::warn!(%introducer, %dest, %ip,
+            "ignoring shared peer whose address is in use");
+          continue
+        }
+      };
+      tracing::info!(%introducer, %dest, %ip, "adding shared peer");
+      let mut peer = Peer::from_dest(dest, Vec::new());
+      peer.mesh_via = via;
+      self.update_routes(&[], &self.peer_routes(ip, &peer)).await;
+      peer_map.insert(ip, peer);
+      transport.request_path(&dest, None).await;
+    }
+  }
+
+  /// Route the subnets a peer advertises to it as far as `accept_routes` allows,
+  /// replacing those it advertised before
+  async fn set_advertised_routes(&self, link_id: LinkId, body: &[u8]) {
+    let Some(dest) = self.authorized_dest(link_id).await else { return };
+    let Some(nets) = frame::parse_routes(body) else {
+      tracing::warn!(%dest, "got invalid routes");
+      return
+    };
+    let mut peer_map = self.peer_map.lock().await;
+    let Some(ip) = peer_map.iter().find(|(_, peer)| peer.dest == dest).map(|(ip, _)| *ip)
+    else {
+      tracing::debug!(%dest, "ignoring routes advertised by an identity that isn't a peer");
+      return
+    };
+    let local_ips = local_ips(&self.config);
+    let accepted = nets.into_iter().map(|net| net.trunc())
+      .filter(|net| {
+        if !self.config.accept_routes.iter().any(|accept| accept.contains(net)) {
+          tracing::info!(%dest, %net, "ignoring advertised route outside accept_routes");
+          return false
+        }
+        if local_ips.iter().any(|local_ip| net.contains(local_ip)) {
+          tracing::warn!(%dest, %net,
+            "ignoring advertised route containing a local address");
+          return false
+        }
+        let taken = peer_map.iter().any(|(other_ip, other)| *other_ip != ip
+          && (other.allowed_ips.contains(net) || other.advertised_routes.contains(net)));
+        if taken {
+          tracing::warn!(%dest, %net, "ignoring advertised route of another peer");
+        }
+        !taken
+      })
+      .collect::<Vec<_>>();
+    let Some(mut peer) = peer_map.remove(&ip) else { return };
+    let routes = self.peer_routes(ip, &peer);
+    tracing::info!(parent: &peer.span, routes = ?accepted, "accepted advertised routes");
+    peer.advertised_routes = accepted;
+    self.update_routes(&routes, &self.peer_routes(ip, &peer)).await;
+    peer_map.insert(ip, peer);
+  }
+
+  /// Write an IP packet, the packets of a batch or an Ethernet frame carried in a
+  /// payload, which may be FEC protected, sequenced and compressed
+  async fn write_payload(&self, transport: &Transport, link_id: LinkId, payload: &[u8])
+    -> Result<(), std::io::Error>
+  {
+    let recovered;
+    let payload = match Frame::parse(payload) {
+      Some(Frame::FecData(body)) => {
+        let Some((group, index, payload)) = frame::parse_fec_data(body) else {
+          tracing::warn!("dropping invalid FEC payload");
+          return Ok(())
+        };
+        let mut fec_decoders = self.fec_decoders.lock().unwrap();
+        if !fec_decoders.entry(link_id).or_default().data(group, index, payload) {
+          tracing::trace!(group, index, "dropping duplicate FEC payload");
+          return Ok(())
+        }
+        payload
+      }
+      Some(Frame::FecParity(body)) => {
+        let Some((group, count, len_parity, parity)) = frame::parse_fec_parity(body)
+        else {
+          tracing::warn!("dropping invalid FEC parity");
+          return Ok(())
+        };
+        let payload = self.fec_decoders.lock().unwrap().entry(link_id).or_default()
+          .parity(group, count, len_parity, parity);
+        let Some(payload) = payload else { return Ok(()) };
+        tracing::debug!(group, "recovered lost payload from FEC parity");
+        Metrics::inc(&self.metrics.fec_recovered_payloads);
+        recovered = payload;
+        recovered.as_slice()
+      }
+      _ => payload
+    };
+    let payload = match Frame::parse(payload) {
+      Some(Frame::Sequenced(body)) => {
+        let Some((seq, payload)) = frame::parse_sequenced(body) else {
+          tracing::warn!("dropping invalid sequenced frame");
+          return Ok(())
+        };
+        if !self.accept_sequence(link_id, seq) {
+          tracing::debug!(seq, "dropping duplicate or replayed payload");
+          Metrics::inc(&self.metrics.replayed_packets);
+          self.dropped(self.in_links.lock().await.get(&link_id).copied(),
+            DropReason::Replayed);
+          return Ok(())
+        }
+        payload
+      }
+      // once a link sequences its payloads, unsequenced ones may be replayed
+      _ if self.replay_windows.lock().unwrap().contains_key(&link_id) => {
+        tracing::warn!("dropping unsequenced payload on sequenced link");
+        Metrics::inc(&self.metrics.replayed_packets);
+        self.dropped(self.in_links.lock().await.get(&link_id).copied(),
+          DropReason::Replayed);
+        return Ok(())
+      }
+      _ => payload
+    };
+    let decompressed;
+    let payload = match Frame::parse(payload) {
+      Some(Frame::Compressed(body)) => {
+        let Some(payload) = frame::decompress(body) else {
+          tracing::warn!("dropping invalid compressed frame");
+          return Ok(())
+        };
+        decompressed = payload;
+        decompressed.as_slice()
+      }
+      _ => payload
+    };
+    let packets = match Frame::parse(payload) {
+      Some(Frame::Ip(packet)) => vec![packet],
+      Some(Frame::Batch(body)) => frame::split_batch(body),
+      Some(Frame::Ethernet(body)) => return self.write_ethernet(link_id, body).await,
+      _ => {
+        tracing::warn!("dropping payload that is neither an IP packet, a batch nor an \
+          Ethernet frame");
+        return Ok(())
+      }
+    };
+    for packet in packets {
+      self.write_tun(transport, link_id, packet).await?;
+    }
+    Ok(())
+  }
+
+  /// Write a payload a peer balanced onto our link to it; the tun failing for good is
+  /// handled by the upstream loop
+  async fn write_balanced(&self, transport: &Transport, link_id: LinkId, payload: &[u8]) {
+    if let Err(err) = self.write_payload(transport, link_id, payload).await {
+      tracing::warn!(link_id = %link_id, "tun write failed: {err:?}");
+    }
+  }
+
+  /// Write an IP packet received on a link to the tun, or relay it to the peer it is
+  /// addressed to; packets are only accepted from links of peers or allowed identities,
+  /// with a source address routed to the sending peer
+  async fn write_tun(&self, transport: &Transport, link_id: LinkId, packet: &[u8])
+    -> Result<(), std::io::Error>
+  {
+    if self.config.mode == DeviceMode::Tap {
+      tracing::warn!("dropping IP packet: running in tap mode");
+      return Ok(())
+    }
+    let Some(dest) = self.authorized_dest(link_id).await else { return Ok(()) };
+    if !self.filter_allows(FilterDirection::In, packet) {
+      return Ok(())
+    }
+    let clamped;
+    {
+      let mut peer_map = self.peer_map.lock().await;
+      let Some((source_ip, destination_ip)) = packet_addrs(packet) else {
+        tracing::debug!(%dest, bytes = packet.len(), "dropping packet: no IP addresses");
+        Metrics::inc(&self.metrics.malformed_packets);
+        self.dropped(Some(dest), DropReason::Malformed);
+        return Ok(())
+      };
+      if !management_allows(&self.config, &source_ip, &destination_ip) {
+        tracing::warn!(source = %source_ip, destination = %destination_ip,
+          "dropping packet to management address: source not allowed");
+        return Ok(())
+      }
+      // as with WireGuard, the source must be routed to the sending peer; a hub also
+      // relays the packets of the mesh peers it shared
+      let owner = peer_map.find(&source_ip).map(|peer| peer.dest);
+      let via_hub = peer_map.find(&source_ip).and_then(|peer| peer.mesh_via)
+        .and_then(|hub_ip| peer_map.get_mut(&hub_ip))
+        .is_some_and(|hub| hub.dest == dest);
+      if owner != Some(dest) && !via_hub {
+        match owner {
+          Some(owner) => tracing::warn!(%dest, ip = %source_ip, %owner,
+            "dropping packet: source address belongs to another peer"),
+          None => tracing::warn!(%dest, ip = %source_ip,
+            "dropping packet: source address is not routed to the peer")
+        }
+        self.spoofed(&mut peer_map, dest);
+        return Ok(())
+      }
+      if let Some(peer) = peer_map.find(&source_ip) {
+        peer.last_activity = Instant::now();
+        peer.rx_packets += 1;
+        peer.rx_bytes += packet.len() as u64;
+        peer.last_packet = Some(peer.last_activity);
+      }
+      let relay_to = peer_map.find_ip(&destination_ip).filter(|_| self.config.relay)
+        .and_then(|ip| Some((ip, peer_map.get(&ip)?.dest)))
+        .filter(|(_, relay_dest)| *relay_dest != dest);
+      if let Some((relay_ip, relay_dest)) = relay_to {
+        let mut relayed = self.buffers.take();
+        relayed.extend_from_slice(packet);
+        if !decrement_ttl(&mut relayed) {
+          tracing::debug!(source = %source_ip, destination = %destination_ip,
+            "dropping relayed packet: TTL exceeded");
+          return Ok(())
+        }
+        self.trace_packet("link -> link", &relayed);
+        if self.filter_allows(FilterDirection::Out, &relayed) {
+          Metrics::inc(&self.metrics.relayed_packets);
+          let queue = self.peer_queue(relay_ip);
+          drop(peer_map);
+          self.count_queued(queue.push(relayed).await, Some(relay_dest));
+        }
+        return Ok(())
+      }
+      // the peer's SYNs limit the segments sent back to it
+      clamped = match self.config.mss_clamp {
+        true => peer_map.values().find(|peer| peer.dest == dest)
+          .and_then(|peer| self.clamp_mss(peer, packet)),
+        false => None
+      };
+    }
+    let packet = clamped.as_deref().unwrap_or(packet);
+    self.trace_packet("link -> tun", packet);
+    let n = self.tun.send(packet).await
+      .inspect_err(|err| self.tun_failed(&self.metrics.tun_write_errors, err))?;
+    tracing::trace!(bytes = n, "tun sent");
+    Ok(())
+  }
+
+  /// Write an Ethernet frame received on a link to the tap, learning the peer behind
+  /// its source address
+  async fn write_ethernet(&self, link_id: LinkId, frame: &[u8])
+    -> Result<(), std::io::Error>
+  {
+    if self.config.mode != DeviceMode::Tap {
+      tracing::warn!("dropping Ethernet frame: not running in tap mode");
+      return Ok(())
+    }
+    let Some((_, source_mac)) = mac_table::frame_addrs(frame) else {
+      tracing::warn!(bytes = frame.len(), "dropping truncated Ethernet frame");
+      return Ok(())
+    };
+    let Some(dest) = self.authorized_dest(link_id).await else { return Ok(()) };
+    self.mac_table.lock().unwrap().learn(source_mac, dest);
+    if let Some(peer) = self.peer_map.lock().await.values_mut().find(|peer| peer.dest == dest)
+    {
+      peer.last_activity = Instant::now();
+      peer.rx_packets += 1;
+      peer.rx_bytes += frame.len() as u64;
+      peer.last_packet = Some(peer.last_activity);
+    }
+    let n = self.tun.send(frame).await
+      .inspect_err(|err| self.tun_failed(&self.metrics.tun_write_errors, err))?;
+    tracing::trace!(bytes = n, "tap sent");
+    Ok(())
+  }
+
+  /// Count a packet dropped for its source address against the peer that sent it
+  fn spoofed(&self, peer_map: &mut PeerMap, dest: AddressHash) {
+    Metrics::inc(&self.metrics.unauthorized_packets);
+    self.dropped(Some(dest), DropReason::Spoofed);
+    if let Some(sender) = peer_map.values_mut().find(|peer| peer.dest == dest) {
+      sender.drops.spoofed += 1;
+    }
+  }
+
+  /// Destination an inbound link identified itself as, if it is a peer's or an allowed
+  /// identity's
+  async fn authorized_dest(&self, link_id: LinkId) -> Option<AddressHash> {
+    let in_dest = self.in_links.lock().await.get(&link_id).copied();
+    let Some(dest) = in_dest else {
+      if let Some(dest) = self.balanced_dest(link_id).await {
+        return Some(dest)
+      }
+      tracing::warn!("dropping packet: link has not identified its destination");
+      Metrics::inc(&self.metrics.unauthorized_packets);
+      self.dropped(None, DropReason::Unauthorized);
+      return None
+    };
+    let peer_map = self.peer_map.lock().await;
+    if !self.allowed_identities.contains(&dest)
+      && !peer_map.values().any(|peer| peer.dest == dest)
+    {
+      tracing::warn!(%dest, "dropping packet from unauthorized destination");
+      Metrics::inc(&self.metrics.unauthorized_packets);
+      self.dropped(Some(dest), DropReason::Unauthorized);
+      return None
+    }
+    let authenticated = matches!(self.link_auth.lock().unwrap().get(&link_id),
+      Some(psk::LinkAuth::Authenticated));
+    let has_psk = peer_map.values().any(|peer| peer.dest == dest && peer.psk.is_some());
+    if has_psk && !authenticated {
+      tracing::debug!(%dest, "dropping packet: link not authenticated with the psk");
+      Metrics::inc(&self.metrics.unauthorized_packets);
+      self.dropped(Some(dest), DropReason::Unauthorized);
+      return None
+    }
+    Some(dest)
+  }
+
+  /// Peer our active link to it is, if it may balance its payloads onto it; the link
+  /// is to the peer's destination, so it needs no identifying
+  async fn balanced_dest(&self, link_id: LinkId) -> Option<AddressHash> {
+    if self.config.link_balance == LinkBalance::Primary {
+      return None
+    }
+    self.peer_map.lock().await.values()
+      .find(|peer| peer.link_id == Some(link_id) && peer.link_active)
+      .map(|peer| peer.dest)
+  }
+
+  /// Queue an Ethernet frame read from the tap for the peer its destination address
+  /// was learned behind, or for all peers with an active link if it is a group address
+  /// or unknown
+  async fn route_ethernet(&self, frame: PooledBuf) {
+    let Some((destination_mac, _)) = mac_table::frame_addrs(&frame) else {
+      tracing::debug!(bytes = frame.len(), "dropping truncated Ethernet frame");
+      return
+    };
+    let learned = self.mac_table.lock().unwrap().lookup(&destination_mac);
+    let Some(learned) = learned else {
+      tracing::trace!(mac = %mac_table::format_mac(&destination_mac), bytes = frame.len(),
+        "flooding frame");
+      self.queue_for_all(&frame).await;
+      return
+    };
+    let queue = self.peer_map.lock().await.iter()
+      .find(|(_, peer)| peer.dest == learned && peer.link_active)
+      .map(|(ip, peer)| {
+        tracing::trace!(parent: &peer.span,
+          mac = %mac_table::format_mac(&destination_mac), bytes = frame.len(),
+          "sending frame");
+        self.peer_queue(*ip)
+      });
+    if let Some(queue) = queue {
+      self.count_queued(queue.push(frame).await, Some(learned));
+    }
+  }
+
+  /// Answer a packet from the tun that can't be sent to a peer with an ICMP destination
+  /// unreachable from our tunnel address
+  async fn send_unreachable(&self, bytes: &[u8]) {
+    let local_ips = self.tunnel_ips().iter().map(IpNet::addr).collect::<Vec<_>>();
+    let Some(error) = icmp::unreachable(bytes, &local_ips) else { return };
+    if !self.icmp_rate_limit.lock().unwrap().take(error.len()) {
+      return
+    }
+    Metrics::inc(&self.metrics.unreachable_packets);
+    self.trace_packet("icmp -> tun", &error);
+    if let Err(err) = self.tun.send(&error).await {
+      self.tun_failed(&self.metrics.tun_write_errors, &err);
+      tracing::warn!(error = ?err, "couldn't write icmp unreachable to the tun");
+    }
+  }
+
+  /// Copy of a TCP SYN to or from the peer with its MSS lowered to fit the peer's MTU,
+  /// if MSS clamping is enabled and the packet needs it
+  fn clamp_mss(&self, peer: &Peer, bytes: &[u8]) -> Option<Vec<u8>> {
+    if !self.config.mss_clamp {
+      return None
+    }
+    let mtu = peer.packet_mtu().unwrap_or(self.config.mtu);
+    let clamped = mss::clamp(bytes, mtu)?;
+    tracing::trace!(parent: &peer.span, mtu, "clamped tcp mss");
+    Some(clamped)
+  }
+
+  /// Take tokens for a packet to the peer from its rate limit and the global one
+  fn rate_allows(&self, peer: &mut Peer, len: usize) -> bool {
+    if let Some(rate_limit) = &mut peer.rate_limit {
+      if !rate_limit.take(len) {
+        return false
+      }
+    }
+    self.rate_limit.as_ref().is_none_or(|rate_limit| rate_limit.lock().unwrap().take(len))
+  }
+
+  /// Whether an address is a multicast group, the limited broadcast address or the
+  /// broadcast address of a tunnel subnet
+  fn is_broadcast(&self, ip: &IpAddr) -> bool {
+    match ip {
+      IpAddr::V4(ip) => ip.is_multicast() || ip.is_broadcast()
+        || self.tunnel_ips().iter().any(|net| match net {
+          IpNet::V4(net) => net.prefix_len() < 31 && net.broadcast() == *ip,
+          IpNet::V6(_) => false
+        }),
+      IpAddr::V6(ip) => ip.is_multicast()
+    }
+  }
+
+  /// Whether the filter rules let an IP packet through, counting it if not
+  fn filter_allows(&self, direction: FilterDirection, bytes: &[u8]) -> bool {
+    if !self.config.filter.is_active() {
+      return true
+    }
+    let packet = PacketInfo::parse(bytes);
+    if self.config.filter.allows(direction, packet.as_ref()) {
+      return true
+    }
+    match packet {
+      Some(packet) => tracing::debug!(?direction, "filtered: {packet}"),
+      None => tracing::debug!(?direction, bytes = bytes.len(), "filtered unparsed packet")
+    }
+    Metrics::inc(&self.metrics.filtered_packets);
+    self.dropped(None, DropReason::Filtered);
+    false
+  }
+
+  fn trace_packet(&self, direction: &str, bytes: &[u8]) {
+    if self.config.trace_packets && tracing::enabled!(tracing::Level::DEBUG) {
+      if let Some(info) = PacketInfo::parse(bytes) {
+        tracing::debug!("{direction}: {info}");
+      }
+    }
+  }
+}
+
+/// Addresses, protocol and (for TCP/UDP) ports of an IP packet
+#[derive(Debug, PartialEq)]
+struct PacketInfo {
+  source: IpAddr,
+  destination: IpAddr,
+  protocol: etherparse::IpNumber,
+  ports: Option<(u16, u16)>,
+  len: usize
+}
+
+impl PacketInfo {
+  fn parse(bytes: &[u8]) -> Option<Self> {
+    let (ip_header, payload) = etherparse::IpHeaders::from_slice(bytes).ok()?;
+    let (source, destination) = if let Some((ipv4_header, _)) = ip_header.ipv4() {
+      (IpAddr::from(ipv4_header.source), IpAddr::from(ipv4_header.destination))
+    } else if let Some((ipv6_header, _)) = ip_header.ipv6() {
+      (IpAddr::from(ipv6_header.source), IpAddr::from(ipv6_header.destination))
+    } else {
+      return None
+    };
+    let protocol = payload.ip_number;
+    let ports = match protocol {
+      etherparse::IpNumber::TCP => etherparse::TcpHeader::from_slice(payload.payload).ok()
+        .map(|(tcp_header, _)| (tcp_header.source_port, tcp_header.destination_port)),
+      etherparse::IpNumber::UDP => etherparse::UdpHeader::from_slice(payload.payload).ok()
+        .map(|(udp_header, _)| (udp_header.source_port, udp_header.destination_port)),
+      _ => None
+    };
+    Some(PacketInfo { source, destination, protocol, ports, len: bytes.len() })
+  }
+
+  /// Hash of the packet's addresses, protocol and ports, the same for each packet of a
+  /// flow
+  fn flow(&self) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::hash::DefaultHasher::new();
+    (self.source, self.destination, self.protocol.0, self.ports).hash(&mut hasher);
+    hasher.finish()
+  }
+}
+
+impl std::fmt::Display for PacketInfo {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self.protocol.keyword_str() {
+      Some(keyword) => write!(f, "{} ", keyword.to_lowercase())?,
+      None => write!(f, "proto {} ", self.protocol.0)?
+    }
+    match self.ports {
+      Some((source_port, destination_port)) => write!(f, "{} -> {}",
+        std::net::SocketAddr::new(self.source, source_port),
+        std::net::SocketAddr::new(self.destination, destination_port))?,
+      None => write!(f, "{} -> {}", self.source, self.destination)?
+    }
+    write!(f, " ({} bytes)", self.len)
+  }
+}
+
+/// Send bytes on the out link to the given destination, fragmenting them if they don't
+/// fit in one link packet; fails with why the bytes were dropped if there is no such
+/// link or they are too large to fragment
+async fn send_link_data(transport: &Transport, dest: &AddressHash, bytes: &[u8])
+  -> Result<(), DropReason>
+{
+  let Some(link) = transport.find_out_link(dest).await else {
+    return Err(DropReason::NoLink)
+  };
+  send_on_link(transport, &link, dest, bytes).await
+}
+
+/// Send bytes on a link to the given destination, fragmenting them as needed
+async fn send_on_link(transport: &Transport, link: &Arc<tokio::sync::Mutex<Link>>,
+  dest: &AddressHash, bytes: &[u8]) -> Result<(), DropReason>
+{
+  if bytes.len() <= LINK_MDU {
+    let packet = link.lock().await.data_packet(bytes).unwrap();
+    transport.send_packet(packet).await;
+    return Ok(())
+  }
+  let id = fragment::next_id();
+  let Some(fragments) = frame::fragments(bytes, id, LINK_MDU) else {
+    tracing::warn!(bytes = bytes.len(), %dest, "dropping payload too large to fragment");
+    return Err(DropReason::TooLarge)
+  };
+  tracing::trace!(bytes = bytes.len(), %dest, fragments = fragments.len(),
+    "sending fragmented payload");
+  for fragment in fragments {
+    let packet = link.lock().await.data_packet(&fragment).unwrap();
+    transport.send_packet(packet).await;
+  }
+  Ok(())
+}
+
+impl Peer {
+  fn new(peer_config: &PeerConfig) -> Self {
+    let mut peer = Peer::from_dest(peer_config.dest.0, peer_config.addresses.clone());
+    if let Some(name) = &peer_config.name {
+      peer.name = Some(name.clone());
+      peer.span = tracing::info_span!("peer", %name);
+    }
+    peer.allowed_ips = peer_config.allowed_ips.iter().map(IpNet::trunc).collect();
+    peer.idle_timeout = peer_config.idle_timeout_secs
+      .map(|secs| Duration::from_secs(secs as u64));
+    peer.persistent_keepalive = peer_config.persistent_keepalive_secs
+      .map(|secs| Duration::from_secs(secs as u64));
+    peer.rate_limit = peer_config.rate_limit_kbps.map(ratelimit::TokenBucket::new);
+    peer.psk = peer_config.psk.clone();
+    peer.identity = peer_config.identity;
+    peer.fec = peer_config.fec_group_size.map(fec::Encoder::new);
+    peer.mtu = peer_config.mtu;
+    peer
+  }
+
+  /// Peer with default settings
+  fn from_dest(dest: AddressHash, addresses: Vec<IpAddr>) -> Self {
+    Peer {
+      dest,
+      name: None,
+      addresses,
+      allowed_ips: Vec::new(),
+      link_id: None,
+      link_active: false,
+      idle_timeout: None,
+      last_activity: Instant::now(),
+      persistent_keepalive: None,
+      rate_limit: None,
+      last_sent: Instant::now(),
+      last_received: Instant::now(),
+      batch: None,
+      batch_started: Instant::now(),
+      path_mtu: None,
+      mtu: None,
+      compression: false,
+      sequencing: false,
+      tx_sequence: 0,
+      link_requested: Instant::now(),
+      desc: None,
+      relink_at: None,
+      relink_backoff: Duration::from_secs(RELINK_BACKOFF_MIN_SECS),
+      tx_packets: 0,
+      tx_bytes: 0,
+      rx_packets: 0,
+      rx_bytes: 0,
+      last_packet: None,
+      last_seen: None,
+      links_established: 0,
+      drops: PeerDrops::default(),
+      mesh_via: None,
+      advertised_routes: Vec::new(),
+      psk: None,
+      identity: None,
+      offline_queue: VecDeque::new(),
+      fec: None,
+      quality: quality::QualityTracker::default(),
+      paths: multipath::Paths::default(),
+      span: tracing::info_span!("peer", %dest)
+    }
+  }
+
+  /// Networks routed to the tun for the peer: its allowed IPs, advertised routes and
+  /// host routes for its tunnel addresses outside the tunnel subnets
+  fn routes(&self, ip: IpAddr, tunnel_nets: &[IpNet]) -> Vec<IpNet> {
+    let hosts = std::iter::once(ip).chain(self.addresses.iter().copied())
+      .filter(|addr| !tunnel_nets.iter().chain(self.allowed_ips.iter())
+        .chain(self.advertised_routes.iter())
+        .any(|net| net.contains(addr)))
+      .map(IpNet::from);
+    self.allowed_ips.iter().chain(self.advertised_routes.iter()).copied().chain(hosts)
+      .collect()
+  }
+
+  /// Largest packet sent to the peer: the smaller of the path MTU and the configured
+  /// MTU, if either is known
+  fn packet_mtu(&self) -> Option<u16> {
+    [self.path_mtu, self.mtu].into_iter().flatten().min()
+  }
+
+  /// Count a packet sent to the peer
+  fn record_sent(&mut self, len: usize) {
+    self.last_activity = Instant::now();
+    self.last_sent = self.last_activity;
+    self.tx_packets += 1;
+    self.tx_bytes += len as u64;
+    self.last_packet = Some(self.last_activity);
+  }
+
+  /// Hold a packet until the link is activated, dropping the oldest held packet if
+  /// `max_packets` are held already
+  fn hold(&mut self, packet: PooledBuf, max_packets: usize) {
+    if self.offline_queue.len() >= max_packets {
+      self.offline_queue.pop_front();
+      self.drops.no_link += 1;
+    }
+    self.offline_queue.push_back((Instant::now(), packet));
+  }
+
+  /// Drop the packets held for longer than `ttl`
+  fn expire_held(&mut self, ttl: Duration) {
+    while self.offline_queue.front().is_some_and(|(held, _)| held.elapsed() >= ttl) {
+      self.offline_queue.pop_front();
+      self.drops.no_link += 1;
+    }
+  }
+
+  /// Take a newly requested link; it is usable once activated
+  fn linked(&mut self, link_id: LinkId) {
+    tracing::debug!(parent: &self.span, link_id = %link_id, "created link");
+    self.link_id = Some(link_id);
+    self.link_active = false;   // wait for link activated event
+    self.relink_at = None;
+    self.last_activity = Instant::now();
+    self.link_requested = self.last_activity;
+    self.last_received = self.last_activity;
+  }
+
+  /// Take the activation of the current link, which can now carry packets
+  fn activate(&mut self) {
+    self.link_active = true;
+    self.links_established += 1;
+    self.relink_backoff = Duration::from_secs(RELINK_BACKOFF_MIN_SECS);
+  }
+
+  /// Forget the current link so that a new one is requested on the next announce
+  fn reset_link(&mut self) {
+    self.link_active = false;
+    self.link_id = None;
+    self.path_mtu = None;
+    self.compression = false;
+    self.sequencing = false;
+    self.tx_sequence = 0;
+    if let Some(fec) = &mut self.fec {
+      fec.reset();
+    }
+    self.quality.reset();
+  }
+
+  /// Forget the current link and re-link after the backoff, doubling it for the next
+  /// attempt
+  fn schedule_relink(&mut self) {
+    self.reset_link();
+    tracing::debug!(parent: &self.span, backoff = ?self.relink_backoff, "re-linking");
+    self.relink_at = Some(Instant::now() + self.relink_backoff);
+    self.relink_backoff = (self.relink_backoff * 2)
+      .min(Duration::from_secs(RELINK_BACKOFF_MAX_SECS));
+  }
+
+  /// Take the settings of a reloaded peer, keeping link state
+  fn update_settings(&mut self, peer: Peer) {
+    self.name = peer.name;
+    self.span = peer.span;
+    self.addresses = peer.addresses;
+    self.allowed_ips = peer.allowed_ips;
+    self.idle_timeout = peer.idle_timeout;
+    self.persistent_keepalive = peer.persistent_keepalive;
+    self.rate_limit = peer.rate_limit;
+    self.psk = peer.psk;
+    self.identity = peer.identity;
+    self.fec = peer.fec;
+    self.mtu = peer.mtu;
+  }
+}
+
+/// Problem with a tun device name for the platform, if any
+fn tun_name_problem(name: &str) -> Option<String> {
+  if cfg!(not(any(target_os = "linux", windows))) {
+    return Some("tun_name is only supported on Linux and Windows".to_owned())
+  }
+  if name.is_empty() || name.contains(['/', '\\']) || name.contains(char::is_whitespace) {
+    return Some(format!("tun_name {name:?} is not a valid device name"))
+  }
+  if name.matches("%d").count() > 1 {
+    return Some("tun_name may contain %d at most once".to_owned())
+  }
+  if cfg!(windows) && name.contains("%d") {
+    return Some("tun_name can't be a pattern on Windows".to_owned())
+  }
+  // Linux device names are at most IFNAMSIZ - 1 bytes, %d included
+  if cfg!(target_os = "linux") && name.len() > 15 {
+    return Some("tun_name must be at most 15 characters".to_owned())
+  }
+  None
+}
+
+/// Where the forward loop sends an IP packet read from the tun
+#[derive(Debug, PartialEq)]
+enum Outbound {
+  /// To the management address: terminated locally, never sent to a peer
+  Local,
+  /// To all peers with an active link
+  Broadcast,
+  /// To the peer its destination is routed to, if any
+  Routed
+}
+
+/// Where to send an IP packet read from the tun: the management address is checked
+/// first, so that it is never sent to a peer even if a peer's allowed IPs (e.g. the
+/// default routes of an exit node) cover it
+fn outbound(config: &Config, destination_ip: &IpAddr,
+  is_broadcast: impl FnOnce(&IpAddr) -> bool) -> Outbound
+{
+  if is_management_ip(config, destination_ip) {
+    Outbound::Local
+  } else if config.forward_broadcast && is_broadcast(destination_ip) {
+    Outbound::Broadcast
+  } else {
+    Outbound::Routed
+  }
+}
+
+fn is_management_ip(config: &Config, ip: &IpAddr) -> bool {
+  config.management_ip.is_some_and(|management_ip| management_ip.addr() == *ip)
+}
+
+/// Whether a packet from a peer may be written to the tun as far as the management
+/// address is concerned: its source must be in `management_allowed`, if set. Sources
+/// are checked against the sending peer before this, so allowing a peer's tunnel IP
+/// allows that peer, and allowing an address within its allowed IPs allows that host
+/// behind it
+fn management_allows(config: &Config, source_ip: &IpAddr, destination_ip: &IpAddr)
+  -> bool
+{
+  !is_management_ip(config, destination_ip) || config.management_allowed.is_empty()
+    || config.management_allowed.contains(source_ip)
+}
+
+/// Addresses terminated locally on the tun
+fn local_ips(config: &Config) -> Vec<IpAddr> {
+  [config.vpn_ip, config.vpn_ip6, config.management_ip].into_iter().flatten()
+    .map(|ip| ip.addr())
+    .collect()
+}
+
+/// Check peers given while running, failing on the first problem
+fn check_peers(config: &Config, peers: &BTreeMap<IpAddr, PeerConfig>)
+  -> Result<(), CreateClientError>
+{
+  match peer_problems(config, peers).into_iter().next() {
+    Some(problem) => Err(CreateClientError::ConfigError(problem)),
+    None => Ok(())
+  }
+}
+
+/// Problems with the peers: invalid settings, peer addresses that aren't unique or
+/// conflict with local addresses and networks routed to more than one peer
+fn peer_problems(config: &Config, peers: &BTreeMap<IpAddr, PeerConfig>) -> Vec<String> {
+  let mut problems = Vec::new();
+  let vpn_ips = [config.vpn_ip, config.vpn_ip6].into_iter().flatten()
+    .map(|ip| ip.addr())
+    .collect::<Vec<_>>();
+  if let Some(management_ip) = config.management_ip {
+    if vpn_ips.contains(&management_ip.addr()) {
+      problems.push(format!("management IP {} is also a VPN IP", management_ip.addr()));
+    }
+  }
+  let local_ips = local_ips(config);
+  let mut peer_ips = BTreeSet::new();
+  let mut peer_nets = BTreeSet::new();
+  for (ip, peer) in peers.iter() {
+    if peer.rate_limit_kbps == Some(0) {
+      problems.push(format!("rate_limit_kbps of peer {ip} must be at least 1"));
+    }
+    if peer.identity.is_some_and(|identity| identity.destination_hash() != peer.dest.0) {
+      problems.push(format!("identity of peer {ip} does not match its destination hash"));
+    }
+    if peer.fec_group_size == Some(0) {
+      problems.push(format!("fec_group_size of peer {ip} must be at least 1"));
+    }
+    let min_mtu = if config.vpn_ip6.is_some() { MIN_MTU_IPV6 } else { MIN_MTU };
+    if peer.mtu.is_some_and(|mtu| mtu < min_mtu) {
+      problems.push(format!("mtu of peer {ip} must be at least {min_mtu}"));
+    }
+    if peer.psk.as_ref().is_some_and(|psk| psk.len() < psk::MIN_LEN) {
+      problems.push(format!("psk of peer {ip} must be at least {} characters",
+        psk::MIN_LEN));
+    }
+    for net in peer.allowed_ips.iter() {
+      if !peer_nets.insert(net.trunc()) {
+        problems.push(format!("allowed IPs {net} are configured for more than one peer"));
+      }
+    }
+    for ip in std::iter::once(ip).chain(peer.addresses.iter()) {
+      if local_ips.contains(ip) {
+        problems.push(format!("peer IP {ip} is also a local IP"));
+      }
+      if !peer_ips.insert(*ip) {
+        problems.push(format!("peer IP {ip} is configured more than once"));
+      }
+    }
+  }
+  problems
+}
+
+/// Problem with the exit node among the peers, if any
+fn exit_node_problem(config: &Config, peers: &BTreeMap<IpAddr, PeerConfig>)
+  -> Option<String>
+{
+  config.exit_node.filter(|exit_node| !peers.contains_key(exit_node))
+    .map(|_| "exit_node is not a configured peer".to_owned())
+}
+
+/// Peers for the peer map, with the default networks routed to the exit node on top
+/// of its allowed IPs, so that they are kept when peers are reloaded
+fn build_peer_map(config: &Config, peers: &BTreeMap<IpAddr, PeerConfig>)
+  -> BTreeMap<IpAddr, Peer>
+{
+  peers.iter()
+    .map(|(ip, peer_config)| {
+      let mut peer = Peer::new(peer_config);
+      if config.exit_node == Some(*ip) {
+        for net in exit_nets(config) {
+          if !peer.allowed_ips.contains(&net) {
+            peer.allowed_ips.push(net);
+          }
+        }
+      }
+      (*ip, peer)
+    })
+    .collect()
+}
+
+/// Default networks routed to the exit node: IPv4, and IPv6 with `vpn_ip6`
+fn exit_nets(config: &Config) -> Vec<IpNet> {
+  [
+    Some(IpNet::new(Ipv4Addr::UNSPECIFIED.into(), 0).unwrap()),
+    config.vpn_ip6.map(|_| IpNet::new(Ipv6Addr::UNSPECIFIED.into(), 0).unwrap())
+  ].into_iter().flatten().collect()
+}
+
+/// Whether a link without traffic since `last_activity` has been idle for the idle
+/// timeout by `now`; without a timeout it never is
+fn is_idle(idle_timeout: Option<Duration>, last_activity: Instant, now: Instant) -> bool {
+  idle_timeout.is_some_and(|timeout| now.duration_since(last_activity) >= timeout)
+}
+
+/// Whether a keepalive is due by `now` for a peer last sent to at `last_sent`, sending
+/// at the shorter of its persistent keepalive and the link keepalive; with neither set
+/// none is ever due
+fn keepalive_due(persistent_keepalive: Option<Duration>, link_keepalive: Option<Duration>,
+  last_sent: Instant, now: Instant) -> bool
+{
+  [persistent_keepalive, link_keepalive].into_iter().flatten().min()
+    .is_some_and(|keepalive| now.duration_since(last_sent) >= keepalive)
+}
+
+/// Frames sent to a peer on a newly activated link: the MTU, capped by the peer's, and
+/// a hello if enabled. The peer takes nothing else until the link is identified, so
+/// the rest follows the answer to its identify challenge
+fn greeting(config: &Config, peer_mtu: Option<u16>, dest: &[u8]) -> Vec<Vec<u8>> {
+  let mtu = peer_mtu.unwrap_or(config.mtu).min(config.mtu);
+  let mut frames = vec![frame::mtu(mtu, dest)];
+  if config.send_hello {
+    frames.push(frame::hello().to_vec());
+  }
+  frames
+}
+
+/// Routes installed for a network: a default route is split into two halves that take
+/// precedence over the existing default route without replacing it, unless the default
+/// route goes in the policy routing table of the fwmark instead
+fn kernel_routes(net: IpNet, config: &Config) -> Vec<IpNet> {
+  if net.prefix_len() != 0 {
+    vec![net]
+  } else if config.exit_node.is_some() && config.fwmark.is_some() {
+    Vec::new()
+  } else {
+    net.subnets(1).unwrap().collect()
+  }
+}
+
+/// Remote addresses of the Reticulum interfaces, resolving host names
+async fn underlay_ips(interfaces: &BTreeMap<String, InterfaceConfig>)
+  -> Result<Vec<IpAddr>, CreateClientError>
+{
+  let mut ips = Vec::new();
+  for (name, interface) in interfaces.iter() {
+    match interface {
+      InterfaceConfig::Udp { forward: Some(forward), .. } => ips.push(forward.ip()),
+      InterfaceConfig::TcpClient { connect } => {
+        let addrs = tokio::net::lookup_host(connect.as_str()).await.map_err(|err| {
+          CreateClientError::ConfigError(format!("can't resolve interface {name} address \
+            {connect}: {err}"))
+        })?;
+        ips.extend(addrs.map(|addr| addr.ip()));
+      }
+      InterfaceConfig::Udp { forward: None, .. } | InterfaceConfig::TcpServer { .. }
+        | InterfaceConfig::SharedInstance { .. } => {}
+    }
+  }
+  ips.sort();
+  ips.dedup();
+  Ok(ips)
+}
+
+/// Vary an interval randomly by up to `ANNOUNCE_JITTER` either way
+fn jitter(interval: Duration) -> Duration {
+  use rand_core::RngCore;
+  let random = rand_core::OsRng.next_u32() as f64 / u32::MAX as f64;
+  interval.mul_f64(1.0 + ANNOUNCE_JITTER * (2.0 * random - 1.0))
+}
+
+/// Close the out link to the given destination, if any
+async fn close_link(transport: &Transport, dest: &AddressHash) {
+  if let Some(link) = transport.find_out_link(dest).await {
+    link.lock().await.close();
+  }
+}
+
+/// Frame the pending batch of coalesced packets for a peer, if any, for sending
+fn frame_batch(buffers: &Arc<BufferPool>, peer: &mut Peer, frames: &mut Vec<PooledBuf>) {
+  let Some(batch) = peer.batch.take() else { return };
+  tracing::trace!(parent: &peer.span, bytes = batch.len(), "sending batch");
+  frame_peer_data(buffers, peer, batch, frames);
+}
+
+/// Log a change in the quality of a peer's link
+fn log_quality(peer: &mut Peer) {
+  let Some(quality) = peer.quality.update() else { return };
+  let rtt = peer.quality.rtt();
+  let loss = peer.quality.loss();
+  match quality {
+    LinkQuality::Unknown => {}
+    LinkQuality::Good => tracing::info!(parent: &peer.span, ?rtt, ?loss, "link good"),
+    LinkQuality::Degraded =>
+      tracing::warn!(parent: &peer.span, ?rtt, ?loss, "link degraded"),
+    LinkQuality::Dead =>
+      tracing::warn!(parent: &peer.span, ?rtt, ?loss, "link dead: probes unanswered")
+  }
+}
+
+/// Frame an IP packet, batch or Ethernet frame for a peer, compressed if the peer
+/// supports it, sequenced if the peer asked for it and FEC protected if enabled for it;
+/// each step builds its frame in a buffer from the pool, and the payload is sent as is
+/// if none applies
+fn frame_peer_data(buffers: &Arc<BufferPool>, peer: &mut Peer, mut payload: PooledBuf,
+  frames: &mut Vec<PooledBuf>)
+{
+  if peer.compression {
+    let mut compressed = buffers.take();
+    if frame::compress(&payload, &mut compressed) {
+      tracing::trace!(parent: &peer.span, bytes = payload.len(),
+        compressed = compressed.len(), "compressed payload");
+      payload = compressed;
+    }
+  }
+  if peer.sequencing {
+    peer.tx_sequence += 1;
+    let mut sequenced = buffers.take();
+    frame::sequenced(peer.tx_sequence, &payload, &mut sequenced);
+    payload = sequenced;
+  }
+  match &mut peer.fec {
+    Some(fec) => {
+      let (data, parity) = fec.encode(&payload, buffers);
+      frames.push(data);
+      frames.extend(parity);
+    }
+    None => frames.push(payload)
+  }
+}
+
+/// Decrement the TTL or hop limit of a relayed IP packet, updating the IPv4 header
+/// checksum; returns false if it has run out and the packet must be dropped
+fn decrement_ttl(packet: &mut [u8]) -> bool {
+  match packet.first().map(|byte| byte >> 4) {
+    Some(4) => {
+      let header_len = (packet[0] & 0x0f) as usize * 4;
+      if header_len < 20 || packet.len() < header_len || packet[8] <= 1 {
+        return false
+      }
+      packet[8] -= 1;
+      packet[10..12].fill(0);
+      let mut sum = packet[..header_len].chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+      while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+      }
+      packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+      true
+    }
+    Some(6) if packet.len() >= 40 && packet[7] > 1 => {
+      packet[7] -= 1;
+      true
+    }
+    _ => false
+  }
+}
+
+/// Run a future to completion or until the timeout, if any; returns whether it
+/// completed
+async fn bounded(timeout: Option<Duration>, future: impl Future<Output = ()>) -> bool {
+  match timeout {
+    Some(timeout) => tokio::time::timeout(timeout, future).await.is_ok(),
+    None => {
+      future.await;
+      true
+    }
+  }
+}
+
+/// Parse the (source, destination) addresses from an IP packet
+fn packet_addrs(bytes: &[u8]) -> Option<(IpAddr, IpAddr)> {
+  let (ip_header, _) = etherparse::IpHeaders::from_slice(bytes)
+    .map_err(|e| tracing::trace!("couldn't parse packet: {e:?}"))
+    .ok()?;
+  if let Some((ipv4_header, _)) = ip_header.ipv4() {
+    Some((IpAddr::from(ipv4_header.source), IpAddr::from(ipv4_header.destination)))
+  } else if let Some((ipv6_header, _)) = ip_header.ipv6() {
+    Some((IpAddr::from(ipv6_header.source), IpAddr::from(ipv6_header.destination)))
+  } else {
+    tracing::trace!("failed to get ipv4 or ipv6 headers from ip header: {:?}", ip_header);
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const EXIT_CONFIG: &str = r#"
+    vpn_ip = "10.0.0.1/24"
+    vpn_ip6 = "fd00::1/64"
+    exit_node = "10.0.0.2"
+    [peers]
+    "10.0.0.2" = "00112233445566778899aabbccddeeff"
+    "10.0.0.3" = "ffeeddccbbaa99887766554433221100"
+  "#;
+
+  #[test]
+  fn misspelled_key_is_rejected() {
+    let config = r#"
+      vpn_ipp = "10.0.0.1/24"
+      [peers]
+      "10.0.0.2" = "00112233445566778899aabbccddeeff"
+    "#;
+    let err = Config::from_toml(config).err().unwrap().to_string();
+    assert!(err.starts_with("invalid config: "), "{err}");
+    assert!(err.contains("unknown field `vpn_ipp`"), "{err}");
+  }
+
+  #[test]
+  fn newer_version_is_rejected() {
+    let config = format!("version = {}\n{EXIT_CONFIG}", CONFIG_VERSION + 1);
+    let err = Config::from_toml(&config).err().unwrap().to_string();
+    assert!(err.contains("is newer than supported version"), "{err}");
+  }
+
+  #[test]
+  fn idle_link_is_closed() {
+    let last_activity = Instant::now();
+    let timeout = Some(Duration::from_secs(60));
+    assert!(is_idle(timeout, last_activity, last_activity + Duration::from_secs(60)));
+    assert!(is_idle(timeout, last_activity, last_activity + Duration::from_secs(300)));
+  }
+
+  #[test]
+  fn busy_link_is_kept() {
+    let last_activity = Instant::now();
+    let timeout = Some(Duration::from_secs(60));
+    assert!(!is_idle(timeout, last_activity, last_activity));
+    assert!(!is_idle(timeout, last_activity, last_activity + Duration::from_secs(59)));
+  }
+
+  #[test]
+  fn link_without_idle_timeout_is_never_idle() {
+    let last_activity = Instant::now();
+    assert!(!is_idle(None, last_activity, last_activity + Duration::from_secs(86400)));
+  }
+
+  #[test]
+  fn keepalives_follow_the_interval() {
+    let start = Instant::now();
+    let secs = Duration::from_secs;
+    let interval = Some(secs(25));
+    assert!(!keepalive_due(interval, None, start, start + secs(24)));
+    assert!(keepalive_due(interval, None, start, start + secs(25)));
+    // sent at 25s, so the next is due at 50s
+    let last_sent = start + secs(25);
+    assert!(!keepalive_due(interval, None, last_sent, start + secs(49)));
+    assert!(keepalive_due(interval, None, last_sent, start + secs(50)));
+  }
+
+  #[test]
+  fn keepalives_use_the_shorter_interval() {
+    let start = Instant::now();
+    let secs = Duration::from_secs;
+    assert!(keepalive_due(Some(secs(60)), Some(secs(10)), start, start + secs(10)));
+    assert!(keepalive_due(Some(secs(10)), Some(secs(60)), start, start + secs(10)));
+    assert!(!keepalive_due(Some(secs(60)), Some(secs(30)), start, start + secs(20)));
+  }
+
+  #[test]
+  fn no_keepalives_without_an_interval() {
+    let start = Instant::now();
+    assert!(!keepalive_due(None, None, start, start + Duration::from_secs(86400)));
+  }
+
+  #[test]
+  fn peer_link_follows_link_state() {
+    let config = Config::from_toml(EXIT_CONFIG).unwrap();
+    let ip = "10.0.0.3".parse().unwrap();
+    let mut peer = Peer::new(&config.peers[&ip]);
+    let link_id = LinkId::new_from_slice(&[1; 16]);
+    let state = PeerLink::new(ip, &peer);
+    assert!(!state.link_active && state.link_id.is_none());
+    // pending until activated
+    peer.linked(link_id);
+    let state = PeerLink::new(ip, &peer);
+    assert!(!state.link_active);
+    assert_eq!(state.link_id, Some(link_id));
+    peer.activate();
+    let state = PeerLink::new(ip, &peer);
+    assert!(state.link_active);
+    assert_eq!(state.link_id, Some(link_id));
+    assert_eq!(state.links_established, 1);
+    // closed
+    peer.schedule_relink();
+    let state = PeerLink::new(ip, &peer);
+    assert!(!state.link_active && state.link_id.is_none());
+    assert_eq!(state.links_established, 1);
+  }
+
+  #[test]
+  fn hello_sent_on_activation() {
+    let mut config = Config::from_toml(EXIT_CONFIG).unwrap();
+    config.send_hello = true;
+    let frames = greeting(&config, Some(1280), &[7; 16]);
+    assert_eq!(frames.len(), 2);
+    let Some(Frame::Mtu(body)) = Frame::parse(&frames[0]) else { panic!("not an mtu") };
+    assert_eq!(frame::parse_mtu(body), Some((1280, &[7; 16][..])));
+    assert!(matches!(Frame::parse(&frames[1]), Some(Frame::Hello)));
+    config.send_hello = false;
+    assert_eq!(greeting(&config, None, &[7; 16]).len(), 1);
+  }
+
+  #[test]
+  fn hello_not_written_to_tun() {
+    let hello = frame::hello();
+    let frame = Frame::parse(&hello).unwrap();
+    assert!(matches!(frame, Frame::Hello));
+    assert!(!frame.carries_packets());
+  }
+
+  #[test]
+  fn exit_node_gets_default_nets() {
+    let config = Config::from_toml(EXIT_CONFIG).unwrap();
+    let peers = build_peer_map(&config, &config.peers);
+    let exit = &peers[&"10.0.0.2".parse::<IpAddr>().unwrap()];
+    assert_eq!(exit.allowed_ips, vec!["0.0.0.0/0".parse::<IpNet>().unwrap(),
+      "::/0".parse().unwrap()]);
+    assert!(peers[&"10.0.0.3".parse::<IpAddr>().unwrap()].allowed_ips.is_empty());
+  }
+
+  #[test]
+  fn reloading_keeps_default_nets() {
+    let config = Config::from_toml(EXIT_CONFIG).unwrap();
+    let mut reloaded = config.peers.clone();
+    reloaded.remove(&"10.0.0.3".parse().unwrap());
+    let peers = build_peer_map(&config, &reloaded);
+    let exit = &peers[&"10.0.0.2".parse::<IpAddr>().unwrap()];
+    assert!(exit.allowed_ips.contains(&"0.0.0.0/0".parse().unwrap()));
+    assert!(exit.allowed_ips.contains(&"::/0".parse().unwrap()));
+    assert_eq!(exit_node_problem(&config, &reloaded), None);
+  }
+
+  #[test]
+  fn reloading_without_exit_node_fails() {
+    let config = Config::from_toml(EXIT_CONFIG).unwrap();
+    let mut reloaded = config.peers.clone();
+    reloaded.remove(&"10.0.0.2".parse().unwrap());
+    assert!(exit_node_problem(&config, &reloaded).is_some());
+  }
+
+  const MANAGEMENT_CONFIG: &str = r#"
+    vpn_ip = "10.0.0.1/24"
+    management_ip = "10.1.0.1/32"
+    management_allowed = ["10.0.0.2"]
+    exit_node = "10.0.0.2"
+    forward_broadcast = true
+    [peers]
+    "10.0.0.2" = "00112233445566778899aabbccddeeff"
+    "10.0.0.3" = "ffeeddccbbaa99887766554433221100"
+  "#;
+
+  #[test]
+  fn management_traffic_is_not_forwarded() {
+    let config = Config::from_toml(MANAGEMENT_CONFIG).unwrap();
+    let management_ip = "10.1.0.1".parse().unwrap();
+    // the default routes of the exit node cover the management address
+    let mut peer_map = PeerMap::new(build_peer_map(&config, &config.peers));
+    assert!(peer_map.route(&management_ip).is_some());
+    assert_eq!(outbound(&config, &management_ip, |_| false), Outbound::Local);
+    assert_eq!(outbound(&config, &management_ip, |_| true), Outbound::Local);
+    assert_eq!(outbound(&config, &"10.1.0.2".parse().unwrap(), |_| false),
+      Outbound::Routed);
+    assert_eq!(outbound(&config, &"10.0.0.255".parse().unwrap(), |_| true),
+      Outbound::Broadcast);
+  }
+
+  #[test]
+  fn management_address_only_reachable_from_allowed_sources() {
+    let config = Config::from_toml(MANAGEMENT_CONFIG).unwrap();
+    let management_ip = "10.1.0.1".parse().unwrap();
+    let allowed = "10.0.0.2".parse().unwrap();
+    let other = "10.0.0.3".parse().unwrap();
+    assert!(management_allows(&config, &allowed, &management_ip));
+    assert!(!management_allows(&config, &other, &management_ip));
+    // other destinations aren't restricted
+    assert!(management_allows(&config, &other, &"10.0.0.1".parse().unwrap()));
+  }
+
+  #[test]
+  fn management_address_reachable_from_all_peers_by_default() {
+    let mut config = Config::from_toml(MANAGEMENT_CONFIG).unwrap();
+    config.management_allowed.clear();
+    let management_ip = "10.1.0.1".parse().unwrap();
+    assert!(management_allows(&config, &"10.0.0.3".parse().unwrap(), &management_ip));
+  }
+
+  fn rendered(packet: &[u8]) -> String {
+    PacketInfo::parse(packet).unwrap().to_string()
+  }
+
+  #[test]
+  fn formats_tcp_packet() {
+    let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+      .tcp(40000, 22, 1, 1024);
+    let mut packet = Vec::with_capacity(builder.size(4));
+    builder.write(&mut packet, b"data").unwrap();
+    assert_eq!(rendered(&packet), "tcp 10.0.0.1:40000 -> 10.0.0.2:22 (44 bytes)");
+  }
+
+  #[test]
+  fn formats_udp_packet() {
+    let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+      .udp(5353, 53);
+    let mut packet = Vec::with_capacity(builder.size(4));
+    builder.write(&mut packet, b"data").unwrap();
+    assert_eq!(rendered(&packet), "udp 10.0.0.1:5353 -> 10.0.0.2:53 (32 bytes)");
+  }
+
+  #[test]
+  fn formats_icmp_packet_without_ports() {
+    let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+      .icmpv4_echo_request(1, 1);
+    let mut packet = Vec::with_capacity(builder.size(4));
+    builder.write(&mut packet, b"data").unwrap();
+    assert_eq!(rendered(&packet), "icmp 10.0.0.1 -> 10.0.0.2 (32 bytes)");
+  }
+
+  #[test]
+  fn formats_ipv6_packet() {
+    let source = "fd00::1".parse::<std::net::Ipv6Addr>().unwrap().octets();
+    let destination = "fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets();
+    let builder = etherparse::PacketBuilder::ipv6(source, destination, 64).udp(5353, 53);
+    let mut packet = Vec::with_capacity(builder.size(4));
+    builder.write(&mut packet, b"data").unwrap();
+    assert_eq!(rendered(&packet), "udp [fd00::1]:5353 -> [fd00::2]:53 (52 bytes)");
+  }
+
+  #[test]
+  fn packets_of_a_flow_share_its_hash() {
+    let flow = |source_port, payload: &[u8]| {
+      let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+        .udp(source_port, 53);
+      let mut packet = Vec::with_capacity(builder.size(payload.len()));
+      builder.write(&mut packet, payload).unwrap();
+      PacketInfo::parse(&packet).unwrap().flow()
+    };
+    assert_eq!(flow(5353, b"data"), flow(5353, b"more data"));
+    assert_ne!(flow(5353, b"data"), flow(5354, b"data"));
+  }
+
+  #[test]
+  fn link_balance_is_checked() {
+    let config = format!("link_balance = \"weighted\"\nlink_weights = [3, 1]\n\
+      {EXIT_CONFIG}");
+    let config = Config::from_toml(&config).unwrap();
+    assert_eq!(config.link_weights, [3, 1]);
+    assert!(config.check().errors.is_empty());
+    let config = Config { replay_protection: true, link_weights: [0, 0], ..config };
+    assert_eq!(config.check().errors, [
+      "link_balance requires replay_protection to be disabled",
+      "link_weights must not both be 0"
+    ]);
+    let config = Config { link_balance: LinkBalance::Flow, coalesce_us: Some(100),
+      replay_protection: false, link_weights: [1, 1], ..config };
+    assert_eq!(config.check().errors,
+      ["flow link_balance requires coalesce_us to be unset"]);
+  }
+
+  #[tokio::test]
+  async fn bounded_completes() {
+    assert!(bounded(Some(Duration::from_secs(10)), async {}).await);
+    assert!(bounded(None, async {}).await);
+  }
+
+  #[tokio::test]
+  async fn bounded_gives_up_on_stalled_teardown() {
+    let started = Instant::now();
+    assert!(!bounded(Some(Duration::from_millis(50)), std::future::pending()).await);
+    assert!(started.elapsed() < Duration::from_secs(5));
   }
 }
@@ -0,0 +1,86 @@
+//! Log file for `--log-file`, rotated once it grows past a size limit or reaches an
+//! age: `<path>` is renamed to `<path>.1`, older files shift up by one and files past
+//! the number kept are removed
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub struct RotatingFile {
+  path: PathBuf,
+  file: File,
+  size: u64,
+  opened: Instant,
+  /// Rotate before a write would take the file past this many bytes
+  max_size: Option<u64>,
+  /// Rotate when the file was opened this long ago
+  max_age: Option<Duration>,
+  /// Number of rotated files kept
+  keep: u32
+}
+
+impl RotatingFile {
+  /// Open the log file for appending
+  pub fn open(path: &Path, max_size: Option<u64>, max_age: Option<Duration>, keep: u32)
+    -> io::Result<Self>
+  {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(RotatingFile {
+      path: path.to_owned(),
+      file,
+      size,
+      opened: Instant::now(),
+      max_size,
+      max_age,
+      keep
+    })
+  }
+
+  fn rotated_path(&self, n: u32) -> PathBuf {
+    let mut path = self.path.clone().into_os_string();
+    path.push(format!(".{n}"));
+    PathBuf::from(path)
+  }
+
+  fn rotate(&mut self) -> io::Result<()> {
+    if self.keep == 0 {
+      fs::remove_file(&self.path)?;
+    } else {
+      let _ = fs::remove_file(self.rotated_path(self.keep));
+      for n in (1..self.keep).rev() {
+        let _ = fs::rename(self.rotated_path(n), self.rotated_path(n + 1));
+      }
+      fs::rename(&self.path, self.rotated_path(1))?;
+    }
+    self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+    self.size = 0;
+    self.opened = Instant::now();
+    Ok(())
+  }
+}
+
+impl Write for RotatingFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let full = self.max_size.is_some_and(|max_size| {
+      self.size > 0 && self.size + buf.len() as u64 > max_size
+    });
+    let expired = self.max_age.is_some_and(|max_age| self.opened.elapsed() >= max_age);
+    if full || expired {
+      // keep logging to the current file if it can't be rotated
+      if let Err(err) = self.rotate() {
+        eprintln!("failed to rotate log file {}: {err:?}", self.path.display());
+        self.size = 0;
+        self.opened = Instant::now();
+      }
+    }
+    let written = self.file.write(buf)?;
+    self.size += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
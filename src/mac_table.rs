@@ -0,0 +1,65 @@
+//! MAC address learning for tap mode: which peer each Ethernet source address was
+//! last seen behind
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use reticulum::hash::AddressHash;
+
+/// Most addresses learned at once; the stalest is forgotten when exceeded
+const MAX_ENTRIES: usize = 4096;
+
+pub type MacAddr = [u8; 6];
+
+#[derive(Default)]
+pub struct MacTable {
+  entries: BTreeMap<MacAddr, (AddressHash, Instant)>
+}
+
+impl MacTable {
+  /// Record that frames from the address arrive from the peer with the destination
+  pub fn learn(&mut self, mac: MacAddr, dest: AddressHash) {
+    if is_group(&mac) {
+      return
+    }
+    if !self.entries.contains_key(&mac) && self.entries.len() >= MAX_ENTRIES {
+      let stalest = self.entries.iter().min_by_key(|(_, (_, seen))| *seen)
+        .map(|(mac, _)| *mac);
+      if let Some(stalest) = stalest {
+        self.entries.remove(&stalest);
+      }
+    }
+    if let Some((old_dest, _)) = self.entries.insert(mac, (dest, Instant::now())) {
+      if old_dest != dest {
+        tracing::debug!("{} moved from {} to {}", format_mac(&mac), old_dest, dest);
+      }
+    }
+  }
+
+  /// Destination of the peer the address was learned behind
+  pub fn lookup(&self, mac: &MacAddr) -> Option<AddressHash> {
+    self.entries.get(mac).map(|(dest, _)| *dest)
+  }
+
+  /// Forget addresses not seen for longer than the given age
+  pub fn expire(&mut self, max_age: Duration) {
+    self.entries.retain(|_, (_, seen)| seen.elapsed() < max_age);
+  }
+}
+
+/// Destination and source address of an Ethernet frame
+pub fn frame_addrs(frame: &[u8]) -> Option<(MacAddr, MacAddr)> {
+  if frame.len() < crate::ETHERNET_HEADER_LEN {
+    return None
+  }
+  Some((frame[0..6].try_into().unwrap(), frame[6..12].try_into().unwrap()))
+}
+
+/// Whether the address is a broadcast or multicast group address
+pub fn is_group(mac: &MacAddr) -> bool {
+  mac[0] & 0x01 != 0
+}
+
+pub fn format_mac(mac: &MacAddr) -> String {
+  mac.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":")
+}
@@ -1,115 +1,1351 @@
 //! Reticulum VPN client
 
 use std::{fs, process};
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 use ed25519_dalek;
-use env_logger;
-use log;
+use tracing;
 use pem;
 use reticulum::identity::PrivateIdentity;
-use reticulum::iface::udp::UdpInterface;
 use reticulum::transport::{Transport, TransportConfig};
 use tokio;
+use tokio_util::sync::CancellationToken;
 use x25519_dalek;
 
 use rns_vpn;
 
-const CONFIG_PATH: &str = "Config.toml";
+const CONFIG_FILE: &str = "Config.toml";
+const CONFIG_DIR: &str = "rns-vpn";
+const CONFIG_ENV: &str = "RNS_VPN_CONFIG";
+const DEFAULT_CONTROL_SOCKET: &str = "/run/rns-vpn.sock";
+const SYSTEM_STATE_DIR: &str = "/var/lib/rns-vpn";
+const PRIVKEY_ENV: &str = "RNS_VPN_PRIVKEY_PATH";
+const SIGNKEY_ENV: &str = "RNS_VPN_SIGNKEY_PATH";
+const PRIVKEY_FILE: &str = "privkey.pem";
+const SIGNKEY_FILE: &str = "signkey.pem";
+/// PKCS#8 DER encoding of an X25519 private key up to the 32 key bytes
+const X25519_PKCS8_PREFIX: [u8; 16] = [
+  0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x04, 0x22, 0x04,
+  0x20
+];
 
 /// Command line arguments
 #[derive(Parser)]
-#[command(name = "Reticulum VPN Client", version)]
+#[command(name = "Reticulum VPN Client", version, subcommand_negates_reqs = true)]
 pub struct Command {
-  /// Reticulum UDP listen port number
+  #[command(subcommand)]
+  pub subcommand: Option<Subcommand>,
+  /// [Optional] Config file path (default: search working directory and XDG
+  /// config dirs)
   #[arg(short, long)]
-  pub port: u16,
+  pub config: Option<PathBuf>,
+  /// [Optional] Config file format: toml, json or yaml (default: by file extension,
+  /// otherwise toml)
+  #[arg(long)]
+  pub config_format: Option<rns_vpn::ConfigFormat>,
+  /// [Optional] IP assigned to this client in CIDR format, overriding `vpn_ip` in the
+  /// config
+  #[arg(long)]
+  pub vpn_ip: Option<ipnet::IpNet>,
+  /// [Optional] Steady-state interval between announces in seconds, overriding
+  /// `announce_freq_secs` in the config
+  #[arg(long)]
+  pub announce_freq: Option<u32>,
+  /// Reticulum UDP listen port number
+  #[arg(short, long, requires = "forward")]
+  pub port: Option<u16>,
   /// Reticulum UDP forward link address
-  #[arg(short, long)]
-  pub forward: std::net::SocketAddr,
+  #[arg(short, long, requires = "port")]
+  pub forward: Option<std::net::SocketAddr>,
+  /// [Optional] Reticulum TCP interface address (<host>:<port>) to connect to
+  #[arg(long)]
+  pub tcp: Option<String>,
+  /// [Optional] Reticulum TCP interface listen address for inbound connections
+  #[arg(long)]
+  pub tcp_listen: Option<std::net::SocketAddr>,
+  /// Connect to the instance shared by a local rnsd
+  #[arg(long)]
+  pub shared_instance: bool,
+  /// [Optional] Also use the interfaces of this Python-RNS config dir or file ("auto":
+  /// where rnsd looks for it), overriding `reticulum_config` in the config
+  #[arg(long)]
+  pub reticulum_config: Option<PathBuf>,
   /// [Optional] Reticulum private ID from name string
   #[arg(short, long)]
-  pub id_string: Option<String>
+  pub id_string: Option<String>,
+  /// [Optional] Name or name pattern of the tun device, overriding `tun_name` in the
+  /// config
+  #[arg(long)]
+  pub tun_name: Option<String>,
+  /// Remove a leftover tun device holding the VPN address instead of failing
+  #[arg(long)]
+  pub force: bool,
+  /// Log addresses, protocol and ports of each forwarded packet at debug level
+  #[arg(long)]
+  pub trace_packets: bool,
+  /// [Optional] Serve Prometheus metrics over HTTP on this address
+  #[arg(long)]
+  pub metrics_listen: Option<std::net::SocketAddr>,
+  /// [Optional] Serve health probes over HTTP on this address
+  #[arg(long)]
+  pub health_listen: Option<std::net::SocketAddr>,
+  /// [Optional] Serve the JSON-RPC management API on a Unix socket at this path
+  #[arg(long)]
+  pub control_socket: Option<PathBuf>,
+  /// [Optional] Switch to this user once the tun device is set up
+  #[arg(long)]
+  pub user: Option<String>,
+  /// [Optional] Switch to this group once the tun device is set up (default: the
+  /// primary group of the user)
+  #[arg(long)]
+  pub group: Option<String>,
+  /// Fork into the background once setup has succeeded
+  #[arg(long)]
+  pub daemon: bool,
+  /// [Optional] Write the process ID to this file, removing it on exit
+  #[arg(long)]
+  pub pid_file: Option<PathBuf>,
+  /// [Optional] Also log to this file
+  #[arg(long)]
+  pub log_file: Option<PathBuf>,
+  /// Level of messages logged to the log file, independent of RUST_LOG
+  #[arg(long, default_value = "info", requires = "log_file")]
+  pub log_file_level: tracing::level_filters::LevelFilter,
+  /// Rotate the log file once it would grow past this many MiB (0: never)
+  #[arg(long, default_value_t = 10, requires = "log_file")]
+  pub log_file_max_size: u64,
+  /// [Optional] Rotate the log file once it was started this many hours ago
+  #[arg(long, requires = "log_file")]
+  pub log_file_max_age: Option<u64>,
+  /// Number of rotated log files to keep
+  #[arg(long, default_value_t = 5, requires = "log_file")]
+  pub log_file_keep: u32
+}
+
+#[derive(clap::Subcommand)]
+pub enum Subcommand {
+  /// Check announces, links and data transfer between two local nodes over
+  /// loopback UDP
+  Selftest,
+  /// Generate an identity if there is none and write a config (to the path given with
+  /// -c, otherwise Config.toml), asking for settings not given as options when run
+  /// in a terminal, then print the peer entry other nodes need to add
+  Init {
+    /// [Optional] IP assigned to this client in CIDR format (default: a random
+    /// address in 10.0.0.0/24)
+    #[arg(long)]
+    vpn_ip: Option<ipnet::IpNet>,
+    /// [Optional] Reticulum TCP interface address (<host>:<port>) to connect to
+    #[arg(long)]
+    tcp: Option<String>
+  },
+  /// Print a signed bundle of this node's identity, VPN IP and allowed IPs for other
+  /// nodes to import with add-peer
+  ExportPeer {
+    /// Networks routed to this node besides its VPN IP
+    #[arg(long)]
+    allowed_ips: Vec<ipnet::IpNet>
+  },
+  /// Verify a peer bundle made with export-peer and add the peer to the config, or to
+  /// a running client with --socket
+  AddPeer {
+    /// Peer bundle
+    bundle: String,
+    /// [Optional] Name of the peer
+    #[arg(long)]
+    name: Option<String>,
+    /// [Optional] Add the peer to the running client using this control socket
+    /// instead of the config
+    #[arg(long)]
+    socket: Option<PathBuf>
+  },
+  /// Generate an X25519 private key and ed25519 signing key as PEM files and print
+  /// the destination hash of the resulting identity
+  Keygen {
+    /// Private key output path
+    #[arg(long, default_value = "privkey.pem")]
+    privkey: PathBuf,
+    /// Signing key output path
+    #[arg(long, default_value = "signkey.pem")]
+    signkey: PathBuf
+  },
+  /// Show each peer of a running client with its link state, bytes transferred and
+  /// time since the last packet, using the control socket
+  Status {
+    /// Control socket path
+    #[arg(long, default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: PathBuf,
+    /// Print the peers as JSON
+    #[arg(long)]
+    json: bool
+  },
+  /// Check the config and key files without starting the client, reporting every
+  /// problem found
+  CheckConfig,
+  /// Send echo requests to a peer of a running client over its link and report the
+  /// round trip time or why there was no reply, using the control socket
+  Ping {
+    /// Tunnel address of the peer
+    peer: std::net::IpAddr,
+    /// Number of echo requests, one per second
+    #[arg(long, default_value_t = 4)]
+    count: u32,
+    /// Control socket path
+    #[arg(long, default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: PathBuf
+  },
+  /// Measure goodput, loss and round trip times to a peer of a running client by
+  /// sending echo requests over its link, using the control socket
+  Bench {
+    /// Tunnel address of the peer
+    #[arg(long)]
+    peer: std::net::IpAddr,
+    /// Number of echo requests
+    #[arg(long, default_value_t = 100)]
+    count: u32,
+    /// Bytes per echo request
+    #[arg(long, default_value_t = 1000)]
+    size: usize,
+    /// Control socket path
+    #[arg(long, default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: PathBuf
+  },
+  /// Add or remove peers of a running client, using the control socket
+  Peer {
+    #[command(subcommand)]
+    command: PeerCommand
+  }
+}
+
+#[derive(clap::Subcommand)]
+pub enum PeerCommand {
+  /// Add a peer, linked on its next announce
+  Add {
+    /// Tunnel address of the peer
+    ip: std::net::IpAddr,
+    /// Destination hash of the peer
+    dest: String,
+    /// [Optional] Name of the peer
+    #[arg(long)]
+    name: Option<String>,
+    /// Networks routed to the peer besides its tunnel address
+    #[arg(long)]
+    allowed_ips: Vec<ipnet::IpNet>,
+    /// Also add the peer to the config file of the running client
+    #[arg(long)]
+    persist: bool,
+    /// Control socket path
+    #[arg(long, default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: PathBuf
+  },
+  /// Remove a peer, closing its link
+  Remove {
+    /// Tunnel address of the peer
+    ip: std::net::IpAddr,
+    /// Also remove the peer from the config file of the running client
+    #[arg(long)]
+    persist: bool,
+    /// Control socket path
+    #[arg(long, default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: PathBuf
+  }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), process::ExitCode> {
+fn main() -> Result<(), process::ExitCode> {
   // parse command line args
   let cmd = Command::parse();
-  // load config
-  let config: rns_vpn::Config = {
-    let s = fs::read_to_string(CONFIG_PATH).unwrap();
-    toml::from_str(&s).unwrap()
-  };
-  // init logging
-  env_logger::Builder::new().filter_level(log::LevelFilter::Info).parse_default_env()
-    .init();
-  log::info!("client start with port {} and forward IP {}", cmd.port, cmd.forward);
+  // init logging: RUST_LOG sets the level of the console log, the log file has its own
+  let log_file = cmd.log_file.as_deref().map(|path| {
+    let max_size = (cmd.log_file_max_size > 0).then(|| cmd.log_file_max_size << 20);
+    let max_age = cmd.log_file_max_age
+      .map(|hours| std::time::Duration::from_secs(hours * 3600));
+    rns_vpn::logfile::RotatingFile::open(path, max_size, max_age, cmd.log_file_keep)
+      .map_err(|err| {
+        eprintln!("failed to open log file {}: {err:?}", path.display());
+        process::ExitCode::FAILURE
+      })
+  }).transpose()?;
+  let log_backend = {
+    use tracing_subscriber::prelude::*;
+    // the backend from the config is added once it's loaded; the filter stays outside
+    // of the reloaded layer, as per-layer filters can't be reloaded
+    let (backend, log_backend) = tracing_subscriber::reload::Layer::new(None);
+    let backend = backend.with_filter(log_filter());
+    let console = tracing_subscriber::fmt::layer()
+      .with_writer(|| if CONSOLE_LOG.load(std::sync::atomic::Ordering::Relaxed) {
+        tracing_subscriber::fmt::writer::OptionalWriter::some(std::io::stdout())
+      } else {
+        tracing_subscriber::fmt::writer::OptionalWriter::none()
+      })
+      .with_filter(log_filter());
+    let file = log_file.map(|log_file| tracing_subscriber::fmt::layer()
+      .with_ansi(false)
+      .with_writer(std::sync::Mutex::new(log_file))
+      .with_filter(cmd.log_file_level));
+    tracing_subscriber::registry().with(backend).with(console).with(file).init();
+    log_backend
+  };
+  // fork before the runtime starts any threads; the parent waits for setup to finish so
+  // that failures are still reported on the terminal
+  let daemon = if cmd.daemon && cmd.subcommand.is_none() {
+    Some(daemonize()?)
+  } else {
+    None
+  };
+  let runtime = tokio::runtime::Runtime::new().map_err(|err| {
+    tracing::error!("failed to start runtime: {err:?}");
+    process::ExitCode::FAILURE
+  })?;
+  let result = runtime.block_on(run(cmd, daemon, log_backend));
+  // exit without waiting for blocking tasks, e.g. a tun read a timed out shutdown left
+  runtime.shutdown_background();
+  result
+}
+
+async fn run(cmd: Command, daemon: Option<Daemon>, log_backend: LogBackendHandle)
+  -> Result<(), process::ExitCode>
+{
+  match cmd.subcommand {
+    Some(Subcommand::Selftest) => return selftest().await,
+    Some(Subcommand::Init { vpn_ip, tcp }) => return init(cmd.config, vpn_ip, tcp),
+    Some(Subcommand::Keygen { privkey, signkey }) => return keygen(&privkey, &signkey),
+    Some(Subcommand::ExportPeer { allowed_ips }) =>
+      return export_peer(cmd.config, cmd.config_format, cmd.id_string, &allowed_ips),
+    Some(Subcommand::AddPeer { bundle, name, socket }) =>
+      return add_peer(cmd.config, cmd.config_format, &bundle, name, socket).await,
+    Some(Subcommand::Status { socket, json }) => return status(&socket, json).await,
+    Some(Subcommand::CheckConfig) => return check_config(cmd.config, cmd.config_format),
+    Some(Subcommand::Ping { peer, count, socket }) => return ping(&socket, peer, count).await,
+    Some(Subcommand::Bench { peer, count, size, socket }) =>
+      return bench(&socket, peer, count, size).await,
+    Some(Subcommand::Peer { command }) => return peer(command).await,
+    None => {}
+  }
+  let _pid_file = cmd.pid_file.as_deref().map(PidFile::create).transpose()?;
+  // load config; the path is kept for reloading on SIGHUP and persisting peers
+  let config_format = cmd.config_format;
+  let (config_path, mut config) = load_config(cmd.config, config_format)?;
+  if config.log_backend != rns_vpn::LogBackend::Console {
+    set_log_backend(&log_backend, config.log_backend)?;
+  }
+  if let Some(vpn_ip) = cmd.vpn_ip {
+    config.vpn_ip = Some(vpn_ip);
+  }
+  config.announce_freq_secs = cmd.announce_freq.unwrap_or(config.announce_freq_secs);
+  config.tun_name = cmd.tun_name.or(config.tun_name.take());
+  config.force |= cmd.force;
+  config.trace_packets |= cmd.trace_packets;
+  config.metrics_listen = cmd.metrics_listen.or(config.metrics_listen);
+  config.health_listen = cmd.health_listen.or(config.health_listen);
+  config.control_socket = cmd.control_socket.or(config.control_socket);
+  config.user = cmd.user.or(config.user.take());
+  config.group = cmd.group.or(config.group.take());
+  let (user, group) = (config.user.clone(), config.group.clone());
+  // interfaces given on the command line are added to those in the config
+  if let (Some(port), Some(forward)) = (cmd.port, cmd.forward) {
+    config.interfaces.insert("cli-udp".to_owned(), rns_vpn::InterfaceConfig::Udp {
+      listen: std::net::SocketAddr::from(([0, 0, 0, 0], port)),
+      forward: Some(forward)
+    });
+  }
+  if let Some(tcp) = cmd.tcp.clone() {
+    config.interfaces.insert("cli-tcp".to_owned(),
+      rns_vpn::InterfaceConfig::TcpClient { connect: tcp });
+  }
+  if let Some(tcp_listen) = cmd.tcp_listen {
+    config.interfaces.insert("cli-tcp-listen".to_owned(),
+      rns_vpn::InterfaceConfig::TcpServer { listen: tcp_listen });
+  }
+  if cmd.shared_instance {
+    config.interfaces.insert("cli-shared-instance".to_owned(),
+      rns_vpn::InterfaceConfig::SharedInstance {
+        instance_name: rns_vpn::shared_instance::DEFAULT_INSTANCE_NAME.to_owned(),
+        port: None
+      });
+  }
+  config.reticulum_config = cmd.reticulum_config.or(config.reticulum_config.take());
+  if let Some(path) = config.reticulum_config.as_deref() {
+    rns_vpn::rns_config::add_interfaces(&mut config.interfaces, path).map_err(|err| {
+      tracing::error!("failed to load Reticulum interfaces: {err}");
+      process::ExitCode::FAILURE
+    })?;
+  }
+  let interfaces = config.interfaces.clone();
+  if interfaces.is_empty() {
+    tracing::error!("no Reticulum interfaces: add an [interfaces] entry to the config or \
+      use -p/-f, --tcp, --tcp-listen, --shared-instance or --reticulum-config");
+    return Err(process::ExitCode::FAILURE)
+  }
   // client
-  let client = match rns_vpn::Client::new(config) {
+  let attach_tun = config.attach_tun;
+  let mut client = match rns_vpn::Client::new(config).await {
     Ok(client) => client,
-    Err(err) => match err {
-      rns_vpn::CreateClientError::RiptunError(riptun::Error::Unix {
-        source: nix::errno::Errno::EPERM
-      }) => {
-        log::error!("EPERM error creating TUN interface: \
-          need to run with root permissions");
-        return Err(process::ExitCode::FAILURE)
-      }
-      _ => {
-        log::error!("error creating VPN client: {:?}", err);
-        return Err(process::ExitCode::FAILURE)
+    Err(err) => {
+      if rns_vpn::is_privileged() || attach_tun {
+        tracing::error!("error creating VPN client: {err}");
+      } else {
+        tracing::error!("error creating VPN client: need to run with root (administrator \
+          on Windows) permissions: {err}");
       }
+      return Err(process::ExitCode::FAILURE)
     }
   };
+  if let Some(path) = config_path.clone() {
+    client.set_config_path(path);
+  }
   // start reticulum
-  log::info!("starting reticulum");
-  let id = if let Some(name) = cmd.id_string {
-    log::info!("using identity string to create reticulum private identity: {name:?}");
-    PrivateIdentity::new_from_name(&name)
-  } else {
-    log::info!("loading reticulum private identity parameters");
-    let private_key = {
-      let path = std::env::var("RNS_VPN_PRIVKEY_PATH").map_err(|err|{
-        log::error!("env variable RNS_VPN_PRIVKEY_PATH not found: {err:?}");
+  tracing::info!("starting reticulum");
+  let id = client_identity(cmd.id_string)?;
+  // the tun device is set up and the identity loaded: only CAP_NET_ADMIN is still
+  // needed
+  if user.is_some() || group.is_some() {
+    drop_privileges(user.as_deref(), group.as_deref())?;
+  }
+  let transport = Transport::new(TransportConfig::new("server", &id, true));
+  let mut spawned = Vec::new();
+  for (name, interface) in interfaces.iter() {
+    if let Some(iface) = interface.spawn(&transport, name).await {
+      spawned.push((name.clone(), iface));
+    }
+  }
+  client.set_interfaces(spawned);
+  // reload peers on SIGHUP
+  #[cfg(not(unix))]
+  let reload_loop = async || std::future::pending::<()>().await;
+  #[cfg(unix)]
+  let reload_loop = async || {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = match signal(SignalKind::hangup()) {
+      Ok(sighup) => sighup,
+      Err(err) => {
+        tracing::error!("failed to install SIGHUP handler: {err:?}");
+        return std::future::pending::<()>().await
+      }
+    };
+    while sighup.recv().await.is_some() {
+      tracing::info!("got SIGHUP: reloading peers");
+      let Ok((_, config)) = load_config(config_path.clone(), config_format) else {
+        continue
+      };
+      if let Err(err) = client.reload_peers(config.peers) {
+        tracing::error!("failed to reload peers: {err}");
+      }
+    }
+  };
+  // cancel the client on ctrl-c or SIGTERM, letting it tear down
+  let shutdown = CancellationToken::new();
+  let signal_loop = async || {
+    let ctrl_c = async { let _ = tokio::signal::ctrl_c().await; };
+    cancel_on_stop(&shutdown, ctrl_c, sigterm()).await;
+    std::future::pending::<()>().await
+  };
+  // setup succeeded: let the foreground process exit
+  if let Some(daemon) = daemon {
+    daemon.ready();
+  }
+  // run
+  let result = tokio::select!{
+    result = client.run(transport, id, shutdown.clone()) => result,
+    _ = reload_loop() => Ok(()),
+    _ = signal_loop() => Ok(())
+  };
+  tracing::info!("server exit");
+  result.map_err(|_| process::ExitCode::FAILURE)
+}
+
+/// Cancel the client once ctrl-c or SIGTERM arrives, so that both take the same
+/// graceful teardown
+async fn cancel_on_stop(shutdown: &CancellationToken, ctrl_c: impl Future<Output = ()>,
+  sigterm: impl Future<Output = ()>)
+{
+  tokio::select!{
+    _ = ctrl_c => tracing::info!("got ctrl-c: shutting down"),
+    _ = sigterm => tracing::info!("got SIGTERM: shutting down")
+  }
+  shutdown.cancel();
+}
+
+/// Wait for SIGTERM, the way systemd and container runtimes stop the client
+#[cfg(not(unix))]
+async fn sigterm() {
+  std::future::pending::<()>().await
+}
+
+#[cfg(unix)]
+async fn sigterm() {
+  use tokio::signal::unix::{signal, SignalKind};
+  match signal(SignalKind::terminate()) {
+    Ok(mut sigterm) => { sigterm.recv().await; }
+    Err(err) => {
+      tracing::error!("failed to install SIGTERM handler: {err:?}");
+      std::future::pending::<()>().await
+    }
+  }
+}
+
+/// Candidate config paths in order of precedence: the given path or `RNS_VPN_CONFIG`
+/// if set, otherwise the working directory followed by the XDG config dirs
+fn config_paths(path: Option<PathBuf>) -> Vec<PathBuf> {
+  if let Some(path) = path.or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from)) {
+    return vec![path]
+  }
+  let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+  let config_home = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+  if let Some(config_home) = config_home {
+    paths.push(config_home.join(CONFIG_DIR).join(CONFIG_FILE));
+  }
+  let config_dirs = std::env::var("XDG_CONFIG_DIRS").ok().filter(|dirs| !dirs.is_empty())
+    .unwrap_or_else(|| "/etc/xdg".to_owned());
+  for dir in config_dirs.split(':').filter(|dir| !dir.is_empty()) {
+    paths.push(PathBuf::from(dir).join(CONFIG_DIR).join(CONFIG_FILE));
+  }
+  paths
+}
+
+/// Load the first config file found with the environment overrides applied, returning
+/// its path; the format is taken from the file extension unless given. Without a config
+/// file the overrides alone make up the config.
+fn load_config(path: Option<PathBuf>, format: Option<rns_vpn::ConfigFormat>)
+  -> Result<(Option<PathBuf>, rns_vpn::Config), process::ExitCode>
+{
+  let overrides = std::env::vars_os()
+    .filter_map(|(name, value)| {
+      Some((name.into_string().ok()?, value.into_string().ok()?))
+    })
+    .filter(|(name, _)| name.starts_with(rns_vpn::CONFIG_ENV_PREFIX))
+    .collect::<Vec<_>>();
+  let given = path.is_some() || std::env::var_os(CONFIG_ENV).is_some();
+  let paths = config_paths(path);
+  for path in paths.iter() {
+    let s = match fs::read_to_string(path) {
+      Ok(s) => s,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+      Err(err) => {
+        tracing::error!("failed to read config {}: {err:?}", path.display());
+        return Err(process::ExitCode::FAILURE)
+      }
+    };
+    tracing::info!("loading config: {}", path.display());
+    let format = format.unwrap_or_else(|| rns_vpn::ConfigFormat::from_path(path));
+    return rns_vpn::Config::parse_with_overrides(Some(&s), format, overrides)
+      .map(|config| (Some(path.clone()), config))
+      .map_err(|err| {
+        tracing::error!("failed to load config {}: {err}", path.display());
         process::ExitCode::FAILURE
-      })?;
-      log::info!("loading privkey: {path}");
-      let pem_data = fs::read(&path).map_err(|err|{
-        log::error!("failed to read privkey {path}: {err:?}");
+      })
+  }
+  if !given && !overrides.is_empty() {
+    tracing::info!("no config file found: loading config from {}* environment variables",
+      rns_vpn::CONFIG_ENV_PREFIX);
+    let format = format.unwrap_or(rns_vpn::ConfigFormat::Toml);
+    return rns_vpn::Config::parse_with_overrides(None, format, overrides)
+      .map(|config| (None, config))
+      .map_err(|err| {
+        tracing::error!("failed to load config from environment: {err}");
         process::ExitCode::FAILURE
-      })?;
-      let pem = pem::parse(pem_data).map_err(|err|{
-        log::error!("failed to parse privkey {path}: {err:?}");
+      })
+  }
+  tracing::error!("config file not found; tried: {}",
+    paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "));
+  Err(process::ExitCode::FAILURE)
+}
+
+async fn selftest() -> Result<(), process::ExitCode> {
+  tracing::info!("running selftest");
+  match rns_vpn::selftest::run().await {
+    Ok(()) => {
+      tracing::info!("selftest passed");
+      Ok(())
+    }
+    Err(err) => {
+      tracing::error!("selftest failed: {err}");
+      Err(process::ExitCode::FAILURE)
+    }
+  }
+}
+
+/// Check the config and the key files that would be loaded, printing each problem
+fn check_config(path: Option<PathBuf>, format: Option<rns_vpn::ConfigFormat>)
+  -> Result<(), process::ExitCode>
+{
+  let (path, mut config) = load_config(path, format)?;
+  let mut report = config.check();
+  if let Some(rns_path) = config.reticulum_config.as_deref() {
+    let result = rns_vpn::rns_config::add_interfaces(&mut config.interfaces, rns_path);
+    if let Err(err) = result {
+      report.errors.push(format!("Reticulum interfaces: {err}"));
+    }
+  }
+  if config.interfaces.is_empty() {
+    report.warnings.push("no Reticulum interfaces: they must be given on the command \
+      line".to_owned());
+  }
+  report.errors.extend(key_problems());
+  for warning in report.warnings.iter() {
+    println!("warning: {warning}");
+  }
+  for error in report.errors.iter() {
+    println!("error: {error}");
+  }
+  let source = path.map_or_else(|| "environment".to_owned(),
+    |path| path.display().to_string());
+  if report.errors.is_empty() {
+    println!("{source}: ok");
+    Ok(())
+  } else {
+    println!("{source}: {} errors", report.errors.len());
+    Err(process::ExitCode::FAILURE)
+  }
+}
+
+/// Key files that are given but missing; keys in the state dir are generated on first
+/// run if neither exists
+fn key_problems() -> Vec<String> {
+  let privkey_path = std::env::var_os(PRIVKEY_ENV).map(PathBuf::from);
+  let signkey_path = std::env::var_os(SIGNKEY_ENV).map(PathBuf::from);
+  let paths = match (privkey_path, signkey_path) {
+    (Some(privkey_path), Some(signkey_path)) => vec![privkey_path, signkey_path],
+    (None, None) => {
+      let Some(dir) = state_dir() else {
+        return vec![format!("no state dir to keep an identity in: set {PRIVKEY_ENV}/\
+          {SIGNKEY_ENV} or use -i")]
+      };
+      let paths = vec![dir.join(PRIVKEY_FILE), dir.join(SIGNKEY_FILE)];
+      if paths.iter().all(|path| !path.exists()) {
+        return Vec::new()
+      }
+      paths
+    }
+    _ => return vec![format!("{PRIVKEY_ENV} and {SIGNKEY_ENV} must be set together")]
+  };
+  paths.iter().filter(|path| !path.exists())
+    .map(|path| format!("key file {} not found", path.display()))
+    .collect()
+}
+
+/// Write a config for a new node, generating its identity if needed, and print the
+/// destination hash and peer entry for other nodes
+fn init(config_path: Option<PathBuf>, vpn_ip: Option<ipnet::IpNet>, tcp: Option<String>)
+  -> Result<(), process::ExitCode>
+{
+  use rand_core::RngCore;
+  let config_path = config_path.unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+  if config_path.exists() {
+    tracing::error!("config {} already exists", config_path.display());
+    return Err(process::ExitCode::FAILURE)
+  }
+  let vpn_ip = match vpn_ip {
+    Some(vpn_ip) => vpn_ip,
+    None => {
+      let host = 2 + rand_core::OsRng.next_u32() % 253;
+      let default: ipnet::IpNet = format!("10.0.0.{host}/24").parse().unwrap();
+      loop {
+        let answer = prompt(&format!("VPN IP of this node [{default}]: "))?;
+        if answer.is_empty() {
+          break default
+        }
+        match answer.parse() {
+          Ok(vpn_ip) => break vpn_ip,
+          Err(_) => eprintln!("not an IP in CIDR format, e.g. 10.0.0.1/24")
+        }
+      }
+    }
+  };
+  let tcp = match tcp {
+    Some(tcp) => Some(tcp),
+    None => Some(prompt("Reticulum TCP interface to connect to (<host>:<port>, empty for \
+      none): ")?).filter(|tcp| !tcp.is_empty())
+  };
+  // the identity is kept where the client looks for it
+  let (privkey_path, signkey_path) = match (std::env::var_os(PRIVKEY_ENV),
+    std::env::var_os(SIGNKEY_ENV))
+  {
+    (Some(privkey_path), Some(signkey_path)) => {
+      let (privkey_path, signkey_path) = (PathBuf::from(privkey_path),
+        PathBuf::from(signkey_path));
+      if !privkey_path.exists() && !signkey_path.exists() {
+        generate_keys(&privkey_path, &signkey_path)?;
+      }
+      (privkey_path, signkey_path)
+    }
+    (None, None) => persistent_key_paths()?,
+    _ => {
+      tracing::error!("{PRIVKEY_ENV} and {SIGNKEY_ENV} must be set together");
+      return Err(process::ExitCode::FAILURE)
+    }
+  };
+  let id = load_identity(&privkey_path, &signkey_path)?;
+  let public_identity = rns_vpn::PeerIdentity::new(id.as_identity());
+  let dest = format!("{}", rns_vpn::destination_hash(id)).trim_matches('/').to_owned();
+  let mut config = format!("vpn_ip = \"{vpn_ip}\"\n");
+  if let Some(tcp) = tcp.as_ref() {
+    config.push_str(&format!("\n[interfaces.hub]\ntype = \"tcp_client\"\nconnect = {}\n",
+      toml::Value::String(tcp.clone())));
+  }
+  config.push_str("\n[peers]\n# \"<peer VPN IP>\" = \"<peer destination hash>\"\n");
+  if let Err(err) = rns_vpn::Config::from_toml(&config) {
+    tracing::error!("invalid settings: {err}");
+    return Err(process::ExitCode::FAILURE)
+  }
+  fs::OpenOptions::new().write(true).create_new(true).open(&config_path)
+    .and_then(|mut file| std::io::Write::write_all(&mut file, config.as_bytes()))
+    .map_err(|err| {
+      tracing::error!("failed to write config {}: {err:?}", config_path.display());
+      process::ExitCode::FAILURE
+    })?;
+  println!("wrote config {}", config_path.display());
+  println!("identity: {} {}", privkey_path.display(), signkey_path.display());
+  println!("destination hash: {dest}");
+  if tcp.is_none() {
+    println!("no Reticulum interface configured: add one to the config or use -p/-f, \
+      --tcp or --tcp-listen when starting");
+  }
+  println!("\nadd this node to the config of its peers:\n\n[peers.\"{}\"]\n\
+    dest = \"{dest}\"\nidentity = \"{public_identity}\"", vpn_ip.addr());
+  Ok(())
+}
+
+/// Ask a question on the terminal; the answer is empty when not run in one
+fn prompt(question: &str) -> Result<String, process::ExitCode> {
+  use std::io::{BufRead, IsTerminal, Write};
+  if !std::io::stdin().is_terminal() {
+    return Ok(String::new())
+  }
+  eprint!("{question}");
+  let _ = std::io::stderr().flush();
+  let mut answer = String::new();
+  std::io::stdin().lock().read_line(&mut answer).map_err(|err| {
+    tracing::error!("failed to read answer: {err:?}");
+    process::ExitCode::FAILURE
+  })?;
+  Ok(answer.trim().to_owned())
+}
+
+/// Print the peer bundle of this node
+fn export_peer(config_path: Option<PathBuf>, format: Option<rns_vpn::ConfigFormat>,
+  id_string: Option<String>, allowed_ips: &[ipnet::IpNet]) -> Result<(), process::ExitCode>
+{
+  let (_, config) = load_config(config_path, format)?;
+  let Some(vpn_ip) = config.vpn_ip else {
+    tracing::error!("vpn_ip = \"auto\": the leased address can't be exported");
+    return Err(process::ExitCode::FAILURE)
+  };
+  let id = client_identity(id_string)?;
+  let bundle = rns_vpn::bundle::export(&id, vpn_ip.addr(), allowed_ips).map_err(|err| {
+    tracing::error!("{err}");
+    process::ExitCode::FAILURE
+  })?;
+  println!("{bundle}");
+  Ok(())
+}
+
+/// Add the peer in a bundle to the config file, or to a running client
+async fn add_peer(config_path: Option<PathBuf>, format: Option<rns_vpn::ConfigFormat>,
+  bundle: &str, name: Option<String>, socket: Option<PathBuf>)
+  -> Result<(), process::ExitCode>
+{
+  let bundle = rns_vpn::bundle::import(bundle).map_err(|err| {
+    tracing::error!("{err}");
+    process::ExitCode::FAILURE
+  })?;
+  let dest = format!("{}", bundle.dest).trim_matches('/').to_owned();
+  if let Some(socket) = socket {
+    control_request(&socket, "add_peer", serde_json::json!({
+      "ip": bundle.ip,
+      "peer": {
+        "dest": dest,
+        "name": name,
+        "allowed_ips": bundle.allowed_ips,
+        "identity": bundle.identity.to_string()
+      }
+    })).await?;
+    println!("added peer {} ({dest}) to the running client", bundle.ip);
+    return Ok(())
+  }
+  // append a table for the peer so that the rest of the file is left as it is
+  let Some(path) = config_paths(config_path).into_iter().find(|path| path.exists()) else {
+    tracing::error!("config file not found");
+    return Err(process::ExitCode::FAILURE)
+  };
+  if format.unwrap_or_else(|| rns_vpn::ConfigFormat::from_path(&path))
+    != rns_vpn::ConfigFormat::Toml
+  {
+    tracing::error!("peers can only be added to TOML configs");
+    return Err(process::ExitCode::FAILURE)
+  }
+  let mut s = fs::read_to_string(&path).map_err(|err| {
+    tracing::error!("failed to read config {}: {err:?}", path.display());
+    process::ExitCode::FAILURE
+  })?;
+  let ip = toml::Value::String(bundle.ip.to_string());
+  let mut table = match name.as_ref() {
+    Some(name) => format!("\n[peers.{}]\nip = {ip}\n", toml::Value::String(name.clone())),
+    None => format!("\n[peers.{ip}]\n")
+  };
+  table.push_str(&format!("dest = \"{dest}\"\nidentity = \"{}\"\n", bundle.identity));
+  if !bundle.allowed_ips.is_empty() {
+    let allowed_ips = bundle.allowed_ips.iter()
+      .map(|net| toml::Value::String(net.to_string()))
+      .collect();
+    table.push_str(&format!("allowed_ips = {}\n", toml::Value::Array(allowed_ips)));
+  }
+  if !s.ends_with('\n') {
+    s.push('\n');
+  }
+  s.push_str(&table);
+  // rejects a peer address or name that is already in use
+  if let Err(err) = rns_vpn::Config::from_toml(&s) {
+    tracing::error!("failed to add peer: {err}");
+    return Err(process::ExitCode::FAILURE)
+  }
+  fs::write(&path, s).map_err(|err| {
+    tracing::error!("failed to write config {}: {err:?}", path.display());
+    process::ExitCode::FAILURE
+  })?;
+  println!("added peer {} ({dest}) to {}; send SIGHUP to a running client to load it",
+    bundle.ip, path.display());
+  Ok(())
+}
+
+fn keygen(privkey_path: &Path, signkey_path: &Path) -> Result<(), process::ExitCode> {
+  let id = generate_keys(privkey_path, signkey_path)?;
+  println!("{}", format!("{}", rns_vpn::destination_hash(id)).trim_matches('/'));
+  Ok(())
+}
+
+/// Generate and write a new private key and signing key, returning the identity
+fn generate_keys(privkey_path: &Path, signkey_path: &Path)
+  -> Result<PrivateIdentity, process::ExitCode>
+{
+  use ed25519_dalek::pkcs8::{EncodePrivateKey, LineEnding};
+  use rand_core::RngCore;
+  let mut private_bytes = [0u8; 32];
+  rand_core::OsRng.fill_bytes(&mut private_bytes);
+  let mut sign_bytes = [0u8; 32];
+  rand_core::OsRng.fill_bytes(&mut sign_bytes);
+  let sign_key = ed25519_dalek::SigningKey::from_bytes(&sign_bytes);
+  let privkey_pem = pem::encode(&pem::Pem::new("PRIVATE KEY",
+    [X25519_PKCS8_PREFIX.as_slice(), private_bytes.as_slice()].concat()));
+  let signkey_pem = sign_key.to_pkcs8_pem(LineEnding::LF).map_err(|err| {
+    tracing::error!("failed to encode signkey: {err:?}");
+    process::ExitCode::FAILURE
+  })?;
+  write_key(privkey_path, privkey_pem.as_bytes())?;
+  write_key(signkey_path, signkey_pem.as_bytes())?;
+  tracing::info!("wrote privkey {} and signkey {}", privkey_path.display(),
+    signkey_path.display());
+  Ok(PrivateIdentity::new(x25519_dalek::StaticSecret::from(private_bytes), sign_key))
+}
+
+/// Load the identity from an X25519 private key and ed25519 signing key in PEM format
+fn load_identity(privkey_path: &Path, signkey_path: &Path)
+  -> Result<PrivateIdentity, process::ExitCode>
+{
+  let private_key = {
+    tracing::info!("loading privkey: {}", privkey_path.display());
+    let pem_data = fs::read(privkey_path).map_err(|err|{
+      tracing::error!("failed to read privkey {}: {err:?}", privkey_path.display());
+      process::ExitCode::FAILURE
+    })?;
+    let pem = pem::parse(pem_data).map_err(|err|{
+      tracing::error!("failed to parse privkey {}: {err:?}", privkey_path.display());
+      process::ExitCode::FAILURE
+    })?;
+    let pem_bytes: [u8; 32] = pem.contents()[pem.contents().len()-32..].try_into()
+      .map_err(|err|{
+        tracing::error!("invalid privkey bytes: {err:?}");
         process::ExitCode::FAILURE
       })?;
-      let pem_bytes: [u8; 32] = pem.contents()[pem.contents().len()-32..].try_into()
-        .map_err(|err|{
-          log::error!("invalid privkey bytes: {err:?}");
-          process::ExitCode::FAILURE
-        })?;
-      x25519_dalek::StaticSecret::from(pem_bytes)
-    };
-    let sign_key = {
-      use ed25519_dalek::pkcs8::DecodePrivateKey;
-      let path = std::env::var("RNS_VPN_SIGNKEY_PATH").map_err(|err|{
-        log::error!("env variable RNS_VPN_SIGNKEY_PATH not found: {err:?}");
+    x25519_dalek::StaticSecret::from(pem_bytes)
+  };
+  let sign_key = {
+    use ed25519_dalek::pkcs8::DecodePrivateKey;
+    tracing::info!("loading signkey: {}", signkey_path.display());
+    ed25519_dalek::SigningKey::read_pkcs8_pem_file(signkey_path).map_err(|err|{
+      tracing::error!("failed to parse signkey {}: {err:?}", signkey_path.display());
+      process::ExitCode::FAILURE
+    })?
+  };
+  Ok(PrivateIdentity::new(private_key, sign_key))
+}
+
+/// Identity of the client: from the identity string, the key files given in the
+/// environment or the state dir
+fn client_identity(id_string: Option<String>) -> Result<PrivateIdentity, process::ExitCode> {
+  if let Some(name) = id_string {
+    tracing::info!("using identity string to create reticulum private identity: {name:?}");
+    return Ok(PrivateIdentity::new_from_name(&name))
+  }
+  tracing::info!("loading reticulum private identity parameters");
+  let privkey_path = std::env::var_os(PRIVKEY_ENV).map(PathBuf::from);
+  let signkey_path = std::env::var_os(SIGNKEY_ENV).map(PathBuf::from);
+  let (privkey_path, signkey_path) = match (privkey_path, signkey_path) {
+    (Some(privkey_path), Some(signkey_path)) => (privkey_path, signkey_path),
+    (None, None) => persistent_key_paths()?,
+    _ => {
+      tracing::error!("{PRIVKEY_ENV} and {SIGNKEY_ENV} must be set together");
+      return Err(process::ExitCode::FAILURE)
+    }
+  };
+  load_identity(&privkey_path, &signkey_path)
+}
+
+/// Key paths in the state dir, generating a new identity there on first run
+fn persistent_key_paths() -> Result<(PathBuf, PathBuf), process::ExitCode> {
+  let dir = state_dir().ok_or_else(|| {
+    tracing::error!("no key paths given and no state dir to keep an identity in: set \
+      RNS_VPN_PRIVKEY_PATH/RNS_VPN_SIGNKEY_PATH or use -i");
+    process::ExitCode::FAILURE
+  })?;
+  let privkey_path = dir.join(PRIVKEY_FILE);
+  let signkey_path = dir.join(SIGNKEY_FILE);
+  if !privkey_path.exists() && !signkey_path.exists() {
+    tracing::info!("generating new identity in {}", dir.display());
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&dir).map_err(|err| {
+      tracing::error!("failed to create state dir {}: {err:?}", dir.display());
+      process::ExitCode::FAILURE
+    })?;
+    generate_keys(&privkey_path, &signkey_path)?;
+  }
+  Ok((privkey_path, signkey_path))
+}
+
+/// Directory for the persistent identity: `/var/lib/rns-vpn` when running as root,
+/// otherwise the XDG state dir
+fn state_dir() -> Option<PathBuf> {
+  if rns_vpn::is_privileged() && cfg!(unix) {
+    return Some(PathBuf::from(SYSTEM_STATE_DIR))
+  }
+  std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+    .or_else(|| std::env::var_os("HOME")
+      .map(|home| PathBuf::from(home).join(".local").join("state")))
+    .map(|dir| dir.join(CONFIG_DIR))
+}
+
+/// Write a new key file readable only by the owner; existing files are not overwritten
+fn write_key(path: &Path, contents: &[u8]) -> Result<(), process::ExitCode> {
+  use std::io::Write;
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options.open(path).and_then(|mut file| file.write_all(contents)).map_err(|err| {
+    tracing::error!("failed to write key {}: {err:?}", path.display());
+    process::ExitCode::FAILURE
+  })
+}
+
+type LogBackendHandle = tracing_subscriber::reload::Handle<
+  Option<Box<dyn tracing_subscriber::Layer<tracing_subscriber::Registry> + Send + Sync>>,
+  tracing_subscriber::Registry>;
+
+/// Cleared when logging to a backend other than the console
+static CONSOLE_LOG: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
+
+/// Level filter from RUST_LOG, INFO by default
+fn log_filter() -> tracing_subscriber::EnvFilter {
+  tracing_subscriber::EnvFilter::builder()
+    .with_default_directive(tracing::level_filters::LevelFilter::INFO.into())
+    .from_env_lossy()
+}
+
+/// Log to the journal or syslog instead of the console
+fn set_log_backend(handle: &LogBackendHandle, backend: rns_vpn::LogBackend)
+  -> Result<(), process::ExitCode>
+{
+  let layer: Box<dyn tracing_subscriber::Layer<_> + Send + Sync> = match backend {
+    rns_vpn::LogBackend::Console => return Ok(()),
+    #[cfg(target_os = "linux")]
+    rns_vpn::LogBackend::Journald => match tracing_journald::layer() {
+      Ok(layer) => Box::new(layer.with_field_prefix(None)),
+      Err(err) => {
+        tracing::error!("failed to connect to journald: {err:?}");
+        return Err(process::ExitCode::FAILURE)
+      }
+    },
+    #[cfg(unix)]
+    rns_vpn::LogBackend::Syslog => match rns_vpn::syslog::Syslog::connect() {
+      Ok(syslog) => Box::new(tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .without_time()
+        .with_writer(syslog)),
+      Err(err) => {
+        tracing::error!("failed to connect to syslog: {err:?}");
+        return Err(process::ExitCode::FAILURE)
+      }
+    },
+    #[allow(unreachable_patterns)]
+    backend => {
+      tracing::error!("log backend {backend:?} is not supported on this platform");
+      return Err(process::ExitCode::FAILURE)
+    }
+  };
+  if let Err(err) = handle.reload(Some(layer)) {
+    tracing::error!("failed to set log backend: {err:?}");
+    return Err(process::ExitCode::FAILURE)
+  }
+  tracing::info!("logging to {backend:?}");
+  CONSOLE_LOG.store(false, std::sync::atomic::Ordering::Relaxed);
+  Ok(())
+}
+
+/// Removes the PID file when dropped
+struct PidFile(PathBuf);
+
+impl PidFile {
+  fn create(path: &Path) -> Result<Self, process::ExitCode> {
+    fs::write(path, format!("{}\n", process::id())).map_err(|err| {
+      tracing::error!("failed to write PID file {}: {err:?}", path.display());
+      process::ExitCode::FAILURE
+    })?;
+    Ok(PidFile(path.to_owned()))
+  }
+}
+
+impl Drop for PidFile {
+  fn drop(&mut self) {
+    if let Err(err) = fs::remove_file(&self.0) {
+      tracing::warn!("failed to remove PID file {}: {err:?}", self.0.display());
+    }
+  }
+}
+
+/// Daemon process's end of the channel to the foreground process waiting for it
+#[cfg(unix)]
+struct Daemon(std::os::unix::net::UnixStream);
+
+#[cfg(not(unix))]
+struct Daemon;
+
+#[cfg(not(unix))]
+fn daemonize() -> Result<Daemon, process::ExitCode> {
+  tracing::error!("--daemon is only supported on unix");
+  Err(process::ExitCode::FAILURE)
+}
+
+/// Fork into a new session; the foreground process exits once the daemon reports that
+/// setup succeeded, or with an error if the daemon exits first
+#[cfg(unix)]
+fn daemonize() -> Result<Daemon, process::ExitCode> {
+  use std::io::Read;
+  use nix::unistd::ForkResult;
+  let (mut parent, child) = std::os::unix::net::UnixStream::pair().map_err(|err| {
+    tracing::error!("failed to create daemon socket: {err:?}");
+    process::ExitCode::FAILURE
+  })?;
+  // safe as long as there is only this thread: the runtime is started after forking
+  match unsafe { nix::unistd::fork() } {
+    Err(err) => {
+      tracing::error!("failed to fork: {err:?}");
+      Err(process::ExitCode::FAILURE)
+    }
+    Ok(ForkResult::Parent { child: pid }) => {
+      drop(child);
+      // the daemon logs its own errors before exiting, closing the socket
+      let mut ready = [0u8; 1];
+      match parent.read(&mut ready) {
+        Ok(1) => {
+          tracing::info!("running in the background as process {pid}");
+          process::exit(0)
+        }
+        _ => process::exit(1)
+      }
+    }
+    Ok(ForkResult::Child) => {
+      drop(parent);
+      nix::unistd::setsid().map_err(|err| {
+        tracing::error!("failed to create session: {err:?}");
         process::ExitCode::FAILURE
       })?;
-      log::info!("loading signkey: {path}");
-      ed25519_dalek::SigningKey::read_pkcs8_pem_file(&path).map_err(|err|{
-        log::error!("failed to parse signkey {path}: {err:?}");
-        process::ExitCode::FAILURE
-      })?
-    };
-    PrivateIdentity::new(private_key, sign_key)
+      Ok(Daemon(child))
+    }
+  }
+}
+
+impl Daemon {
+  /// Detach from the terminal and let the foreground process exit
+  #[cfg(unix)]
+  fn ready(mut self) {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    match fs::OpenOptions::new().read(true).write(true).open("/dev/null") {
+      Ok(null) => for fd in 0..=2 {
+        let _ = nix::unistd::dup2(null.as_raw_fd(), fd);
+      },
+      Err(err) => tracing::warn!("failed to open /dev/null: {err:?}")
+    }
+    let _ = self.0.write_all(&[1]);
+  }
+
+  #[cfg(not(unix))]
+  fn ready(self) {}
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_user: Option<&str>, _group: Option<&str>)
+  -> Result<(), process::ExitCode>
+{
+  tracing::error!("user and group are only supported on unix");
+  Err(process::ExitCode::FAILURE)
+}
+
+/// Switch to the given user and group, clearing supplementary groups; the group
+/// defaults to the primary group of the user
+#[cfg(unix)]
+fn drop_privileges(user: Option<&str>, group: Option<&str>)
+  -> Result<(), process::ExitCode>
+{
+  use nix::unistd::{Group, User};
+  let user = user.map(|name| match User::from_name(name) {
+    Ok(Some(user)) => Ok(user),
+    Ok(None) => {
+      tracing::error!("no such user: {name}");
+      Err(process::ExitCode::FAILURE)
+    }
+    Err(err) => {
+      tracing::error!("failed to look up user {name}: {err:?}");
+      Err(process::ExitCode::FAILURE)
+    }
+  }).transpose()?;
+  let gid = match group {
+    Some(name) => match Group::from_name(name) {
+      Ok(Some(group)) => Some(group.gid),
+      Ok(None) => {
+        tracing::error!("no such group: {name}");
+        return Err(process::ExitCode::FAILURE)
+      }
+      Err(err) => {
+        tracing::error!("failed to look up group {name}: {err:?}");
+        return Err(process::ExitCode::FAILURE)
+      }
+    },
+    None => user.as_ref().map(|user| user.gid)
   };
-  let transport = Transport::new(TransportConfig::new("server", &id, true));
-  let _ = transport.iface_manager().lock().await.spawn(
-    UdpInterface::new(format!("0.0.0.0:{}", cmd.port), Some(cmd.forward.to_string())),
-    UdpInterface::spawn);
-  // run
-  client.run(transport, id).await;
-  log::info!("server exit");
+  // keep the permitted capabilities across the switch to the user, to be narrowed down
+  // to CAP_NET_ADMIN after it
+  #[cfg(target_os = "linux")]
+  if user.is_some() {
+    caps::securebits::set_keepcaps(true).map_err(|err| {
+      tracing::error!("failed to keep capabilities: {err}");
+      process::ExitCode::FAILURE
+    })?;
+  }
+  // groups must be changed while still root
+  if let Some(gid) = gid {
+    #[cfg(not(target_os = "macos"))]
+    nix::unistd::setgroups(&[gid]).map_err(|err| {
+      tracing::error!("failed to clear supplementary groups: {err:?}");
+      process::ExitCode::FAILURE
+    })?;
+    nix::unistd::setgid(gid).map_err(|err| {
+      tracing::error!("failed to switch to group {gid}: {err:?}");
+      process::ExitCode::FAILURE
+    })?;
+  }
+  if let Some(user) = user {
+    nix::unistd::setuid(user.uid).map_err(|err| {
+      tracing::error!("failed to switch to user {}: {err:?}", user.name);
+      process::ExitCode::FAILURE
+    })?;
+    #[cfg(target_os = "linux")]
+    keep_net_admin()?;
+    #[cfg(not(target_os = "linux"))]
+    tracing::warn!("running without root: routes can't be updated while running and \
+      addresses and routes won't be removed on shutdown");
+  }
+  tracing::info!("dropped privileges: running as uid {} gid {}", nix::unistd::getuid(),
+    nix::unistd::getgid());
+  Ok(())
+}
+
+/// Keep only CAP_NET_ADMIN after switching user, which updating routes and addresses
+/// while running and removing them on shutdown need; it is made inheritable and ambient
+/// so that the `ip`, `nft` and `iptables` commands the client runs have it too
+#[cfg(target_os = "linux")]
+fn keep_net_admin() -> Result<(), process::ExitCode> {
+  use caps::{CapSet, Capability, CapsHashSet};
+  let net_admin = CapsHashSet::from([Capability::CAP_NET_ADMIN]);
+  [CapSet::Permitted, CapSet::Effective, CapSet::Inheritable].into_iter()
+    .try_for_each(|set| caps::set(None, set, &net_admin))
+    .and_then(|()| caps::raise(None, CapSet::Ambient, Capability::CAP_NET_ADMIN))
+    .map_err(|err| {
+      tracing::error!("failed to keep CAP_NET_ADMIN: {err}");
+      process::ExitCode::FAILURE
+    })
+}
+
+/// Add or remove a peer of a running client
+async fn peer(command: PeerCommand) -> Result<(), process::ExitCode> {
+  match command {
+    PeerCommand::Add { ip, dest, name, allowed_ips, persist, socket } => {
+      control_request(&socket, "add_peer", serde_json::json!({
+        "ip": ip,
+        "peer": { "dest": dest, "name": name, "allowed_ips": allowed_ips },
+        "persist": persist
+      })).await?;
+      println!("added peer {ip} ({dest})");
+    }
+    PeerCommand::Remove { ip, persist, socket } => {
+      control_request(&socket, "remove_peer", serde_json::json!({
+        "ip": ip,
+        "persist": persist
+      })).await?;
+      println!("removed peer {ip}");
+    }
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+async fn control_request(_socket: &Path, method: &str, _params: serde_json::Value)
+  -> Result<serde_json::Value, process::ExitCode>
+{
+  tracing::error!("{method} requires a control socket, which is only available on unix");
+  Err(process::ExitCode::FAILURE)
+}
+
+/// Send a request to the control socket of a running client, returning its result
+#[cfg(unix)]
+async fn control_request(socket: &Path, method: &str, params: serde_json::Value)
+  -> Result<serde_json::Value, process::ExitCode>
+{
+  use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+  let request = serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": method,
+    "params": params});
+  let result = async {
+    let mut stream = tokio::net::UnixStream::connect(socket).await?;
+    stream.write_all(format!("{request}\n").as_bytes()).await?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    Ok::<_, std::io::Error>(line)
+  }.await;
+  let line = result.map_err(|err| {
+    tracing::error!("failed to query control socket {}: {err:?}", socket.display());
+    process::ExitCode::FAILURE
+  })?;
+  let mut response = serde_json::from_str::<serde_json::Value>(&line).map_err(|err| {
+    tracing::error!("invalid response from control socket: {err:?}");
+    process::ExitCode::FAILURE
+  })?;
+  match response.get_mut("result") {
+    Some(result) => Ok(result.take()),
+    None => {
+      tracing::error!("control socket error: {}", response["error"]);
+      Err(process::ExitCode::FAILURE)
+    }
+  }
+}
+
+async fn status(socket: &Path, json: bool) -> Result<(), process::ExitCode> {
+  let result = control_request(socket, "peers", serde_json::Value::Null).await?;
+  let Some(peers) = result.as_array() else {
+    tracing::error!("invalid peers from control socket: {result}");
+    return Err(process::ExitCode::FAILURE)
+  };
+  if json {
+    println!("{}", serde_json::Value::Array(peers.clone()));
+    return Ok(())
+  }
+  println!("{:<40} {:<16} {:<32} {:<8} {:<9} {:>9} {:>6} {:>12} {:>12} {:>12}",
+    "PEER", "NAME", "DESTINATION", "LINK", "QUALITY", "RTT", "LOSS", "TX BYTES",
+    "RX BYTES", "LAST PACKET");
+  for peer in peers {
+    let last_packet = peer["since_last_packet_secs"].as_f64()
+      .map_or("never".to_owned(), |secs| format!("{secs:.0}s ago"));
+    let rtt = peer["rtt_ms"].as_f64().map_or("-".to_owned(), |ms| format!("{ms:.0}ms"));
+    let loss = peer["loss"].as_f64()
+      .map_or("-".to_owned(), |loss| format!("{:.0}%", loss * 100.0));
+    println!("{:<40} {:<16} {:<32} {:<8} {:<9} {:>9} {:>6} {:>12} {:>12} {:>12}",
+      peer["ip"].as_str().unwrap_or_default(),
+      peer["name"].as_str().unwrap_or("-"),
+      peer["dest"].as_str().unwrap_or_default(),
+      if peer["link_active"].as_bool().unwrap_or_default() { "active" } else { "down" },
+      peer["quality"].as_str().unwrap_or("-"), rtt, loss,
+      peer["tx_bytes"].as_u64().unwrap_or_default(),
+      peer["rx_bytes"].as_u64().unwrap_or_default(), last_packet);
+  }
+  Ok(())
+}
+
+/// Ping a peer once a second; fails if no reply arrived
+async fn ping(socket: &Path, peer: std::net::IpAddr, count: u32)
+  -> Result<(), process::ExitCode>
+{
+  let mut replies = 0;
+  for i in 0..count {
+    if i > 0 {
+      tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    let ping = control_request(socket, "ping", serde_json::json!({"ip": peer})).await?;
+    let link_id = ping["link_id"].as_str().unwrap_or_default();
+    match ping["state"].as_str().unwrap_or_default() {
+      "no_link" => println!("{peer}: no link"),
+      "link_inactive" => println!("{peer}: link {link_id} inactive"),
+      "unresponsive" => println!("{peer}: peer unresponsive on link {link_id}"),
+      _ => {
+        replies += 1;
+        println!("{peer}: reply on link {link_id}: time={:.1} ms",
+          ping["rtt_ms"].as_f64().unwrap_or_default());
+      }
+    }
+  }
+  println!("{count} requests, {replies} replies");
+  if replies == 0 {
+    return Err(process::ExitCode::FAILURE)
+  }
+  Ok(())
+}
+
+async fn bench(socket: &Path, peer: std::net::IpAddr, count: u32, size: usize)
+  -> Result<(), process::ExitCode>
+{
+  println!("sending {count} echo requests of {size} bytes to {peer}");
+  let params = serde_json::json!({"ip": peer, "count": count, "size": size});
+  let report = control_request(socket, "bench", params).await?;
+  let ms = |key: &str| report[key].as_f64()
+    .map_or("-".to_owned(), |ms| format!("{ms:.1}"));
+  println!("sent {}, received {} ({:.1}% loss) in {:.2}s",
+    report["sent"].as_u64().unwrap_or_default(),
+    report["received"].as_u64().unwrap_or_default(),
+    report["loss"].as_f64().unwrap_or_default() * 100.0,
+    report["duration_secs"].as_f64().unwrap_or_default());
+  println!("goodput {:.1} kbit/s", report["goodput_kbps"].as_f64().unwrap_or_default());
+  println!("rtt min/avg/max {}/{}/{} ms",
+    ms("rtt_min_ms"), ms("rtt_avg_ms"), ms("rtt_max_ms"));
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn sigterm_cancels_client() {
+    let shutdown = CancellationToken::new();
+    cancel_on_stop(&shutdown, std::future::pending(), async {}).await;
+    assert!(shutdown.is_cancelled());
+  }
+
+  #[tokio::test]
+  async fn ctrl_c_cancels_client() {
+    let shutdown = CancellationToken::new();
+    cancel_on_stop(&shutdown, async {}, std::future::pending()).await;
+    assert!(shutdown.is_cancelled());
+  }
+
+  #[tokio::test]
+  async fn no_signal_leaves_client_running() {
+    let shutdown = CancellationToken::new();
+    let stop = cancel_on_stop(&shutdown, std::future::pending(), std::future::pending());
+    let timeout = std::time::Duration::from_millis(50);
+    assert!(tokio::time::timeout(timeout, stop).await.is_err());
+    assert!(!shutdown.is_cancelled());
+  }
+}
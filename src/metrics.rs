@@ -0,0 +1,184 @@
+//! Prometheus metrics served over HTTP
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::PeerLink;
+
+/// Upper bounds in seconds of the link activation latency histogram buckets
+const LINK_ACTIVATION_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+/// Largest HTTP request head read before responding
+const MAX_REQUEST_LEN: usize = 8192;
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+/// Client-wide counters; per-peer counters are kept with each peer
+#[derive(Default)]
+pub struct Metrics {
+  pub announces_sent: AtomicU64,
+  pub announces_received: AtomicU64,
+  pub tun_read_errors: AtomicU64,
+  pub tun_write_errors: AtomicU64,
+  pub unauthorized_packets: AtomicU64,
+  pub peers_down: AtomicU64,
+  pub rate_limited_packets: AtomicU64,
+  pub filtered_packets: AtomicU64,
+  pub replayed_packets: AtomicU64,
+  pub malformed_packets: AtomicU64,
+  pub link_failovers: AtomicU64,
+  pub queue_dropped_packets: AtomicU64,
+  pub queue_dropped_oldest_packets: AtomicU64,
+  pub queue_dropped_newest_packets: AtomicU64,
+  pub queue_blocked_packets: AtomicU64,
+  pub relayed_packets: AtomicU64,
+  pub rejected_announces: AtomicU64,
+  pub fec_recovered_payloads: AtomicU64,
+  pub unreachable_packets: AtomicU64,
+  pub loop_restarts: AtomicU64,
+  link_activation_buckets: [AtomicU64; LINK_ACTIVATION_BUCKETS.len()],
+  link_activation_count: AtomicU64,
+  link_activation_sum_micros: AtomicU64
+}
+
+impl Metrics {
+  pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Name, description and value of each client-wide counter
+  pub fn counters(&self) -> [(&'static str, &'static str, u64); 20] {
+    [
+      ("announces_sent", "Announces sent", &self.announces_sent),
+      ("announces_received", "Announces received", &self.announces_received),
+      ("tun_read_errors", "Errors reading from the tun", &self.tun_read_errors),
+      ("tun_write_errors", "Errors writing to the tun", &self.tun_write_errors),
+      ("unauthorized_packets", "Packets dropped from unauthorized links or spoofed \
+        peer addresses", &self.unauthorized_packets),
+      ("peers_down", "Peers marked down after not responding", &self.peers_down),
+      ("rate_limited_packets", "Packets dropped over a rate limit",
+        &self.rate_limited_packets),
+      ("filtered_packets", "Packets dropped by filter rules", &self.filtered_packets),
+      ("replayed_packets", "Duplicate, replayed or unsequenced payloads dropped",
+        &self.replayed_packets),
+      ("malformed_packets", "Packets from peers dropped for not being IP packets",
+        &self.malformed_packets),
+      ("link_failovers", "Links replaced after missing keepalives", &self.link_failovers),
+      ("queue_dropped_packets", "Packets dropped from a full tun or peer queue",
+        &self.queue_dropped_packets),
+      ("queue_dropped_oldest_packets", "Queued packets dropped for a new one with the \
+        drop_oldest queue policy", &self.queue_dropped_oldest_packets),
+      ("queue_dropped_newest_packets", "Packets dropped from a full queue with the \
+        drop_newest queue policy", &self.queue_dropped_newest_packets),
+      ("queue_blocked_packets", "Packets that waited for room in a full queue with the \
+        block queue policy", &self.queue_blocked_packets),
+      ("relayed_packets", "Packets relayed from one peer to another",
+        &self.relayed_packets),
+      ("rejected_announces", "Announces for a peer's destination hash from another \
+        identity", &self.rejected_announces),
+      ("fec_recovered_payloads", "Lost payloads rebuilt from FEC parity",
+        &self.fec_recovered_payloads),
+      ("unreachable_packets", "Packets from the tun without a peer link answered with \
+        an ICMP unreachable", &self.unreachable_packets),
+      ("loop_restarts", "Client loops restarted after failing", &self.loop_restarts)
+    ].map(|(name, help, counter)| (name, help, counter.load(Ordering::Relaxed)))
+  }
+
+  /// Record the time from requesting a link to its activation
+  pub fn observe_link_activation(&self, latency: Duration) {
+    let secs = latency.as_secs_f64();
+    for (bucket, bound) in self.link_activation_buckets.iter()
+      .zip(LINK_ACTIVATION_BUCKETS)
+    {
+      if secs <= bound {
+        bucket.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    self.link_activation_count.fetch_add(1, Ordering::Relaxed);
+    self.link_activation_sum_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  /// Render in the Prometheus text exposition format
+  pub fn render(&self, peers: &[PeerLink]) -> String {
+    let mut out = String::new();
+    for (name, help, value) in self.counters() {
+      let _ = writeln!(out, "# HELP rns_vpn_{name}_total {help}");
+      let _ = writeln!(out, "# TYPE rns_vpn_{name}_total counter");
+      let _ = writeln!(out, "rns_vpn_{name}_total {value}");
+    }
+    let _ = writeln!(out, "# HELP rns_vpn_links_active Peers with an active link");
+    let _ = writeln!(out, "# TYPE rns_vpn_links_active gauge");
+    let _ = writeln!(out, "rns_vpn_links_active {}",
+      peers.iter().filter(|peer| peer.link_active).count());
+    let peer_counters: [(&str, &str, fn(&PeerLink) -> u64); 4] = [
+      ("peer_tx_packets_total", "Packets sent to the peer", |peer| peer.tx_packets),
+      ("peer_tx_bytes_total", "Bytes sent to the peer", |peer| peer.tx_bytes),
+      ("peer_rx_packets_total", "Packets received from the peer", |peer| peer.rx_packets),
+      ("peer_rx_bytes_total", "Bytes received from the peer", |peer| peer.rx_bytes)
+    ];
+    for (name, help, value) in peer_counters {
+      let _ = writeln!(out, "# HELP rns_vpn_{name} {help}");
+      let _ = writeln!(out, "# TYPE rns_vpn_{name} counter");
+      for peer in peers {
+        let _ = writeln!(out, "rns_vpn_{name}{{peer=\"{}\",dest=\"{}\"}} {}",
+          peer.ip.addr(), format!("{}", peer.dest).trim_matches('/'), value(peer));
+      }
+    }
+    let name = "rns_vpn_link_activation_seconds";
+    let _ = writeln!(out, "# HELP {name} Time from requesting a link to its activation");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    for (bucket, bound) in self.link_activation_buckets.iter().zip(LINK_ACTIVATION_BUCKETS) {
+      let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {}",
+        bucket.load(Ordering::Relaxed));
+    }
+    let count = self.link_activation_count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+    let _ = writeln!(out, "{name}_sum {}",
+      self.link_activation_sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+    let _ = writeln!(out, "{name}_count {count}");
+    out
+  }
+}
+
+/// Answer one HTTP request on the connection: the rendered metrics for
+/// `GET /metrics`, 404 otherwise
+pub async fn respond(stream: &mut tokio::net::TcpStream, metrics: &str) {
+  serve(stream, "metrics", |request| {
+    if request.starts_with(b"GET /metrics ") {
+      response("200 OK", "text/plain; version=0.0.4", metrics)
+    } else {
+      response("404 Not Found", "text/plain", "")
+    }
+  }).await
+}
+
+/// Read one HTTP request from the connection and write the response `route` gives for
+/// its head
+pub async fn serve(stream: &mut tokio::net::TcpStream, name: &str,
+  route: impl FnOnce(&[u8]) -> String)
+{
+  let result = tokio::time::timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), async {
+    let mut request = Vec::new();
+    let mut buf = [0x0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+      let n = stream.read(&mut buf).await?;
+      if n == 0 {
+        break
+      }
+      request.extend_from_slice(&buf[..n]);
+    }
+    stream.write_all(route(&request).as_bytes()).await
+  }).await;
+  match result {
+    Ok(Ok(())) => {}
+    Ok(Err(err)) => tracing::debug!("{name} request failed: {err:?}"),
+    Err(_) => tracing::debug!("{name} request timed out")
+  }
+}
+
+/// HTTP response closing the connection
+pub fn response(status: &str, content_type: &str, body: &str) -> String {
+  format!("HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\
+    Content-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+}
@@ -0,0 +1,105 @@
+//! TCP MSS clamping: the maximum segment size option of TCP SYN packets is lowered to
+//! fit the MTU of a peer's link, so that TCP flows through narrow links don't depend
+//! on path MTU discovery
+
+/// Bytes of a TCP header without options
+const TCP_HEADER_LEN: usize = 20;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_FLAG_SYN: u8 = 0x02;
+
+/// Copy of a TCP SYN or SYN-ACK packet with its MSS lowered to fit `mtu` and its
+/// checksum updated, or `None` if the packet needs no change
+pub fn clamp(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+  let (headers, payload) = etherparse::IpHeaders::from_slice(packet).ok()?;
+  if payload.ip_number != etherparse::IpNumber::TCP || payload.fragmented {
+    return None
+  }
+  let ip_header_len = if headers.ipv4().is_some() { 20 } else { 40 };
+  let max_mss = mtu.checked_sub(ip_header_len + TCP_HEADER_LEN as u16)?;
+  // from the header lengths, as the packet may have trailing padding after the payload
+  let tcp_offset = headers.header_len();
+  let tcp = payload.payload;
+  if tcp.len() < TCP_HEADER_LEN || tcp[13] & TCP_FLAG_SYN == 0 {
+    return None
+  }
+  let header_len = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+  let mut i = TCP_HEADER_LEN;
+  while i < header_len {
+    match tcp[i] {
+      TCP_OPTION_END => return None,
+      TCP_OPTION_NOP => i += 1,
+      kind => {
+        let len = *tcp.get(i + 1)? as usize;
+        if len < 2 || i + len > header_len {
+          return None
+        }
+        // the checksum is updated by 16-bit words, so only aligned values are changed
+        if kind == TCP_OPTION_MSS && len == 4 && i % 2 == 0 {
+          let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+          if mss <= max_mss {
+            return None
+          }
+          let mut packet = packet.to_vec();
+          let tcp = &mut packet[tcp_offset..];
+          tcp[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+          // incremental update (RFC 1624): HC' = ~(~HC + ~m + m')
+          let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+          let mut sum = (!checksum) as u32 + (!mss) as u32 + max_mss as u32;
+          while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+          }
+          tcp[16..18].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+          return Some(packet)
+        }
+        i += len;
+      }
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use etherparse::{Ipv4Header, PacketBuilder, TcpHeader, TcpOptionElement};
+
+  fn syn(mss: u16) -> Vec<u8> {
+    let mut packet = Vec::new();
+    PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+      .tcp(40000, 80, 1, 65535)
+      .syn()
+      .options(&[TcpOptionElement::MaximumSegmentSize(mss)]).unwrap()
+      .write(&mut packet, &[]).unwrap();
+    packet
+  }
+
+  fn check(packet: &[u8], mss: u16) {
+    let (ip, rest) = Ipv4Header::from_slice(packet).unwrap();
+    let (tcp, _) = TcpHeader::from_slice(rest).unwrap();
+    assert_eq!(tcp.options.elements_iter().next().unwrap().unwrap(),
+      TcpOptionElement::MaximumSegmentSize(mss));
+    assert_eq!(tcp.checksum, tcp.calc_checksum_ipv4(&ip, &[]).unwrap());
+  }
+
+  #[test]
+  fn clamps_mss() {
+    let packet = clamp(&syn(1460), 1280).unwrap();
+    check(&packet, 1240);
+  }
+
+  #[test]
+  fn keeps_smaller_mss() {
+    assert!(clamp(&syn(1200), 1280).is_none());
+  }
+
+  #[test]
+  fn clamps_padded_packet() {
+    let mut packet = syn(1460);
+    packet.extend_from_slice(&[0; 6]);
+    let clamped = clamp(&packet, 1280).unwrap();
+    assert_eq!(clamped[packet.len() - 6..], [0; 6]);
+    check(&clamped, 1240);
+  }
+}
@@ -0,0 +1,125 @@
+//! Path quality to a peer over each underlay interface: the round trip time of links
+//! through an interface and when it last stopped passing keepalives, used to pick the
+//! interface a failing link is moved to
+
+use std::time::{Duration, Instant};
+
+/// An interface that stopped passing keepalives this recently is only failed over to
+/// when every other one did as well
+const FAILURE_HOLD_DOWN_SECS: u64 = 300;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct PathStats {
+  /// Smoothed round trip time of the last link through the interface
+  rtt: Option<Duration>,
+  last_failure: Option<Instant>
+}
+
+/// Paths to a peer, by index of the interface in the client's interfaces
+#[derive(Debug, Default)]
+pub struct Paths {
+  stats: Vec<PathStats>,
+  /// Interface the path of the current link was requested through; none until the
+  /// first failover, leaving the choice to the transport
+  current: Option<usize>
+}
+
+impl Paths {
+  pub fn current(&self) -> Option<usize> {
+    self.current
+  }
+
+  fn stats_mut(&mut self, iface: usize) -> &mut PathStats {
+    if self.stats.len() <= iface {
+      self.stats.resize(iface + 1, PathStats::default());
+    }
+    &mut self.stats[iface]
+  }
+
+  /// Record the round trip time measured on the current link
+  pub fn rtt_sample(&mut self, rtt: Duration) {
+    if let Some(current) = self.current {
+      self.stats_mut(current).rtt = Some(rtt);
+    }
+  }
+
+  /// Round trip time of the last link through an interface
+  pub fn rtt(&self, iface: usize) -> Option<Duration> {
+    self.stats.get(iface).and_then(|stats| stats.rtt)
+  }
+
+  /// Record that the current link stopped passing keepalives and move to the best of
+  /// the other `interfaces`: one that hasn't failed recently, then the one with the
+  /// lowest round trip time, then the first configured; returns the interface to
+  /// request the new path through, or none with a single interface
+  pub fn fail_over(&mut self, interfaces: usize, now: Instant) -> Option<usize> {
+    if interfaces < 2 {
+      return None
+    }
+    if let Some(current) = self.current {
+      self.stats_mut(current).last_failure = Some(now);
+    }
+    let hold_down = Duration::from_secs(FAILURE_HOLD_DOWN_SECS);
+    let next = (0..interfaces)
+      .filter(|iface| Some(*iface) != self.current)
+      .min_by_key(|iface| {
+        let stats = self.stats.get(*iface).copied().unwrap_or_default();
+        let failed = stats.last_failure
+          .is_some_and(|failed| now.saturating_duration_since(failed) < hold_down);
+        (failed, stats.rtt.unwrap_or(Duration::MAX), *iface)
+      })?;
+    self.current = Some(next);
+    Some(next)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn single_interface_is_left_to_the_transport() {
+    let mut paths = Paths::default();
+    assert_eq!(paths.fail_over(1, Instant::now()), None);
+    assert_eq!(paths.current(), None);
+  }
+
+  #[test]
+  fn fails_over_to_the_lowest_rtt() {
+    let now = Instant::now();
+    let mut paths = Paths::default();
+    assert_eq!(paths.fail_over(3, now), Some(0));
+    paths.rtt_sample(Duration::from_millis(200));
+    assert_eq!(paths.fail_over(3, now), Some(1));
+    paths.rtt_sample(Duration::from_millis(50));
+    assert_eq!(paths.fail_over(3, now), Some(2));
+    paths.rtt_sample(Duration::from_millis(100));
+    // every interface failed recently: back to the fastest other one
+    assert_eq!(paths.fail_over(3, now), Some(1));
+    assert_eq!(paths.rtt(1), Some(Duration::from_millis(50)));
+  }
+
+  #[test]
+  fn avoids_recently_failed_interfaces() {
+    let now = Instant::now();
+    let mut paths = Paths::default();
+    assert_eq!(paths.fail_over(3, now), Some(0));
+    paths.rtt_sample(Duration::from_millis(10));
+    assert_eq!(paths.fail_over(3, now), Some(1));
+    paths.rtt_sample(Duration::from_millis(500));
+    // interface 0 is faster but failed a moment ago
+    assert_eq!(paths.fail_over(3, now + Duration::from_secs(1)), Some(2));
+  }
+
+  #[test]
+  fn failures_are_forgotten_after_the_hold_down() {
+    let now = Instant::now();
+    let mut paths = Paths::default();
+    assert_eq!(paths.fail_over(2, now), Some(0));
+    paths.rtt_sample(Duration::from_millis(10));
+    assert_eq!(paths.fail_over(2, now), Some(1));
+    paths.rtt_sample(Duration::from_millis(500));
+    let later = now + Duration::from_secs(FAILURE_HOLD_DOWN_SECS);
+    assert_eq!(paths.fail_over(2, later), Some(0));
+  }
+}
@@ -0,0 +1,50 @@
+//! Config values overridden by environment variables: `RNS_VPN__<KEY>[__<KEY>...]`
+//! sets the value at that path in the config, e.g. `RNS_VPN__ANNOUNCE_FREQ_SECS=30` or
+//! `RNS_VPN__PEERS__<name>__DEST=<hash>`
+
+use serde_json::Value;
+
+use crate::CONFIG_ENV_PREFIX as PREFIX;
+
+/// Set the value of the variable `name`, given without the prefix, in the config;
+/// keys are lowercased except for peer addresses and names
+pub fn apply(config: &mut Value, name: &str, value: &str) -> Result<(), String> {
+  let segments = name.split("__").collect::<Vec<_>>();
+  if segments.iter().any(|segment| segment.is_empty()) {
+    return Err(format!("invalid config override {PREFIX}{name}"))
+  }
+  let (last, parents) = segments.split_last().unwrap();
+  let mut table = config;
+  for (i, segment) in parents.iter().enumerate() {
+    let key = key(&segments, i, segment);
+    let Value::Object(map) = table else {
+      return Err(format!("config override {PREFIX}{name}: {} is not a table",
+        segments[..i].join("__").to_lowercase()))
+    };
+    table = map.entry(key).or_insert_with(|| Value::Object(Default::default()));
+  }
+  let Value::Object(map) = table else {
+    return Err(format!("config override {PREFIX}{name}: {} is not a table",
+      parents.join("__").to_lowercase()))
+  };
+  map.insert(key(&segments, parents.len(), last), parse_value(value));
+  Ok(())
+}
+
+/// The key following `peers` is a peer address or name and keeps its case
+fn key(segments: &[&str], i: usize, segment: &str) -> String {
+  if i == 1 && segments[0].eq_ignore_ascii_case("peers") {
+    segment.to_owned()
+  } else {
+    segment.to_lowercase()
+  }
+}
+
+/// Values are read as TOML (numbers, booleans, arrays, inline tables and quoted
+/// strings), anything else as a string
+fn parse_value(value: &str) -> Value {
+  toml::from_str::<toml::Table>(&format!("value = {value}")).ok()
+    .and_then(|mut table| table.remove("value"))
+    .and_then(|value| serde_json::to_value(value).ok())
+    .unwrap_or_else(|| Value::String(value.to_owned()))
+}
@@ -0,0 +1,115 @@
+//! Peers by tunnel address, with a routing table for finding the peer of a packet
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::Peer;
+use crate::routing::RoutingTable;
+
+/// Peers keyed by their tunnel address; the tunnel addresses, additional addresses,
+/// allowed IPs and advertised routes of each peer are routed to it
+#[derive(Default)]
+pub struct PeerMap {
+  peers: BTreeMap<IpAddr, Peer>,
+  routes: RoutingTable<IpAddr>
+}
+
+impl PeerMap {
+  pub fn new(peers: BTreeMap<IpAddr, Peer>) -> Self {
+    let mut peer_map = PeerMap::default();
+    for (ip, peer) in peers {
+      peer_map.insert(ip, peer);
+    }
+    peer_map
+  }
+
+  pub fn contains_key(&self, ip: &IpAddr) -> bool {
+    self.peers.contains_key(ip)
+  }
+
+  pub fn get(&self, ip: &IpAddr) -> Option<&Peer> {
+    self.peers.get(ip)
+  }
+
+  pub fn get_mut(&mut self, ip: &IpAddr) -> Option<&mut Peer> {
+    self.peers.get_mut(ip)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&IpAddr, &Peer)> {
+    self.peers.iter()
+  }
+
+  pub fn values(&self) -> impl Iterator<Item = &Peer> {
+    self.peers.values()
+  }
+
+  /// Peers for updating link state; addresses must be changed with `update_settings`
+  /// so that routes are kept in sync
+  pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Peer> {
+    self.peers.values_mut()
+  }
+
+  /// Peers with their tunnel address for updating link state, as with `values_mut`
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = (&IpAddr, &mut Peer)> {
+    self.peers.iter_mut()
+  }
+
+  pub fn insert(&mut self, ip: IpAddr, peer: Peer) {
+    self.remove(&ip);
+    for net in peer_nets(ip, &peer) {
+      self.routes.insert(net, ip);
+    }
+    self.peers.insert(ip, peer);
+  }
+
+  pub fn remove(&mut self, ip: &IpAddr) -> Option<Peer> {
+    let peer = self.peers.remove(ip)?;
+    for net in peer_nets(*ip, &peer) {
+      // keep a route taken over by another peer
+      if let Some(route_ip) = self.routes.remove(&net).filter(|route_ip| route_ip != ip) {
+        self.routes.insert(net, route_ip);
+      }
+    }
+    Some(peer)
+  }
+
+  /// Take the settings of a reloaded peer, keeping link state and updating its routes
+  pub fn update_settings(&mut self, ip: &IpAddr, new_peer: Peer) {
+    let Some(mut peer) = self.remove(ip) else { return };
+    peer.update_settings(new_peer);
+    self.insert(*ip, peer);
+  }
+
+  /// Find the peer routed for an address: the peer with the address, or else the peer
+  /// with the longest allowed IPs prefix containing it
+  pub fn find(&mut self, ip: &IpAddr) -> Option<&mut Peer> {
+    let peer_ip = self.find_ip(ip)?;
+    self.peers.get_mut(&peer_ip)
+  }
+
+  /// Tunnel address of the peer found with `find`
+  pub fn find_ip(&self, ip: &IpAddr) -> Option<IpAddr> {
+    self.routes.longest_match(ip).copied()
+  }
+
+  /// Find the peer to send a packet for an address to, with its tunnel address: as with
+  /// `find`, except that traffic for a mesh or shared peer goes through the peer it was
+  /// learned from until there is a direct link
+  pub fn route(&mut self, ip: &IpAddr) -> Option<(IpAddr, &mut Peer)> {
+    let peer_ip = self.find_ip(ip)?;
+    let peer_ip = match self.peers.get(&peer_ip)? {
+      peer if !peer.link_active => peer.mesh_via.unwrap_or(peer_ip),
+      _ => peer_ip
+    };
+    self.peers.get_mut(&peer_ip).map(|peer| (peer_ip, peer))
+  }
+}
+
+/// Networks routed to a peer
+fn peer_nets(ip: IpAddr, peer: &Peer) -> impl Iterator<Item = IpNet> + '_ {
+  std::iter::once(ip).chain(peer.addresses.iter().copied()).map(IpNet::from)
+    .chain(peer.allowed_ips.iter().copied())
+    .chain(peer.advertised_routes.iter().copied())
+}
@@ -0,0 +1,63 @@
+//! Peers added or removed while running written back to the TOML config file,
+//! leaving the rest of the file, comments included, as it is
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use ipnet::IpNet;
+
+use crate::{ConfigFormat, PeerConfig};
+
+/// Add a table for the peer keyed by its address, with the settings not at their
+/// defaults
+pub fn add_peer(path: &Path, ip: IpAddr, peer: &PeerConfig)
+  -> Result<(), std::io::Error>
+{
+  let mut settings = toml::Table::try_from(peer).map_err(std::io::Error::other)?;
+  settings.retain(|_, value| !matches!(value, toml::Value::Boolean(false))
+    && value.as_array().is_none_or(|array| !array.is_empty()));
+  let settings = toml::to_string(&settings).map_err(std::io::Error::other)?
+    .parse::<toml_edit::DocumentMut>().map_err(std::io::Error::other)?;
+  edit(path, |peers| {
+    peers.insert(&ip.to_string(), toml_edit::Item::Table(settings.as_table().clone()));
+  })
+}
+
+/// Remove the peer with this address, whether keyed by it or named; returns false
+/// if the file has no such peer
+pub fn remove_peer(path: &Path, ip: IpAddr) -> Result<bool, std::io::Error> {
+  let mut removed = false;
+  edit(path, |peers| {
+    let keys = peers.iter().filter(|(key, item)| {
+      key.parse::<IpAddr>().is_ok_and(|key| key == ip)
+        || item.get("ip").and_then(|named_ip| named_ip.as_str())
+          .and_then(|named_ip| named_ip.parse::<IpNet>().ok())
+          .is_some_and(|named_ip| named_ip.addr() == ip)
+    }).map(|(key, _)| key.to_owned()).collect::<Vec<_>>();
+    for key in keys {
+      peers.remove(&key);
+      removed = true;
+    }
+  })?;
+  Ok(removed)
+}
+
+/// Apply a change to the `peers` table of the config file
+fn edit(path: &Path, change: impl FnOnce(&mut toml_edit::Table))
+  -> Result<(), std::io::Error>
+{
+  if ConfigFormat::from_path(path) != ConfigFormat::Toml {
+    return Err(std::io::Error::other("peers can only be written to TOML configs"))
+  }
+  let mut document = std::fs::read_to_string(path)?
+    .parse::<toml_edit::DocumentMut>().map_err(std::io::Error::other)?;
+  let peers = document.entry("peers").or_insert_with(toml_edit::table).as_table_mut()
+    .ok_or_else(|| std::io::Error::other("peers is not a table"))?;
+  change(peers);
+  tracing::debug!("writing peers to {}", path.display());
+  // replace the file atomically, keeping its permissions as it may hold psks
+  let tmp_path = path.with_extension("tmp");
+  std::fs::write(&tmp_path, document.to_string())?;
+  std::fs::set_permissions(&tmp_path, std::fs::metadata(path)?.permissions())?;
+  std::fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,188 @@
+//! Policy routing for exit node mode with an fwmark (Linux)
+//!
+//! The default routes through the tun go in a routing table of their own, numbered
+//! after the fwmark, which `ip rule` applies to all packets without the mark; the main
+//! table keeps deciding everything but its default route. The Reticulum interfaces'
+//! sockets belong to the transport, so their packets are marked by nftables as they
+//! are sent instead, and the mark is restored on replies through conntrack: transport
+//! traffic keeps using the main table's default route and never loops into the tun.
+//! Everything is removed again on shutdown.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::{CreateClientError, InterfaceConfig};
+
+const NFT_TABLE: &str = "rns_vpn_fwmark";
+/// Replies to marked packets must pass reverse path filtering by their mark
+const SRC_VALID_MARK: &str = "/proc/sys/net/ipv4/conf/all/src_valid_mark";
+
+/// Routing table, rules and marking installed by `new`, undone by `cleanup`
+pub struct PolicyRouting {
+  fwmark: u32,
+  tun_name: String,
+  /// `ip` address family options with installed rules and routes
+  families: std::sync::Mutex<Vec<&'static str>>,
+  nft_table: std::sync::Mutex<bool>,
+  /// Previous value of `src_valid_mark` if it was changed
+  src_valid_mark: std::sync::Mutex<Option<String>>
+}
+
+impl PolicyRouting {
+  /// Route everything but the Reticulum interfaces' traffic through the tun
+  pub async fn new(fwmark: u32, tun_name: &str, ipv6: bool,
+    interfaces: &BTreeMap<String, InterfaceConfig>) -> Result<Self, CreateClientError>
+  {
+    let policy = PolicyRouting {
+      fwmark,
+      tun_name: tun_name.to_owned(),
+      families: std::sync::Mutex::new(Vec::new()),
+      nft_table: std::sync::Mutex::new(false),
+      src_valid_mark: std::sync::Mutex::new(None)
+    };
+    tracing::info!(fwmark, "routing all traffic through {} except marked traffic",
+      tun_name);
+    let matches = transport_matches(interfaces).await?;
+    let result = policy.mark_transport(&matches)
+      .and_then(|()| policy.enable_src_valid_mark())
+      .and_then(|()| {
+        std::iter::once("-4").chain(ipv6.then_some("-6"))
+          .try_for_each(|family| policy.add_rules(family))
+      });
+    if let Err(err) = result {
+      policy.cleanup();
+      return Err(CreateClientError::PolicyRoutingError(err))
+    }
+    Ok(policy)
+  }
+
+  fn mark_transport(&self, matches: &[String]) -> Result<(), std::io::Error> {
+    let fwmark = self.fwmark;
+    // declaring the table first makes deleting it succeed when it doesn't exist, so a
+    // table left behind by an unclean shutdown is replaced
+    let mut script = format!("table inet {NFT_TABLE}\n\
+      delete table inet {NFT_TABLE}\n\
+      table inet {NFT_TABLE} {{\n\
+        chain output {{ type route hook output priority mangle; }}\n\
+        chain prerouting {{ type filter hook prerouting priority mangle; }}\n\
+      }}\n");
+    for transport_match in matches.iter() {
+      script.push_str(&format!(
+        "add rule inet {NFT_TABLE} output {transport_match} meta mark set {fwmark} \
+          ct mark set meta mark\n"));
+    }
+    script.push_str(&format!(
+      "add rule inet {NFT_TABLE} prerouting ct mark {fwmark} meta mark set ct mark\n"));
+    nft(&script)?;
+    *self.nft_table.lock().unwrap() = true;
+    Ok(())
+  }
+
+  fn enable_src_valid_mark(&self) -> Result<(), std::io::Error> {
+    let previous = std::fs::read_to_string(SRC_VALID_MARK)?;
+    if previous.trim() == "1" {
+      return Ok(())
+    }
+    tracing::debug!("enabling {}", SRC_VALID_MARK);
+    std::fs::write(SRC_VALID_MARK, "1")?;
+    *self.src_valid_mark.lock().unwrap() = Some(previous.trim().to_owned());
+    Ok(())
+  }
+
+  /// Add the default route through the tun in the fwmark table and the rules for it
+  fn add_rules(&self, family: &'static str) -> Result<(), std::io::Error> {
+    let table = self.fwmark.to_string();
+    ip(&[family, "route", "add", "default", "dev", &self.tun_name, "table", &table])?;
+    self.families.lock().unwrap().push(family);
+    // each rule added goes ahead of the previous one: the main table without its
+    // default route is looked up first
+    ip(&[family, "rule", "add", "not", "fwmark", &table, "table", &table])?;
+    ip(&[family, "rule", "add", "table", "main", "suppress_prefixlength", "0"])
+  }
+
+  /// Remove the routing table, rules and marking
+  pub fn cleanup(&self) {
+    let table = self.fwmark.to_string();
+    for family in std::mem::take(&mut *self.families.lock().unwrap()) {
+      tracing::debug!("removing {} policy routing rules", family);
+      let rules = [
+        vec![family, "rule", "del", "table", "main", "suppress_prefixlength", "0"],
+        vec![family, "rule", "del", "not", "fwmark", &table, "table", &table],
+        vec![family, "route", "flush", "table", &table]
+      ];
+      for args in rules {
+        if let Err(err) = ip(&args) {
+          tracing::warn!(error = ?err, "failed to run ip {}", args.join(" "));
+        }
+      }
+    }
+    if std::mem::take(&mut *self.nft_table.lock().unwrap()) {
+      tracing::debug!("removing nftables table {}", NFT_TABLE);
+      if let Err(err) = nft(&format!("delete table inet {NFT_TABLE}\n")) {
+        tracing::warn!("failed to remove nftables table {}: {:?}", NFT_TABLE, err);
+      }
+    }
+    if let Some(previous) = self.src_valid_mark.lock().unwrap().take() {
+      tracing::debug!("restoring {} to {}", SRC_VALID_MARK, previous);
+      if let Err(err) = std::fs::write(SRC_VALID_MARK, previous) {
+        tracing::warn!("failed to restore {}: {:?}", SRC_VALID_MARK, err);
+      }
+    }
+  }
+}
+
+/// nftables matches for the packets the Reticulum interfaces send: from the ports UDP
+/// interfaces and TCP servers listen on, and to the addresses TCP clients connect to
+async fn transport_matches(interfaces: &BTreeMap<String, InterfaceConfig>)
+  -> Result<Vec<String>, CreateClientError>
+{
+  let mut matches = Vec::new();
+  for (name, interface) in interfaces.iter() {
+    match interface {
+      InterfaceConfig::Udp { listen, .. } => matches.push(format!("udp sport {}",
+        listen.port())),
+      InterfaceConfig::TcpServer { listen } => matches.push(format!("tcp sport {}",
+        listen.port())),
+      InterfaceConfig::TcpClient { connect } => {
+        let addrs = tokio::net::lookup_host(connect.as_str()).await.map_err(|err| {
+          CreateClientError::ConfigError(format!("can't resolve interface {name} address \
+            {connect}: {err}"))
+        })?;
+        for addr in addrs {
+          let family = if addr.is_ipv6() { "ip6" } else { "ip" };
+          matches.push(format!("{family} daddr {} tcp dport {}", addr.ip(), addr.port()));
+        }
+      }
+      // rnsd sends the traffic of its shared instance itself
+      InterfaceConfig::SharedInstance { .. } => {}
+    }
+  }
+  matches.sort();
+  matches.dedup();
+  Ok(matches)
+}
+
+fn ip(args: &[&str]) -> Result<(), std::io::Error> {
+  tracing::debug!("ip {}", args.join(" "));
+  let output = Command::new("ip").args(args).output()?;
+  check_output("ip", output)
+}
+
+/// Apply an nftables script atomically
+fn nft(script: &str) -> Result<(), std::io::Error> {
+  tracing::debug!("nft -f -\n{}", script);
+  let mut child = Command::new("nft").args(["-f", "-"]).stdin(Stdio::piped())
+    .stdout(Stdio::null()).stderr(Stdio::piped()).spawn()?;
+  child.stdin.take().unwrap().write_all(script.as_bytes())?;
+  check_output("nft", child.wait_with_output()?)
+}
+
+fn check_output(program: &str, output: std::process::Output) -> Result<(), std::io::Error> {
+  if output.status.success() {
+    Ok(())
+  } else {
+    Err(std::io::Error::other(format!("{program} failed: {}",
+      String::from_utf8_lossy(&output.stderr).trim())))
+  }
+}
@@ -0,0 +1,98 @@
+//! Pool of packet buffers for the tun to link path: packets are read, batched, framed
+//! and compressed into buffers taken from the pool, which are given back once the
+//! packet is sent, so that forwarding doesn't allocate for each packet
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+/// Most idle buffers kept; more are freed when given back
+const MAX_IDLE: usize = 1024;
+
+pub struct BufferPool {
+  idle: Mutex<Vec<BytesMut>>,
+  /// Capacity of new buffers, enough for a packet read from the tun
+  capacity: usize
+}
+
+/// Buffer taken from a pool and given back to it when dropped
+pub struct PooledBuf {
+  buf: BytesMut,
+  pool: Arc<BufferPool>
+}
+
+impl BufferPool {
+  pub fn new(capacity: usize) -> Arc<Self> {
+    Arc::new(BufferPool { idle: Mutex::new(Vec::new()), capacity })
+  }
+
+  /// Empty buffer, reusing an idle one if there is any
+  pub fn take(self: &Arc<Self>) -> PooledBuf {
+    let buf = self.idle.lock().unwrap().pop()
+      .unwrap_or_else(|| BytesMut::with_capacity(self.capacity));
+    PooledBuf { buf, pool: self.clone() }
+  }
+}
+
+impl Deref for PooledBuf {
+  type Target = BytesMut;
+
+  fn deref(&self) -> &BytesMut {
+    &self.buf
+  }
+}
+
+impl DerefMut for PooledBuf {
+  fn deref_mut(&mut self) -> &mut BytesMut {
+    &mut self.buf
+  }
+}
+
+impl Drop for PooledBuf {
+  fn drop(&mut self) {
+    let mut buf = std::mem::take(&mut self.buf);
+    buf.clear();
+    let mut idle = self.pool.idle.lock().unwrap();
+    if idle.len() < MAX_IDLE {
+      idle.push(buf);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reuses_given_back_buffers() {
+    let pool = BufferPool::new(64);
+    let mut buf = pool.take();
+    buf.extend_from_slice(&[1, 2, 3]);
+    let ptr = buf.as_ptr();
+    drop(buf);
+    let buf = pool.take();
+    assert!(buf.is_empty());
+    assert_eq!(buf.as_ptr(), ptr);
+    assert!(buf.capacity() >= 64);
+  }
+
+  #[test]
+  fn allocates_when_none_idle() {
+    let pool = BufferPool::new(64);
+    let first = pool.take();
+    let second = pool.take();
+    assert_ne!(first.as_ptr(), second.as_ptr());
+    drop((first, second));
+    assert_eq!(pool.idle.lock().unwrap().len(), 2);
+  }
+
+  #[test]
+  fn keeps_grown_buffers() {
+    let pool = BufferPool::new(16);
+    let mut buf = pool.take();
+    buf.resize(1000, 0);
+    drop(buf);
+    assert!(pool.take().capacity() >= 1000);
+  }
+}
@@ -0,0 +1,43 @@
+//! Pre-shared key authentication of inbound links: a link identified as a peer's that
+//! has a psk gets a random challenge, and its packets are accepted only once the peer
+//! answers with the HMAC-SHA256 of the challenge keyed by the psk
+
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+
+/// Shortest psk accepted in the config
+pub const MIN_LEN: usize = 16;
+pub const CHALLENGE_LEN: usize = 16;
+pub const RESPONSE_LEN: usize = 32;
+/// Authenticated along with the challenge so that responses are never valid MACs for
+/// anything else keyed by the psk
+const CONTEXT: &[u8] = b"rns-vpn psk";
+
+/// Authentication state of an inbound link
+pub enum LinkAuth {
+  Challenged([u8; CHALLENGE_LEN]),
+  Authenticated
+}
+
+pub fn challenge() -> [u8; CHALLENGE_LEN] {
+  let mut challenge = [0u8; CHALLENGE_LEN];
+  OsRng.fill_bytes(&mut challenge);
+  challenge
+}
+
+pub fn response(psk: &[u8], challenge: &[u8]) -> [u8; RESPONSE_LEN] {
+  mac(psk, challenge).finalize().into_bytes().into()
+}
+
+/// Check a response in constant time
+pub fn verify(psk: &[u8], challenge: &[u8], response: &[u8]) -> bool {
+  mac(psk, challenge).verify_slice(response).is_ok()
+}
+
+fn mac(psk: &[u8], challenge: &[u8]) -> Hmac<Sha256> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(psk).expect("HMAC takes keys of any length");
+  mac.update(CONTEXT);
+  mac.update(challenge);
+  mac
+}
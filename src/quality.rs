@@ -0,0 +1,174 @@
+//! Link quality estimated from echo probes sent over each active link: smoothed round
+//! trip time and loss over recent probes, classified as good, degraded or dead, along
+//! with the throughput to and from the peer
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Probes take sequence numbers from here up, leaving the ones below to `bench`
+pub const PROBE_SEQ_BASE: u64 = 1 << 63;
+/// A probe unanswered for this long is lost
+const PROBE_TIMEOUT_SECS: u64 = 5;
+/// Loss is estimated over this many recent probes
+const LOSS_WINDOW: usize = 20;
+/// A link losing at least this fraction of probes is degraded
+const DEGRADED_LOSS: f64 = 0.1;
+/// A link is dead once this many probes in a row are lost
+const DEAD_PROBES: usize = 3;
+/// Weight of a new sample in the smoothed round trip time and throughput
+const SMOOTHING: f64 = 0.125;
+
+static NEXT_PROBE_SEQ: AtomicU64 = AtomicU64::new(PROBE_SEQ_BASE);
+
+/// Sequence number of the next probe
+pub fn next_probe_seq() -> u64 {
+  NEXT_PROBE_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LinkQuality {
+  /// No probe answered or lost yet
+  #[default]
+  Unknown,
+  Good,
+  Degraded,
+  Dead
+}
+
+impl std::fmt::Display for LinkQuality {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str(match self {
+      LinkQuality::Unknown => "unknown",
+      LinkQuality::Good => "good",
+      LinkQuality::Degraded => "degraded",
+      LinkQuality::Dead => "dead"
+    })
+  }
+}
+
+#[derive(Default)]
+pub struct QualityTracker {
+  /// Probes awaiting a reply, with when they were sent
+  outstanding: Vec<(u64, Instant)>,
+  /// Outcome of recent probes, newest last: true if answered
+  results: VecDeque<bool>,
+  srtt: Option<Duration>,
+  pub last_probe: Option<Instant>,
+  /// Smoothed bytes per second sent and received
+  tx_rate: f64,
+  rx_rate: f64,
+  /// Byte counters at the last throughput sample
+  last_sample: Option<(Instant, u64, u64)>,
+  quality: LinkQuality
+}
+
+impl QualityTracker {
+  pub fn probe_sent(&mut self, seq: u64, sent: Instant) {
+    self.outstanding.push((seq, sent));
+    self.last_probe = Some(sent);
+  }
+
+  /// Record the reply to a probe; returns false if the probe wasn't sent on this link
+  pub fn reply(&mut self, seq: u64, received: Instant) -> bool {
+    let Some(i) = self.outstanding.iter().position(|(probe_seq, _)| *probe_seq == seq)
+    else {
+      return false
+    };
+    let (_, sent) = self.outstanding.swap_remove(i);
+    let rtt = received - sent;
+    self.srtt = Some(match self.srtt {
+      Some(srtt) => srtt.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING),
+      None => rtt
+    });
+    self.push_result(true);
+    true
+  }
+
+  /// Count probes left unanswered for too long as lost
+  pub fn expire(&mut self) {
+    let timeout = Duration::from_secs(PROBE_TIMEOUT_SECS);
+    let before = self.outstanding.len();
+    self.outstanding.retain(|(_, sent)| sent.elapsed() < timeout);
+    for _ in self.outstanding.len()..before {
+      self.push_result(false);
+    }
+  }
+
+  fn push_result(&mut self, answered: bool) {
+    if self.results.len() >= LOSS_WINDOW {
+      self.results.pop_front();
+    }
+    self.results.push_back(answered);
+  }
+
+  /// Update the throughput from the peer's byte counters
+  pub fn sample(&mut self, tx_bytes: u64, rx_bytes: u64) {
+    let now = Instant::now();
+    if let Some((at, last_tx, last_rx)) = self.last_sample {
+      let secs = (now - at).as_secs_f64();
+      if secs > 0.0 {
+        let tx_rate = tx_bytes.saturating_sub(last_tx) as f64 / secs;
+        let rx_rate = rx_bytes.saturating_sub(last_rx) as f64 / secs;
+        self.tx_rate += (tx_rate - self.tx_rate) * SMOOTHING;
+        self.rx_rate += (rx_rate - self.rx_rate) * SMOOTHING;
+      }
+    }
+    self.last_sample = Some((now, tx_bytes, rx_bytes));
+  }
+
+  /// Classify the link from the recent probes, returning the new quality if it changed
+  pub fn update(&mut self) -> Option<LinkQuality> {
+    let quality = if self.results.is_empty() {
+      LinkQuality::Unknown
+    } else if self.results.len() >= DEAD_PROBES
+      && self.results.iter().rev().take(DEAD_PROBES).all(|answered| !answered)
+    {
+      LinkQuality::Dead
+    } else if self.loss().is_some_and(|loss| loss >= DEGRADED_LOSS) {
+      LinkQuality::Degraded
+    } else {
+      LinkQuality::Good
+    };
+    if quality == self.quality {
+      return None
+    }
+    self.quality = quality;
+    Some(quality)
+  }
+
+  /// Forget the probes of a link that went away; throughput is kept
+  pub fn reset(&mut self) {
+    self.outstanding.clear();
+    self.results.clear();
+    self.srtt = None;
+    self.last_probe = None;
+    self.quality = LinkQuality::Unknown;
+  }
+
+  pub fn quality(&self) -> LinkQuality {
+    self.quality
+  }
+
+  /// Smoothed round trip time
+  pub fn rtt(&self) -> Option<Duration> {
+    self.srtt
+  }
+
+  /// Fraction of the recent probes that were lost
+  pub fn loss(&self) -> Option<f64> {
+    if self.results.is_empty() {
+      return None
+    }
+    let lost = self.results.iter().filter(|answered| !**answered).count();
+    Some(lost as f64 / self.results.len() as f64)
+  }
+
+  pub fn tx_rate_kbps(&self) -> f64 {
+    self.tx_rate * 8.0 / 1000.0
+  }
+
+  pub fn rx_rate_kbps(&self) -> f64 {
+    self.rx_rate * 8.0 / 1000.0
+  }
+}
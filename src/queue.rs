@@ -0,0 +1,168 @@
+//! Bounded packet queue between the stages of the tun to link path: tun readers queue
+//! packets for routing, which queues them for the sender of each peer. When a stage
+//! falls behind, the queue's policy decides whether the oldest or the newest packet is
+//! dropped, or the previous stage waits for room, so that memory stays bounded
+
+use std::collections::VecDeque;
+
+use crate::QueuePolicy;
+
+/// What became of a pushed packet
+#[derive(Debug, PartialEq)]
+pub enum Pushed {
+  Queued,
+  /// Queued after dropping the oldest queued packet
+  DroppedOldest,
+  /// Dropped as the queue is full
+  DroppedNewest,
+  /// Queued after waiting for room
+  Blocked,
+  /// Dropped as the queue is closed
+  Closed
+}
+
+pub struct PacketQueue<T> {
+  /// `None` once the queue is closed
+  packets: std::sync::Mutex<Option<VecDeque<T>>>,
+  capacity: usize,
+  policy: QueuePolicy,
+  ready: tokio::sync::Notify,
+  room: tokio::sync::Notify
+}
+
+impl<T> PacketQueue<T> {
+  pub fn new(capacity: usize, policy: QueuePolicy) -> Self {
+    PacketQueue {
+      packets: std::sync::Mutex::new(Some(VecDeque::with_capacity(capacity))),
+      capacity,
+      policy,
+      ready: tokio::sync::Notify::new(),
+      room: tokio::sync::Notify::new()
+    }
+  }
+
+  /// Queue a packet, applying the queue's policy if it is full
+  pub async fn push(&self, packet: T) -> Pushed {
+    let mut blocked = false;
+    let pushed = loop {
+      // waited on only if there is no room, but created first so that a close in
+      // between isn't missed
+      let room = self.room.notified();
+      {
+        let mut packets = self.packets.lock().unwrap();
+        let Some(packets) = packets.as_mut() else { return Pushed::Closed };
+        if packets.len() < self.capacity {
+          packets.push_back(packet);
+          break if blocked { Pushed::Blocked } else { Pushed::Queued }
+        }
+        match self.policy {
+          QueuePolicy::DropOldest => {
+            packets.pop_front();
+            packets.push_back(packet);
+            break Pushed::DroppedOldest
+          }
+          QueuePolicy::DropNewest => return Pushed::DroppedNewest,
+          QueuePolicy::Block => blocked = true
+        }
+      }
+      room.await;
+    };
+    self.ready.notify_one();
+    pushed
+  }
+
+  /// Wait for the next packet; `None` once the queue is closed
+  pub async fn pop(&self) -> Option<T> {
+    loop {
+      let ready = self.ready.notified();
+      {
+        let mut packets = self.packets.lock().unwrap();
+        if let Some(packet) = packets.as_mut()?.pop_front() {
+          self.room.notify_one();
+          return Some(packet)
+        }
+      }
+      ready.await;
+    }
+  }
+
+  /// Close the queue, dropping the packets in it and any pushed later
+  pub fn close(&self) {
+    *self.packets.lock().unwrap() = None;
+    self.ready.notify_waiters();
+    self.room.notify_waiters();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  /// Queue of two packets filled with `[1]` and `[2]`
+  async fn full(policy: QueuePolicy) -> PacketQueue<Vec<u8>> {
+    let queue = PacketQueue::new(2, policy);
+    assert_eq!(queue.push(vec![1]).await, Pushed::Queued);
+    assert_eq!(queue.push(vec![2]).await, Pushed::Queued);
+    queue
+  }
+
+  #[tokio::test]
+  async fn drop_oldest() {
+    let queue = full(QueuePolicy::DropOldest).await;
+    assert_eq!(queue.push(vec![3]).await, Pushed::DroppedOldest);
+    assert_eq!(queue.pop().await, Some(vec![2]));
+    assert_eq!(queue.pop().await, Some(vec![3]));
+  }
+
+  #[tokio::test]
+  async fn drop_newest() {
+    let queue = full(QueuePolicy::DropNewest).await;
+    assert_eq!(queue.push(vec![3]).await, Pushed::DroppedNewest);
+    assert_eq!(queue.pop().await, Some(vec![1]));
+    assert_eq!(queue.pop().await, Some(vec![2]));
+  }
+
+  #[tokio::test]
+  async fn block() {
+    let queue = full(QueuePolicy::Block).await;
+    let timeout = Duration::from_millis(50);
+    assert!(tokio::time::timeout(timeout, queue.push(vec![3])).await.is_err());
+    let (pushed, popped) = tokio::join!(queue.push(vec![3]), async {
+      tokio::time::sleep(timeout).await;
+      queue.pop().await
+    });
+    assert_eq!(pushed, Pushed::Blocked);
+    assert_eq!(popped, Some(vec![1]));
+    assert_eq!(queue.pop().await, Some(vec![2]));
+    assert_eq!(queue.pop().await, Some(vec![3]));
+  }
+
+  #[tokio::test]
+  async fn pop_waits_for_push() {
+    let queue = PacketQueue::new(2, QueuePolicy::DropOldest);
+    let (popped, _) = tokio::join!(queue.pop(), queue.push(vec![1]));
+    assert_eq!(popped, Some(vec![1]));
+  }
+
+  #[tokio::test]
+  async fn close_drops_packets() {
+    let queue = full(QueuePolicy::DropOldest).await;
+    queue.close();
+    assert_eq!(queue.pop().await, None);
+    assert_eq!(queue.push(vec![3]).await, Pushed::Closed);
+  }
+
+  #[tokio::test]
+  async fn close_wakes_waiters() {
+    let queue = full(QueuePolicy::Block).await;
+    let empty = PacketQueue::<Vec<u8>>::new(2, QueuePolicy::Block);
+    let (pushed, popped, _) = tokio::join!(queue.push(vec![3]), empty.pop(), async {
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      queue.close();
+      empty.close();
+    });
+    assert_eq!(pushed, Pushed::Closed);
+    assert_eq!(popped, None);
+  }
+}
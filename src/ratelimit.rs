@@ -0,0 +1,37 @@
+//! Token bucket rate limiting of packets sent to peers
+
+use std::time::Instant;
+
+pub struct TokenBucket {
+  /// Refill rate in bytes per second
+  rate: f64,
+  /// Most tokens the bucket holds, i.e. the largest burst in bytes
+  capacity: f64,
+  tokens: f64,
+  last_refill: Instant
+}
+
+impl TokenBucket {
+  /// Bucket for the given rate in kilobits per second, allowing a burst of one second
+  /// of traffic
+  pub fn new(kbps: u32) -> Self {
+    let rate = kbps as f64 * 1000.0 / 8.0;
+    let capacity = rate;
+    TokenBucket { rate, capacity, tokens: capacity, last_refill: Instant::now() }
+  }
+
+  /// Take tokens for a packet of the given length, returning false if there are not
+  /// enough and the packet should be dropped; a packet larger than the burst is sent
+  /// once the bucket is full, leaving it in debt
+  pub fn take(&mut self, len: usize) -> bool {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+    self.last_refill = now;
+    if self.tokens < (len as f64).min(self.capacity) {
+      return false
+    }
+    self.tokens -= len as f64;
+    true
+  }
+}
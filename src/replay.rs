@@ -0,0 +1,84 @@
+//! Sliding window of sequence numbers received on an inbound link, rejecting
+//! duplicated and replayed payloads
+
+/// Sequence numbers this far behind the highest one received are rejected
+const WINDOW: u64 = 64;
+
+#[derive(Default)]
+pub struct ReplayWindow {
+  /// Highest sequence number received; sequence numbers start at 1
+  highest: u64,
+  /// Bit `n` is set if `highest - n` has been received
+  received: u64
+}
+
+impl ReplayWindow {
+  /// Record a sequence number, returning false if it was received before or is too
+  /// far behind to tell
+  pub fn accept(&mut self, seq: u64) -> bool {
+    if seq > self.highest {
+      let shift = seq - self.highest;
+      self.received = if shift >= WINDOW { 0 } else { self.received << shift };
+      self.received |= 1;
+      self.highest = seq;
+      return true
+    }
+    let offset = self.highest - seq;
+    if seq == 0 || offset >= WINDOW || self.received & (1 << offset) != 0 {
+      return false
+    }
+    self.received |= 1 << offset;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accepts_in_order() {
+    let mut window = ReplayWindow::default();
+    assert!((1..=200).all(|seq| window.accept(seq)));
+  }
+
+  #[test]
+  fn rejects_duplicates() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(1));
+    assert!(window.accept(2));
+    assert!(!window.accept(2));
+    assert!(!window.accept(1));
+  }
+
+  #[test]
+  fn rejects_zero() {
+    assert!(!ReplayWindow::default().accept(0));
+  }
+
+  #[test]
+  fn accepts_reordered_within_window() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(10));
+    assert!(window.accept(5));
+    assert!(window.accept(9));
+    assert!(!window.accept(5));
+    assert!(window.accept(10 - WINDOW + 1));
+  }
+
+  #[test]
+  fn rejects_too_old() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(100));
+    assert!(!window.accept(100 - WINDOW));
+  }
+
+  #[test]
+  fn forgets_after_large_jump() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(1));
+    assert!(window.accept(1 + WINDOW * 2));
+    assert!(window.accept(WINDOW * 2));
+    assert!(!window.accept(1 + WINDOW * 2));
+  }
+}
@@ -0,0 +1,204 @@
+//! Interfaces of a Python-RNS config file (`~/.reticulum/config`), so that a node already
+//! running Reticulum can reuse its interface setup
+//!
+//! The file is in ConfigObj format: `[section]` headers, `[[subsection]]` headers for
+//! each interface within `[interfaces]`, `key = value` lines and `#` comments. Interface
+//! types without an equivalent here (e.g. `AutoInterface` or `RNodeInterface`) and
+//! disabled interfaces are skipped with a warning.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use crate::InterfaceConfig;
+
+/// Value of `reticulum_config` that looks for the config where rnsd does
+const AUTO: &str = "auto";
+const CONFIG_FILE: &str = "config";
+
+/// Interfaces of the config at `path`, a Reticulum config dir or the config file in it
+pub fn load(path: &Path) -> Result<BTreeMap<String, InterfaceConfig>, String> {
+  let path = resolve(path)?;
+  tracing::info!("loading Reticulum interfaces: {}", path.display());
+  let s = std::fs::read_to_string(&path)
+    .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+  parse(&s).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+/// Add the interfaces of the config at `path` to `interfaces`, except those named like
+/// one already there
+pub fn add_interfaces(interfaces: &mut BTreeMap<String, InterfaceConfig>, path: &Path)
+  -> Result<(), String>
+{
+  for (name, interface) in load(path)? {
+    if interfaces.contains_key(&name) {
+      tracing::warn!("Reticulum interface {name}: already configured, skipping");
+      continue
+    }
+    interfaces.insert(name, interface);
+  }
+  Ok(())
+}
+
+/// Config file at `path`, or for `auto` the first of `/etc/reticulum`,
+/// `~/.config/reticulum` and `~/.reticulum` holding one
+fn resolve(path: &Path) -> Result<PathBuf, String> {
+  if path != Path::new(AUTO) {
+    return Ok(if path.is_dir() { path.join(CONFIG_FILE) } else { path.to_owned() })
+  }
+  let mut dirs = vec![PathBuf::from("/etc/reticulum")];
+  if let Some(home) = std::env::var_os("HOME").map(PathBuf::from) {
+    dirs.push(home.join(".config/reticulum"));
+    dirs.push(home.join(".reticulum"));
+  }
+  dirs.iter().map(|dir| dir.join(CONFIG_FILE)).find(|path| path.is_file())
+    .ok_or_else(|| format!("no Reticulum config found; tried: {}", dirs.iter()
+      .map(|dir| dir.display().to_string()).collect::<Vec<_>>().join(", ")))
+}
+
+/// Interfaces of a config file's `[interfaces]` section
+pub fn parse(s: &str) -> Result<BTreeMap<String, InterfaceConfig>, String> {
+  let mut sections = Vec::<(String, BTreeMap<String, String>)>::new();
+  let mut in_interfaces = false;
+  for (i, line) in s.lines().enumerate() {
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+      continue
+    }
+    if line.starts_with('[') {
+      let depth = line.chars().take_while(|c| *c == '[').count();
+      let name = line[depth..].strip_suffix(&"]".repeat(depth))
+        .ok_or_else(|| format!("line {}: malformed section header", i + 1))?
+        .trim();
+      match depth {
+        1 => in_interfaces = name == "interfaces",
+        2 if in_interfaces => sections.push((name.to_owned(), BTreeMap::new())),
+        _ => {}
+      }
+      continue
+    }
+    let Some((key, value)) = line.split_once('=') else {
+      return Err(format!("line {}: expected `key = value`", i + 1))
+    };
+    if let Some((_, options)) = sections.last_mut().filter(|_| in_interfaces) {
+      options.insert(key.trim().to_owned(), unquote(value.trim()).to_owned());
+    }
+  }
+  let mut interfaces = BTreeMap::new();
+  for (name, mut options) in sections {
+    if let Some(interface) = interface(&name, &mut options)? {
+      for key in options.keys() {
+        tracing::warn!("Reticulum interface {name}: option {key} is not supported and \
+          ignored");
+      }
+      interfaces.insert(name, interface);
+    }
+  }
+  Ok(interfaces)
+}
+
+/// Interface of a `[[name]]` section, taking the options it uses out of `options`;
+/// `None` if it is disabled or of an unsupported type
+fn interface(name: &str, options: &mut BTreeMap<String, String>)
+  -> Result<Option<InterfaceConfig>, String>
+{
+  let err = |message: &str| format!("interface {name}: {message}");
+  let enabled = [options.remove("interface_enabled"), options.remove("enabled")]
+    .into_iter().flatten()
+    .map(|value| parse_bool(&value).ok_or_else(|| err("invalid value for enabled")))
+    .collect::<Result<Vec<_>, _>>()?;
+  // rnsd also leaves interfaces without either option disabled
+  if !enabled.contains(&true) {
+    tracing::info!("Reticulum interface {name}: disabled, skipping");
+    return Ok(None)
+  }
+  let kind = options.remove("type").ok_or_else(|| err("missing type"))?;
+  let mut port = |key: &str| options.remove(key).or_else(|| options.get("port").cloned())
+    .map(|port| port.parse::<u16>().map_err(|_| err(&format!("invalid {key}"))))
+    .transpose();
+  let interface = match kind.as_str() {
+    "UDPInterface" => {
+      let (listen_port, forward_port) = (port("listen_port")?, port("forward_port")?);
+      options.remove("port");
+      if options.contains_key("device") {
+        tracing::warn!("Reticulum interface {name}: device is not supported, skipping");
+        return Ok(None)
+      }
+      let listen = socket_addr(options.remove("listen_ip"), listen_port)
+        .ok_or_else(|| err("listen_ip and listen_port are required"))?
+        .map_err(|_| err("invalid listen_ip"))?;
+      let forward = socket_addr(options.remove("forward_ip"), forward_port).transpose()
+        .map_err(|_| err("invalid forward_ip"))?;
+      InterfaceConfig::Udp { listen, forward }
+    }
+    "TCPClientInterface" => {
+      if options.remove("kiss_framing").as_deref().and_then(parse_bool) == Some(true) {
+        tracing::warn!("Reticulum interface {name}: kiss_framing is not supported, \
+          skipping");
+        return Ok(None)
+      }
+      let host = options.remove("target_host").ok_or_else(|| err("missing target_host"))?;
+      let port = options.remove("target_port").ok_or_else(|| err("missing target_port"))?
+        .parse::<u16>().map_err(|_| err("invalid target_port"))?;
+      let connect = match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{host}:{port}")
+      };
+      InterfaceConfig::TcpClient { connect }
+    }
+    "TCPServerInterface" => {
+      let listen_port = port("listen_port")?;
+      options.remove("port");
+      if options.contains_key("device") {
+        tracing::warn!("Reticulum interface {name}: device is not supported, skipping");
+        return Ok(None)
+      }
+      let listen = socket_addr(options.remove("listen_ip"), listen_port)
+        .ok_or_else(|| err("listen_ip and listen_port are required"))?
+        .map_err(|_| err("invalid listen_ip"))?;
+      InterfaceConfig::TcpServer { listen }
+    }
+    _ => {
+      tracing::warn!("Reticulum interface {name}: type {kind} is not supported, \
+        skipping");
+      return Ok(None)
+    }
+  };
+  Ok(Some(interface))
+}
+
+fn socket_addr(ip: Option<String>, port: Option<u16>)
+  -> Option<Result<SocketAddr, std::net::AddrParseError>>
+{
+  let (ip, port) = (ip?, port?);
+  Some(ip.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port)))
+}
+
+/// ConfigObj booleans
+fn parse_bool(value: &str) -> Option<bool> {
+  match value.to_ascii_lowercase().as_str() {
+    "yes" | "true" | "on" | "1" => Some(true),
+    "no" | "false" | "off" | "0" => Some(false),
+    _ => None
+  }
+}
+
+/// The line up to a `#` outside of quotes
+fn strip_comment(line: &str) -> &str {
+  let mut quote = None;
+  for (i, c) in line.char_indices() {
+    match (c, quote) {
+      ('"' | '\'', None) => quote = Some(c),
+      (c, Some(open)) if c == open => quote = None,
+      ('#', None) => return &line[..i],
+      _ => {}
+    }
+  }
+  line
+}
+
+fn unquote(value: &str) -> &str {
+  ['"', '\''].into_iter()
+    .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote))
+    .unwrap_or(value)
+}
@@ -0,0 +1,48 @@
+//! Longest-prefix-match routing table
+//!
+//! Networks are kept in one map per prefix length; a lookup tries each prefix length in
+//! use from the longest down, so it costs at most one map lookup per distinct prefix
+//! length.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+pub struct RoutingTable<T> {
+  prefixes: BTreeMap<u8, HashMap<IpNet, T>>
+}
+
+impl<T> Default for RoutingTable<T> {
+  fn default() -> Self {
+    RoutingTable { prefixes: BTreeMap::new() }
+  }
+}
+
+impl<T> RoutingTable<T> {
+  /// Add a route, returning the value previously routed for the same network
+  pub fn insert(&mut self, net: IpNet, value: T) -> Option<T> {
+    self.prefixes.entry(net.prefix_len()).or_default().insert(net.trunc(), value)
+  }
+
+  pub fn remove(&mut self, net: &IpNet) -> Option<T> {
+    let nets = self.prefixes.get_mut(&net.prefix_len())?;
+    let value = nets.remove(&net.trunc());
+    if nets.is_empty() {
+      self.prefixes.remove(&net.prefix_len());
+    }
+    value
+  }
+
+  /// Value routed for the longest network containing the address
+  pub fn longest_match(&self, ip: &IpAddr) -> Option<&T> {
+    let max_prefix_len = match ip {
+      IpAddr::V4(_) => 32,
+      IpAddr::V6(_) => 128
+    };
+    self.prefixes.range(..=max_prefix_len).rev().find_map(|(prefix_len, nets)| {
+      let net = IpNet::new(*ip, *prefix_len).ok()?.trunc();
+      nets.get(&net)
+    })
+  }
+}
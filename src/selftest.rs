@@ -0,0 +1,125 @@
+//! Local end-to-end check of identity creation, announces, link establishment and
+//! data transfer between two in-process nodes over loopback UDP (no tun required)
+
+use std::time::Duration;
+
+use rand_core::OsRng;
+
+use reticulum::destination::DestinationName;
+use reticulum::destination::link::LinkEvent;
+use reticulum::identity::PrivateIdentity;
+use reticulum::iface::udp::UdpInterface;
+use reticulum::transport::{Transport, TransportConfig};
+
+use crate::frame::Frame;
+use crate::{DESTINATION_APP, DESTINATION_ASPECT};
+
+const STEP_TIMEOUT_SECS: u64 = 10;
+const ANNOUNCE_FREQ_SECS: u64 = 1;
+
+/// Step of the self-test that failed
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum SelftestError {
+  /// Couldn't reserve loopback UDP ports
+  #[error("failed to reserve loopback UDP ports: {0}")]
+  InterfaceError(#[source] std::io::Error),
+  /// Node A never received the announce of node B
+  #[error("announce not received")]
+  AnnounceTimeout,
+  /// Link from node A to node B was never activated
+  #[error("link not activated")]
+  LinkTimeout,
+  /// Packet sent by node A never arrived at node B
+  #[error("packet not received")]
+  DataTimeout,
+  /// Packet arrived at node B but differs from what was sent
+  #[error("packet received differs from the one sent")]
+  DataMismatch
+}
+
+/// Run the self-test, logging each step as it passes
+pub async fn run() -> Result<(), SelftestError> {
+  let step_timeout = Duration::from_secs(STEP_TIMEOUT_SECS);
+  // set up two nodes forwarding to each other
+  let port_a = free_udp_port().map_err(SelftestError::InterfaceError)?;
+  let port_b = free_udp_port().map_err(SelftestError::InterfaceError)?;
+  let id_a = PrivateIdentity::new_from_rand(OsRng);
+  let id_b = PrivateIdentity::new_from_rand(OsRng);
+  let transport_a = Transport::new(TransportConfig::new("selftest-a", &id_a, true));
+  let mut transport_b = Transport::new(TransportConfig::new("selftest-b", &id_b, true));
+  let _ = transport_a.iface_manager().lock().await.spawn(
+    UdpInterface::new(format!("127.0.0.1:{port_a}"), Some(format!("127.0.0.1:{port_b}"))),
+    UdpInterface::spawn);
+  let _ = transport_b.iface_manager().lock().await.spawn(
+    UdpInterface::new(format!("127.0.0.1:{port_b}"), Some(format!("127.0.0.1:{port_a}"))),
+    UdpInterface::spawn);
+  let destination_b = transport_b
+    .add_destination(id_b, DestinationName::new(DESTINATION_APP, DESTINATION_ASPECT)).await;
+  let destination_b_hash = destination_b.lock().await.desc.address_hash;
+  tracing::info!("selftest: created identities and interfaces (udp ports {port_a}, {port_b})");
+  // announce: node A must see node B
+  let mut announce_recv = transport_a.recv_announces().await;
+  let desc = tokio::time::timeout(step_timeout, async {
+    loop {
+      transport_b.send_announce(&destination_b, None).await;
+      let recv = tokio::time::timeout(Duration::from_secs(ANNOUNCE_FREQ_SECS),
+        announce_recv.recv()).await;
+      if let Ok(Ok(announce)) = recv {
+        let destination = announce.destination.lock().await;
+        if destination.desc.address_hash == destination_b_hash {
+          return destination.desc
+        }
+      }
+    }
+  }).await.map_err(|_| SelftestError::AnnounceTimeout)?;
+  tracing::info!("selftest: announce received");
+  // link: node A links to node B
+  let mut out_link_events = transport_a.out_link_events();
+  let link = transport_a.link(desc).await;
+  let link_id = *link.lock().await.id();
+  tokio::time::timeout(step_timeout, async {
+    while let Ok(link_event) = out_link_events.recv().await {
+      if link_event.id == link_id && matches!(link_event.event, LinkEvent::Activated) {
+        return
+      }
+    }
+    std::future::pending::<()>().await
+  }).await.map_err(|_| SelftestError::LinkTimeout)?;
+  tracing::info!("selftest: link {link_id} activated");
+  // data: a packet sent by node A must arrive unchanged at node B
+  let packet = test_packet();
+  let mut in_link_events = transport_b.in_link_events();
+  let data_packet = link.lock().await.data_packet(&packet).unwrap();
+  transport_a.send_packet(data_packet).await;
+  let received = tokio::time::timeout(step_timeout, async {
+    while let Ok(link_event) = in_link_events.recv().await {
+      if let LinkEvent::Data(payload) = link_event.event {
+        return payload.as_slice().to_vec()
+      }
+    }
+    std::future::pending().await
+  }).await.map_err(|_| SelftestError::DataTimeout)?;
+  match Frame::parse(&received) {
+    Some(Frame::Ip(received)) if received == packet.as_slice() => {}
+    _ => return Err(SelftestError::DataMismatch)
+  }
+  tracing::info!("selftest: packet received ({} bytes)", received.len());
+  Ok(())
+}
+
+/// Reserve a loopback UDP port by binding to an ephemeral port
+fn free_udp_port() -> Result<u16, std::io::Error> {
+  let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+  Ok(socket.local_addr()?.port())
+}
+
+/// IPv4 UDP packet between two VPN addresses
+fn test_packet() -> Vec<u8> {
+  let payload = b"rns-vpn selftest";
+  let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+    .udp(12345, 12345);
+  let mut packet = Vec::with_capacity(builder.size(payload.len()));
+  builder.write(&mut packet, payload).unwrap();
+  packet
+}
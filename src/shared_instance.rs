@@ -0,0 +1,80 @@
+//! Connection to the instance a local rnsd shares with the Reticulum applications on the
+//! same host, so that they all use its interfaces and routing instead of each opening
+//! their own
+//!
+//! rnsd shares its instance over TCP on a loopback port, or on Linux by default on the
+//! abstract Unix socket `rns/<instance name>`, framing packets the same way as its TCP
+//! interfaces in both cases.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// Port rnsd shares its instance on over TCP unless configured otherwise
+pub const DEFAULT_PORT: u16 = 37428;
+/// Instance name rnsd uses unless configured otherwise
+pub const DEFAULT_INSTANCE_NAME: &str = "default";
+
+/// Loopback address for a TCP client interface to connect to the shared instance: the
+/// instance's own if it is shared over TCP on `port`, otherwise one relayed to its
+/// Unix socket
+pub async fn address(instance_name: &str, port: Option<u16>)
+  -> Result<SocketAddr, std::io::Error>
+{
+  match port {
+    Some(port) => Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port))),
+    None => unix_address(instance_name).await
+  }
+}
+
+/// rnsd only shares its instance on a Unix socket on Linux
+#[cfg(not(target_os = "linux"))]
+async fn unix_address(_instance_name: &str) -> Result<SocketAddr, std::io::Error> {
+  Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT)))
+}
+
+/// Relay connections to a loopback port to the instance's Unix socket, as the transport
+/// only has TCP clients; this gives no access the socket doesn't, which any local user
+/// can connect to
+#[cfg(target_os = "linux")]
+async fn unix_address(instance_name: &str) -> Result<SocketAddr, std::io::Error> {
+  let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  let addr = listener.local_addr()?;
+  let socket_name = format!("rns/{instance_name}");
+  tracing::debug!("relaying {addr} to shared instance socket @{socket_name}");
+  tokio::spawn(async move {
+    loop {
+      let mut tcp = match listener.accept().await {
+        Ok((tcp, _)) => tcp,
+        Err(err) => {
+          tracing::warn!("failed to accept shared instance connection: {err:?}");
+          tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+          continue
+        }
+      };
+      let socket_name = socket_name.clone();
+      tokio::spawn(async move {
+        let mut unix = match connect(&socket_name) {
+          Ok(unix) => unix,
+          Err(err) => {
+            tracing::warn!("failed to connect to shared instance socket @{socket_name}: \
+              {err}");
+            return
+          }
+        };
+        if let Err(err) = tokio::io::copy_bidirectional(&mut tcp, &mut unix).await {
+          tracing::debug!("shared instance connection closed: {err}");
+        }
+      });
+    }
+  });
+  Ok(addr)
+}
+
+#[cfg(target_os = "linux")]
+fn connect(socket_name: &str) -> Result<tokio::net::UnixStream, std::io::Error> {
+  use std::os::linux::net::SocketAddrExt;
+  let addr = std::os::unix::net::SocketAddr::from_abstract_name(socket_name)?;
+  // connecting to a local socket doesn't block
+  let unix = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+  unix.set_nonblocking(true)?;
+  tokio::net::UnixStream::from_std(unix)
+}
@@ -0,0 +1,66 @@
+//! Supervision of the client's loops: a loop that stops on a recoverable error is
+//! restarted after a backoff, so a failed tun read or write or a lagging transport
+//! channel doesn't tear the client down; fatal errors, like the tun device going away,
+//! and loops failing over and over still stop it
+
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+
+const BACKOFF_MIN_MILLIS: u64 = 100;
+const BACKOFF_MAX_SECS: u64 = 30;
+/// A loop that ran this long before failing is restarted as if it never failed
+const HEALTHY_SECS: u64 = 60;
+/// Failures in a row, each shortly after the previous restart, treated as fatal
+const MAX_RESTARTS: u32 = 10;
+
+/// Why a loop stopped
+pub enum Failure {
+  /// Restarting the loop may help
+  Recoverable(String),
+  /// The loop can't go on
+  Fatal(String)
+}
+
+/// Run a loop until it fails fatally, restarting it with backoff on recoverable
+/// failures; the reason it failed for good is returned
+pub async fn supervise(name: &str, metrics: &Metrics, run: impl AsyncFn() -> Failure)
+  -> String
+{
+  let mut backoff = Duration::from_millis(BACKOFF_MIN_MILLIS);
+  let mut restarts = 0;
+  loop {
+    let started = Instant::now();
+    let reason = match run().await {
+      Failure::Recoverable(reason) => reason,
+      Failure::Fatal(reason) => return reason
+    };
+    if started.elapsed() >= Duration::from_secs(HEALTHY_SECS) {
+      backoff = Duration::from_millis(BACKOFF_MIN_MILLIS);
+      restarts = 0;
+    }
+    restarts += 1;
+    if restarts > MAX_RESTARTS {
+      return format!("{reason} ({MAX_RESTARTS} times in a row)")
+    }
+    tracing::warn!("{name} loop failed: {reason}: restarting in {backoff:?}");
+    Metrics::inc(&metrics.loop_restarts);
+    tokio::time::sleep(backoff).await;
+    backoff = (backoff * 2).min(Duration::from_secs(BACKOFF_MAX_SECS));
+  }
+}
+
+/// Whether an error reading or writing the tun means the device is gone
+pub fn is_device_gone(err: &std::io::Error) -> bool {
+  #[cfg(unix)]
+  {
+    use nix::errno::Errno;
+    err.raw_os_error().map(Errno::from_i32)
+      .is_some_and(|errno| [Errno::ENODEV, Errno::ENXIO, Errno::EBADF].contains(&errno))
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = err;
+    false
+  }
+}
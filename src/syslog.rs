@@ -0,0 +1,73 @@
+//! Log messages sent to the local syslog daemon over `/dev/log` in the traditional BSD
+//! format, with the severity taken from the level of each message
+
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+
+use tracing_subscriber::fmt::MakeWriter;
+
+const SOCKET_PATH: &str = "/dev/log";
+/// LOG_DAEMON
+const FACILITY: u8 = 3;
+
+pub struct Syslog {
+  socket: UnixDatagram
+}
+
+impl Syslog {
+  pub fn connect() -> io::Result<Self> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(SOCKET_PATH)?;
+    Ok(Syslog { socket })
+  }
+}
+
+/// One message, sent when dropped
+pub struct Message<'a> {
+  syslog: &'a Syslog,
+  severity: u8,
+  buf: Vec<u8>
+}
+
+impl Write for Message<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.buf.extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl Drop for Message<'_> {
+  fn drop(&mut self) {
+    let text = String::from_utf8_lossy(&self.buf);
+    let text = text.trim_end();
+    if text.is_empty() {
+      return
+    }
+    let message = format!("<{}>rns-vpn[{}]: {text}", FACILITY * 8 + self.severity,
+      std::process::id());
+    // nowhere to report a failure to log
+    let _ = self.syslog.socket.send(message.as_bytes());
+  }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+  type Writer = Message<'a>;
+
+  fn make_writer(&'a self) -> Message<'a> {
+    Message { syslog: self, severity: 6, buf: Vec::new() }
+  }
+
+  fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Message<'a> {
+    let severity = match *meta.level() {
+      tracing::Level::ERROR => 3,
+      tracing::Level::WARN => 4,
+      tracing::Level::INFO => 6,
+      _ => 7
+    };
+    Message { syslog: self, severity, buf: Vec::new() }
+  }
+}
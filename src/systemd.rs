@@ -0,0 +1,38 @@
+//! systemd service notifications; all calls are no-ops unless started by systemd with
+//! `Type=notify`
+
+use std::time::Duration;
+
+use sd_notify::NotifyState;
+
+/// Interval between status updates when the watchdog is disabled
+const STATUS_INTERVAL_SECS: u64 = 10;
+
+pub fn notify_ready() {
+  notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+  notify(&[NotifyState::Stopping]);
+}
+
+/// Feed the watchdog, if enabled, and report a status line
+pub fn notify_alive(status: &str) {
+  notify(&[NotifyState::Watchdog, NotifyState::Status(status)]);
+}
+
+/// How often to call `notify_alive`: half the watchdog timeout if it's enabled
+pub fn alive_interval() -> Duration {
+  let mut usec = 0;
+  if sd_notify::watchdog_enabled(false, &mut usec) {
+    Duration::from_micros(usec / 2)
+  } else {
+    Duration::from_secs(STATUS_INTERVAL_SECS)
+  }
+}
+
+fn notify(state: &[NotifyState]) {
+  if let Err(err) = sd_notify::notify(false, state) {
+    tracing::debug!("systemd notify failed: {err:?}");
+  }
+}
@@ -0,0 +1,241 @@
+//! FreeBSD/OpenBSD tun device opened from `/dev/tunN`, configured with `ifconfig` and
+//! `route`
+//!
+//! Packets on the device are prefixed with a 4-byte address family in network byte
+//! order; on FreeBSD this is enabled with `TUNSIFHEAD` to match OpenBSD.
+
+use std::io::{IoSlice, IoSliceMut, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(target_os = "freebsd")]
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+
+use ipnet::IpNet;
+use nix::libc;
+use tokio::io::unix::AsyncFd;
+
+use crate::{Config, CreateClientError};
+
+/// Highest tun unit number tried when looking for a free device
+const MAX_UNIT: u32 = 255;
+const AF_HEADER_LEN: usize = 4;
+
+#[cfg(target_os = "freebsd")]
+nix::ioctl_write_ptr!(tun_set_head, b't', 96, libc::c_int);
+
+pub struct Tun {
+  fd: AsyncFd<std::fs::File>,
+  name: String,
+  /// Addresses assigned to the tun, removed again on teardown
+  addresses: std::sync::Mutex<Vec<IpNet>>,
+  /// Routes to the tun added for networks behind peers
+  routes: std::sync::Mutex<Vec<IpNet>>,
+  /// Host routes through the default gateway kept out of the tunnel
+  bypass_routes: std::sync::Mutex<Vec<IpNet>>
+}
+
+impl Tun {
+  pub async fn new(addresses: &[IpNet], config: &Config)
+    -> Result<Self, CreateClientError>
+  {
+    if config.tun_queues > 1 {
+      tracing::warn!("multiple tun queues are not supported on this platform: using 1");
+    }
+    tracing::debug!("creating tun device");
+    let (file, name) = open_tun().map_err(CreateClientError::TunDeviceError)?;
+    #[cfg(target_os = "freebsd")]
+    unsafe { tun_set_head(file.as_raw_fd(), &1) }
+      .map_err(|errno| CreateClientError::TunDeviceError(errno.into()))?;
+    tracing::debug!("created tun device: {}", name);
+    let fd = AsyncFd::new(file).map_err(CreateClientError::TunDeviceError)?;
+    let adapter = Tun {
+      fd, name, addresses: std::sync::Mutex::new(Vec::new()),
+      routes: std::sync::Mutex::new(Vec::new()),
+      bypass_routes: std::sync::Mutex::new(Vec::new())
+    };
+    for ip in addresses.iter() {
+      adapter.add_address(*ip, config.force).await?;
+    }
+    if let Some(management_ip) = config.management_ip {
+      tracing::debug!("adding management address");
+      adapter.add_address(management_ip, config.force).await?;
+    }
+    tracing::debug!("{} setting mtu {} and link up", adapter.name, config.mtu);
+    run("ifconfig", &[&adapter.name, "mtu", &config.mtu.to_string(), "up"])
+      .map_err(CreateClientError::IfconfigError)?;
+    Ok(adapter)
+  }
+
+  /// Add an address to the tun and a route for its prefix
+  pub async fn add_address(&self, ip: IpNet, _force: bool) -> Result<(), CreateClientError> {
+    tracing::debug!("adding ip addr: {}", ip);
+    let addr = ip.addr().to_string();
+    let prefix_len = ip.prefix_len().to_string();
+    let net = ip.trunc().to_string();
+    match ip {
+      IpNet::V4(ip) => {
+        let netmask = ip.netmask().to_string();
+        // point-to-point device: the local address doubles as destination
+        run("ifconfig", &[&self.name, "inet", &addr, &addr, "netmask", &netmask, "alias"])
+          .map_err(CreateClientError::IfconfigError)?;
+        run("route", &["-q", "add", "-inet", &net, "-interface", &self.name])
+      }
+      IpNet::V6(_) => {
+        run("ifconfig", &[&self.name, "inet6", &addr, "prefixlen", &prefix_len, "alias"])
+          .map_err(CreateClientError::IfconfigError)?;
+        run("route", &["-q", "add", "-inet6", &net, "-interface", &self.name])
+      }
+    }.map_err(CreateClientError::IpRouteAddError)?;
+    self.addresses.lock().unwrap().push(ip);
+    Ok(())
+  }
+
+  /// Route a network behind a peer to the tun
+  pub async fn add_route(&self, net: IpNet) -> Result<(), CreateClientError> {
+    tracing::debug!("adding route: {}", net);
+    run("route", &["-q", "add", route_family(net), &net.to_string(), "-interface",
+      &self.name]).map_err(CreateClientError::IpRouteAddError)?;
+    self.routes.lock().unwrap().push(net);
+    Ok(())
+  }
+
+  pub async fn remove_route(&self, net: IpNet) {
+    tracing::debug!("removing route: {}", net);
+    self.routes.lock().unwrap().retain(|route| *route != net);
+    let result = run("route", &["-q", "delete", route_family(net), &net.to_string(),
+      "-interface", &self.name]);
+    if let Err(err) = result {
+      tracing::warn!("failed to remove route {}: {:?}", net, err);
+    }
+  }
+
+  /// Route an address through the current default gateway instead of the tun, so that
+  /// the Reticulum underlay keeps working once the default route points to the tun
+  pub async fn add_bypass_route(&self, ip: IpAddr) -> Result<(), CreateClientError> {
+    let net = IpNet::from(ip);
+    let output = output("route", &["-n", "get", route_family(net), "default"])
+      .map_err(CreateClientError::IpRouteGetError)?;
+    let Some(gateway) = output.lines()
+      .find_map(|line| line.trim().strip_prefix("gateway:").map(str::trim))
+    else {
+      return Err(CreateClientError::ConfigError(
+        format!("no default gateway to reach {ip} outside the tunnel")))
+    };
+    tracing::debug!("adding bypass route: {} via {}", ip, gateway);
+    run("route", &["-q", "add", route_family(net), "-host", &ip.to_string(), gateway])
+      .map_err(CreateClientError::IpRouteAddError)?;
+    self.bypass_routes.lock().unwrap().push(net);
+    Ok(())
+  }
+
+  /// Remove the routes and addresses added to the tun; tun devices persist on BSD
+  /// after they are closed
+  pub async fn remove_addresses(&self) {
+    let routes = std::mem::take(&mut *self.routes.lock().unwrap());
+    for net in routes {
+      self.remove_route(net).await;
+    }
+    let bypass_routes = std::mem::take(&mut *self.bypass_routes.lock().unwrap());
+    for net in bypass_routes {
+      tracing::debug!("removing bypass route: {}", net);
+      let result =
+        run("route", &["-q", "delete", route_family(net), "-host", &net.addr().to_string()]);
+      if let Err(err) = result {
+        tracing::warn!("failed to remove bypass route {}: {:?}", net, err);
+      }
+    }
+    let addresses = std::mem::take(&mut *self.addresses.lock().unwrap());
+    for ip in addresses {
+      tracing::debug!("removing ip addr: {}", ip);
+      let addr = ip.addr().to_string();
+      let net = ip.trunc().to_string();
+      let family = match ip {
+        IpNet::V4(_) => "inet",
+        IpNet::V6(_) => "inet6"
+      };
+      let result =
+        run("route", &["-q", "delete", route_family(ip), &net, "-interface", &self.name])
+        .and_then(|_| run("ifconfig", &[&self.name, family, &addr, "-alias"]));
+      if let Err(err) = result {
+        tracing::warn!("failed to remove address {}: {:?}", ip, err);
+      }
+    }
+  }
+
+  /// Only a single queue is supported
+  pub fn queues(&self) -> usize {
+    1
+  }
+
+  /// Read a packet into the buffer, returning its length without the address family
+  /// header
+  pub async fn read(&self, _queue: usize, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut header = [0x0; AF_HEADER_LEN];
+    loop {
+      let mut guard = self.fd.readable().await?;
+      let mut frame = [IoSliceMut::new(&mut header), IoSliceMut::new(&mut *buf)];
+      match guard.try_io(|fd| fd.get_ref().read_vectored(&mut frame)) {
+        Ok(result) => return result.map(|nbytes| nbytes.saturating_sub(AF_HEADER_LEN)),
+        Err(_would_block) => continue
+      }
+    }
+  }
+
+  pub async fn send(&self, datagram: &[u8]) -> Result<usize, std::io::Error> {
+    let family = match datagram.first().map(|b| b >> 4) {
+      Some(6) => libc::AF_INET6,
+      _ => libc::AF_INET
+    };
+    let header = (family as u32).to_be_bytes();
+    let frame = [IoSlice::new(&header), IoSlice::new(datagram)];
+    loop {
+      let mut guard = self.fd.writable().await?;
+      match guard.try_io(|fd| fd.get_ref().write_vectored(&frame)) {
+        Ok(result) => return result.map(|nbytes| nbytes.saturating_sub(AF_HEADER_LEN)),
+        Err(_would_block) => continue
+      }
+    }
+  }
+}
+
+/// Open the first free `/dev/tunN` device
+fn open_tun() -> Result<(std::fs::File, String), std::io::Error> {
+  for unit in 0..=MAX_UNIT {
+    let name = format!("tun{unit}");
+    let result = std::fs::OpenOptions::new().read(true).write(true)
+      .custom_flags(libc::O_NONBLOCK).open(format!("/dev/{name}"));
+    match result {
+      Ok(file) => return Ok((file, name)),
+      // device in use by another process
+      Err(err) if err.raw_os_error() == Some(libc::EBUSY) => continue,
+      Err(err) => return Err(err)
+    }
+  }
+  Err(std::io::Error::other("no free tun device"))
+}
+
+/// `route` address family flag for a network
+fn route_family(net: IpNet) -> &'static str {
+  match net {
+    IpNet::V4(_) => "-inet",
+    IpNet::V6(_) => "-inet6"
+  }
+}
+
+/// Run a configuration command, failing with its stderr on a non-zero exit
+fn run(program: &str, args: &[&str]) -> Result<(), std::io::Error> {
+  output(program, args).map(|_| ())
+}
+
+/// Run a command, returning its stdout or failing with its stderr on a non-zero exit
+fn output(program: &str, args: &[&str]) -> Result<String, std::io::Error> {
+  tracing::debug!("{} {}", program, args.join(" "));
+  let output = Command::new(program).args(args).output()?;
+  if output.status.success() {
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+  } else {
+    Err(std::io::Error::other(format!("{program} failed: {}",
+      String::from_utf8_lossy(&output.stderr).trim())))
+  }
+}
@@ -0,0 +1,359 @@
+//! Linux tun or tap device configured over netlink
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use riptun::TokioTun;
+
+use crate::{Config, CreateClientError, DeviceMode, TAP_NAME, TUN_NAME};
+use super::netlink::Netlink;
+use super::networkd::Networkd;
+use super::tap::Tap;
+
+/// Multiqueue tun device carrying IP packets, or tap device carrying Ethernet frames
+enum Device {
+  Tun(TokioTun),
+  Tap(Tap)
+}
+
+pub struct Tun {
+  device: Device,
+  /// Interface index of the tun device
+  index: u32,
+  netlink: Netlink,
+  queues: usize,
+  /// Addresses assigned to the tun, removed again on teardown
+  addresses: std::sync::Mutex<Vec<IpNet>>,
+  /// Routes to the tun added for networks behind peers
+  routes: std::sync::Mutex<Vec<IpNet>>,
+  /// Host routes through the default route's device kept out of the tunnel
+  bypass_routes: std::sync::Mutex<Vec<(u32, IpNet)>>,
+  /// Configured device name or pattern
+  tun_name: Option<String>,
+  /// Addresses and routes are configured by the client, not by whoever created the
+  /// device
+  manage_network: bool,
+  /// systemd-networkd configures the device's addresses and routes for the client
+  networkd: Option<Networkd>
+}
+
+impl Tun {
+  pub async fn new(addresses: &[IpNet], config: &Config)
+    -> Result<Self, CreateClientError>
+  {
+    let (device, queues) = match config.mode {
+      DeviceMode::Tun => {
+        let queues = config.tun_queues;
+        tracing::debug!("opening tun device with {} queues", queues);
+        let name = config.tun_name.as_deref().unwrap_or(TUN_NAME);
+        let tun = TokioTun::new(name, queues)
+          .map_err(CreateClientError::RiptunError)?;
+        (Device::Tun(tun), queues)
+      }
+      DeviceMode::Tap => {
+        if config.tun_queues > 1 {
+          tracing::warn!("multiple queues are not supported in tap mode: using 1");
+        }
+        tracing::debug!("opening tap device");
+        let name = config.tun_name.as_deref().unwrap_or(TAP_NAME);
+        let tap = Tap::new(name).map_err(CreateClientError::TapDeviceError)?;
+        (Device::Tap(tap), 1)
+      }
+    };
+    let netlink = Netlink::new()?;
+    let name = match &device {
+      Device::Tun(tun) => tun.name(),
+      Device::Tap(tap) => tap.name()
+    };
+    tracing::debug!("opened device: {}", name);
+    let index = netlink.link_index(name).await?;
+    let mut adapter = Tun {
+      device, index, netlink, queues, addresses: std::sync::Mutex::new(Vec::new()),
+      routes: std::sync::Mutex::new(Vec::new()),
+      bypass_routes: std::sync::Mutex::new(Vec::new()),
+      tun_name: config.tun_name.clone(),
+      manage_network: config.manages_network(),
+      networkd: None
+    };
+    if !adapter.manage_network {
+      tracing::info!("leaving the configuration of {} to network management",
+        adapter.name());
+      return Ok(adapter)
+    }
+    if config.networkd {
+      // a bridge port doesn't take part in routing: its addresses belong on the bridge
+      let addresses = addresses.iter().copied().chain(config.management_ip)
+        .filter(|_| config.bridge.is_none())
+        .collect();
+      let networkd = Networkd::new(adapter.name(), config.mtu, config.bridge.clone(),
+        addresses).map_err(CreateClientError::NetworkdError)?;
+      adapter.networkd = Some(networkd);
+      return Ok(adapter)
+    }
+    // a bridge port doesn't take part in routing: its addresses belong on the bridge
+    if config.bridge.is_none() {
+      // adding an address also installs the route for its prefix
+      for ip in addresses.iter() {
+        adapter.add_address(*ip, config.force).await?;
+      }
+      if let Some(management_ip) = config.management_ip {
+        tracing::debug!("adding management address");
+        adapter.add_address(management_ip, config.force).await?;
+      }
+    }
+    tracing::debug!("{} setting mtu {}", adapter.name(), config.mtu);
+    adapter.netlink.set_link_mtu(index, config.mtu).await?;
+    if let Some(bridge) = &config.bridge {
+      tracing::debug!("{} adding to bridge {}", adapter.name(), bridge);
+      let bridge_index = adapter.netlink.link_index(bridge).await?;
+      adapter.netlink.set_link_controller(index, bridge_index).await?;
+    }
+    tracing::debug!("{} setting link up", adapter.name());
+    adapter.netlink.set_link_up(index).await?;
+    Ok(adapter)
+  }
+
+  /// Add an address to the tun, handling the address being left on a stale device
+  pub async fn add_address(&self, ip: IpNet, force: bool) -> Result<(), CreateClientError> {
+    if !self.manage_network {
+      tracing::debug!("not adding ip addr {}: device configured externally", ip);
+      return Ok(())
+    }
+    if let Some(networkd) = &self.networkd {
+      return networkd.add_address(ip).map_err(CreateClientError::NetworkdError)
+    }
+    let dev = self.name();
+    let existing_dev = self.netlink.address_device(ip.addr()).await?;
+    let own_device = existing_dev.as_deref()
+      .is_some_and(|existing_dev| is_own_device(existing_dev, self.tun_name.as_deref()));
+    match ExistingAddress::decide(existing_dev.as_deref(), dev, own_device, force) {
+      ExistingAddress::Absent => {}
+      ExistingAddress::Adopt => {
+        tracing::info!("address {} already assigned to {}: adopting", ip, dev);
+        self.addresses.lock().unwrap().push(ip);
+        return Ok(())
+      }
+      ExistingAddress::Recreate(existing_dev) => {
+        tracing::warn!("removing leftover device {} holding address {}", existing_dev, ip);
+        self.netlink.delete_link(&existing_dev).await?;
+      }
+      ExistingAddress::Conflict(reason) => {
+        tracing::error!("can't assign address {}: {}", ip, reason);
+        return Err(CreateClientError::IpAddrInUseError(reason))
+      }
+    }
+    tracing::debug!("adding ip addr: {}", ip);
+    self.netlink.add_address(self.index, ip).await?;
+    self.addresses.lock().unwrap().push(ip);
+    Ok(())
+  }
+
+  /// Route a network behind a peer to the tun
+  pub async fn add_route(&self, net: IpNet) -> Result<(), CreateClientError> {
+    if !self.manage_network {
+      tracing::debug!("not adding route {}: device configured externally", net);
+      return Ok(())
+    }
+    if let Some(networkd) = &self.networkd {
+      return networkd.add_route(net).map_err(CreateClientError::NetworkdError)
+    }
+    tracing::debug!("adding route: {}", net);
+    self.netlink.add_route(self.index, net).await?;
+    self.routes.lock().unwrap().push(net);
+    Ok(())
+  }
+
+  pub async fn remove_route(&self, net: IpNet) {
+    if !self.manage_network {
+      return
+    }
+    if let Some(networkd) = &self.networkd {
+      if let Err(err) = networkd.remove_route(net) {
+        tracing::warn!("failed to remove route {}: {:?}", net, err);
+      }
+      return
+    }
+    tracing::debug!("removing route: {}", net);
+    self.routes.lock().unwrap().retain(|route| *route != net);
+    if let Err(err) = self.netlink.delete_route(self.index, net).await {
+      tracing::warn!("failed to remove route {}: {:?}", net, err);
+    }
+  }
+
+  /// Route an address through the current default route instead of the tun, so that
+  /// the Reticulum underlay keeps working once the default route points to the tun
+  pub async fn add_bypass_route(&self, ip: IpAddr) -> Result<(), CreateClientError> {
+    if !self.manage_network {
+      tracing::debug!("not adding bypass route {}: device configured externally", ip);
+      return Ok(())
+    }
+    let Some((gateway, index)) = self.netlink.default_route(ip.is_ipv6()).await? else {
+      return Err(CreateClientError::ConfigError(
+        format!("no default route to reach {ip} outside the tunnel")))
+    };
+    tracing::debug!("adding bypass route: {} via {:?} (index {})", ip, gateway, index);
+    self.netlink.add_route_via(index, IpNet::from(ip), gateway).await?;
+    self.bypass_routes.lock().unwrap().push((index, IpNet::from(ip)));
+    Ok(())
+  }
+
+  /// Remove the routes and addresses assigned to the tun
+  pub async fn remove_addresses(&self) {
+    if let Some(networkd) = &self.networkd {
+      networkd.cleanup();
+    }
+    let routes = std::mem::take(&mut *self.routes.lock().unwrap());
+    for net in routes {
+      self.remove_route(net).await;
+    }
+    let bypass_routes = std::mem::take(&mut *self.bypass_routes.lock().unwrap());
+    for (index, net) in bypass_routes {
+      tracing::debug!("removing bypass route: {}", net);
+      if let Err(err) = self.netlink.delete_route(index, net).await {
+        tracing::warn!("failed to remove bypass route {}: {:?}", net, err);
+      }
+    }
+    let addresses = std::mem::take(&mut *self.addresses.lock().unwrap());
+    for ip in addresses {
+      tracing::debug!("removing ip addr: {}", ip);
+      if let Err(err) = self.netlink.delete_address(self.index, ip).await {
+        tracing::warn!("failed to remove address {}: {:?}", ip, err);
+      }
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    match &self.device {
+      Device::Tun(tun) => tun.name(),
+      Device::Tap(tap) => tap.name()
+    }
+  }
+
+  pub fn queues(&self) -> usize {
+    self.queues
+  }
+
+  /// Read a packet, or a frame in tap mode, from the given queue into the buffer,
+  /// returning its length
+  ///
+  /// A tun file descriptor delivers one packet per `read` and takes one per `write`:
+  /// `recvmmsg`-style batching only works on sockets. Moving several packets per
+  /// syscall needs `IFF_VNET_HDR` with TSO/GRO offload, which riptun doesn't expose;
+  /// extra queues (`tun_queues`) are the way to spread the per-packet cost instead.
+  pub async fn read(&self, queue: usize, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    match &self.device {
+      Device::Tun(tun) => tun.recv_via(queue, buf).await.map_err(std::io::Error::other),
+      Device::Tap(tap) => tap.read(buf).await
+    }
+  }
+
+  pub async fn send(&self, datagram: &[u8]) -> Result<usize, std::io::Error> {
+    match &self.device {
+      Device::Tun(tun) => tun.send(datagram).await,
+      Device::Tap(tap) => tap.send(datagram).await
+    }
+  }
+}
+
+/// What to do when the address to be assigned already exists on a device
+#[derive(Debug, PartialEq)]
+enum ExistingAddress {
+  /// Address does not exist
+  Absent,
+  /// Address is already assigned to our device
+  Adopt,
+  /// Address is held by a leftover device of ours which should be removed
+  Recreate(String),
+  /// Address is held by a device we don't own, or force is not set
+  Conflict(String)
+}
+
+impl ExistingAddress {
+  fn decide(existing_dev: Option<&str>, dev: &str, own_device: bool, force: bool) -> Self {
+    let Some(existing_dev) = existing_dev else {
+      return ExistingAddress::Absent
+    };
+    if existing_dev == dev {
+      ExistingAddress::Adopt
+    } else if !own_device {
+      ExistingAddress::Conflict(format!("address exists on device {existing_dev} \
+        not created by rns-vpn"))
+    } else if force {
+      ExistingAddress::Recreate(existing_dev.to_owned())
+    } else {
+      ExistingAddress::Conflict(format!("address exists on leftover device \
+        {existing_dev}: use --force to remove it"))
+    }
+  }
+}
+
+/// Whether a device name matches a tun or tap name or pattern used by the client
+fn is_own_device(dev: &str, tun_name: Option<&str>) -> bool {
+  [TUN_NAME, TAP_NAME].into_iter().chain(tun_name).any(|pattern| {
+    let Some((prefix, suffix)) = pattern.split_once("%d") else {
+      return dev == pattern
+    };
+    dev.strip_prefix(prefix).and_then(|dev| dev.strip_suffix(suffix))
+      .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn absent_address() {
+    assert_eq!(ExistingAddress::decide(None, "tun0", false, false),
+      ExistingAddress::Absent);
+  }
+
+  #[test]
+  fn adopts_address_on_own_device() {
+    assert_eq!(ExistingAddress::decide(Some("tun0"), "tun0", true, false),
+      ExistingAddress::Adopt);
+  }
+
+  #[test]
+  fn recreates_leftover_device_with_force() {
+    assert_eq!(ExistingAddress::decide(Some("tun1"), "tun0", true, true),
+      ExistingAddress::Recreate("tun1".to_owned()));
+  }
+
+  #[test]
+  fn leftover_device_conflicts_without_force() {
+    let decision = ExistingAddress::decide(Some("tun1"), "tun0", true, false);
+    assert!(matches!(decision, ExistingAddress::Conflict(reason)
+      if reason.contains("--force")));
+  }
+
+  #[test]
+  fn foreign_device_conflicts_even_with_force() {
+    let decision = ExistingAddress::decide(Some("eth0"), "tun0", false, true);
+    assert!(matches!(decision, ExistingAddress::Conflict(reason)
+      if reason.contains("not created by rns-vpn")));
+  }
+
+  #[test]
+  fn default_device_names() {
+    assert!(is_own_device("rip0", None));
+    assert!(is_own_device("riptap12", None));
+    assert!(!is_own_device("rip", None));
+    assert!(!is_own_device("ripx0", None));
+  }
+
+  #[test]
+  fn configured_device_pattern() {
+    assert!(is_own_device("tun0", Some("tun%d")));
+    assert!(is_own_device("tun12", Some("tun%d")));
+    assert!(!is_own_device("tun", Some("tun%d")));
+    assert!(!is_own_device("tunnel0", Some("tun%d")));
+    assert!(!is_own_device("tun0", None));
+  }
+
+  #[test]
+  fn configured_device_name() {
+    assert!(is_own_device("vpn", Some("vpn")));
+    assert!(!is_own_device("vpn0", Some("vpn")));
+  }
+}
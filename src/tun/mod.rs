@@ -0,0 +1,44 @@
+//! Platform tun device backends
+//!
+//! Each backend provides a `Tun` with `new`, `add_address`, `add_route`, `remove_route`,
+//! `add_bypass_route`, `remove_addresses`, `queues`, `read` and `send`; `read` reads
+//! from the given queue into a caller-owned buffer.
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+mod bsd;
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(target_os = "linux")]
+mod networkd;
+#[cfg(target_os = "linux")]
+mod tap;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+pub use bsd::Tun;
+#[cfg(target_os = "linux")]
+pub use linux::Tun;
+#[cfg(windows)]
+pub use windows::Tun;
+
+#[cfg(not(any(
+  target_os = "linux", target_os = "freebsd", target_os = "openbsd", windows
+)))]
+compile_error!("no tun backend for this platform");
+
+/// Whether the process runs with the privileges needed to create a tun device: root on
+/// unix, an elevated administrator on Windows
+#[cfg(unix)]
+pub fn is_privileged() -> bool {
+  nix::unistd::geteuid().is_root()
+}
+
+/// Whether the process runs with the privileges needed to create a tun device: root on
+/// unix, an elevated administrator on Windows
+#[cfg(windows)]
+pub fn is_privileged() -> bool {
+  unsafe { windows_sys::Win32::UI::Shell::IsUserAnAdmin() != 0 }
+}
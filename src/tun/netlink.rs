@@ -0,0 +1,179 @@
+//! Network device configuration over rtnetlink
+
+use std::net::IpAddr;
+
+use futures::TryStreamExt;
+use ipnet::IpNet;
+use netlink_packet_route::link::LinkAttribute;
+use netlink_packet_route::route::{RouteAddress, RouteAttribute, RouteHeader};
+use rtnetlink::IpVersion;
+
+use crate::CreateClientError;
+
+pub struct Netlink {
+  handle: rtnetlink::Handle
+}
+
+impl Netlink {
+  /// Open a netlink connection; must be called from within a tokio runtime
+  pub fn new() -> Result<Self, CreateClientError> {
+    let (connection, handle, _) = rtnetlink::new_connection()
+      .map_err(CreateClientError::NetlinkError)?;
+    tokio::spawn(connection);
+    Ok(Netlink { handle })
+  }
+
+  pub async fn link_index(&self, name: &str) -> Result<u32, CreateClientError> {
+    let link = self.handle.link().get().match_name(name.to_owned()).execute()
+      .try_next().await
+      .map_err(CreateClientError::IpLinkGetError)?
+      .ok_or_else(|| CreateClientError::IpLinkNotFoundError(name.to_owned()))?;
+    Ok(link.header.index)
+  }
+
+  async fn link_name(&self, index: u32) -> Result<Option<String>, CreateClientError> {
+    let link = self.handle.link().get().match_index(index).execute()
+      .try_next().await
+      .map_err(CreateClientError::IpLinkGetError)?;
+    Ok(link.and_then(|link| link.attributes.into_iter().find_map(|attribute| {
+      match attribute {
+        LinkAttribute::IfName(name) => Some(name),
+        _ => None
+      }
+    })))
+  }
+
+  /// Name of the device the address is currently assigned to, if any
+  pub async fn address_device(&self, addr: IpAddr)
+    -> Result<Option<String>, CreateClientError>
+  {
+    let address = self.handle.address().get().set_address_filter(addr).execute()
+      .try_next().await
+      .map_err(CreateClientError::IpAddrGetError)?;
+    match address {
+      Some(address) => self.link_name(address.header.index).await,
+      None => Ok(None)
+    }
+  }
+
+  /// Add an address to a device; this also installs the route for its prefix
+  pub async fn add_address(&self, index: u32, ip: IpNet) -> Result<(), CreateClientError> {
+    self.handle.address().add(index, ip.addr(), ip.prefix_len()).execute().await
+      .map_err(CreateClientError::IpAddrAddError)
+  }
+
+  /// Remove an address from a device; this also removes the route for its prefix
+  pub async fn delete_address(&self, index: u32, ip: IpNet) -> Result<(), CreateClientError> {
+    let mut addresses = self.handle.address().get().set_link_index_filter(index)
+      .set_address_filter(ip.addr()).execute();
+    while let Some(address) = addresses.try_next().await
+      .map_err(CreateClientError::IpAddrGetError)?
+    {
+      self.handle.address().del(address).execute().await
+        .map_err(CreateClientError::IpAddrDelError)?;
+    }
+    Ok(())
+  }
+
+  /// Route a network to a device
+  pub async fn add_route(&self, index: u32, net: IpNet) -> Result<(), CreateClientError> {
+    let route = self.handle.route().add().output_interface(index);
+    match net {
+      IpNet::V4(net) => route.v4().destination_prefix(net.addr(), net.prefix_len())
+        .execute().await,
+      IpNet::V6(net) => route.v6().destination_prefix(net.addr(), net.prefix_len())
+        .execute().await
+    }.map_err(|err| CreateClientError::IpRouteAddError(std::io::Error::other(err)))
+  }
+
+  /// Route a network to a device through a gateway, or on-link without one
+  pub async fn add_route_via(&self, index: u32, net: IpNet, gateway: Option<IpAddr>)
+    -> Result<(), CreateClientError>
+  {
+    let route = self.handle.route().add().output_interface(index);
+    match net {
+      IpNet::V4(net) => {
+        let mut route = route.v4().destination_prefix(net.addr(), net.prefix_len());
+        if let Some(IpAddr::V4(gateway)) = gateway {
+          route = route.gateway(gateway);
+        }
+        route.execute().await
+      }
+      IpNet::V6(net) => {
+        let mut route = route.v6().destination_prefix(net.addr(), net.prefix_len());
+        if let Some(IpAddr::V6(gateway)) = gateway {
+          route = route.gateway(gateway);
+        }
+        route.execute().await
+      }
+    }.map_err(|err| CreateClientError::IpRouteAddError(std::io::Error::other(err)))
+  }
+
+  /// Gateway and device of the default route in the main table
+  pub async fn default_route(&self, ipv6: bool)
+    -> Result<Option<(Option<IpAddr>, u32)>, CreateClientError>
+  {
+    let version = if ipv6 { IpVersion::V6 } else { IpVersion::V4 };
+    let mut routes = self.handle.route().get(version).execute();
+    while let Some(route) = routes.try_next().await
+      .map_err(|err| CreateClientError::IpRouteGetError(std::io::Error::other(err)))?
+    {
+      if route.header.destination_prefix_length != 0
+        || route.header.table != RouteHeader::RT_TABLE_MAIN
+      {
+        continue
+      }
+      let mut gateway = None;
+      let mut index = None;
+      for attribute in route.attributes {
+        match attribute {
+          RouteAttribute::Gateway(RouteAddress::Inet(addr)) => gateway = Some(addr.into()),
+          RouteAttribute::Gateway(RouteAddress::Inet6(addr)) => gateway = Some(addr.into()),
+          RouteAttribute::Oif(oif) => index = Some(oif),
+          _ => {}
+        }
+      }
+      if let Some(index) = index {
+        return Ok(Some((gateway, index)))
+      }
+    }
+    Ok(None)
+  }
+
+  /// Remove the route of a network to a device
+  pub async fn delete_route(&self, index: u32, net: IpNet) -> Result<(), CreateClientError> {
+    let route = self.handle.route().add().output_interface(index);
+    let message = match net {
+      IpNet::V4(net) => route.v4().destination_prefix(net.addr(), net.prefix_len())
+        .message_mut().clone(),
+      IpNet::V6(net) => route.v6().destination_prefix(net.addr(), net.prefix_len())
+        .message_mut().clone()
+    };
+    self.handle.route().del(message).execute().await
+      .map_err(|err| CreateClientError::IpRouteDelError(std::io::Error::other(err)))
+  }
+
+  pub async fn set_link_up(&self, index: u32) -> Result<(), CreateClientError> {
+    self.handle.link().set(index).up().execute().await
+      .map_err(CreateClientError::IpLinkUpError)
+  }
+
+  pub async fn set_link_mtu(&self, index: u32, mtu: u16) -> Result<(), CreateClientError> {
+    self.handle.link().set(index).mtu(mtu as u32).execute().await
+      .map_err(CreateClientError::IpLinkSetMtuError)
+  }
+
+  /// Enslave a device to a bridge
+  pub async fn set_link_controller(&self, index: u32, controller: u32)
+    -> Result<(), CreateClientError>
+  {
+    self.handle.link().set(index).controller(controller).execute().await
+      .map_err(CreateClientError::IpLinkSetControllerError)
+  }
+
+  pub async fn delete_link(&self, name: &str) -> Result<(), CreateClientError> {
+    let index = self.link_index(name).await?;
+    self.handle.link().del(index).execute().await
+      .map_err(CreateClientError::IpLinkDeleteError)
+  }
+}
@@ -0,0 +1,103 @@
+//! Handoff of the tun device to systemd-networkd: its addresses, routes and MTU are
+//! written to a `.network` file matching the device, rewritten as they change, and
+//! networkd is reloaded to apply them; the file is removed again on shutdown
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use ipnet::IpNet;
+
+/// Runtime networkd config, gone after a reboot like the device itself
+const NETWORK_DIR: &str = "/run/systemd/network";
+
+pub struct Networkd {
+  path: PathBuf,
+  dev: String,
+  mtu: u16,
+  bridge: Option<String>,
+  addresses: std::sync::Mutex<Vec<IpNet>>,
+  routes: std::sync::Mutex<Vec<IpNet>>
+}
+
+impl Networkd {
+  /// Hand the device over to networkd with the given addresses
+  pub fn new(dev: &str, mtu: u16, bridge: Option<String>, addresses: Vec<IpNet>)
+    -> Result<Self, std::io::Error>
+  {
+    let networkd = Networkd {
+      path: PathBuf::from(NETWORK_DIR).join(format!("50-rns-vpn-{dev}.network")),
+      dev: dev.to_owned(),
+      mtu,
+      bridge,
+      addresses: std::sync::Mutex::new(addresses),
+      routes: std::sync::Mutex::new(Vec::new())
+    };
+    tracing::info!("handing {} over to systemd-networkd: {}", dev,
+      networkd.path.display());
+    networkd.apply()?;
+    Ok(networkd)
+  }
+
+  pub fn add_address(&self, ip: IpNet) -> Result<(), std::io::Error> {
+    tracing::debug!("adding ip addr to {}: {}", self.path.display(), ip);
+    self.addresses.lock().unwrap().push(ip);
+    self.apply()
+  }
+
+  pub fn add_route(&self, net: IpNet) -> Result<(), std::io::Error> {
+    tracing::debug!("adding route to {}: {}", self.path.display(), net);
+    self.routes.lock().unwrap().push(net);
+    self.apply()
+  }
+
+  pub fn remove_route(&self, net: IpNet) -> Result<(), std::io::Error> {
+    tracing::debug!("removing route from {}: {}", self.path.display(), net);
+    self.routes.lock().unwrap().retain(|route| *route != net);
+    self.apply()
+  }
+
+  /// Remove the `.network` file; the device goes away with the client
+  pub fn cleanup(&self) {
+    tracing::debug!("removing {}", self.path.display());
+    if let Err(err) = std::fs::remove_file(&self.path) {
+      tracing::warn!("failed to remove {}: {:?}", self.path.display(), err);
+      return
+    }
+    if let Err(err) = reload() {
+      tracing::warn!(error = ?err, "failed to reload systemd-networkd");
+    }
+  }
+
+  /// Write the `.network` file and have networkd apply it
+  fn apply(&self) -> Result<(), std::io::Error> {
+    let mut network = format!("# written by rns-vpn, removed when it stops\n\
+      [Match]\nName={}\n\n[Link]\nMTUBytes={}\n\n\
+      [Network]\nLinkLocalAddressing=no\nIPv6AcceptRA=no\n", self.dev, self.mtu);
+    if let Some(bridge) = &self.bridge {
+      network.push_str(&format!("Bridge={bridge}\n"));
+    }
+    for ip in self.addresses.lock().unwrap().iter() {
+      network.push_str(&format!("Address={ip}\n"));
+    }
+    for net in self.routes.lock().unwrap().iter() {
+      network.push_str(&format!("\n[Route]\nDestination={net}\n"));
+    }
+    std::fs::create_dir_all(NETWORK_DIR)?;
+    // replace the file atomically so networkd never reads it half written
+    let tmp_path = self.path.with_extension("tmp");
+    std::fs::write(&tmp_path, network)?;
+    std::fs::rename(&tmp_path, &self.path)?;
+    reload()
+  }
+}
+
+fn reload() -> Result<(), std::io::Error> {
+  tracing::debug!("networkctl reload");
+  let output = Command::new("networkctl").arg("reload").output()?;
+  if output.status.success() {
+    Ok(())
+  } else {
+    Err(std::io::Error::other(format!("networkctl failed: {}",
+      String::from_utf8_lossy(&output.stderr).trim())))
+  }
+}
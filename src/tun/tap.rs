@@ -0,0 +1,70 @@
+//! Linux tap device carrying Ethernet frames, opened from `/dev/net/tun`
+
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use nix::libc;
+use tokio::io::unix::AsyncFd;
+
+const TUN_DEVICE: &str = "/dev/net/tun";
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+nix::ioctl_write_ptr_bad!(tun_set_iff, nix::request_code_write!(b'T', 202,
+  std::mem::size_of::<libc::c_int>()), IfReq);
+
+/// `struct ifreq` with the flags member of its union
+#[repr(C)]
+struct IfReq {
+  name: [u8; libc::IFNAMSIZ],
+  flags: libc::c_short,
+  _pad: [u8; 22]
+}
+
+pub struct Tap {
+  fd: AsyncFd<std::fs::File>,
+  name: String
+}
+
+impl Tap {
+  /// Create a tap device from a name pattern such as `rtap%d`
+  pub fn new(name: &str) -> Result<Self, std::io::Error> {
+    let file = std::fs::OpenOptions::new().read(true).write(true)
+      .custom_flags(libc::O_NONBLOCK).open(TUN_DEVICE)?;
+    let mut req = IfReq {
+      name: [0; libc::IFNAMSIZ], flags: IFF_TAP | IFF_NO_PI, _pad: [0; 22]
+    };
+    let len = name.len().min(libc::IFNAMSIZ - 1);
+    req.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    unsafe { tun_set_iff(file.as_raw_fd(), &req) }?;
+    let len = req.name.iter().position(|b| *b == 0).unwrap_or(req.name.len());
+    let name = String::from_utf8_lossy(&req.name[..len]).into_owned();
+    Ok(Tap { fd: AsyncFd::new(file)?, name })
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Read an Ethernet frame into the buffer, returning its length
+  pub async fn read(&self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    loop {
+      let mut guard = self.fd.readable().await?;
+      match guard.try_io(|fd| fd.get_ref().read(buf)) {
+        Ok(result) => return result,
+        Err(_would_block) => continue
+      }
+    }
+  }
+
+  pub async fn send(&self, frame: &[u8]) -> Result<usize, std::io::Error> {
+    loop {
+      let mut guard = self.fd.writable().await?;
+      match guard.try_io(|fd| fd.get_ref().write(frame)) {
+        Ok(result) => return result,
+        Err(_would_block) => continue
+      }
+    }
+  }
+}
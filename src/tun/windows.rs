@@ -0,0 +1,235 @@
+//! Windows tun device using wintun, configured with the IP Helper API
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use windows_sys::Win32::NetworkManagement::IpHelper::{
+  ConvertInterfaceIndexToLuid, CreateIpForwardEntry2, CreateUnicastIpAddressEntry,
+  DeleteIpForwardEntry2, DeleteUnicastIpAddressEntry, GetIpInterfaceEntry,
+  InitializeIpForwardEntry, InitializeIpInterfaceEntry, InitializeUnicastIpAddressEntry,
+  SetIpInterfaceEntry, MIB_IPFORWARD_ROW2, MIB_IPINTERFACE_ROW, MIB_UNICASTIPADDRESS_ROW
+};
+use windows_sys::Win32::NetworkManagement::Ndis::NET_LUID_LH;
+use windows_sys::Win32::Networking::WinSock::{AF_INET, AF_INET6, SOCKADDR_INET};
+
+use crate::{Config, CreateClientError};
+
+const ADAPTER_NAME: &str = "rns-vpn";
+const TUNNEL_TYPE: &str = "Reticulum";
+/// Packets received from the adapter waiting to be read
+const READ_QUEUE_LEN: usize = 64;
+/// Windows error code for an address that is already assigned
+const ERROR_OBJECT_ALREADY_EXISTS: u32 = 5010;
+
+pub struct Tun {
+  session: Arc<wintun::Session>,
+  luid: NET_LUID_LH,
+  read_rx: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<wintun::Packet>>,
+  /// Addresses assigned to the adapter, removed again on teardown
+  addresses: std::sync::Mutex<Vec<IpNet>>,
+  /// Routes to the adapter added for networks behind peers
+  routes: std::sync::Mutex<Vec<IpNet>>,
+  name: String,
+  /// Kept so the adapter lives as long as the session
+  _adapter: Arc<wintun::Adapter>
+}
+
+impl Tun {
+  pub async fn new(addresses: &[IpNet], config: &Config)
+    -> Result<Self, CreateClientError>
+  {
+    if config.tun_queues > 1 {
+      tracing::warn!("multiple tun queues are not supported on this platform: using 1");
+    }
+    tracing::debug!("loading wintun");
+    let wintun = unsafe { wintun::load() }.map_err(CreateClientError::WintunError)?;
+    tracing::debug!("creating wintun adapter");
+    let name = config.tun_name.as_deref().unwrap_or(ADAPTER_NAME);
+    let adapter = wintun::Adapter::create(&wintun, name, TUNNEL_TYPE, None)
+      .map_err(CreateClientError::WintunError)?;
+    let index = adapter.get_adapter_index().map_err(CreateClientError::WintunError)?;
+    tracing::debug!("created wintun adapter: {} (index {})", name, index);
+    let mut luid: NET_LUID_LH = unsafe { std::mem::zeroed() };
+    check(unsafe { ConvertInterfaceIndexToLuid(index, &mut luid) })?;
+    let session = Arc::new(adapter.start_session(wintun::MAX_RING_CAPACITY)
+      .map_err(CreateClientError::WintunError)?);
+    // wintun only offers a blocking receive: forward packets from a dedicated thread
+    let (read_tx, read_rx) = tokio::sync::mpsc::channel(READ_QUEUE_LEN);
+    let receiver = session.clone();
+    std::thread::spawn(move || {
+      while let Ok(packet) = receiver.receive_blocking() {
+        if read_tx.blocking_send(packet).is_err() {
+          break
+        }
+      }
+      tracing::debug!("wintun receive thread exited");
+    });
+    let adapter = Tun {
+      session, luid, read_rx: tokio::sync::Mutex::new(read_rx),
+      addresses: std::sync::Mutex::new(Vec::new()),
+      routes: std::sync::Mutex::new(Vec::new()), name: name.to_owned(), _adapter: adapter
+    };
+    // adding an address also installs the route for its prefix
+    for ip in addresses.iter() {
+      adapter.add_address(*ip, config.force).await?;
+    }
+    if let Some(management_ip) = config.management_ip {
+      tracing::debug!("adding management address");
+      adapter.add_address(management_ip, config.force).await?;
+    }
+    tracing::debug!("{} setting mtu {}", adapter.name, config.mtu);
+    for family in [AF_INET, AF_INET6] {
+      adapter.set_mtu(family, config.mtu)?;
+    }
+    Ok(adapter)
+  }
+
+  /// Set the MTU of the adapter for an address family
+  fn set_mtu(&self, family: u16, mtu: u16) -> Result<(), CreateClientError> {
+    let mut row: MIB_IPINTERFACE_ROW = unsafe { std::mem::zeroed() };
+    unsafe {
+      InitializeIpInterfaceEntry(&mut row);
+      row.Family = family;
+      row.InterfaceLuid = self.luid;
+      check(GetIpInterfaceEntry(&mut row))?;
+      row.NlMtu = mtu as u32;
+      // must be zeroed when setting an IPv4 interface entry
+      row.SitePrefixLength = 0;
+      check(SetIpInterfaceEntry(&mut row))
+    }
+  }
+
+  /// Add an address to the adapter; an address already assigned to it is adopted
+  pub async fn add_address(&self, ip: IpNet, _force: bool) -> Result<(), CreateClientError> {
+    tracing::debug!("adding ip addr: {}", ip);
+    let row = self.address_row(ip);
+    let result = unsafe { CreateUnicastIpAddressEntry(&row) };
+    if result == ERROR_OBJECT_ALREADY_EXISTS {
+      tracing::info!("address {} already assigned to {}: adopting", ip, self.name);
+    } else {
+      check(result)?;
+    }
+    self.addresses.lock().unwrap().push(ip);
+    Ok(())
+  }
+
+  /// Route a network behind a peer to the adapter
+  pub async fn add_route(&self, net: IpNet) -> Result<(), CreateClientError> {
+    tracing::debug!("adding route: {}", net);
+    let row = self.route_row(net);
+    check(unsafe { CreateIpForwardEntry2(&row) }).map_err(|err| match err {
+      CreateClientError::IpHelperError(err) => CreateClientError::IpRouteAddError(err),
+      err => err
+    })?;
+    self.routes.lock().unwrap().push(net);
+    Ok(())
+  }
+
+  pub async fn remove_route(&self, net: IpNet) {
+    tracing::debug!("removing route: {}", net);
+    self.routes.lock().unwrap().retain(|route| *route != net);
+    let row = self.route_row(net);
+    if let Err(err) = check(unsafe { DeleteIpForwardEntry2(&row) }) {
+      tracing::warn!("failed to remove route {}: {:?}", net, err);
+    }
+  }
+
+  /// Routing the Reticulum underlay around a default route through the adapter is not
+  /// implemented on Windows
+  pub async fn add_bypass_route(&self, ip: IpAddr) -> Result<(), CreateClientError> {
+    Err(CreateClientError::ConfigError(
+      format!("can't route {ip} outside the tunnel: exit_node is not supported on Windows")))
+  }
+
+  /// Remove the routes and addresses assigned to the adapter
+  pub async fn remove_addresses(&self) {
+    let routes = std::mem::take(&mut *self.routes.lock().unwrap());
+    for net in routes {
+      self.remove_route(net).await;
+    }
+    let addresses = std::mem::take(&mut *self.addresses.lock().unwrap());
+    for ip in addresses {
+      tracing::debug!("removing ip addr: {}", ip);
+      let row = self.address_row(ip);
+      if let Err(err) = check(unsafe { DeleteUnicastIpAddressEntry(&row) }) {
+        tracing::warn!("failed to remove address {}: {:?}", ip, err);
+      }
+    }
+  }
+
+  fn address_row(&self, ip: IpNet) -> MIB_UNICASTIPADDRESS_ROW {
+    let mut row: MIB_UNICASTIPADDRESS_ROW = unsafe { std::mem::zeroed() };
+    unsafe {
+      InitializeUnicastIpAddressEntry(&mut row);
+      row.InterfaceLuid = self.luid;
+      set_sockaddr(&mut row.Address, ip.addr());
+    }
+    row.OnLinkPrefixLength = ip.prefix_len();
+    row
+  }
+
+  /// On-link route of a network to the adapter
+  fn route_row(&self, net: IpNet) -> MIB_IPFORWARD_ROW2 {
+    let mut row: MIB_IPFORWARD_ROW2 = unsafe { std::mem::zeroed() };
+    unsafe {
+      InitializeIpForwardEntry(&mut row);
+      row.InterfaceLuid = self.luid;
+      set_sockaddr(&mut row.DestinationPrefix.Prefix, net.trunc().addr());
+      // unspecified next hop of the same family: the network is on-link
+      let unspecified = match net {
+        IpNet::V4(_) => IpAddr::from([0u8; 4]),
+        IpNet::V6(_) => IpAddr::from([0u8; 16])
+      };
+      set_sockaddr(&mut row.NextHop, unspecified);
+    }
+    row.DestinationPrefix.PrefixLength = net.prefix_len();
+    row
+  }
+
+  /// Only a single queue is supported
+  pub fn queues(&self) -> usize {
+    1
+  }
+
+  /// Read a packet into the buffer, returning its length; longer packets are truncated
+  pub async fn read(&self, _queue: usize, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    let packet = self.read_rx.lock().await.recv().await.ok_or_else(|| std::io::Error::new(
+      std::io::ErrorKind::BrokenPipe, "wintun session closed"))?;
+    let nbytes = packet.bytes().len().min(buf.len());
+    buf[..nbytes].copy_from_slice(&packet.bytes()[..nbytes]);
+    Ok(nbytes)
+  }
+
+  pub async fn send(&self, datagram: &[u8]) -> Result<usize, std::io::Error> {
+    let len = u16::try_from(datagram.len()).map_err(|_| std::io::Error::new(
+      std::io::ErrorKind::InvalidInput, "packet too large"))?;
+    let mut packet = self.session.allocate_send_packet(len)
+      .map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+    packet.bytes_mut().copy_from_slice(datagram);
+    self.session.send_packet(packet);
+    Ok(datagram.len())
+  }
+}
+
+unsafe fn set_sockaddr(sockaddr: &mut SOCKADDR_INET, addr: IpAddr) {
+  match addr {
+    IpAddr::V4(addr) => {
+      sockaddr.Ipv4.sin_family = AF_INET;
+      sockaddr.Ipv4.sin_addr.S_un.S_addr = u32::from_ne_bytes(addr.octets());
+    }
+    IpAddr::V6(addr) => {
+      sockaddr.Ipv6.sin6_family = AF_INET6;
+      sockaddr.Ipv6.sin6_addr.u.Byte = addr.octets();
+    }
+  }
+}
+
+/// Map a Win32 error code returned by the IP Helper API
+fn check(result: u32) -> Result<(), CreateClientError> {
+  if result == 0 {
+    Ok(())
+  } else {
+    Err(CreateClientError::IpHelperError(std::io::Error::from_raw_os_error(result as i32)))
+  }
+}